@@ -0,0 +1,137 @@
+//! ACL policies: declare the ACLs expected on paths, and find nodes that drift from them.
+//!
+//! A policy is checked node by node, so the same policy can be used on a live subtree or on the
+//! nodes of a snapshot.
+
+use crate::error::ParseError;
+use crate::path::PathMatcher;
+use crate::proto::SetACLRequest;
+use crate::OptionalVersion;
+use crate::Version;
+use crate::ACL;
+
+/// A policy rule: nodes matching `matcher` should have exactly `acl`.
+#[derive(Debug, Clone)]
+pub struct AclRule {
+    pub matcher: PathMatcher,
+    pub acl: Vec<ACL>,
+}
+
+/// An ordered list of rules. The first rule matching a path defines its expected ACLs, and paths
+/// that match no rule aren't checked.
+#[derive(Debug, Clone, Default)]
+pub struct AclPolicy {
+    rules: Vec<AclRule>,
+}
+
+impl AclPolicy {
+    pub fn new() -> AclPolicy {
+        Self::default()
+    }
+
+    /// Add a rule. `pattern` is parsed with `PathMatcher::parse`.
+    pub fn rule(mut self, pattern: &str, acl: Vec<ACL>) -> Result<AclPolicy, ParseError> {
+        self.rules.push(AclRule {
+            matcher: PathMatcher::parse(pattern)?,
+            acl,
+        });
+        Ok(self)
+    }
+
+    pub fn rules(&self) -> &[AclRule] {
+        &self.rules
+    }
+
+    /// ACLs expected on `path`, if a rule matches it
+    pub fn expected(&self, path: &str) -> Option<&[ACL]> {
+        self.rules
+            .iter()
+            .find(|r| r.matcher.matches(path))
+            .map(|r| r.acl.as_slice())
+    }
+
+    /// Compare the actual ACLs of a node to the policy. The order of ACL entries isn't significant.
+    pub fn check(&self, path: &str, actual: &[ACL]) -> Option<AclDrift> {
+        let expected = self.expected(path)?;
+
+        let same = expected.len() == actual.len()
+            && expected.iter().all(|e| actual.contains(e))
+            && actual.iter().all(|a| expected.contains(a));
+
+        if same {
+            None
+        } else {
+            Some(AclDrift {
+                path: path.to_owned(),
+                expected: expected.to_vec(),
+                actual: actual.to_vec(),
+            })
+        }
+    }
+}
+
+/// A node whose ACLs differ from the policy.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AclDrift {
+    pub path: String,
+    pub expected: Vec<ACL>,
+    pub actual: Vec<ACL>,
+}
+
+impl AclDrift {
+    /// Expected entries that are not on the node
+    pub fn missing(&self) -> Vec<&ACL> {
+        self.expected.iter().filter(|e| !self.actual.contains(e)).collect()
+    }
+
+    /// Entries on the node that are not expected
+    pub fn extra(&self) -> Vec<&ACL> {
+        self.actual.iter().filter(|a| !self.expected.contains(a)).collect()
+    }
+
+    /// A request that sets the expected ACLs. It will fail if the node's ACLs have changed since
+    /// `aversion` was read, so that a concurrent change isn't overwritten.
+    pub fn repair(&self, aversion: Version) -> SetACLRequest {
+        SetACLRequest {
+            path: self.path.clone(),
+            acl: self.expected.clone(),
+            version: OptionalVersion(aversion.0),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Id, PERM_ALL, PERM_READ};
+
+    #[test]
+    fn check_policy() {
+        let admin = ACL {
+            perms: PERM_ALL,
+            id: Id::new("digest", "admin:xxx"),
+        };
+        let read = ACL {
+            perms: PERM_READ,
+            id: Id::anyone(),
+        };
+
+        let policy = AclPolicy::new()
+            .rule("/app/secret/**", vec![admin.clone()])
+            .unwrap()
+            .rule("/app/**", vec![admin.clone(), read.clone()])
+            .unwrap();
+
+        assert!(policy.check("/other", &[]).is_none());
+        assert!(policy.check("/app/foo", &[read.clone(), admin.clone()]).is_none());
+
+        let drift = policy.check("/app/secret/key", std::slice::from_ref(&read)).unwrap();
+        assert_eq!(drift.missing(), vec![&admin]);
+        assert_eq!(drift.extra(), vec![&read]);
+
+        let request = drift.repair(Version(3));
+        assert_eq!(request.path, "/app/secret/key");
+        assert_eq!(request.acl, vec![admin]);
+        assert_eq!(request.version, OptionalVersion(3));
+    }
+}
@@ -0,0 +1,147 @@
+//! Authentication schemes: the credentials clients send in `AuthPacket`s, and the ids that ACLs
+//! give them.
+//!
+//! See the `AuthenticationProvider` implementations of the ZooKeeper server. With the `digest`
+//! scheme, clients send `user:password` and ACLs use `user:base64(sha1(user:password))`, so that
+//! ACL tools can compute ids offline from credentials. The `ip` and `x509` schemes identify clients
+//! by their address and TLS certificate: the content of their auth packets is ignored. The super
+//! user is a `digest` user, whose digest is set on servers with the
+//! `zookeeper.DigestAuthenticationProvider.superDigest` system property.
+
+use std::net::IpAddr;
+
+use sha1::{Digest, Sha1};
+
+use crate::error::ParseError;
+use crate::proto::{AuthPacket, OpCode};
+use crate::Id;
+
+pub const DIGEST_SCHEME: &str = "digest";
+pub const IP_SCHEME: &str = "ip";
+pub const X509_SCHEME: &str = "x509";
+pub const WORLD_SCHEME: &str = "world";
+
+/// Name of the super user, with the digest scheme
+pub const SUPER_USER: &str = "super";
+
+impl AuthPacket {
+    pub fn new(scheme: &str, auth: &[u8]) -> AuthPacket {
+        AuthPacket {
+            // Always 0, see ClientCnxn.java
+            typ: OpCode::Notification,
+            scheme: scheme.to_owned(),
+            buffer: auth.to_vec(),
+        }
+    }
+
+    /// Credentials of a `digest` user.
+    pub fn digest(user: &str, password: &str) -> AuthPacket {
+        Self::new(DIGEST_SCHEME, format!("{}:{}", user, password).as_bytes())
+    }
+
+    /// Credentials of the super user.
+    pub fn super_user(password: &str) -> AuthPacket {
+        Self::digest(SUPER_USER, password)
+    }
+
+    /// Authenticate with the client's address.
+    pub fn ip() -> AuthPacket {
+        Self::new(IP_SCHEME, &[])
+    }
+
+    /// Authenticate with the client certificate of a TLS connection.
+    pub fn x509() -> AuthPacket {
+        Self::new(X509_SCHEME, &[])
+    }
+}
+
+impl Id {
+    /// Id of a `digest` user, as found in ACLs.
+    pub fn digest(user: &str, password: &str) -> Id {
+        Id::new(DIGEST_SCHEME, &digest(user, password))
+    }
+
+    /// Id of clients whose address is `addr`, or in the `addr/bits` network.
+    pub fn ip(addr: &str) -> Result<Id, ParseError> {
+        let (ip, bits) = match addr.find('/') {
+            Some(idx) => (&addr[..idx], Some(&addr[idx + 1..])),
+            None => (addr, None),
+        };
+
+        let ip = ip.parse::<IpAddr>().map_err(|_| ParseError::invalid("ip id", addr))?;
+        let max_bits = if ip.is_ipv4() { 32 } else { 128 };
+        if let Some(bits) = bits {
+            match bits.parse::<u8>() {
+                Ok(bits) if bits <= max_bits => {}
+                _ => return Err(ParseError::invalid("ip id", addr)),
+            }
+        }
+
+        Ok(Id::new(IP_SCHEME, addr))
+    }
+
+    /// Id of clients whose certificate has this subject, as an RFC 2253 distinguished name, e.g.
+    /// `CN=client,O=example`.
+    pub fn x509(subject: &str) -> Id {
+        Id::new(X509_SCHEME, subject)
+    }
+}
+
+/// The digest of a user's credentials, `user:base64(sha1(user:password))`.
+pub fn digest(user: &str, password: &str) -> String {
+    let hash = Sha1::digest(format!("{}:{}", user, password).as_bytes());
+    format!("{}:{}", user, base64(&hash))
+}
+
+/// Value of the `zookeeper.DigestAuthenticationProvider.superDigest` system property of servers,
+/// for a super user with this password.
+pub fn super_digest(password: &str) -> String {
+    digest(SUPER_USER, password)
+}
+
+/// Standard base64 with padding, to avoid a dependency for a single use.
+fn base64(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+    let mut result = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |n, (i, b)| n | (*b as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                result.push(ALPHABET[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                result.push('=');
+            }
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn digest_ids() {
+        assert_eq!(super_digest("admin"), "super:xQJmxLMiHGwaqBvst5y6rkB6HQs=");
+        assert_eq!(
+            Id::digest("bob", "secret"),
+            Id::new("digest", "bob:fyVmFCwVbTJYrznoSu1koqYEYF0=")
+        );
+        assert_eq!(AuthPacket::digest("bob", "secret").buffer, b"bob:secret");
+        assert_eq!(AuthPacket::super_user("admin").scheme, "digest");
+
+        assert_eq!(base64(b"a"), "YQ==");
+        assert_eq!(base64(b"ab"), "YWI=");
+        assert_eq!(base64(b"abc"), "YWJj");
+        assert_eq!(base64(b""), "");
+
+        assert_eq!(Id::ip("10.0.0.0/8").unwrap().id, "10.0.0.0/8");
+        assert!(Id::ip("fe80::1/64").is_ok());
+        assert!(Id::ip("10.0.0.0/33").is_err());
+        assert!(Id::ip("zk1").is_err());
+    }
+}
@@ -0,0 +1,240 @@
+//! Restore points of a backup repository.
+//!
+//! A backup can restore an ensemble to the zxid of one of its snapshots, and to any later zxid
+//! reached by replaying its txnlogs, and those of the incremental backups that follow it, without
+//! a gap. Snapshots are fuzzy: the txns that follow a snapshot's zxid are replayed on top of it,
+//! even if some were already applied to it.
+
+use std::path::{Path, PathBuf};
+
+use super::manifest::{BackupFile, BackupManifest, FileKind, MANIFEST_FILE};
+use crate::error::BackupError;
+use crate::Zxid;
+
+/// A range of zxids that can be restored from a snapshot and the txnlogs that follow it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RestorePoint {
+    /// Backup containing the files
+    pub backup: String,
+    /// Name of the ensemble the backup was taken from
+    pub ensemble: String,
+    /// Zxid of the snapshot, which is also the first zxid that can be restored
+    pub snapshot: Zxid,
+    /// Last zxid that can be restored
+    pub last_zxid: Zxid,
+    /// The snapshot file followed by the txnlogs to replay, relative to the repository
+    pub files: Vec<PathBuf>,
+}
+
+impl RestorePoint {
+    pub fn contains(&self, zxid: Zxid) -> bool {
+        self.snapshot <= zxid && zxid <= self.last_zxid
+    }
+}
+
+/// Does a txnlog starting at `first` directly follow a txn at `last`? The first txnlog of a new
+/// epoch follows any txn of an older epoch, as a leader election starts a new log.
+pub(crate) fn follows(last: Zxid, first: Zxid) -> bool {
+    let counter = first.0 & 0xffff_ffff;
+    first.0 <= last.0.saturating_add(1) || ((first.0 >> 32) > (last.0 >> 32) && counter <= 1)
+}
+
+/// Path of a backup file, relative to the repository
+fn file_path(manifest: &BackupManifest, file: &BackupFile) -> PathBuf {
+    Path::new(&manifest.id).join(&file.path)
+}
+
+/// Replay txnlogs ordered by zxid from `zxid`, returning the last zxid reached and the txnlogs
+/// that were used.
+fn replay(zxid: Zxid, logs: &[(&BackupManifest, &BackupFile)]) -> (Zxid, Vec<PathBuf>) {
+    let mut end = zxid;
+    let mut used = Vec::new();
+    for (manifest, log) in logs {
+        if log.last_zxid <= end {
+            continue;
+        }
+        if !follows(end, log.first_zxid) {
+            break;
+        }
+        end = log.last_zxid;
+        used.push(file_path(manifest, log));
+    }
+    (end, used)
+}
+
+/// The manifests of a backup repository.
+#[derive(Debug, Clone, Default)]
+pub struct Catalog {
+    manifests: Vec<BackupManifest>,
+}
+
+impl Catalog {
+    pub fn new(manifests: Vec<BackupManifest>) -> Catalog {
+        let mut catalog = Catalog { manifests };
+        catalog
+            .manifests
+            .sort_by(|a, b| (a.created, &a.id).cmp(&(b.created, &b.id)));
+        catalog
+    }
+
+    /// Read the manifests of all backups in a repository directory. Subdirectories without a
+    /// manifest, e.g. backups in progress, are ignored.
+    pub fn open(repository: impl AsRef<Path>) -> Result<Catalog, BackupError> {
+        let mut manifests = Vec::new();
+        for entry in std::fs::read_dir(repository)? {
+            let path = entry?.path();
+            if path.join(MANIFEST_FILE).is_file() {
+                manifests.push(BackupManifest::read(&path)?);
+            }
+        }
+        Ok(Catalog::new(manifests))
+    }
+
+    /// Manifests, oldest first.
+    pub fn manifests(&self) -> &[BackupManifest] {
+        &self.manifests
+    }
+
+    pub fn manifest(&self, id: &str) -> Option<&BackupManifest> {
+        self.manifests.iter().find(|m| m.id == id)
+    }
+
+    /// A backup and the incremental backups that descend from it.
+    pub fn lineage(&self, id: &str) -> Vec<&BackupManifest> {
+        let mut lineage = self.manifests.iter().filter(|m| m.id == id).collect::<Vec<_>>();
+        // Manifests are ordered by creation time, parents first
+        for manifest in &self.manifests {
+            if let Some(parent) = &manifest.parent {
+                if lineage.iter().any(|m| &m.id == parent) {
+                    lineage.push(manifest);
+                }
+            }
+        }
+        lineage
+    }
+
+    /// Restore points of the snapshots of a backup.
+    fn backup_restore_points(&self, manifest: &BackupManifest) -> Vec<RestorePoint> {
+        let mut logs = self
+            .lineage(&manifest.id)
+            .into_iter()
+            .flat_map(|m| m.files(FileKind::Txnlog).into_iter().map(move |f| (m, f)))
+            .collect::<Vec<_>>();
+        logs.sort_by_key(|(_, f)| (f.first_zxid, f.last_zxid));
+
+        manifest
+            .files(FileKind::Snapshot)
+            .into_iter()
+            .map(|snapshot| {
+                let (last_zxid, txnlogs) = replay(snapshot.first_zxid, &logs);
+                let mut files = vec![file_path(manifest, snapshot)];
+                files.extend(txnlogs);
+                RestorePoint {
+                    backup: manifest.id.clone(),
+                    ensemble: manifest.ensemble.name.clone(),
+                    snapshot: snapshot.first_zxid,
+                    last_zxid,
+                    files,
+                }
+            })
+            .collect()
+    }
+
+    /// Restore points that can restore some zxid between `from` and `to` (inclusive), ordered by
+    /// snapshot zxid.
+    pub fn restore_points(&self, from: Zxid, to: Zxid) -> Vec<RestorePoint> {
+        let mut points = self
+            .manifests
+            .iter()
+            .flat_map(|m| self.backup_restore_points(m))
+            .filter(|p| p.snapshot <= to && p.last_zxid >= from)
+            .collect::<Vec<_>>();
+        points.sort_by(|a, b| (a.snapshot, &a.backup).cmp(&(b.snapshot, &b.backup)));
+        points
+    }
+
+    /// The restore point for a zxid with the most recent snapshot, which needs the fewest txns to
+    /// be replayed.
+    pub fn restore_point(&self, zxid: Zxid) -> Option<RestorePoint> {
+        self.restore_points(zxid, zxid).into_iter().next_back()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backup::manifest::{Compression, Ensemble};
+    use crate::persistence::digest::FileDigest;
+    use crate::persistence::testing::temp_dir;
+    use crate::Timestamp;
+
+    fn manifest(id: &str, created: u64, files: &[(FileKind, i64, i64)]) -> BackupManifest {
+        let ensemble = Ensemble {
+            name: "prod".to_owned(),
+            servers: vec!["zk1:2181".to_owned()],
+        };
+        let mut manifest = BackupManifest::new(id, ensemble, Timestamp(created));
+        for (kind, first, last) in files {
+            let prefix = if *kind == FileKind::Snapshot { "snapshot" } else { "log" };
+            manifest.files.push(BackupFile {
+                kind: *kind,
+                path: format!("{}.{:x}", prefix, first),
+                first_zxid: Zxid(*first),
+                last_zxid: Zxid(*last),
+                compression: Compression::Zstd,
+                digest: FileDigest::compute(&[][..], 16).unwrap(),
+            });
+        }
+        manifest
+    }
+
+    #[test]
+    fn catalog_restore_points() {
+        use FileKind::*;
+
+        let repository = temp_dir("catalog-restore-points");
+        let epoch2 = 2 << 32;
+
+        // Gap between 20 and 30
+        let first = manifest(
+            "first",
+            1,
+            &[(Snapshot, 5, 5), (Txnlog, 1, 12), (Txnlog, 13, 20), (Txnlog, 30, 40)],
+        );
+        // Logs continue in a new epoch
+        let second = manifest(
+            "second",
+            2,
+            &[(Snapshot, 35, 35), (Txnlog, 30, 50), (Txnlog, epoch2 + 1, epoch2 + 9)],
+        );
+        for m in &[&first, &second] {
+            let dir = repository.join(&m.id);
+            std::fs::create_dir(&dir).unwrap();
+            m.write(&dir).unwrap();
+        }
+        std::fs::create_dir(repository.join("in-progress")).unwrap();
+
+        let catalog = Catalog::open(&repository).unwrap();
+        assert_eq!(catalog.manifests().len(), 2);
+        assert_eq!(catalog.manifest("second"), Some(&second));
+
+        let ranges = |from, to| {
+            catalog
+                .restore_points(Zxid(from), Zxid(to))
+                .into_iter()
+                .map(|p| (p.backup, p.snapshot.0, p.last_zxid.0))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            ranges(0, i64::MAX),
+            vec![("first".to_owned(), 5, 20), ("second".to_owned(), 35, epoch2 + 9)]
+        );
+        assert_eq!(ranges(21, 34), vec![]);
+        assert_eq!(ranges(15, 15), vec![("first".to_owned(), 5, 20)]);
+
+        assert_eq!(catalog.restore_point(Zxid(4)), None);
+        assert_eq!(catalog.restore_point(Zxid(epoch2 + 3)).unwrap().backup, "second");
+
+        std::fs::remove_dir_all(&repository).unwrap();
+    }
+}
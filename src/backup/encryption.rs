@@ -0,0 +1,375 @@
+//! Client-side encryption of backup files.
+//!
+//! Snapshots and txnlogs contain application data, that may be sensitive. Backups can therefore
+//! be encrypted before they leave the server, with AES-256-GCM and a random data key per backup.
+//! The data key is wrapped by a `KeyProvider`, which is either a static key or a key management
+//! service (KMS), and stored in the backup's manifest along with the id of the wrapping key.
+//!
+//! Files are encrypted in chunks, so that they can be streamed: an encrypted file starts with a
+//! random nonce prefix, and each chunk is sealed with a nonce made of this prefix and the chunk
+//! index. The associated data flags the last chunk, so that truncating a file at a chunk boundary
+//! is detected.
+
+use aes_gcm::aead::rand_core::RngCore;
+use aes_gcm::aead::{Aead, OsRng, Payload};
+use aes_gcm::{Aes256Gcm, KeyInit, Nonce};
+use serde_derive::Deserialize;
+use serde_derive::Serialize;
+use std::io;
+use std::io::{BufRead, BufReader, Read, Write};
+
+use crate::error::BackupError;
+use crate::persistence::digest::{from_hex, to_hex};
+
+/// Encryption algorithm of backup files
+pub const ALGORITHM: &str = "AES-256-GCM";
+
+/// Default size of plaintext chunks: 64 kiB
+pub const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Largest chunk size accepted when reading a manifest
+const MAX_CHUNK_SIZE: usize = 16 * 1024 * 1024;
+
+const NONCE_SIZE: usize = 12;
+const NONCE_PREFIX_SIZE: usize = 8;
+const TAG_SIZE: usize = 16;
+
+/// Wraps and unwraps the data keys of backups.
+///
+/// Implement this trait to delegate key wrapping to a key management service: the data key is
+/// sent to the service for encryption, and only its encrypted form is stored in backups.
+pub trait KeyProvider {
+    /// Id of the key that wraps data keys, stored in manifests.
+    fn key_id(&self) -> String;
+
+    fn wrap_key(&self, key: &[u8]) -> Result<Vec<u8>, BackupError>;
+
+    /// Unwrap a data key that was wrapped by the key `key_id`.
+    fn unwrap_key(&self, key_id: &str, wrapped: &[u8]) -> Result<Vec<u8>, BackupError>;
+}
+
+/// A key provider that wraps data keys with a fixed AES-256 key.
+pub struct StaticKey {
+    id: String,
+    cipher: Aes256Gcm,
+}
+
+impl StaticKey {
+    pub fn new(id: impl Into<String>, key: [u8; 32]) -> StaticKey {
+        StaticKey {
+            id: id.into(),
+            cipher: Aes256Gcm::new(&key.into()),
+        }
+    }
+}
+
+impl KeyProvider for StaticKey {
+    fn key_id(&self) -> String {
+        self.id.clone()
+    }
+
+    /// The wrapped key is a random nonce followed by the sealed key.
+    fn wrap_key(&self, key: &[u8]) -> Result<Vec<u8>, BackupError> {
+        let mut nonce = [0u8; NONCE_SIZE];
+        OsRng.fill_bytes(&mut nonce);
+        let payload = Payload {
+            msg: key,
+            aad: self.id.as_bytes(),
+        };
+        let sealed = self
+            .cipher
+            .encrypt(Nonce::from_slice(&nonce), payload)
+            .map_err(|_| BackupError::Encryption("Cannot wrap data key".to_owned()))?;
+
+        let mut wrapped = nonce.to_vec();
+        wrapped.extend_from_slice(&sealed);
+        Ok(wrapped)
+    }
+
+    fn unwrap_key(&self, key_id: &str, wrapped: &[u8]) -> Result<Vec<u8>, BackupError> {
+        if key_id != self.id {
+            return Err(BackupError::Encryption(format!(
+                "Data key was wrapped by key {}, not {}",
+                key_id, self.id
+            )));
+        }
+        if wrapped.len() < NONCE_SIZE {
+            return Err(BackupError::Encryption("Invalid wrapped data key".to_owned()));
+        }
+        let (nonce, sealed) = wrapped.split_at(NONCE_SIZE);
+        let payload = Payload {
+            msg: sealed,
+            aad: self.id.as_bytes(),
+        };
+        self.cipher
+            .decrypt(Nonce::from_slice(nonce), payload)
+            .map_err(|_| BackupError::Encryption(format!("Cannot unwrap data key with key {}", key_id)))
+    }
+}
+
+/// Encryption of a backup, stored in its manifest.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Deserialize, Serialize)]
+pub struct Encryption {
+    pub algorithm: String,
+    /// Id of the key provider's key that wrapped the data key
+    pub key_id: String,
+    /// Hex-encoded data key, as wrapped by the key provider
+    pub wrapped_key: String,
+    /// Size of plaintext chunks
+    pub chunk_size: usize,
+}
+
+/// The data key of a backup.
+pub struct DataKey {
+    cipher: Aes256Gcm,
+    chunk_size: usize,
+}
+
+impl DataKey {
+    /// Generate a random data key. Returns the key and the manifest's encryption metadata.
+    pub fn generate(provider: &dyn KeyProvider) -> Result<(DataKey, Encryption), BackupError> {
+        let key = Aes256Gcm::generate_key(OsRng);
+        let encryption = Encryption {
+            algorithm: ALGORITHM.to_owned(),
+            key_id: provider.key_id(),
+            wrapped_key: to_hex(&provider.wrap_key(&key)?),
+            chunk_size: CHUNK_SIZE,
+        };
+        let data_key = DataKey {
+            cipher: Aes256Gcm::new(&key),
+            chunk_size: CHUNK_SIZE,
+        };
+        Ok((data_key, encryption))
+    }
+
+    /// Unwrap the data key of a backup.
+    pub fn unwrap(provider: &dyn KeyProvider, encryption: &Encryption) -> Result<DataKey, BackupError> {
+        if encryption.algorithm != ALGORITHM {
+            return Err(BackupError::Encryption(format!(
+                "Unsupported encryption algorithm {}",
+                encryption.algorithm
+            )));
+        }
+        if encryption.chunk_size == 0 || encryption.chunk_size > MAX_CHUNK_SIZE {
+            return Err(BackupError::Encryption(format!(
+                "Invalid encryption chunk size {}",
+                encryption.chunk_size
+            )));
+        }
+        let wrapped = from_hex(&encryption.wrapped_key)
+            .ok_or_else(|| BackupError::Encryption("Invalid wrapped data key".to_owned()))?;
+        let key = provider.unwrap_key(&encryption.key_id, &wrapped)?;
+        let cipher = Aes256Gcm::new_from_slice(&key)
+            .map_err(|_| BackupError::Encryption("Invalid data key length".to_owned()))?;
+        Ok(DataKey {
+            cipher,
+            chunk_size: encryption.chunk_size,
+        })
+    }
+
+    /// Encrypt a stream. `EncryptWriter::finish` must be called to write the last chunk.
+    pub fn encrypt<W: Write>(&self, mut out: W) -> Result<EncryptWriter<W>, BackupError> {
+        let mut prefix = [0u8; NONCE_PREFIX_SIZE];
+        OsRng.fill_bytes(&mut prefix);
+        out.write_all(&prefix)?;
+        Ok(EncryptWriter {
+            out,
+            chunks: Chunks::new(self, prefix),
+            buffer: Vec::with_capacity(self.chunk_size),
+        })
+    }
+
+    /// Decrypt a stream written by an `EncryptWriter`.
+    pub fn decrypt<R: Read>(&self, input: R) -> Result<DecryptReader<R>, BackupError> {
+        let mut input = BufReader::new(input);
+        let mut prefix = [0u8; NONCE_PREFIX_SIZE];
+        input.read_exact(&mut prefix)?;
+        Ok(DecryptReader {
+            input,
+            chunks: Chunks::new(self, prefix),
+            chunk: Vec::new(),
+            position: 0,
+            done: false,
+        })
+    }
+}
+
+/// Nonces and associated data of the chunks of a stream.
+struct Chunks {
+    cipher: Aes256Gcm,
+    chunk_size: usize,
+    prefix: [u8; NONCE_PREFIX_SIZE],
+    index: u32,
+}
+
+impl Chunks {
+    fn new(key: &DataKey, prefix: [u8; NONCE_PREFIX_SIZE]) -> Chunks {
+        Chunks {
+            cipher: key.cipher.clone(),
+            chunk_size: key.chunk_size,
+            prefix,
+            index: 0,
+        }
+    }
+
+    fn next_nonce(&mut self) -> io::Result<[u8; NONCE_SIZE]> {
+        let mut nonce = [0u8; NONCE_SIZE];
+        nonce[..NONCE_PREFIX_SIZE].copy_from_slice(&self.prefix);
+        nonce[NONCE_PREFIX_SIZE..].copy_from_slice(&self.index.to_be_bytes());
+        self.index = self
+            .index
+            .checked_add(1)
+            .ok_or_else(|| io::Error::other("Too many chunks in encrypted stream"))?;
+        Ok(nonce)
+    }
+
+    fn seal(&mut self, chunk: &[u8], last: bool) -> io::Result<Vec<u8>> {
+        let nonce = self.next_nonce()?;
+        let payload = Payload {
+            msg: chunk,
+            aad: &[last as u8],
+        };
+        self.cipher
+            .encrypt(Nonce::from_slice(&nonce), payload)
+            .map_err(|_| io::Error::other("Encryption failed"))
+    }
+
+    fn open(&mut self, sealed: &[u8], last: bool) -> io::Result<Vec<u8>> {
+        let nonce = self.next_nonce()?;
+        let payload = Payload {
+            msg: sealed,
+            aad: &[last as u8],
+        };
+        self.cipher.decrypt(Nonce::from_slice(&nonce), payload).map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Decryption failed: wrong key, or corrupted or truncated file",
+            )
+        })
+    }
+}
+
+/// Encrypts a stream in chunks. Full chunks are only written once the next write shows they're
+/// not the last one, so `flush` doesn't flush buffered data.
+pub struct EncryptWriter<W: Write> {
+    out: W,
+    chunks: Chunks,
+    buffer: Vec<u8>,
+}
+
+impl<W: Write> EncryptWriter<W> {
+    /// Write the last chunk, and return the underlying writer.
+    pub fn finish(mut self) -> Result<W, BackupError> {
+        let sealed = self.chunks.seal(&self.buffer, true)?;
+        self.out.write_all(&sealed)?;
+        self.out.flush()?;
+        Ok(self.out)
+    }
+}
+
+impl<W: Write> Write for EncryptWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        if self.buffer.len() == self.chunks.chunk_size {
+            let sealed = self.chunks.seal(&self.buffer, false)?;
+            self.out.write_all(&sealed)?;
+            self.buffer.clear();
+        }
+        let len = buf.len().min(self.chunks.chunk_size - self.buffer.len());
+        self.buffer.extend_from_slice(&buf[..len]);
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+/// Decrypts a stream written by an `EncryptWriter`.
+pub struct DecryptReader<R: Read> {
+    input: BufReader<R>,
+    chunks: Chunks,
+    chunk: Vec<u8>,
+    position: usize,
+    done: bool,
+}
+
+impl<R: Read> DecryptReader<R> {
+    fn next_chunk(&mut self) -> io::Result<()> {
+        let sealed_size = self.chunks.chunk_size + TAG_SIZE;
+        let mut sealed = Vec::with_capacity(sealed_size);
+        (&mut self.input).take(sealed_size as u64).read_to_end(&mut sealed)?;
+        let last = sealed.len() < sealed_size || self.input.fill_buf()?.is_empty();
+
+        self.chunk = self.chunks.open(&sealed, last)?;
+        self.position = 0;
+        self.done = last;
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for DecryptReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.position == self.chunk.len() {
+            if self.done {
+                return Ok(0);
+            }
+            self.next_chunk()?;
+        }
+        let len = buf.len().min(self.chunk.len() - self.position);
+        buf[..len].copy_from_slice(&self.chunk[self.position..self.position + len]);
+        self.position += len;
+        Ok(len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(key: &DataKey, data: &[u8]) -> Vec<u8> {
+        let mut writer = key.encrypt(Vec::new()).unwrap();
+        writer.write_all(data).unwrap();
+        writer.finish().unwrap()
+    }
+
+    fn decrypt(key: &DataKey, sealed: &[u8]) -> io::Result<Vec<u8>> {
+        let mut result = Vec::new();
+        key.decrypt(sealed).unwrap().read_to_end(&mut result)?;
+        Ok(result)
+    }
+
+    #[test]
+    fn encrypted_stream() {
+        let provider = StaticKey::new("static-1", [7u8; 32]);
+        let (_, mut encryption) = DataKey::generate(&provider).unwrap();
+        encryption.chunk_size = 100;
+        let key = DataKey::unwrap(&provider, &encryption).unwrap();
+
+        let data = (0..1000u32).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        for &len in &[0usize, 99, 100, 101, 200, 1000] {
+            let sealed = round_trip(&key, &data[..len]);
+            assert_eq!(
+                sealed.len(),
+                NONCE_PREFIX_SIZE + len + len.max(1).div_ceil(100) * TAG_SIZE
+            );
+            assert_eq!(decrypt(&key, &sealed).unwrap(), &data[..len]);
+        }
+
+        // Truncated at a chunk boundary, or tampered
+        let sealed = round_trip(&key, &data[..250]);
+        assert!(decrypt(&key, &sealed[..NONCE_PREFIX_SIZE + 2 * (100 + TAG_SIZE)]).is_err());
+        let mut tampered = sealed.clone();
+        tampered[150] ^= 1;
+        assert!(decrypt(&key, &tampered).is_err());
+
+        // Another data key, or another provider
+        let (other, _) = DataKey::generate(&provider).unwrap();
+        assert!(decrypt(&other, &sealed).is_err());
+        assert!(DataKey::unwrap(&StaticKey::new("static-1", [8u8; 32]), &encryption).is_err());
+        assert!(DataKey::unwrap(&StaticKey::new("static-2", [7u8; 32]), &encryption).is_err());
+    }
+}
@@ -0,0 +1,596 @@
+//! Backups to a repository directory, e.g. on a local disk or a mounted network file system.
+//!
+//! A full backup copies the most recent valid snapshot and the txns that follow it. Compressed
+//! snapshots are decompressed, so that restored snapshots can be read without knowing the
+//! compression method of the server they were taken from. An
+//! incremental backup only copies the txns written since a previous backup, up to the last
+//! complete record of the active txnlog. Restoring copies a snapshot, and stitches the txnlogs of
+//! its backup and of the incremental backups that follow it into a single txnlog. A restore can
+//! be rehearsed with `verify_restore`, which checks that a backup restores without touching any
+//! data directory.
+//!
+//! When given a `KeyProvider`, backups are encrypted as they are written, with a new data key for
+//! each backup (see `encryption`). Restoring encrypted backups needs a provider for their keys.
+
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+
+use aes_gcm::aead::rand_core::RngCore;
+use aes_gcm::aead::OsRng;
+
+use super::catalog::{follows, Catalog, RestorePoint};
+use super::encryption::{DataKey, EncryptWriter, KeyProvider};
+use super::manifest::{BackupFile, BackupManifest, Compression, FileKind};
+use super::segment::{Segment, SegmentWriter};
+use crate::error::BackupError;
+use crate::persistence::check::{check_stats, StatViolation};
+use crate::persistence::compression;
+use crate::persistence::datatree::DataTree;
+use crate::persistence::file_digest;
+use crate::persistence::snapshot::{SnapshotFile, MAX_SNAPSHOT_CANDIDATES};
+use crate::persistence::txnlog::TxnlogFile;
+use crate::Zxid;
+
+const MAX_ZXID: Zxid = Zxid(i64::MAX);
+
+/// Back up the most recent valid snapshot of `snap_dir` and the txns of `log_dir` that follow it,
+/// in a new directory of the repository named after the manifest's id. Returns the manifest, with
+/// the backup's files. The backup is encrypted if `keys` is provided.
+pub fn full_backup(
+    snap_dir: impl AsRef<Path>,
+    log_dir: impl AsRef<Path>,
+    repository: impl AsRef<Path>,
+    mut manifest: BackupManifest,
+    keys: Option<&dyn KeyProvider>,
+) -> Result<BackupManifest, BackupError> {
+    let snapshot = SnapshotFile::find_verified_snapshot(&snap_dir, MAX_SNAPSHOT_CANDIDATES, false)?
+        .ok_or_else(|| BackupError::NotFound(format!("No valid snapshot in {}", snap_dir.as_ref().display())))?;
+    let zxid = snapshot.zxid();
+    let snapshot_path = snapshot.path().to_owned();
+    drop(snapshot);
+
+    let key = data_key(&mut manifest, keys)?;
+    let dir = create_backup_dir(repository.as_ref(), &manifest.id)?;
+
+    let name = format!("snapshot.{:x}", zxid.0);
+    let mut out = Output::create(&dir.join(&name), key.as_ref())?;
+    let compression = compression::Compression::from_path(&snapshot_path);
+    std::io::copy(&mut compression.reader(File::open(&snapshot_path)?)?, &mut out)?;
+    out.finish()?;
+    manifest.files.push(BackupFile {
+        kind: FileKind::Snapshot,
+        digest: file_digest(dir.join(&name))?,
+        path: name,
+        first_zxid: zxid,
+        last_zxid: zxid,
+        compression: Compression::None,
+    });
+
+    let paths = TxnlogFile::find_txnlog_paths(log_dir, zxid)?;
+    if let Some(segment) = write_segment(open_all(&paths)?, zxid, MAX_ZXID, &dir, key.as_ref())? {
+        manifest.files.push(segment_file(&dir, segment)?);
+    }
+
+    manifest.parent = None;
+    manifest.write(&dir)?;
+    Ok(manifest)
+}
+
+/// Back up the txns of `log_dir` written since the `base` backup, in a new directory of the
+/// repository named after the manifest's id. Returns the manifest, with the backup's files. The
+/// backup is encrypted if `keys` is provided.
+///
+/// Fails if there are no new txns, or if some were purged from `log_dir` since the base backup, in
+/// which case a full backup is needed.
+pub fn incremental_backup(
+    log_dir: impl AsRef<Path>,
+    repository: impl AsRef<Path>,
+    mut manifest: BackupManifest,
+    base: &BackupManifest,
+    keys: Option<&dyn KeyProvider>,
+) -> Result<BackupManifest, BackupError> {
+    let (_, after) = base
+        .zxid_range()
+        .ok_or_else(|| BackupError::NotFound(format!("Backup {} has no files", base.id)))?;
+    let paths = TxnlogFile::find_txnlog_paths(&log_dir, after).map_err(|_| BackupError::Purged(after))?;
+
+    let key = data_key(&mut manifest, keys)?;
+    let dir = create_backup_dir(repository.as_ref(), &manifest.id)?;
+    let result = open_all(&paths)
+        .and_then(|inputs| write_segment(inputs, after, MAX_ZXID, &dir, key.as_ref()))
+        .and_then(|segment| match segment {
+            None => Err(BackupError::NoNewTxns(base.id.clone())),
+            Some(segment) if !follows(after, segment.first_zxid) => Err(BackupError::Purged(after)),
+            Some(segment) => segment_file(&dir, segment),
+        });
+
+    let file = match result {
+        Ok(file) => file,
+        Err(e) => {
+            std::fs::remove_dir_all(&dir)?;
+            return Err(e);
+        }
+    };
+
+    manifest.parent = Some(base.id.clone());
+    manifest.files.push(file);
+    manifest.write(&dir)?;
+    Ok(manifest)
+}
+
+/// Restore the state of the ensemble at `zxid` from the most recent snapshot that can reach it.
+/// The snapshot is copied to `snap_dir`, and its txns up to `zxid` to a single txnlog in
+/// `log_dir`. Both directories should be empty, so that the server doesn't load other files.
+///
+/// `keys` unwraps the data keys of encrypted backups.
+pub fn restore(
+    repository: impl AsRef<Path>,
+    catalog: &Catalog,
+    zxid: Zxid,
+    snap_dir: impl AsRef<Path>,
+    log_dir: impl AsRef<Path>,
+    keys: Option<&dyn KeyProvider>,
+) -> Result<RestorePoint, BackupError> {
+    let point = catalog
+        .restore_point(zxid)
+        .ok_or_else(|| BackupError::NotFound(format!("No restore point for zxid {:x}", zxid.0)))?;
+
+    restore_point(
+        repository.as_ref(),
+        catalog,
+        &point,
+        zxid,
+        snap_dir.as_ref(),
+        log_dir.as_ref(),
+        keys,
+    )?;
+    Ok(point)
+}
+
+fn restore_point(
+    repository: &Path,
+    catalog: &Catalog,
+    point: &RestorePoint,
+    zxid: Zxid,
+    snap_dir: &Path,
+    log_dir: &Path,
+    keys: Option<&dyn KeyProvider>,
+) -> Result<(), BackupError> {
+    let mut inputs = point
+        .files
+        .iter()
+        .map(|path| open_backup_file(repository, catalog, path, keys))
+        .collect::<Result<Vec<_>, BackupError>>()?;
+
+    let txnlogs = inputs.split_off(1);
+    let snapshot = snap_dir.join(format!("snapshot.{:x}", point.snapshot.0));
+    std::io::copy(&mut inputs[0], &mut File::create(snapshot)?)?;
+
+    write_segment(txnlogs, point.snapshot, zxid, log_dir, None)?;
+    Ok(())
+}
+
+/// Outcome of a restore rehearsal.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RestoreReport {
+    pub point: RestorePoint,
+    /// Zxid the backup was restored at
+    pub zxid: Zxid,
+    /// Files of the restore point whose digest doesn't match their manifest, relative to the
+    /// repository, with the indices of their corrupted chunks
+    pub corrupted: Vec<(PathBuf, Vec<usize>)>,
+    /// Zxid and number of nodes of the restored tree. `None` if files are corrupted, in which case
+    /// the restore isn't attempted. The zxid differs from `zxid` if its txn is missing.
+    pub restored: Option<(Zxid, usize)>,
+    /// Broken stat invariants of the restored tree, as (path, violation)
+    pub violations: Vec<(String, StatViolation)>,
+}
+
+impl RestoreReport {
+    /// Did the backup restore an intact and consistent tree, at the requested zxid?
+    pub fn is_ok(&self) -> bool {
+        let restored = matches!(self.restored, Some((zxid, _)) if zxid == self.zxid);
+        self.corrupted.is_empty() && restored && self.violations.is_empty()
+    }
+}
+
+/// Rehearse the restore of the backup `manifest` at `zxid`: check the digests of the files of its
+/// restore point, restore them to a temporary directory and load the resulting tree to check its
+/// zxid, snapshot digest and invariants. The temporary directory is removed once done.
+///
+/// `keys` unwraps the data keys of encrypted backups.
+pub fn verify_restore(
+    repository: impl AsRef<Path>,
+    catalog: &Catalog,
+    manifest: &BackupManifest,
+    zxid: Zxid,
+    keys: Option<&dyn KeyProvider>,
+) -> Result<RestoreReport, BackupError> {
+    let repository = repository.as_ref();
+    let point = catalog
+        .restore_points(zxid, zxid)
+        .into_iter()
+        .rfind(|p| p.backup == manifest.id)
+        .ok_or_else(|| {
+            BackupError::NotFound(format!(
+                "No restore point for zxid {:x} in backup {}",
+                zxid.0, manifest.id
+            ))
+        })?;
+
+    let mut report = RestoreReport {
+        point,
+        zxid,
+        corrupted: Vec::new(),
+        restored: None,
+        violations: Vec::new(),
+    };
+
+    for path in &report.point.files {
+        let file = backup_file(catalog, path)
+            .ok_or_else(|| BackupError::NotFound(format!("No manifest entry for {}", path.display())))?;
+        let changed = file.digest.verify(repository.join(path))?;
+        if !changed.is_empty() {
+            report.corrupted.push((path.clone(), changed));
+        }
+    }
+    if !report.corrupted.is_empty() {
+        return Ok(report);
+    }
+
+    let dir = TempDir::create("zookeepers-verify")?;
+    restore_point(repository, catalog, &report.point, zxid, &dir.0, &dir.0, keys)?;
+    let tree = load_tree(&dir.0)?;
+
+    report.restored = Some((tree.zxid(), tree.node_count()));
+    report.violations = check_stats(tree.nodes().map(|(path, node)| Ok((path.to_owned(), node.clone()))))?;
+    Ok(report)
+}
+
+/// Load a restored directory. Its txnlog starts after the snapshot, which `DataTree::load` doesn't
+/// accept.
+fn load_tree(dir: &Path) -> Result<DataTree, BackupError> {
+    let snapshot = SnapshotFile::find_valid_snapshot(dir, MAX_SNAPSHOT_CANDIDATES)?
+        .ok_or_else(|| BackupError::NotFound(format!("No valid snapshot in {}", dir.display())))?;
+    let mut tree = DataTree::from_snapshot(snapshot.with_digest_check(true))?;
+    for path in TxnlogFile::txnlog_paths(dir)? {
+        tree.replay(TxnlogFile::new(path)?)?;
+    }
+    Ok(tree)
+}
+
+/// A new temporary directory, that is removed when dropped.
+struct TempDir(PathBuf);
+
+impl TempDir {
+    /// Create a directory with a random name, that isn't readable by other users: restored files
+    /// are decrypted.
+    fn create(prefix: &str) -> Result<TempDir, BackupError> {
+        let path = std::env::temp_dir().join(format!("{}-{:016x}", prefix, OsRng.next_u64()));
+        let mut builder = std::fs::DirBuilder::new();
+        #[cfg(unix)]
+        std::os::unix::fs::DirBuilderExt::mode(&mut builder, 0o700);
+        // Fails if it already exists
+        builder.create(&path)?;
+        Ok(TempDir(path))
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+/// The manifest entry of a file of a restore point.
+fn backup_file<'a>(catalog: &'a Catalog, path: &Path) -> Option<&'a BackupFile> {
+    let id = path.iter().next()?.to_str()?;
+    let name = path.strip_prefix(id).ok()?.to_str()?;
+    catalog.manifest(id)?.files.iter().find(|f| f.path == name)
+}
+
+fn create_backup_dir(repository: &Path, id: &str) -> Result<PathBuf, BackupError> {
+    let dir = repository.join(id);
+    if dir.exists() {
+        return Err(BackupError::AlreadyExists(id.to_owned()));
+    }
+    std::fs::create_dir_all(&dir)?;
+    Ok(dir)
+}
+
+/// Generate the data key of a new backup, if it's encrypted.
+fn data_key(manifest: &mut BackupManifest, keys: Option<&dyn KeyProvider>) -> Result<Option<DataKey>, BackupError> {
+    manifest.encryption = None;
+    match keys {
+        None => Ok(None),
+        Some(keys) => {
+            let (key, encryption) = DataKey::generate(keys)?;
+            manifest.encryption = Some(encryption);
+            Ok(Some(key))
+        }
+    }
+}
+
+fn open_all(paths: &[PathBuf]) -> Result<Vec<Box<dyn Read>>, BackupError> {
+    paths
+        .iter()
+        .map(|path| Ok(Box::new(BufReader::new(File::open(path)?)) as Box<dyn Read>))
+        .collect()
+}
+
+/// Open a file of a restore point, decrypting it if its backup is encrypted.
+fn open_backup_file(
+    repository: &Path,
+    catalog: &Catalog,
+    path: &Path,
+    keys: Option<&dyn KeyProvider>,
+) -> Result<Box<dyn Read>, BackupError> {
+    let input = BufReader::new(File::open(repository.join(path))?);
+    let id = path.iter().next().and_then(|id| id.to_str()).unwrap_or_default();
+    let encryption = catalog.manifest(id).and_then(|m| m.encryption.as_ref());
+    match (encryption, keys) {
+        (None, _) => Ok(Box::new(input)),
+        (Some(_), None) => Err(BackupError::Encrypted(id.to_owned())),
+        (Some(encryption), Some(keys)) => Ok(Box::new(DataKey::unwrap(keys, encryption)?.decrypt(input)?)),
+    }
+}
+
+/// A file being written to a backup, encrypted or not.
+enum Output {
+    Plain(BufWriter<File>),
+    Encrypted(Box<EncryptWriter<BufWriter<File>>>),
+}
+
+impl Output {
+    fn create(path: &Path, key: Option<&DataKey>) -> Result<Output, BackupError> {
+        let out = BufWriter::new(File::create(path)?);
+        Ok(match key {
+            None => Output::Plain(out),
+            Some(key) => Output::Encrypted(Box::new(key.encrypt(out)?)),
+        })
+    }
+
+    fn finish(self) -> Result<(), BackupError> {
+        let mut out = match self {
+            Output::Plain(out) => out,
+            Output::Encrypted(out) => out.finish()?,
+        };
+        out.flush()?;
+        Ok(())
+    }
+}
+
+impl Write for Output {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            Output::Plain(out) => out.write(buf),
+            Output::Encrypted(out) => out.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            Output::Plain(out) => out.flush(),
+            Output::Encrypted(out) => out.flush(),
+        }
+    }
+}
+
+/// Write the txns of txnlogs in `(after, until]` to a `log.<first zxid>` file in `dir`, encrypted
+/// with `key` if provided.
+fn write_segment(
+    inputs: Vec<Box<dyn Read>>,
+    after: Zxid,
+    until: Zxid,
+    dir: &Path,
+    key: Option<&DataKey>,
+) -> Result<Option<Segment>, BackupError> {
+    let tmp = dir.join("log.tmp");
+    let mut writer = SegmentWriter::new(Output::create(&tmp, key)?)?;
+    for input in inputs {
+        writer.copy(input, after, until)?;
+    }
+
+    let (out, segment) = writer.finish()?;
+    out.finish()?;
+
+    match segment {
+        Some(segment) => std::fs::rename(&tmp, dir.join(format!("log.{:x}", segment.first_zxid.0)))?,
+        None => std::fs::remove_file(&tmp)?,
+    }
+    Ok(segment)
+}
+
+fn segment_file(dir: &Path, segment: Segment) -> Result<BackupFile, BackupError> {
+    let name = format!("log.{:x}", segment.first_zxid.0);
+    Ok(BackupFile {
+        kind: FileKind::Txnlog,
+        digest: file_digest(dir.join(&name))?,
+        path: name,
+        first_zxid: segment.first_zxid,
+        last_zxid: segment.last_zxid,
+        compression: Compression::None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backup::encryption::StaticKey;
+    use crate::backup::manifest::Ensemble;
+    use crate::persistence::testing::*;
+    use crate::Timestamp;
+
+    fn manifest(id: &str, created: u64) -> BackupManifest {
+        let ensemble = Ensemble {
+            name: "prod".to_owned(),
+            servers: vec!["zk1:2181".to_owned()],
+        };
+        BackupManifest::new(id, ensemble, Timestamp(created))
+    }
+
+    fn bodies(zxids: std::ops::RangeInclusive<i64>) -> Vec<Vec<u8>> {
+        zxids
+            .map(|zxid| txn_body(zxid, 10, 5, &path_op("/app", Some("data"))))
+            .collect()
+    }
+
+    fn zxids(dir: &Path) -> Vec<i64> {
+        TxnlogFile::txnlog_paths(dir)
+            .unwrap()
+            .into_iter()
+            .flat_map(|path| TxnlogFile::new(path).unwrap())
+            .map(|txn| txn.unwrap().header.zxid.0)
+            .collect()
+    }
+
+    #[test]
+    fn incremental_backups() {
+        let snapshot = write_snapshot_at("incremental-backups", 3, &[], &[], &[("", node("", -1, 0, 0))]);
+        let data = snapshot.parent().unwrap().to_owned();
+        let repository = data.join("repository");
+        std::fs::create_dir(&repository).unwrap();
+
+        write_txnlog(&data, 1, &bodies(1..=6));
+        let full = full_backup(&data, &data, &repository, manifest("full", 1), None).unwrap();
+        assert_eq!(full.zxid_range(), Some((Zxid(3), Zxid(6))));
+
+        // Logs are rolled, and the active log has a partial record
+        let active = write_txnlog(&data, 7, &bodies(7..=12));
+        let mut bytes = std::fs::read(&active).unwrap();
+        bytes.truncate(bytes.len() - 12);
+        bytes.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 40, 0, 0]);
+        std::fs::write(&active, bytes).unwrap();
+
+        let first = incremental_backup(&data, &repository, manifest("incr-1", 2), &full, None).unwrap();
+        assert_eq!(first.parent.as_deref(), Some("full"));
+        assert_eq!(first.zxid_range(), Some((Zxid(7), Zxid(12))));
+        assert!(incremental_backup(&data, &repository, manifest("incr-2", 3), &first, None).is_err());
+        assert!(!repository.join("incr-2").exists());
+
+        write_txnlog(&data, 13, &bodies(13..=15));
+        let second = incremental_backup(&data, &repository, manifest("incr-2", 3), &first, None).unwrap();
+        assert_eq!(second.zxid_range(), Some((Zxid(13), Zxid(15))));
+        assert!(incremental_backup(&data, &repository, manifest("incr-2", 4), &first, None).is_err());
+
+        let catalog = Catalog::open(&repository).unwrap();
+        let target = data.join("restored");
+        std::fs::create_dir(&target).unwrap();
+        let point = restore(&repository, &catalog, Zxid(14), &target, &target, None).unwrap();
+        assert_eq!((point.snapshot, point.last_zxid), (Zxid(3), Zxid(15)));
+        assert_eq!(point.files.len(), 4);
+
+        assert!(SnapshotFile::is_valid_snapshot(target.join("snapshot.3")).unwrap());
+        assert_eq!(zxids(&target), (4..=14).collect::<Vec<_>>());
+
+        remove_snapshot(&snapshot);
+    }
+
+    #[cfg(feature = "gzip")]
+    #[test]
+    fn compressed_snapshot_backup() {
+        let snapshot = write_snapshot_at("compressed-backup", 3, &[], &[], &[("", node("", -1, 0, 0))]);
+        let data = snapshot.parent().unwrap().to_owned();
+        let compressed = data.join("snapshot.3.gz");
+        let mut encoder = flate2::write::GzEncoder::new(File::create(&compressed).unwrap(), Default::default());
+        encoder.write_all(&std::fs::read(&snapshot).unwrap()).unwrap();
+        encoder.finish().unwrap();
+        std::fs::remove_file(&snapshot).unwrap();
+        let repository = data.join("repository");
+        std::fs::create_dir(&repository).unwrap();
+
+        write_txnlog(&data, 1, &bodies(1..=6));
+        let full = full_backup(&data, &data, &repository, manifest("full", 1), None).unwrap();
+        assert_eq!(full.files[0].path, "snapshot.3");
+        assert_eq!(full.files[0].compression, Compression::None);
+
+        let catalog = Catalog::open(&repository).unwrap();
+        let target = data.join("restored");
+        std::fs::create_dir(&target).unwrap();
+        restore(&repository, &catalog, Zxid(6), &target, &target, None).unwrap();
+        assert!(SnapshotFile::is_valid_snapshot(target.join("snapshot.3")).unwrap());
+        assert_eq!(load_tree(&target).unwrap().zxid(), Zxid(6));
+
+        remove_snapshot(&snapshot);
+    }
+
+    #[test]
+    fn restore_rehearsal() {
+        let snapshot = write_snapshot_at("restore-rehearsal", 3, &[], &[], &[("", node("", -1, 0, 0))]);
+        let data = snapshot.parent().unwrap().to_owned();
+        let repository = data.join("repository");
+        std::fs::create_dir(&repository).unwrap();
+
+        write_txnlog(&data, 1, &bodies(1..=6));
+        let full = full_backup(&data, &data, &repository, manifest("full", 1), None).unwrap();
+        let catalog = Catalog::open(&repository).unwrap();
+
+        let report = verify_restore(&repository, &catalog, &full, Zxid(5), None).unwrap();
+        assert!(report.is_ok(), "{:?}", report);
+        assert_eq!(report.restored, Some((Zxid(5), 1)));
+        assert!(verify_restore(&repository, &catalog, &full, Zxid(7), None).is_err());
+
+        // A corrupted txnlog isn't restored
+        let log = repository.join("full").join("log.4");
+        let mut bytes = std::fs::read(&log).unwrap();
+        bytes[40] ^= 0xff;
+        std::fs::write(&log, bytes).unwrap();
+        let report = verify_restore(&repository, &catalog, &full, Zxid(5), None).unwrap();
+        assert!(!report.is_ok());
+        assert_eq!(report.corrupted, vec![(PathBuf::from("full/log.4"), vec![0])]);
+        assert_eq!(report.restored, None);
+        remove_snapshot(&snapshot);
+
+        // A backup that misses the txn of the zxid restores an earlier tree
+        let snapshot = write_snapshot_at("restore-rehearsal-gap", 3, &[], &[], &[("", node("", -1, 0, 0))]);
+        let data = snapshot.parent().unwrap().to_owned();
+        let repository = data.join("repository");
+        std::fs::create_dir(&repository).unwrap();
+
+        write_txnlog(&data, 1, &[bodies(1..=4), bodies(6..=8)].concat());
+        let full = full_backup(&data, &data, &repository, manifest("full", 1), None).unwrap();
+        let catalog = Catalog::open(&repository).unwrap();
+        let report = verify_restore(&repository, &catalog, &full, Zxid(5), None).unwrap();
+        assert!(!report.is_ok());
+        assert_eq!((report.zxid, report.restored), (Zxid(5), Some((Zxid(4), 1))));
+
+        remove_snapshot(&snapshot);
+    }
+
+    #[test]
+    fn encrypted_backups() {
+        let snapshot = write_snapshot_at("encrypted-backups", 3, &[], &[], &[("", node("", -1, 0, 0))]);
+        let data = snapshot.parent().unwrap().to_owned();
+        let repository = data.join("repository");
+        std::fs::create_dir(&repository).unwrap();
+        let keys = StaticKey::new("static-1", [42u8; 32]);
+
+        write_txnlog(&data, 1, &bodies(1..=6));
+        let full = full_backup(&data, &data, &repository, manifest("full", 1), Some(&keys)).unwrap();
+        write_txnlog(&data, 7, &bodies(7..=9));
+        let incr = incremental_backup(&data, &repository, manifest("incr", 2), &full, Some(&keys)).unwrap();
+
+        let encryption = full.encryption.as_ref().unwrap();
+        assert_eq!(encryption.key_id, "static-1");
+        assert_ne!(incr.encryption.as_ref().unwrap().wrapped_key, encryption.wrapped_key);
+
+        // Stored files are encrypted, and their digests cover the stored bytes
+        let stored = repository.join("full").join("snapshot.3");
+        assert!(!SnapshotFile::is_valid_snapshot(&stored).unwrap_or(false));
+        assert!(full.files[0].digest.verify(&stored).unwrap().is_empty());
+
+        let catalog = Catalog::open(&repository).unwrap();
+        let target = data.join("restored");
+        std::fs::create_dir(&target).unwrap();
+        assert!(restore(&repository, &catalog, Zxid(8), &target, &target, None).is_err());
+        let other = StaticKey::new("static-1", [43u8; 32]);
+        assert!(restore(&repository, &catalog, Zxid(8), &target, &target, Some(&other)).is_err());
+
+        restore(&repository, &catalog, Zxid(8), &target, &target, Some(&keys)).unwrap();
+        assert!(SnapshotFile::is_valid_snapshot(target.join("snapshot.3")).unwrap());
+        assert_eq!(zxids(&target), (4..=8).collect::<Vec<_>>());
+
+        remove_snapshot(&snapshot);
+    }
+}
@@ -0,0 +1,194 @@
+//! Backup manifests.
+//!
+//! A manifest lists the files of a backup with the range of zxids they contain and their digest,
+//! and identifies the ensemble they were taken from. It is stored as JSON in the backup
+//! directory, with a format version: manifests written by a more recent version of this crate are
+//! rejected rather than misread.
+
+use serde_derive::Deserialize;
+use serde_derive::Serialize;
+use std::path::Path;
+
+use super::encryption::Encryption;
+use crate::error::BackupError;
+use crate::persistence::digest::FileDigest;
+use crate::Timestamp;
+use crate::Zxid;
+
+/// Version of the manifest format written by this crate
+pub const MANIFEST_VERSION: u32 = 1;
+
+/// Name of the manifest file in a backup directory
+pub const MANIFEST_FILE: &str = "manifest.json";
+
+/// Compression of a file in a backup.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[derive(Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    None,
+    Gzip,
+    Zstd,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[derive(Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FileKind {
+    Snapshot,
+    Txnlog,
+}
+
+/// Identity of the ensemble a backup was taken from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Deserialize, Serialize)]
+pub struct Ensemble {
+    pub name: String,
+    /// Server addresses, as `host:port`
+    pub servers: Vec<String>,
+}
+
+/// A snapshot or txnlog file in a backup.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Deserialize, Serialize)]
+pub struct BackupFile {
+    pub kind: FileKind,
+    /// Path relative to the backup directory
+    pub path: String,
+    /// Zxid of a snapshot, or of the first txn of a txnlog
+    pub first_zxid: Zxid,
+    /// Zxid of a snapshot, or of the last txn of a txnlog
+    pub last_zxid: Zxid,
+    pub compression: Compression,
+    /// Digest of the file as stored, i.e. after compression and encryption
+    pub digest: FileDigest,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Deserialize, Serialize)]
+pub struct BackupManifest {
+    pub version: u32,
+    /// Backup identifier, which is also the name of its directory in the repository
+    pub id: String,
+    pub ensemble: Ensemble,
+    /// Creation time of the backup
+    pub created: Timestamp,
+    /// For incremental backups, the backup whose txns this one continues
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub parent: Option<String>,
+    /// For encrypted backups, the wrapped data key that decrypts its files
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub encryption: Option<Encryption>,
+    pub files: Vec<BackupFile>,
+}
+
+/// The part of a manifest that is common to all format versions.
+#[derive(Deserialize)]
+struct Versioned {
+    version: u32,
+}
+
+impl BackupManifest {
+    pub fn new(id: impl Into<String>, ensemble: Ensemble, created: Timestamp) -> BackupManifest {
+        BackupManifest {
+            version: MANIFEST_VERSION,
+            id: id.into(),
+            ensemble,
+            created,
+            parent: None,
+            encryption: None,
+            files: Vec::new(),
+        }
+    }
+
+    /// Files of a kind, ordered by zxid.
+    pub fn files(&self, kind: FileKind) -> Vec<&BackupFile> {
+        let mut files = self.files.iter().filter(|f| f.kind == kind).collect::<Vec<_>>();
+        files.sort_by_key(|f| (f.first_zxid, f.last_zxid));
+        files
+    }
+
+    /// Range of zxids covered by the files of this backup.
+    pub fn zxid_range(&self) -> Option<(Zxid, Zxid)> {
+        let first = self.files.iter().map(|f| f.first_zxid).min()?;
+        let last = self.files.iter().map(|f| f.last_zxid).max()?;
+        Some((first, last))
+    }
+
+    pub fn to_json(&self) -> Result<String, BackupError> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+
+    pub fn from_json(json: &str) -> Result<BackupManifest, BackupError> {
+        let Versioned { version } = serde_json::from_str(json)?;
+        if version > MANIFEST_VERSION {
+            return Err(BackupError::ManifestVersion {
+                version,
+                latest: MANIFEST_VERSION,
+            });
+        }
+        Ok(serde_json::from_str(json)?)
+    }
+
+    /// Read the manifest of a backup directory.
+    pub fn read(dir: impl AsRef<Path>) -> Result<BackupManifest, BackupError> {
+        let path = dir.as_ref().join(MANIFEST_FILE);
+        let json = std::fs::read_to_string(&path)?;
+        Self::from_json(&json).map_err(|e| BackupError::InvalidManifest {
+            path,
+            source: Box::new(e),
+        })
+    }
+
+    /// Write the manifest in a backup directory. The manifest is written to a temporary file that
+    /// is then renamed, so that a backup never has a partial manifest.
+    pub fn write(&self, dir: impl AsRef<Path>) -> Result<(), BackupError> {
+        let dir = dir.as_ref();
+        let tmp = dir.join(format!("{}.tmp", MANIFEST_FILE));
+        std::fs::write(&tmp, self.to_json()?)?;
+        std::fs::rename(&tmp, dir.join(MANIFEST_FILE))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn file(kind: FileKind, path: &str, first: i64, last: i64) -> BackupFile {
+        BackupFile {
+            kind,
+            path: path.to_owned(),
+            first_zxid: Zxid(first),
+            last_zxid: Zxid(last),
+            compression: Compression::None,
+            digest: FileDigest::compute(path.as_bytes(), 16).unwrap(),
+        }
+    }
+
+    #[test]
+    fn manifest_json() {
+        let ensemble = Ensemble {
+            name: "prod".to_owned(),
+            servers: vec!["zk1:2181".to_owned(), "zk2:2181".to_owned()],
+        };
+        let mut manifest = BackupManifest::new("2019-06-01", ensemble, Timestamp(1_559_347_200_000));
+        manifest.files.push(file(FileKind::Txnlog, "log.5", 5, 9));
+        manifest.files.push(file(FileKind::Snapshot, "snapshot.4", 4, 4));
+
+        assert_eq!(manifest.zxid_range(), Some((Zxid(4), Zxid(9))));
+        assert_eq!(manifest.files(FileKind::Snapshot)[0].path, "snapshot.4");
+
+        let json = manifest.to_json().unwrap();
+        assert!(json.contains("\"kind\": \"snapshot\""));
+        assert!(json.contains("\"first_zxid\": 5"));
+        assert!(!json.contains("encryption"));
+        assert_eq!(BackupManifest::from_json(&json).unwrap(), manifest);
+
+        let newer = json.replace("\"version\": 1", "\"version\": 2");
+        assert!(BackupManifest::from_json(&newer)
+            .unwrap_err()
+            .to_string()
+            .contains("version 2"));
+    }
+}
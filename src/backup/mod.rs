@@ -0,0 +1,22 @@
+//! Backups of snapshots and txnlogs.
+//!
+//! A backup repository is a directory with one subdirectory per backup. Each backup holds copies
+//! of snapshot and txnlog files, and a `manifest.json` file describing them (see `manifest`).
+//! The `catalog` lists the restore points available in a repository.
+//!
+//! Backups are full, with a snapshot and the txns that follow it, or incremental, with only the
+//! txns written since a previous backup (see `local`). Old backups are deleted according to a
+//! `RetentionPolicy`. Backups can be encrypted on the client side (see `encryption`).
+
+pub mod catalog;
+pub mod encryption;
+pub mod local;
+pub mod manifest;
+pub mod retention;
+pub mod segment;
+
+pub use catalog::{Catalog, RestorePoint};
+pub use encryption::{Encryption, KeyProvider, StaticKey};
+pub use local::{full_backup, incremental_backup, restore, verify_restore, RestoreReport};
+pub use manifest::{BackupFile, BackupManifest, Compression, Ensemble, FileKind};
+pub use retention::{collect_garbage, GcPlan, RetentionPolicy};
@@ -0,0 +1,165 @@
+//! Retention of backups.
+//!
+//! A `RetentionPolicy` selects the backups to keep: the most recent ones, and the most recent
+//! backup of each of the last days and weeks that have backups. Like the purge of a server's data
+//! directory, it never breaks a restore point that is kept: the ancestors of a kept incremental
+//! backup, which hold its snapshot and the txnlogs leading to it, are kept as well.
+
+use serde_derive::Deserialize;
+use serde_derive::Serialize;
+use std::collections::BTreeSet;
+use std::path::Path;
+
+use crate::error::BackupError;
+use super::catalog::Catalog;
+use super::manifest::BackupManifest;
+
+const DAY_MILLIS: u64 = 24 * 60 * 60 * 1000;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[derive(Deserialize, Serialize)]
+pub struct RetentionPolicy {
+    /// Number of days whose most recent backup is kept
+    pub dailies: usize,
+    /// Number of weeks (starting on Monday, UTC) whose most recent backup is kept
+    pub weeklies: usize,
+    /// Number of most recent backups that are kept. The most recent backup is always kept.
+    pub last: usize,
+}
+
+impl Default for RetentionPolicy {
+    fn default() -> Self {
+        RetentionPolicy {
+            dailies: 7,
+            weeklies: 4,
+            last: 3,
+        }
+    }
+}
+
+/// Backups to keep and to delete, by id.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GcPlan {
+    /// Backups to keep, oldest first
+    pub keep: Vec<String>,
+    /// Backups to delete, most recent first
+    pub delete: Vec<String>,
+}
+
+impl RetentionPolicy {
+    pub fn plan(&self, catalog: &Catalog) -> GcPlan {
+        // Most recent first
+        let backups = catalog.manifests().iter().rev().collect::<Vec<_>>();
+        let mut keep = BTreeSet::new();
+
+        for backup in backups.iter().take(self.last.max(1)) {
+            keep.insert(backup.id.as_str());
+        }
+        keep.extend(most_recent_by(&backups, self.dailies, |days| days));
+        // Day 0 (1970-01-01) is a Thursday
+        keep.extend(most_recent_by(&backups, self.weeklies, |days| (days + 3) / 7));
+
+        // Keep the backups that kept backups depend on
+        for backup in &backups {
+            if keep.contains(backup.id.as_str()) {
+                let mut parent = backup.parent.as_deref();
+                while let Some(id) = parent {
+                    keep.insert(id);
+                    parent = catalog.manifest(id).and_then(|m| m.parent.as_deref());
+                }
+            }
+        }
+
+        let (kept, deleted): (Vec<&BackupManifest>, Vec<_>) =
+            backups.into_iter().partition(|m| keep.contains(m.id.as_str()));
+        GcPlan {
+            keep: kept.iter().rev().map(|m| m.id.clone()).collect(),
+            delete: deleted.iter().map(|m| m.id.clone()).collect(),
+        }
+    }
+}
+
+/// Ids of the most recent backup of the last `count` periods that have backups. `period` maps a
+/// number of days since the epoch to a period number.
+fn most_recent_by<'a>(backups: &[&'a BackupManifest], count: usize, period: impl Fn(u64) -> u64) -> Vec<&'a str> {
+    let mut result = Vec::new();
+    let mut last_period = None;
+    for backup in backups {
+        let p = period(backup.created.0 / DAY_MILLIS);
+        if last_period != Some(p) {
+            if result.len() == count {
+                break;
+            }
+            result.push(backup.id.as_str());
+            last_period = Some(p);
+        }
+    }
+    result
+}
+
+/// Delete the backups of a plan from a repository.
+pub fn collect_garbage(repository: impl AsRef<Path>, plan: &GcPlan) -> Result<(), BackupError> {
+    for id in &plan.delete {
+        std::fs::remove_dir_all(repository.as_ref().join(id))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backup::manifest::Ensemble;
+    use crate::Timestamp;
+
+    fn backup(id: &str, day: u64, hour: u64, parent: Option<&str>) -> BackupManifest {
+        let ensemble = Ensemble {
+            name: "prod".to_owned(),
+            servers: vec![],
+        };
+        let mut manifest = BackupManifest::new(id, ensemble, Timestamp(day * DAY_MILLIS + hour * 3_600_000));
+        manifest.parent = parent.map(str::to_owned);
+        manifest
+    }
+
+    #[test]
+    fn retention_plan() {
+        // Day 4 is Monday 1970-01-05
+        let catalog = Catalog::new(vec![
+            backup("w1-full", 1, 0, None),
+            backup("w2-full", 4, 0, None),
+            backup("d5-full", 5, 0, None),
+            backup("d5-incr", 5, 12, Some("d5-full")),
+            backup("d6-full", 6, 0, None),
+            backup("d6-incr", 6, 6, Some("d6-full")),
+            backup("d6-incr2", 6, 12, Some("d6-incr")),
+        ]);
+
+        let ids = |ids: &[&str]| ids.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+
+        let policy = RetentionPolicy {
+            dailies: 0,
+            weeklies: 0,
+            last: 0,
+        };
+        assert_eq!(
+            policy.plan(&catalog),
+            GcPlan {
+                keep: ids(&["d6-full", "d6-incr", "d6-incr2"]),
+                delete: ids(&["d5-incr", "d5-full", "w2-full", "w1-full"]),
+            }
+        );
+
+        let policy = RetentionPolicy {
+            dailies: 2,
+            weeklies: 2,
+            last: 1,
+        };
+        assert_eq!(
+            policy.plan(&catalog),
+            GcPlan {
+                keep: ids(&["w1-full", "d5-full", "d5-incr", "d6-full", "d6-incr", "d6-incr2"]),
+                delete: ids(&["w2-full"]),
+            }
+        );
+    }
+}
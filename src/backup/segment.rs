@@ -0,0 +1,195 @@
+//! Txnlog segments.
+//!
+//! A segment is a txnlog holding a range of the txns of other txnlogs, e.g. the txns written since
+//! the last backup. Records are copied without being decoded, so segments can be cut from logs of
+//! any server version.
+//!
+//! The active txnlog of a server is copied up to its last complete record: a record that is being
+//! written is either truncated or followed by the zeros the server preallocates, and ends the
+//! copy like the end of the log.
+
+use byteorder::{BigEndian, ByteOrder, WriteBytesExt};
+use serde::Deserialize;
+use std::io::{Read, Write};
+
+use crate::error::PersistenceError;
+use crate::persistence::checksum::{Adler32, Checksum};
+use crate::persistence::{FileHeader, TXNLOG_MAGIC};
+use crate::Zxid;
+
+/// Zxids of the txns in a segment.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Segment {
+    pub first_zxid: Zxid,
+    pub last_zxid: Zxid,
+    pub txns: u64,
+}
+
+/// Writes a segment from the records of one or more txnlogs.
+pub struct SegmentWriter<W: Write> {
+    out: W,
+    segment: Option<Segment>,
+    body: Vec<u8>,
+}
+
+impl<W: Write> SegmentWriter<W> {
+    /// Create a segment, writing its header.
+    pub fn new(mut out: W) -> Result<Self, PersistenceError> {
+        out.write_i32::<BigEndian>(TXNLOG_MAGIC)?;
+        out.write_i32::<BigEndian>(2)?; // version
+        out.write_i64::<BigEndian>(0)?; // dbid
+
+        Ok(SegmentWriter {
+            out,
+            segment: None,
+            body: Vec::new(),
+        })
+    }
+
+    /// Copy the txns of a txnlog whose zxid is in `(after, until]`. Txns already in the segment are
+    /// skipped, so that overlapping txnlogs can be copied in order.
+    pub fn copy(&mut self, mut input: impl Read, after: Zxid, until: Zxid) -> Result<(), PersistenceError> {
+        let header = FileHeader::deserialize(&mut crate::serde::de::from_reader(&mut input))?;
+        if header.magic != TXNLOG_MAGIC {
+            return Err(PersistenceError::WrongMagic);
+        }
+
+        let after = match self.segment {
+            Some(segment) => after.max(segment.last_zxid),
+            None => after,
+        };
+
+        while let Some(crc) = self.read_record(&mut input)? {
+            if self.body.len() < 20 {
+                return Err(PersistenceError::Corrupted(format!(
+                    "Txnlog record too short: {} bytes",
+                    self.body.len()
+                )));
+            }
+            // Txn header: session id (i64), cxid (i32), zxid (i64)
+            let zxid = Zxid(BigEndian::read_i64(&self.body[12..20]));
+            if zxid <= after {
+                continue;
+            }
+            if zxid > until {
+                break;
+            }
+
+            self.out.write_u64::<BigEndian>(crc)?;
+            self.out.write_u32::<BigEndian>(self.body.len() as u32)?;
+            self.out.write_all(&self.body)?;
+            self.out.write_u8(b'B')?;
+
+            self.segment = Some(match self.segment {
+                None => Segment {
+                    first_zxid: zxid,
+                    last_zxid: zxid,
+                    txns: 1,
+                },
+                Some(segment) => Segment {
+                    last_zxid: zxid,
+                    txns: segment.txns + 1,
+                    ..segment
+                },
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Read the next complete record in `self.body`, returning its CRC.
+    fn read_record(&mut self, input: &mut impl Read) -> Result<Option<u64>, PersistenceError> {
+        self.body.clear();
+
+        if input.by_ref().take(12).read_to_end(&mut self.body)? < 12 {
+            return Ok(None);
+        }
+        let crc = BigEndian::read_u64(&self.body[..8]);
+        let len = BigEndian::read_u32(&self.body[8..12]) as u64;
+        if len == 0 {
+            return Ok(None);
+        }
+
+        // Body and trailer
+        self.body.clear();
+        input.by_ref().take(len + 1).read_to_end(&mut self.body)?;
+        if self.body.len() as u64 != len + 1 || self.body.pop() != Some(b'B') {
+            return Ok(None);
+        }
+
+        if Adler32.compute(&self.body) != crc {
+            return Err(PersistenceError::ChecksumMismatch {
+                algorithm: Adler32.name(),
+                zxid: crate::persistence::txnlog::record_zxid(&self.body),
+                detected: None,
+            });
+        }
+
+        Ok(Some(crc))
+    }
+
+    /// Terminate the segment, returning the output and the segment's zxids if it isn't empty.
+    pub fn finish(mut self) -> Result<(W, Option<Segment>), PersistenceError> {
+        self.out.write_all(&[0; 12])?; // end of log
+        self.out.flush()?;
+        Ok((self.out, self.segment))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::persistence::testing::*;
+    use crate::persistence::txnlog::TxnlogFile;
+
+    #[test]
+    fn copy_segment() {
+        let dir = temp_dir("copy-segment");
+        let bodies = |zxids: std::ops::RangeInclusive<i64>| {
+            zxids
+                .map(|zxid| txn_body(zxid, 10, 5, &path_op("/app", Some("data"))))
+                .collect::<Vec<_>>()
+        };
+        let old = write_txnlog(&dir, 1, &bodies(1..=5));
+        let active = write_txnlog(&dir, 4, &bodies(4..=9));
+
+        // A record being written at the end of the active log
+        let mut bytes = std::fs::read(&active).unwrap();
+        bytes.truncate(bytes.len() - 12);
+        bytes.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 40, 0, 0]);
+        std::fs::write(&active, bytes).unwrap();
+
+        let end = Zxid(i64::MAX);
+        let mut writer = SegmentWriter::new(Vec::new()).unwrap();
+        writer.copy(&std::fs::read(&old).unwrap()[..], Zxid(2), end).unwrap();
+        writer.copy(&std::fs::read(&active).unwrap()[..], Zxid(2), end).unwrap();
+        let (bytes, segment) = writer.finish().unwrap();
+        assert_eq!(
+            segment,
+            Some(Segment {
+                first_zxid: Zxid(3),
+                last_zxid: Zxid(9),
+                txns: 7
+            })
+        );
+
+        let path = dir.join("log.3");
+        std::fs::write(&path, bytes).unwrap();
+        let zxids = TxnlogFile::new(&path)
+            .unwrap()
+            .map(|txn| txn.unwrap().header.zxid.0)
+            .collect::<Vec<_>>();
+        assert_eq!(zxids, vec![3, 4, 5, 6, 7, 8, 9]);
+
+        let mut writer = SegmentWriter::new(Vec::new()).unwrap();
+        writer
+            .copy(&std::fs::read(&active).unwrap()[..], Zxid(4), Zxid(6))
+            .unwrap();
+        assert_eq!(writer.finish().unwrap().1.map(|s| s.txns), Some(2));
+
+        let (_, segment) = SegmentWriter::new(Vec::new()).unwrap().finish().unwrap();
+        assert_eq!(segment, None);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,360 @@
+//! Bulk loading of a data tree into a live ensemble.
+//!
+//! `BulkLoader` creates the nodes of a `DataTree`, e.g. rebuilt from the data directory of
+//! another ensemble, with their data and ACL. Nodes are loaded level by level, so that parents are
+//! created before their children. The nodes of a level are split between several clients, which
+//! send their requests in parallel, one thread per client: the number of clients bounds the number
+//! of requests in flight.
+//!
+//! Progress can be saved to a checkpoint file, with the last path loaded after each batch. A load
+//! that failed or was interrupted resumes after it when started again with the same checkpoint.
+//!
+//! Ephemeral nodes aren't loaded, since their sessions don't exist in the target ensemble, and
+//! neither are the `/zookeeper` system nodes. Containers and TTL nodes keep their type. Stats
+//! (zxids, versions, times) are those of the target ensemble.
+
+use std::path::{Path, PathBuf};
+
+use super::sync::{Transport, ZooKeeper};
+use crate::error::{ClientError, ParseError};
+use crate::persistence::datatree::DataTree;
+use crate::persistence::snapshot::{DataNode, EphemeralType};
+use crate::proto::ErrorCode;
+use crate::{CreateMode, ANY_VERSION};
+
+/// What to do with nodes that already exist in the target ensemble.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Conflict {
+    /// Keep the existing node
+    Skip,
+    /// Replace the data of the existing node. Its ACL is kept.
+    Overwrite,
+    /// Stop the load with a `NodeExists` error
+    Fail,
+}
+
+/// Result of `BulkLoader::load`.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct LoadReport {
+    pub created: usize,
+    /// Existing nodes whose data was overwritten
+    pub updated: usize,
+    /// Existing nodes that were kept
+    pub skipped: usize,
+    /// Nodes loaded by a previous run, according to the checkpoint
+    pub resumed: usize,
+}
+
+impl LoadReport {
+    fn add(&mut self, other: &LoadReport) {
+        self.created += other.created;
+        self.updated += other.updated;
+        self.skipped += other.skipped;
+    }
+}
+
+/// Loads a data tree into a live ensemble.
+#[derive(Debug, Clone)]
+pub struct BulkLoader {
+    conflict: Conflict,
+    checkpoint: Option<PathBuf>,
+    batch_size: usize,
+}
+
+impl BulkLoader {
+    pub fn new(conflict: Conflict) -> BulkLoader {
+        BulkLoader {
+            conflict,
+            checkpoint: None,
+            batch_size: 1000,
+        }
+    }
+
+    /// Save progress to a file, and resume from it if it exists.
+    pub fn with_checkpoint(mut self, path: impl Into<PathBuf>) -> Self {
+        self.checkpoint = Some(path.into());
+        self
+    }
+
+    /// Number of nodes loaded between checkpoints (1000 by default).
+    pub fn with_batch_size(mut self, batch_size: usize) -> Self {
+        self.batch_size = batch_size.max(1);
+        self
+    }
+
+    /// Load the nodes of `tree`, splitting each level between `clients`.
+    pub fn load<S: Transport + Send>(
+        &self,
+        tree: &DataTree,
+        clients: &mut [ZooKeeper<S>],
+    ) -> Result<LoadReport, ClientError> {
+        if clients.is_empty() {
+            return Err(ParseError::invalid("number of clients", 0).into());
+        }
+
+        let mut nodes = tree
+            .nodes()
+            .filter(|(path, node)| is_loaded(path, node))
+            .collect::<Vec<_>>();
+        nodes.sort_by_key(|(path, _)| (depth(path), *path));
+
+        let mut report = LoadReport::default();
+        if let Some(last) = self.read_checkpoint()? {
+            let key = (depth(&last), last.as_str());
+            report.resumed = nodes.iter().take_while(|(path, _)| (depth(path), *path) <= key).count();
+            nodes.drain(..report.resumed);
+        }
+
+        let mut start = 0;
+        while start < nodes.len() {
+            // A batch has nodes of a single level, which don't depend on each other
+            let level = depth(nodes[start].0);
+            let end = nodes[start..]
+                .iter()
+                .take(self.batch_size)
+                .take_while(|(path, _)| depth(path) == level)
+                .count()
+                + start;
+            let batch = &nodes[start..end];
+
+            let per_client = batch.len().div_ceil(clients.len());
+            let results = std::thread::scope(|scope| {
+                let workers = batch
+                    .chunks(per_client)
+                    .zip(clients.iter_mut())
+                    .map(|(part, zk)| scope.spawn(move || self.load_nodes(tree, zk, part)))
+                    .collect::<Vec<_>>();
+                workers
+                    .into_iter()
+                    .map(|w| w.join().expect("bulk load thread panicked"))
+                    .collect::<Vec<_>>()
+            });
+            for result in results {
+                report.add(&result?);
+            }
+
+            self.write_checkpoint(batch[batch.len() - 1].0)?;
+            start = end;
+        }
+
+        Ok(report)
+    }
+
+    fn load_nodes<S: Transport>(
+        &self,
+        tree: &DataTree,
+        zk: &mut ZooKeeper<S>,
+        nodes: &[(&str, &DataNode)],
+    ) -> Result<LoadReport, ClientError> {
+        let mut report = LoadReport::default();
+        for (path, node) in nodes {
+            match create(tree, zk, path, node) {
+                Ok(()) => report.created += 1,
+                Err(ClientError::Server(ErrorCode::NodeExists)) => match self.conflict {
+                    Conflict::Skip => report.skipped += 1,
+                    Conflict::Overwrite => {
+                        zk.set_data(path, &node.data, ANY_VERSION)?;
+                        report.updated += 1;
+                    }
+                    Conflict::Fail => return Err(ErrorCode::NodeExists.into()),
+                },
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(report)
+    }
+
+    /// Last path loaded by a previous run.
+    fn read_checkpoint(&self) -> Result<Option<String>, ClientError> {
+        match &self.checkpoint {
+            Some(path) if path.exists() => Ok(Some(std::fs::read_to_string(path)?)),
+            _ => Ok(None),
+        }
+    }
+
+    /// Replace the checkpoint, so that it's never partially written.
+    fn write_checkpoint(&self, last: &str) -> Result<(), ClientError> {
+        if let Some(path) = &self.checkpoint {
+            let tmp = tmp_path(path);
+            std::fs::write(&tmp, last)?;
+            std::fs::rename(&tmp, path)?;
+        }
+        Ok(())
+    }
+}
+
+/// Create a node with the type it has in the tree.
+fn create<S: Transport>(
+    tree: &DataTree,
+    zk: &mut ZooKeeper<S>,
+    path: &str,
+    node: &DataNode,
+) -> Result<(), ClientError> {
+    let acl = tree
+        .acl(path)
+        .ok_or_else(|| ParseError::invalid("ACL reference", node.acl.0))?;
+    match node.stat.ephemeral_info.ephemeral_type() {
+        EphemeralType::Container => {
+            zk.create_container(path, &node.data, acl)?;
+        }
+        EphemeralType::TTL(ttl) => {
+            zk.create_ttl(path, &node.data, acl, CreateMode::PersistentWithTTL, ttl)?;
+        }
+        _ => {
+            zk.create(path, &node.data, acl, CreateMode::Persistent)?;
+        }
+    }
+    Ok(())
+}
+
+/// Is a node loaded? The root and system nodes already exist, and ephemeral nodes have no owner.
+fn is_loaded(path: &str, node: &DataNode) -> bool {
+    let system = path == "/zookeeper" || path.starts_with("/zookeeper/");
+    let ephemeral = matches!(node.stat.ephemeral_info.ephemeral_type(), EphemeralType::Normal(_));
+    !path.is_empty() && path != "/" && !system && !ephemeral
+}
+
+fn depth(path: &str) -> usize {
+    path.matches('/').count()
+}
+
+fn tmp_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".tmp");
+    path.with_file_name(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::testing::{handshake, reply_at};
+    use crate::persistence::snapshot::SnapshotFile;
+    use crate::persistence::testing::*;
+    use crate::proto::codec::{self, MAX_PACKET_LENGTH};
+    use crate::proto::*;
+    use crate::{Duration, Id, SessionId, Zxid, ACL, PERM_ALL};
+    use serde::Deserialize;
+    use std::net::{TcpListener, TcpStream};
+    use std::sync::{Arc, Mutex};
+
+    /// Accepts `count` sessions, and answers their requests. Creating `/app` fails with
+    /// `NodeExists`. Returns the operations and paths of the requests, in no particular order.
+    fn serve(listener: TcpListener, count: usize) -> std::thread::JoinHandle<Vec<(OpCode, String)>> {
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        std::thread::spawn(move || {
+            let handlers = (0..count)
+                .map(|_| {
+                    let (mut stream, _) = listener.accept().unwrap();
+                    let requests = requests.clone();
+                    std::thread::spawn(move || handle(&mut stream, &requests))
+                })
+                .collect::<Vec<_>>();
+            for handler in handlers {
+                handler.join().unwrap();
+            }
+            let requests = requests.lock().unwrap().clone();
+            requests
+        })
+    }
+
+    fn handle(stream: &mut TcpStream, requests: &Mutex<Vec<(OpCode, String)>>) {
+        handshake(stream);
+
+        // Until the client is dropped
+        while let Ok(buf) = codec::read_packet(stream, MAX_PACKET_LENGTH) {
+            let (header, mut de) = codec::decode_request(&buf).unwrap();
+            let path = String::deserialize(&mut de).unwrap();
+            requests.lock().unwrap().push((header.typ, path.clone()));
+            let xid = header.xid.0;
+            match header.typ {
+                OpCode::Create if path == "/app" => reply_at(stream, xid, 1, ErrorCode::NodeExists, &()),
+                OpCode::Create => reply_at(stream, xid, 1, ErrorCode::Ok, &CreateResponse { path }),
+                _ => reply_at(stream, xid, 1, ErrorCode::Ok, &SetDataResponse { stat: stat() }),
+            }
+        }
+    }
+
+    fn stat() -> crate::Stat {
+        crate::Stat {
+            czxid: Zxid(1),
+            mzxid: Zxid(1),
+            ctime: crate::Timestamp(0),
+            mtime: crate::Timestamp(0),
+            version: crate::Version(1),
+            cversion: crate::Version(0),
+            aversion: crate::Version(0),
+            ephemeral_owner: SessionId(0),
+            data_length: 0,
+            num_children: 0,
+            pzxid: Zxid(1),
+        }
+    }
+
+    fn connect(addr: &str, count: usize) -> Vec<ZooKeeper> {
+        (0..count)
+            .map(|_| ZooKeeper::connect(addr, Duration(10_000)).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn bulk_load() {
+        let acl = vec![ACL {
+            perms: PERM_ALL,
+            id: Id::anyone(),
+        }];
+        let snapshot = write_snapshot(
+            "bulk-load",
+            &[(11, 3000)],
+            &[(1, acl)],
+            &[
+                ("", node("", -1, 0, 0)),
+                ("/app", node("v1", 1, 0, 1)),
+                ("/app/a", node("a", 1, 0, 2)),
+                ("/app/a/x", node("x", 1, 0, 3)),
+                ("/app/b", node("b", 1, 0, 4)),
+                ("/app/lock", node("", 1, 11, 5)),
+                ("/zookeeper", node("", -1, 0, 0)),
+            ],
+        );
+        let tree = DataTree::from_snapshot(SnapshotFile::new(&snapshot).unwrap()).unwrap();
+        let checkpoint = snapshot.parent().unwrap().join("checkpoint");
+
+        // Parents are created before their children
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let server = serve(listener, 2);
+        let loader = BulkLoader::new(Conflict::Overwrite)
+            .with_checkpoint(&checkpoint)
+            .with_batch_size(2);
+        let report = loader.load(&tree, &mut connect(&addr, 2)).unwrap();
+        let expected = LoadReport {
+            created: 3,
+            updated: 1,
+            skipped: 0,
+            resumed: 0,
+        };
+        assert_eq!(report, expected);
+        let requests = server.join().unwrap();
+        let created = requests.iter().filter(|(op, _)| *op == OpCode::Create).count();
+        assert_eq!(created, 4);
+        assert!(requests.contains(&(OpCode::SetData, "/app".to_owned())));
+        assert_eq!(std::fs::read_to_string(&checkpoint).unwrap(), "/app/a/x");
+
+        // Resuming from the checkpoint of the second batch
+        std::fs::write(&checkpoint, "/app/a").unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let server = serve(listener, 1);
+        let report = BulkLoader::new(Conflict::Fail)
+            .with_checkpoint(&checkpoint)
+            .load(&tree, &mut connect(&addr, 1))
+            .unwrap();
+        assert_eq!((report.created, report.resumed), (2, 2));
+        let requests = server.join().unwrap();
+        let mut paths = requests.iter().map(|(_, path)| path.as_str()).collect::<Vec<_>>();
+        paths.sort_unstable();
+        assert_eq!(paths, vec!["/app/a/x", "/app/b"]);
+
+        remove_snapshot(&snapshot);
+    }
+}
@@ -0,0 +1,167 @@
+//! Cache of `Exists` results.
+//!
+//! Lookups of configuration nodes often poll paths that don't exist, e.g. an optional override.
+//! `ExistsCache` keeps the result of an `Exists` request, including "no node", and sets a watch
+//! with it: the entry is removed when the watch is triggered, by the node's creation, deletion or
+//! a change of its data, and the next lookup sends a new request.
+//!
+//! Watches are only delivered while the client reads responses: an entry may be stale until the
+//! client sends its next request. Watches survive reconnections, and entries are kept. They're all
+//! removed when the session expires or is closed.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, PoisonError};
+
+use super::sync::{Transport, ZooKeeper};
+use crate::error::ClientError;
+use crate::proto::{WatcherEvent, WatcherEventType};
+use crate::Stat;
+
+/// Stats of nodes, or `None` for missing nodes, by path.
+#[derive(Debug, Clone, Default)]
+pub struct ExistsCache {
+    /// Shared with the watchers that remove entries
+    entries: Arc<Mutex<HashMap<String, Option<Stat>>>>,
+}
+
+impl ExistsCache {
+    pub fn new() -> ExistsCache {
+        ExistsCache::default()
+    }
+
+    /// Stat of a node, or `None` if it doesn't exist: from the cache, or from an `Exists` request
+    /// sent with `zk` that sets a watch to remove the entry.
+    pub fn exists<S: Transport>(&self, zk: &mut ZooKeeper<S>, path: &str) -> Result<Option<Stat>, ClientError> {
+        if let Some(stat) = self.lock().get(path) {
+            return Ok(stat.clone());
+        }
+
+        // The lock isn't held during the request, as watchers are called while reading responses
+        let entries = self.entries.clone();
+        let watched = path.to_owned();
+        let stat = zk.watch_exists(path, move |event: &WatcherEvent| {
+            let invalidated = match event.typ {
+                WatcherEventType::None => event.state.is_terminal(),
+                _ => event.path == watched,
+            };
+            if invalidated {
+                entries.lock().unwrap_or_else(PoisonError::into_inner).remove(&watched);
+            }
+        })?;
+        self.lock().insert(path.to_owned(), stat.clone());
+        Ok(stat)
+    }
+
+    /// Does the node exist?
+    pub fn contains<S: Transport>(&self, zk: &mut ZooKeeper<S>, path: &str) -> Result<bool, ClientError> {
+        Ok(self.exists(zk, path)?.is_some())
+    }
+
+    /// Number of cached entries.
+    pub fn len(&self) -> usize {
+        self.lock().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.lock().is_empty()
+    }
+
+    /// Remove all entries. Their watches stay set, and remove nothing when triggered.
+    pub fn clear(&self) {
+        self.lock().clear();
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<String, Option<Stat>>> {
+        self.entries.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::testing::{accept, reply};
+    use crate::proto::codec::{self, MAX_PACKET_LENGTH};
+    use crate::proto::*;
+    use crate::{Duration, SessionId, Timestamp, Version, Zxid};
+    use serde::Deserialize;
+    use std::net::TcpListener;
+
+    /// Accepts a session, and returns the paths of its `Exists` requests. A `NodeCreated`
+    /// notification for `/missing` precedes the response to the first ping.
+    fn serve(listener: TcpListener) -> std::thread::JoinHandle<Vec<String>> {
+        std::thread::spawn(move || {
+            let mut stream = accept(&listener);
+
+            let mut created = false;
+            let mut requests = Vec::new();
+            while let Ok(buf) = codec::read_packet(&mut stream, MAX_PACKET_LENGTH) {
+                let (header, mut de) = codec::decode_request(&buf).unwrap();
+                match header.typ {
+                    OpCode::Ping => {
+                        let event = WatcherEvent {
+                            typ: WatcherEventType::NodeCreated,
+                            state: KeeperState::SyncConnected,
+                            path: "/missing".to_owned(),
+                        };
+                        reply(&mut stream, -1, ErrorCode::Ok, &event);
+                        reply(&mut stream, header.xid.0, ErrorCode::Ok, &());
+                        created = true;
+                    }
+                    OpCode::Exists => {
+                        let request = ExistsRequest::deserialize(&mut de).unwrap();
+                        assert!(request.watch);
+                        if request.path == "/missing" && !created {
+                            reply(&mut stream, header.xid.0, ErrorCode::NoNode, &());
+                        } else {
+                            let stat = Stat {
+                                czxid: Zxid(1),
+                                mzxid: Zxid(1),
+                                ctime: Timestamp(0),
+                                mtime: Timestamp(0),
+                                version: Version(0),
+                                cversion: Version(0),
+                                aversion: Version(0),
+                                ephemeral_owner: SessionId(0),
+                                data_length: 0,
+                                num_children: 0,
+                                pzxid: Zxid(1),
+                            };
+                            reply(&mut stream, header.xid.0, ErrorCode::Ok, &ExistsResponse { stat });
+                        }
+                        requests.push(request.path);
+                    }
+                    OpCode::CloseSession => reply(&mut stream, header.xid.0, ErrorCode::Ok, &()),
+                    op => panic!("unexpected {:?}", op),
+                }
+            }
+            requests
+        })
+    }
+
+    #[test]
+    fn exists_cache() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let server = serve(listener);
+
+        let mut zk = ZooKeeper::connect(&addr, Duration(10_000)).unwrap();
+        let cache = ExistsCache::new();
+        for _ in 0..3 {
+            assert!(cache.exists(&mut zk, "/missing").unwrap().is_none());
+            assert!(cache.contains(&mut zk, "/app").unwrap());
+        }
+        assert_eq!(cache.len(), 2);
+
+        // The creation of the missing node removes its entry
+        zk.ping().unwrap();
+        assert_eq!(cache.len(), 1);
+        assert!(cache.contains(&mut zk, "/missing").unwrap());
+        assert!(cache.contains(&mut zk, "/missing").unwrap());
+
+        // Closing the session empties the cache
+        zk.close().unwrap();
+        assert!(cache.is_empty());
+        let requests = server.join().unwrap();
+        assert_eq!(requests, vec!["/missing", "/app", "/missing"]);
+    }
+}
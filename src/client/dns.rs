@@ -0,0 +1,322 @@
+//! A minimal DNS client, to discover the servers of an ensemble from SRV records.
+//!
+//! The std library can only resolve host names to addresses. Rather than depending on a full
+//! resolver, `DnsResolver` sends SRV queries (RFC 2782) to the name servers of `/etc/resolv.conf`
+//! over UDP, and again over TCP if the response is truncated. Names must be fully qualified, e.g.
+//! `_zookeeper._tcp.example.com`: search domains aren't used.
+
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, SocketAddr, TcpStream, UdpSocket};
+use std::time::Duration;
+
+use super::host::SrvResolver;
+
+pub const DNS_PORT: u16 = 53;
+
+/// Default timeout of a query to a name server
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(5);
+
+const TYPE_SRV: u16 = 33;
+const CLASS_IN: u16 = 1;
+const HEADER_LENGTH: usize = 12;
+/// Maximum length of UDP responses, as we don't advertise a larger one with EDNS
+const MAX_UDP_LENGTH: usize = 512;
+/// Recursion desired
+const FLAG_RD: u16 = 0x0100;
+/// Response
+const FLAG_QR: u8 = 0x80;
+/// Truncated response
+const FLAG_TC: u8 = 0x02;
+/// Maximum number of compression pointers in a name, to detect loops
+const MAX_POINTERS: usize = 32;
+
+/// A SRV record: a server of the service, and when to use it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SrvRecord {
+    /// Servers with the lowest priority must be used first
+    pub priority: u16,
+    /// Relative weight of servers with the same priority
+    pub weight: u16,
+    pub port: u16,
+    /// Host name of the server
+    pub target: String,
+    /// Time to live of the record, in seconds
+    pub ttl: u32,
+}
+
+impl SrvRecord {
+    /// The server as `host:port`
+    pub fn host(&self) -> String {
+        format!("{}:{}", self.target.trim_end_matches('.'), self.port)
+    }
+}
+
+/// Sends SRV queries to name servers, in turn until one answers.
+#[derive(Debug, Clone)]
+pub struct DnsResolver {
+    nameservers: Vec<SocketAddr>,
+    timeout: Duration,
+}
+
+impl DnsResolver {
+    pub fn new(nameservers: Vec<SocketAddr>) -> DnsResolver {
+        DnsResolver {
+            nameservers,
+            timeout: DEFAULT_TIMEOUT,
+        }
+    }
+
+    /// A resolver using the name servers of `/etc/resolv.conf`.
+    pub fn system() -> io::Result<DnsResolver> {
+        let conf = std::fs::read_to_string("/etc/resolv.conf")?;
+        Ok(Self::new(parse_resolv_conf(&conf)))
+    }
+
+    /// Set the timeout of queries to each name server.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn nameservers(&self) -> &[SocketAddr] {
+        &self.nameservers
+    }
+
+    fn query(&self, server: SocketAddr, query: &[u8]) -> io::Result<Vec<u8>> {
+        let local: SocketAddr = match server.ip() {
+            IpAddr::V4(_) => ([0u8; 4], 0).into(),
+            IpAddr::V6(_) => ([0u16; 8], 0).into(),
+        };
+        let socket = UdpSocket::bind(local)?;
+        socket.set_read_timeout(Some(self.timeout))?;
+        socket.connect(server)?;
+        socket.send(query)?;
+
+        let mut buf = vec![0u8; MAX_UDP_LENGTH];
+        loop {
+            let len = socket.recv(&mut buf)?;
+            // Ignore late responses to previous queries
+            if len >= HEADER_LENGTH && buf[..2] == query[..2] {
+                buf.truncate(len);
+                break;
+            }
+        }
+
+        if buf[2] & FLAG_TC != 0 {
+            return self.query_tcp(server, query);
+        }
+        Ok(buf)
+    }
+
+    fn query_tcp(&self, server: SocketAddr, query: &[u8]) -> io::Result<Vec<u8>> {
+        let mut stream = TcpStream::connect_timeout(&server, self.timeout)?;
+        stream.set_read_timeout(Some(self.timeout))?;
+        stream.set_write_timeout(Some(self.timeout))?;
+
+        stream.write_all(&(query.len() as u16).to_be_bytes())?;
+        stream.write_all(query)?;
+
+        let mut len = [0u8; 2];
+        stream.read_exact(&mut len)?;
+        let mut buf = vec![0u8; u16::from_be_bytes(len) as usize];
+        stream.read_exact(&mut buf)?;
+        Ok(buf)
+    }
+}
+
+impl SrvResolver for DnsResolver {
+    fn lookup_srv(&self, name: &str) -> io::Result<Vec<SrvRecord>> {
+        let id = query_id();
+        let query = encode_query(id, name, TYPE_SRV)?;
+
+        let mut last_error = io::Error::new(io::ErrorKind::NotFound, "No name server");
+        for server in &self.nameservers {
+            match self.query(*server, &query).and_then(|r| decode_srv_response(id, &r)) {
+                Ok(records) => return Ok(records),
+                Err(e) => last_error = e,
+            }
+        }
+        Err(last_error)
+    }
+}
+
+/// Name servers of a `resolv.conf` file. Link-local IPv6 addresses with a zone are ignored.
+pub fn parse_resolv_conf(conf: &str) -> Vec<SocketAddr> {
+    conf.lines()
+        .filter_map(|line| {
+            let mut words = line.split(['#', ';']).next()?.split_whitespace();
+            match (words.next(), words.next()) {
+                (Some("nameserver"), Some(addr)) => addr.parse::<IpAddr>().ok(),
+                _ => None,
+            }
+        })
+        .map(|ip| SocketAddr::new(ip, DNS_PORT))
+        .collect()
+}
+
+/// Random query id, using the random keys of the std library to avoid a dependency on `rand`.
+fn query_id() -> u16 {
+    RandomState::new().build_hasher().finish() as u16
+}
+
+/// Encode a recursive query for records of type `typ`.
+fn encode_query(id: u16, name: &str, typ: u16) -> io::Result<Vec<u8>> {
+    let mut buf = Vec::with_capacity(HEADER_LENGTH + name.len() + 6);
+    for value in &[id, FLAG_RD, 1, 0, 0, 0] {
+        buf.extend_from_slice(&value.to_be_bytes());
+    }
+
+    for label in name.trim_end_matches('.').split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Invalid DNS name: {}", name),
+            ));
+        }
+        buf.push(label.len() as u8);
+        buf.extend_from_slice(label.as_bytes());
+    }
+    buf.push(0);
+
+    buf.extend_from_slice(&typ.to_be_bytes());
+    buf.extend_from_slice(&CLASS_IN.to_be_bytes());
+    Ok(buf)
+}
+
+/// Decode the SRV records of the response to query `id`. Other records are ignored.
+fn decode_srv_response(id: u16, buf: &[u8]) -> io::Result<Vec<SrvRecord>> {
+    if buf.len() < HEADER_LENGTH || u16_at(buf, 0)? != id || buf[2] & FLAG_QR == 0 {
+        return Err(invalid("Not a response to the query"));
+    }
+    match buf[3] & 0x0f {
+        0 => {}
+        3 => return Err(io::Error::new(io::ErrorKind::NotFound, "DNS name not found")),
+        code => return Err(io::Error::other(format!("DNS error code {}", code))),
+    }
+
+    let questions = u16_at(buf, 4)?;
+    let answers = u16_at(buf, 6)?;
+
+    let mut pos = HEADER_LENGTH;
+    for _ in 0..questions {
+        pos = read_name(buf, pos)?.1 + 4;
+    }
+
+    let mut records = Vec::new();
+    for _ in 0..answers {
+        pos = read_name(buf, pos)?.1;
+        let typ = u16_at(buf, pos)?;
+        let class = u16_at(buf, pos + 2)?;
+        let ttl = (u16_at(buf, pos + 4)? as u32) << 16 | u16_at(buf, pos + 6)? as u32;
+        let data_len = u16_at(buf, pos + 8)? as usize;
+        pos += 10;
+
+        if typ == TYPE_SRV && class == CLASS_IN {
+            records.push(SrvRecord {
+                priority: u16_at(buf, pos)?,
+                weight: u16_at(buf, pos + 2)?,
+                port: u16_at(buf, pos + 4)?,
+                target: read_name(buf, pos + 6)?.0,
+                ttl,
+            });
+        }
+        pos += data_len;
+    }
+
+    Ok(records)
+}
+
+/// Read a possibly compressed name, and return it with the position that follows it.
+fn read_name(buf: &[u8], mut pos: usize) -> io::Result<(String, usize)> {
+    let mut name = String::new();
+    let mut end = None;
+    let mut pointers = 0;
+    loop {
+        let len = *buf.get(pos).ok_or_else(truncated)? as usize;
+        match len & 0xc0 {
+            0x00 if len == 0 => return Ok((name, end.unwrap_or(pos + 1))),
+            0x00 => {
+                let label = buf.get(pos + 1..pos + 1 + len).ok_or_else(truncated)?;
+                name.push_str(&String::from_utf8_lossy(label));
+                name.push('.');
+                pos += 1 + len;
+            }
+            0xc0 => {
+                pointers += 1;
+                if pointers > MAX_POINTERS {
+                    return Err(invalid("Compression loop in DNS name"));
+                }
+                end.get_or_insert(pos + 2);
+                pos = (u16_at(buf, pos)? & 0x3fff) as usize;
+            }
+            _ => return Err(invalid("Unsupported DNS label type")),
+        }
+    }
+}
+
+fn u16_at(buf: &[u8], pos: usize) -> io::Result<u16> {
+    let bytes = buf.get(pos..pos + 2).ok_or_else(truncated)?;
+    Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+}
+
+fn truncated() -> io::Error {
+    invalid("Truncated DNS response")
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn srv_response() {
+        let query = encode_query(0x1234, "_zk._tcp.example.com.", TYPE_SRV).unwrap();
+        assert_eq!(&query[12..17], b"\x03_zk\x04");
+        assert!(encode_query(1, "a..b", TYPE_SRV).is_err());
+
+        // The query, with response flags and two answers whose names point to the question's
+        let mut response = query.clone();
+        response[2] |= FLAG_QR;
+        response[7] = 2;
+        for (priority, target) in &[(10u16, &b"\x03zk1\xc0\x15"[..]), (20, b"\x03zk2\xc0\x15")] {
+            response.extend_from_slice(&[0xc0, 0x0c, 0, 33, 0, 1, 0, 0, 0x0e, 0x10]);
+            response.extend_from_slice(&(6 + target.len() as u16).to_be_bytes());
+            response.extend_from_slice(&priority.to_be_bytes());
+            response.extend_from_slice(&[0, 5, 0x08, 0x85]);
+            response.extend_from_slice(target);
+        }
+
+        let records = decode_srv_response(0x1234, &response).unwrap();
+        assert_eq!(
+            records[0],
+            SrvRecord {
+                priority: 10,
+                weight: 5,
+                port: 2181,
+                target: "zk1.example.com.".to_owned(),
+                ttl: 3600,
+            }
+        );
+        assert_eq!(records[1].host(), "zk2.example.com:2181");
+
+        assert!(decode_srv_response(0x4321, &response).is_err());
+        assert!(decode_srv_response(0x1234, &response[..response.len() - 1]).is_err());
+        response[3] = 3;
+        let err = decode_srv_response(0x1234, &response).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+
+        let conf = "# comment\nsearch example.com\nnameserver 10.0.0.1 # local\nnameserver ::1\n";
+        assert_eq!(
+            parse_resolv_conf(conf),
+            vec![
+                SocketAddr::from(([10, 0, 0, 1], 53)),
+                SocketAddr::from(([0, 0, 0, 0, 0, 0, 0, 1], 53))
+            ]
+        );
+    }
+}
@@ -0,0 +1,655 @@
+//! Selection of the server a client connects to.
+//!
+//! See [`HostProvider.java`] and [`StaticHostProvider.java`] in the Java client.
+//!
+//! `StaticHostProvider` resolves host names once. In environments where server addresses change,
+//! such as Kubernetes, `ResolvingHostProvider` resolves them every time the client reconnects, and
+//! `SrvHostProvider` discovers the servers themselves from DNS SRV records.
+//!
+//! [`HostProvider.java`]: https://github.com/apache/zookeeper/blob/master/zookeeper-server/src/main/java/org/apache/zookeeper/client/HostProvider.java
+//! [`StaticHostProvider.java`]: https://github.com/apache/zookeeper/blob/master/zookeeper-server/src/main/java/org/apache/zookeeper/client/StaticHostProvider.java
+
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::sync::Arc;
+use std::time::Duration;
+
+use super::dns::{DnsResolver, SrvRecord};
+use crate::clock::{self, Clock};
+use crate::error::ParseError;
+use crate::Timestamp;
+
+pub const DEFAULT_PORT: u16 = 2181;
+
+/// A connect string, e.g. `zk1:2181,zk2:2181,zk3:2181/app`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConnectString {
+    /// Servers as `host:port`
+    pub hosts: Vec<String>,
+    /// Path prefix for all operations
+    pub chroot: Option<String>,
+}
+
+impl ConnectString {
+    pub fn parse(s: &str) -> Result<ConnectString, ParseError> {
+        let (hosts, chroot) = match s.find('/') {
+            Some(idx) => (&s[..idx], Some(&s[idx..])),
+            None => (s, None),
+        };
+
+        let chroot = match chroot {
+            None | Some("/") => None,
+            Some(c) if c.ends_with('/') => return Err(ParseError::invalid("chroot, ending with '/'", c)),
+            Some(c) => Some(c.to_owned()),
+        };
+
+        let hosts = hosts
+            .split(',')
+            .map(str::trim)
+            .filter(|h| !h.is_empty())
+            .map(|h| {
+                // Add the default port if there's none (IPv6 addresses are enclosed in brackets)
+                let has_port = if h.starts_with('[') {
+                    h.contains("]:")
+                } else {
+                    h.contains(':')
+                };
+                if has_port {
+                    h.to_owned()
+                } else {
+                    format!("{}:{}", h, DEFAULT_PORT)
+                }
+            })
+            .collect::<Vec<_>>();
+
+        if hosts.is_empty() {
+            return Err(ParseError::invalid("connect string, without hosts", s));
+        }
+
+        Ok(ConnectString { hosts, chroot })
+    }
+
+    /// Resolve all hosts. Hosts that resolve to several addresses are expanded.
+    pub fn resolve(&self) -> std::io::Result<Vec<SocketAddr>> {
+        let mut addrs = Vec::new();
+        for host in &self.hosts {
+            addrs.extend(host.to_socket_addrs()?);
+        }
+        Ok(addrs)
+    }
+}
+
+/// Provides the addresses of the servers a client connects to.
+pub trait HostProvider {
+    /// Number of servers
+    fn size(&self) -> usize;
+
+    /// Next server to try. Returns `None` if there's no server.
+    fn next(&mut self) -> Option<SocketAddr>;
+
+    /// Notifies that the client has successfully connected to `addr`.
+    fn on_connected(&mut self, addr: SocketAddr);
+
+    /// Notifies that the connection to `addr` failed or was lost.
+    fn on_disconnected(&mut self, addr: SocketAddr);
+}
+
+/// A host provider with a fixed list of servers, shuffled at creation time so that clients are
+/// spread over the ensemble.
+///
+/// After a disconnection it won't return the server that just dropped us, unless it's the only one.
+/// A preferred server (e.g. the leader, for write-heavy clients) can be set: it will be tried first
+/// every time the client has to reconnect.
+///
+pub struct StaticHostProvider {
+    servers: Vec<SocketAddr>,
+    current: usize,
+    preferred: Option<SocketAddr>,
+    try_preferred: bool,
+    last_failed: Option<SocketAddr>,
+}
+
+impl StaticHostProvider {
+    pub fn new(mut servers: Vec<SocketAddr>) -> StaticHostProvider {
+        shuffle(&mut servers);
+        Self::new_ordered(servers)
+    }
+
+    /// Create a provider that uses servers in the given order.
+    pub fn new_ordered(servers: Vec<SocketAddr>) -> StaticHostProvider {
+        StaticHostProvider {
+            servers,
+            current: 0,
+            preferred: None,
+            try_preferred: false,
+            last_failed: None,
+        }
+    }
+
+    pub fn from_connect_string(connect: &ConnectString) -> std::io::Result<StaticHostProvider> {
+        Ok(Self::new(connect.resolve()?))
+    }
+
+    /// Set a server to try first when reconnecting. It must be one of this provider's servers.
+    pub fn set_preferred(&mut self, addr: Option<SocketAddr>) {
+        self.preferred = addr.filter(|a| self.servers.contains(a));
+        self.try_preferred = self.preferred.is_some();
+    }
+
+    pub fn preferred(&self) -> Option<SocketAddr> {
+        self.preferred
+    }
+}
+
+impl HostProvider for StaticHostProvider {
+    fn size(&self) -> usize {
+        self.servers.len()
+    }
+
+    fn next(&mut self) -> Option<SocketAddr> {
+        if self.servers.is_empty() {
+            return None;
+        }
+
+        if self.try_preferred {
+            self.try_preferred = false;
+            if self.preferred != self.last_failed {
+                return self.preferred;
+            }
+        }
+
+        for _ in 0..self.servers.len() {
+            let addr = self.servers[self.current];
+            self.current = (self.current + 1) % self.servers.len();
+
+            if Some(addr) != self.last_failed || self.servers.len() == 1 {
+                return Some(addr);
+            }
+        }
+
+        None
+    }
+
+    fn on_connected(&mut self, _addr: SocketAddr) {
+        self.last_failed = None;
+    }
+
+    fn on_disconnected(&mut self, addr: SocketAddr) {
+        self.last_failed = Some(addr);
+        self.try_preferred = self.preferred.is_some();
+    }
+}
+
+/// Fisher-Yates shuffle, using the random keys of the std library to avoid a dependency on `rand`.
+fn shuffle<T>(items: &mut [T]) {
+    let state = RandomState::new();
+    for i in (1..items.len()).rev() {
+        let mut hasher = state.build_hasher();
+        hasher.write_usize(i);
+        let j = (hasher.finish() % (i as u64 + 1)) as usize;
+        items.swap(i, j);
+    }
+}
+
+//----- Name resolution
+
+/// Resolves host names to addresses.
+pub trait Resolver: Send + Sync {
+    /// Addresses of `host`, given as `host:port`
+    fn resolve(&self, host: &str) -> std::io::Result<Vec<SocketAddr>>;
+}
+
+/// Resolves host names with the system resolver.
+#[derive(Debug, Copy, Clone, Default)]
+pub struct SystemResolver;
+
+impl Resolver for SystemResolver {
+    fn resolve(&self, host: &str) -> std::io::Result<Vec<SocketAddr>> {
+        Ok(host.to_socket_addrs()?.collect())
+    }
+}
+
+impl<F> Resolver for F
+where
+    F: Fn(&str) -> std::io::Result<Vec<SocketAddr>> + Send + Sync,
+{
+    fn resolve(&self, host: &str) -> std::io::Result<Vec<SocketAddr>> {
+        self(host)
+    }
+}
+
+/// Looks up the SRV records of a service name.
+pub trait SrvResolver: Send + Sync {
+    fn lookup_srv(&self, name: &str) -> std::io::Result<Vec<SrvRecord>>;
+}
+
+impl<F> SrvResolver for F
+where
+    F: Fn(&str) -> std::io::Result<Vec<SrvRecord>> + Send + Sync,
+{
+    fn lookup_srv(&self, name: &str) -> std::io::Result<Vec<SrvRecord>> {
+        self(name)
+    }
+}
+
+/// A host provider that resolves host names every time it's asked for a server, so that servers
+/// whose address has changed are found again when reconnecting. Hosts are shuffled at creation
+/// time, and those that can't be resolved are skipped.
+///
+/// A host that resolves to several addresses is reached through the first one, unless it's the
+/// one that just dropped us.
+///
+pub struct ResolvingHostProvider {
+    hosts: Vec<String>,
+    current: usize,
+    resolver: Arc<dyn Resolver>,
+    last_failed: Option<SocketAddr>,
+}
+
+impl ResolvingHostProvider {
+    /// Create a provider for hosts given as `host:port`.
+    pub fn new(mut hosts: Vec<String>) -> ResolvingHostProvider {
+        shuffle(&mut hosts);
+        Self::new_ordered(hosts)
+    }
+
+    /// Create a provider that uses hosts in the given order.
+    pub fn new_ordered(hosts: Vec<String>) -> ResolvingHostProvider {
+        ResolvingHostProvider {
+            hosts,
+            current: 0,
+            resolver: Arc::new(SystemResolver),
+            last_failed: None,
+        }
+    }
+
+    pub fn from_connect_string(connect: &ConnectString) -> ResolvingHostProvider {
+        Self::new(connect.hosts.clone())
+    }
+
+    /// Set the resolver used instead of the system's.
+    pub fn with_resolver(mut self, resolver: Arc<dyn Resolver>) -> Self {
+        self.resolver = resolver;
+        self
+    }
+
+    pub fn hosts(&self) -> &[String] {
+        &self.hosts
+    }
+}
+
+impl HostProvider for ResolvingHostProvider {
+    fn size(&self) -> usize {
+        self.hosts.len()
+    }
+
+    fn next(&mut self) -> Option<SocketAddr> {
+        let mut failed = None;
+        for _ in 0..self.hosts.len() {
+            let host = &self.hosts[self.current];
+            self.current = (self.current + 1) % self.hosts.len();
+
+            let addrs = match self.resolver.resolve(host) {
+                Ok(addrs) => addrs,
+                Err(_) => continue,
+            };
+            match addrs.iter().find(|a| Some(**a) != self.last_failed) {
+                Some(addr) => return Some(*addr),
+                None => failed = failed.or_else(|| addrs.first().cloned()),
+            }
+        }
+
+        // Only the server that just dropped us is available
+        failed
+    }
+
+    fn on_connected(&mut self, _addr: SocketAddr) {
+        self.last_failed = None;
+    }
+
+    fn on_disconnected(&mut self, addr: SocketAddr) {
+        self.last_failed = Some(addr);
+    }
+}
+
+/// A host provider that discovers the servers of the ensemble from the DNS SRV records of a
+/// service name, e.g. `_zookeeper._tcp.example.com`.
+///
+/// Records are looked up again once their TTL has expired, and if that fails the servers that
+/// were previously found are kept. Host names of servers are resolved every time the client
+/// reconnects, like `ResolvingHostProvider`. Servers with the lowest priority are tried first, in
+/// random order: weights are ignored.
+///
+pub struct SrvHostProvider {
+    name: String,
+    srv: Arc<dyn SrvResolver>,
+    resolver: Arc<dyn Resolver>,
+    clock: Arc<dyn Clock>,
+    looked_up: Timestamp,
+    ttl: Duration,
+    hosts: ResolvingHostProvider,
+}
+
+impl SrvHostProvider {
+    /// Look up the servers of `name` with `srv`. Fails if there's no server.
+    pub fn lookup(name: &str, srv: Arc<dyn SrvResolver>) -> std::io::Result<SrvHostProvider> {
+        let clock = clock::system();
+        let mut provider = SrvHostProvider {
+            name: name.to_owned(),
+            srv,
+            resolver: Arc::new(SystemResolver),
+            looked_up: clock.now(),
+            clock,
+            ttl: Duration::default(),
+            hosts: ResolvingHostProvider::new_ordered(Vec::new()),
+        };
+        provider.refresh()?;
+        Ok(provider)
+    }
+
+    /// Look up the servers of `name` with the name servers of `/etc/resolv.conf`.
+    pub fn from_dns(name: &str) -> std::io::Result<SrvHostProvider> {
+        Self::lookup(name, Arc::new(DnsResolver::system()?))
+    }
+
+    /// Set the resolver of host names used instead of the system's.
+    pub fn with_resolver(mut self, resolver: Arc<dyn Resolver>) -> Self {
+        self.hosts.resolver = resolver.clone();
+        self.resolver = resolver;
+        self
+    }
+
+    /// Set the clock used to expire SRV records.
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.looked_up = clock.now();
+        self.clock = clock;
+        self
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Servers found in the SRV records, as `host:port`
+    pub fn hosts(&self) -> &[String] {
+        self.hosts.hosts()
+    }
+
+    fn refresh(&mut self) -> std::io::Result<()> {
+        let mut records = self.srv.lookup_srv(&self.name)?;
+        if records.is_empty() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("No SRV record for {}", self.name),
+            ));
+        }
+
+        // Stable sort, that keeps the shuffled order of servers with the same priority
+        shuffle(&mut records);
+        records.sort_by_key(|r| r.priority);
+
+        let last_failed = self.hosts.last_failed;
+        self.hosts = ResolvingHostProvider::new_ordered(records.iter().map(SrvRecord::host).collect())
+            .with_resolver(self.resolver.clone());
+        self.hosts.last_failed = last_failed;
+
+        self.ttl = Duration::from_secs(records.iter().map(|r| r.ttl).min().unwrap_or_default() as u64);
+        self.looked_up = self.clock.now();
+        Ok(())
+    }
+}
+
+impl HostProvider for SrvHostProvider {
+    fn size(&self) -> usize {
+        self.hosts.size()
+    }
+
+    fn next(&mut self) -> Option<SocketAddr> {
+        if self.clock.elapsed(self.looked_up) >= self.ttl {
+            // Keep the previous servers if the lookup fails
+            let _ = self.refresh();
+        }
+        self.hosts.next()
+    }
+
+    fn on_connected(&mut self, addr: SocketAddr) {
+        self.hosts.on_connected(addr);
+    }
+
+    fn on_disconnected(&mut self, addr: SocketAddr) {
+        self.hosts.on_disconnected(addr);
+    }
+}
+
+//----- Server mode discovery
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ServerMode {
+    Leader,
+    Follower,
+    Observer,
+    Standalone,
+}
+
+impl ServerMode {
+    /// Find the server mode in the output of the `srvr` four letter word.
+    pub fn from_srvr_output(output: &str) -> Option<ServerMode> {
+        let mode = output.lines().find_map(|l| l.trim().strip_prefix("Mode:"))?;
+        match mode.trim() {
+            "leader" => Some(ServerMode::Leader),
+            "follower" => Some(ServerMode::Follower),
+            "observer" => Some(ServerMode::Observer),
+            "standalone" => Some(ServerMode::Standalone),
+            _ => None,
+        }
+    }
+
+    /// Ask a server its mode using the `srvr` four letter word, which must be whitelisted on the
+    /// server (see `4lw.commands.whitelist`).
+    pub fn probe(addr: SocketAddr, timeout: Duration) -> std::io::Result<Option<ServerMode>> {
+        let mut stream = TcpStream::connect_timeout(&addr, timeout)?;
+        stream.set_read_timeout(Some(timeout))?;
+        stream.set_write_timeout(Some(timeout))?;
+
+        stream.write_all(b"srvr")?;
+        let mut output = String::new();
+        stream.read_to_string(&mut output)?;
+
+        Ok(Self::from_srvr_output(&output))
+    }
+}
+
+/// Find the leader (or standalone server) among a set of servers. Servers that can't be reached
+/// are ignored.
+pub fn discover_leader(servers: &[SocketAddr], timeout: Duration) -> Option<SocketAddr> {
+    servers.iter().cloned().find(|addr| {
+        matches!(
+            ServerMode::probe(*addr, timeout),
+            Ok(Some(ServerMode::Leader)) | Ok(Some(ServerMode::Standalone))
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::sync::Mutex;
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    #[test]
+    fn parse_connect_string() {
+        let cs = ConnectString::parse("zk1:2181, zk2,[::1]:2183,[::1]/app/foo").unwrap();
+        assert_eq!(cs.hosts, vec!["zk1:2181", "zk2:2181", "[::1]:2183", "[::1]:2181"]);
+        assert_eq!(cs.chroot, Some("/app/foo".to_owned()));
+
+        assert_eq!(ConnectString::parse("zk1/").unwrap().chroot, None);
+        assert!(ConnectString::parse("zk1/app/").is_err());
+        assert!(ConnectString::parse("/app").is_err());
+    }
+
+    #[test]
+    fn avoid_failed_host() {
+        let mut hosts = StaticHostProvider::new_ordered(vec![addr(1), addr(2), addr(3)]);
+
+        assert_eq!(hosts.next(), Some(addr(1)));
+        hosts.on_connected(addr(1));
+        hosts.on_disconnected(addr(1));
+
+        assert_eq!(hosts.next(), Some(addr(2)));
+        assert_eq!(hosts.next(), Some(addr(3)));
+        // Skip 1
+        assert_eq!(hosts.next(), Some(addr(2)));
+        hosts.on_connected(addr(2));
+        assert_eq!(hosts.next(), Some(addr(3)));
+        assert_eq!(hosts.next(), Some(addr(1)));
+    }
+
+    #[test]
+    fn single_host() {
+        let mut hosts = StaticHostProvider::new(vec![addr(1)]);
+        hosts.on_disconnected(addr(1));
+        assert_eq!(hosts.next(), Some(addr(1)));
+    }
+
+    #[test]
+    fn preferred_host() {
+        let mut hosts = StaticHostProvider::new_ordered(vec![addr(1), addr(2), addr(3)]);
+        hosts.set_preferred(Some(addr(3)));
+
+        assert_eq!(hosts.next(), Some(addr(3)));
+        assert_eq!(hosts.next(), Some(addr(1)));
+        hosts.on_connected(addr(1));
+        hosts.on_disconnected(addr(1));
+
+        assert_eq!(hosts.next(), Some(addr(3)));
+        hosts.on_connected(addr(3));
+        hosts.on_disconnected(addr(3));
+
+        // Don't go back to the preferred host that just dropped us
+        assert_eq!(hosts.next(), Some(addr(2)));
+
+        // Not one of our servers
+        hosts.set_preferred(Some(addr(4)));
+        assert_eq!(hosts.preferred(), None);
+    }
+
+    #[test]
+    fn shuffled_hosts() {
+        let servers = (1..20).map(addr).collect::<Vec<_>>();
+        let mut hosts = StaticHostProvider::new(servers.clone());
+
+        let mut result = (0..servers.len()).map(|_| hosts.next().unwrap()).collect::<Vec<_>>();
+        result.sort();
+        assert_eq!(result, servers);
+    }
+
+    #[test]
+    fn srvr_output() {
+        let output =
+            "Zookeeper version: 3.5.5-390fe37ea45dee01bf87dc1c042b5e3dcce88653, built on 05/03/2019 12:07 GMT\n\
+                      Latency min/avg/max: 0/0/0\n\
+                      Received: 1\n\
+                      Mode: leader\n\
+                      Node count: 5\n";
+
+        assert_eq!(ServerMode::from_srvr_output(output), Some(ServerMode::Leader));
+        assert_eq!(
+            ServerMode::from_srvr_output("Mode: observer"),
+            Some(ServerMode::Observer)
+        );
+        assert_eq!(ServerMode::from_srvr_output("srvr is not executed"), None);
+    }
+
+    #[test]
+    fn re_resolve_hosts() {
+        let dns = Arc::new(Mutex::new(HashMap::new()));
+        dns.lock().unwrap().insert("zk1:2181", vec![addr(1)]);
+        dns.lock().unwrap().insert("zk2:2181", vec![addr(2), addr(3)]);
+
+        let resolver = {
+            let dns = dns.clone();
+            move |host: &str| {
+                let addrs = dns.lock().unwrap().get(host).cloned();
+                addrs.ok_or_else(|| std::io::Error::from(std::io::ErrorKind::NotFound))
+            }
+        };
+        let mut hosts = ResolvingHostProvider::new_ordered(vec!["zk1:2181".to_owned(), "zk2:2181".to_owned()])
+            .with_resolver(Arc::new(resolver));
+
+        assert_eq!(hosts.next(), Some(addr(1)));
+        hosts.on_disconnected(addr(1));
+        assert_eq!(hosts.next(), Some(addr(2)));
+        hosts.on_disconnected(addr(2));
+        assert_eq!(hosts.next(), Some(addr(1)));
+        // Another address of the host that dropped us
+        assert_eq!(hosts.next(), Some(addr(3)));
+        hosts.on_connected(addr(3));
+
+        // zk1 moved, and zk2 can't be resolved anymore
+        dns.lock().unwrap().remove("zk2:2181");
+        dns.lock().unwrap().insert("zk1:2181", vec![addr(4)]);
+        assert_eq!(hosts.next(), Some(addr(4)));
+        assert_eq!(hosts.next(), Some(addr(4)));
+        hosts.on_disconnected(addr(4));
+        // The only server left
+        assert_eq!(hosts.next(), Some(addr(4)));
+    }
+
+    #[test]
+    fn srv_hosts() {
+        let records = Arc::new(Mutex::new(Vec::new()));
+        let srv = {
+            let records = records.clone();
+            move |_: &str| Ok(records.lock().unwrap().clone())
+        };
+        let record = |priority, target: &str, port| SrvRecord {
+            priority,
+            weight: 1,
+            port,
+            target: format!("{}.", target),
+            ttl: 60,
+        };
+        let resolver = |host: &str| {
+            let port = host.rsplit(':').next().unwrap().parse().unwrap();
+            Ok(vec![addr(port)])
+        };
+
+        assert!(SrvHostProvider::lookup("_zk._tcp.example.com", Arc::new(srv.clone())).is_err());
+
+        records
+            .lock()
+            .unwrap()
+            .extend(vec![record(20, "zk3", 3), record(10, "zk1", 1), record(10, "zk2", 2)]);
+        let clock = clock::MockClock::new(Timestamp(0));
+        let mut hosts = SrvHostProvider::lookup("_zk._tcp.example.com", Arc::new(srv))
+            .unwrap()
+            .with_resolver(Arc::new(resolver))
+            .with_clock(Arc::new(clock.clone()));
+
+        let mut first = hosts.hosts()[..2].to_vec();
+        first.sort();
+        assert_eq!(first, vec!["zk1:1", "zk2:2"]);
+        assert_eq!(hosts.hosts()[2], "zk3:3");
+        assert_eq!(hosts.size(), 3);
+
+        // Records are looked up again once expired, and kept if there are none
+        records.lock().unwrap().push(record(0, "zk4", 4));
+        hosts.next();
+        assert_eq!(hosts.size(), 3);
+        clock.advance(Duration::from_secs(60));
+        assert_eq!(hosts.next(), Some(addr(4)));
+        assert_eq!(hosts.size(), 4);
+
+        records.lock().unwrap().clear();
+        clock.advance(Duration::from_secs(60));
+        hosts.on_disconnected(addr(4));
+        assert_ne!(hosts.next(), Some(addr(4)));
+        assert_eq!(hosts.size(), 4);
+    }
+}
@@ -0,0 +1,109 @@
+//! Hooks around the requests of a client.
+//!
+//! Interceptors are added to a client with `ZooKeeper::add_interceptor`, and are called in turn
+//! for each request, including pings and the requests that set watches again after a
+//! reconnection. They see requests and replies as encoded bytes, since they can be any operation,
+//! and decode the ones they're interested in: most request bodies start with the node's path.
+//!
+//! This is where cross-cutting concerns go: recording traffic, adding metadata to requests, or
+//! enforcing a path prefix per tenant. A request that an interceptor rejects isn't sent.
+
+use crate::error::ClientError;
+use crate::proto::{ReplyHeader, RequestHeader};
+
+/// Hooks called around each request of a client.
+pub trait Interceptor: Send {
+    /// Called before a request is sent, with its encoded body that can be changed. An error fails
+    /// the request, which isn't sent.
+    fn on_request(&mut self, header: &RequestHeader, body: &mut Vec<u8>) -> Result<(), ClientError> {
+        let _ = (header, body);
+        Ok(())
+    }
+
+    /// Called with the reply to a request, before its body is decoded. Replies with an error
+    /// have no body. An error fails the request, which has been processed by the server.
+    fn on_response(&mut self, request: &RequestHeader, reply: &ReplyHeader, body: &[u8]) -> Result<(), ClientError> {
+        let _ = (request, reply, body);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::sync::ZooKeeper;
+    use crate::client::testing::{accept, reply};
+    use crate::proto::codec::{self, MAX_PACKET_LENGTH};
+    use crate::proto::*;
+    use crate::serde::from_slice;
+    use crate::Duration;
+    use serde::Deserialize;
+    use std::net::TcpListener;
+    use std::sync::{Arc, Mutex};
+
+    /// Only allows paths under a prefix, and records the operations and their error codes.
+    struct Tenant {
+        prefix: &'static str,
+        log: Arc<Mutex<Vec<(OpCode, i32)>>>,
+    }
+
+    impl Interceptor for Tenant {
+        fn on_request(&mut self, header: &RequestHeader, body: &mut Vec<u8>) -> Result<(), ClientError> {
+            if header.typ == OpCode::GetData {
+                let path: String = from_slice(body)?;
+                if !path.starts_with(self.prefix) {
+                    return Err(ErrorCode::NoAuth.into());
+                }
+            }
+            Ok(())
+        }
+
+        fn on_response(&mut self, request: &RequestHeader, reply: &ReplyHeader, _: &[u8]) -> Result<(), ClientError> {
+            self.log.lock().unwrap().push((request.typ, reply.err));
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn interceptors() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+
+        let server = std::thread::spawn(move || {
+            let mut stream = accept(&listener);
+            let mut paths = Vec::new();
+            while let Ok(buf) = codec::read_packet(&mut stream, MAX_PACKET_LENGTH) {
+                let (header, mut de) = codec::decode_request(&buf).unwrap();
+                match header.typ {
+                    OpCode::GetData => {
+                        paths.push(String::deserialize(&mut de).unwrap());
+                        reply(&mut stream, header.xid.0, ErrorCode::NoNode, &());
+                    }
+                    OpCode::CloseSession => reply(&mut stream, header.xid.0, ErrorCode::Ok, &()),
+                    op => panic!("unexpected {:?}", op),
+                }
+            }
+            paths
+        });
+
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let mut zk = ZooKeeper::connect(&addr, Duration(10_000)).unwrap();
+        zk.add_interceptor(Tenant {
+            prefix: "/tenant/",
+            log: log.clone(),
+        });
+
+        let err = zk.get_data("/other", false).unwrap_err();
+        assert_eq!(err.code(), Some(ErrorCode::NoAuth));
+        let err = zk.get_data("/tenant/a", false).unwrap_err();
+        assert_eq!(err.code(), Some(ErrorCode::NoNode));
+        zk.close().unwrap();
+
+        assert_eq!(server.join().unwrap(), vec!["/tenant/a"]);
+        let log = log.lock().unwrap();
+        assert_eq!(
+            *log,
+            vec![(OpCode::GetData, ErrorCode::NoNode as i32), (OpCode::CloseSession, 0)]
+        );
+    }
+}
@@ -0,0 +1,192 @@
+//! A local file that keeps the watches of a client across restarts.
+//!
+//! Watches belong to a session, and are lost when the process that set them exits. A
+//! `WatchJournal` saves them with the last zxid seen by the client, as the `SetWatches2` request
+//! that sets them again. A restarted process loads it and sends it on its new session: servers
+//! then register the watches, and send the notifications of the changes that followed the saved
+//! zxid, by comparing it with the nodes' mzxid and pzxid. Changes missed while the process was
+//! down are delivered as if the watches had been set all along.
+//!
+//! One-shot watches that were triggered since the journal was saved are set again. The journal
+//! should be saved when watches are added, and before the process exits.
+
+use serde_derive::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+use super::sync::{Transport, ZooKeeper};
+use crate::error::{ClientError, ParseError};
+use crate::proto::SetWatches2;
+use crate::Zxid;
+
+/// "ZKWJ"
+const JOURNAL_MAGIC: i32 = 0x5a4b574a;
+const JOURNAL_VERSION: i32 = 1;
+
+#[derive(Serialize, Deserialize)]
+struct JournalFile {
+    magic: i32,
+    version: i32,
+    watches: SetWatches2,
+}
+
+/// A file with the watches of a client and its last zxid.
+#[derive(Debug, Clone)]
+pub struct WatchJournal {
+    path: PathBuf,
+}
+
+impl WatchJournal {
+    pub fn new(path: impl AsRef<Path>) -> WatchJournal {
+        WatchJournal {
+            path: path.as_ref().to_owned(),
+        }
+    }
+
+    /// Replace the journal with the watches of a client and its last zxid. It's written to a
+    /// temporary file that is then renamed, so that it's never partially written.
+    pub fn save<S: Transport>(&self, zk: &ZooKeeper<S>) -> Result<(), ClientError> {
+        let watches = zk.watches().set_watches(zk.last_zxid()).unwrap_or(SetWatches2 {
+            relative_zxid: zk.last_zxid(),
+            data_watches: Vec::new(),
+            exist_watches: Vec::new(),
+            child_watches: Vec::new(),
+            persistent_watches: Vec::new(),
+            persistent_recursive_watches: Vec::new(),
+        });
+        let file = JournalFile {
+            magic: JOURNAL_MAGIC,
+            version: JOURNAL_VERSION,
+            watches,
+        };
+
+        let mut name = self.path.file_name().unwrap_or_default().to_os_string();
+        name.push(".tmp");
+        let tmp = self.path.with_file_name(name);
+        std::fs::write(&tmp, crate::serde::to_vec(&file)?)?;
+        std::fs::rename(&tmp, &self.path)?;
+        Ok(())
+    }
+
+    /// The saved watches, or `None` if the journal doesn't exist.
+    pub fn load(&self) -> Result<Option<SetWatches2>, ClientError> {
+        if !self.path.exists() {
+            return Ok(None);
+        }
+        let bytes = std::fs::read(&self.path)?;
+        let file: JournalFile = crate::serde::from_slice(&bytes)?;
+        if file.magic != JOURNAL_MAGIC || file.version != JOURNAL_VERSION {
+            return Err(ParseError::invalid("watch journal", self.path.display()).into());
+        }
+        Ok(Some(file.watches))
+    }
+
+    /// Set the saved watches on a client. Returns the zxid they were saved at, or `None` if the
+    /// journal doesn't exist.
+    pub fn restore<S: Transport>(&self, zk: &mut ZooKeeper<S>) -> Result<Option<Zxid>, ClientError> {
+        match self.load()? {
+            Some(watches) => {
+                zk.set_watches(&watches)?;
+                Ok(Some(watches.relative_zxid))
+            }
+            None => Ok(None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::testing::{accept, reply};
+    use crate::client::watch::WatchKind;
+    use crate::proto::codec::{self, MAX_PACKET_LENGTH};
+    use crate::proto::*;
+    use crate::{Duration, SessionId, Timestamp, Version};
+    use serde::Deserialize;
+    use std::net::TcpListener;
+
+    fn stat() -> crate::Stat {
+        crate::Stat {
+            czxid: Zxid(1),
+            mzxid: Zxid(1),
+            ctime: Timestamp(0),
+            mtime: Timestamp(0),
+            version: Version(0),
+            cversion: Version(0),
+            aversion: Version(0),
+            ephemeral_owner: SessionId(0),
+            data_length: 0,
+            num_children: 0,
+            pzxid: Zxid(1),
+        }
+    }
+
+    #[test]
+    fn watch_journal() {
+        let path = std::env::temp_dir().join("zookeepers-watch-journal");
+        let _ = std::fs::remove_file(&path);
+        let journal = WatchJournal::new(&path);
+        assert!(journal.load().unwrap().is_none());
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let server = std::thread::spawn(move || {
+            // The first process sets a recursive watch and a data watch
+            let mut stream = accept(&listener);
+            for _ in 0..3 {
+                let buf = codec::read_packet(&mut stream, MAX_PACKET_LENGTH).unwrap();
+                let (header, _) = codec::decode_request(&buf).unwrap();
+                match header.typ {
+                    OpCode::AddWatch => reply(
+                        &mut stream,
+                        header.xid.0,
+                        ErrorCode::Ok,
+                        &ErrorResponse { err: ErrorCode::Ok },
+                    ),
+                    OpCode::GetData => {
+                        let response = GetDataResponse {
+                            data: Vec::new(),
+                            stat: stat(),
+                        };
+                        reply(&mut stream, header.xid.0, ErrorCode::Ok, &response)
+                    }
+                    _ => reply(&mut stream, header.xid.0, ErrorCode::Ok, &()),
+                }
+            }
+
+            // The restarted one sets them again, and is told of a missed change
+            let mut stream = accept(&listener);
+            let buf = codec::read_packet(&mut stream, MAX_PACKET_LENGTH).unwrap();
+            let (header, mut de) = codec::decode_request(&buf).unwrap();
+            assert_eq!(header.typ, OpCode::SetWatches2);
+            let request = SetWatches2::deserialize(&mut de).unwrap();
+            let event = WatcherEvent {
+                typ: WatcherEventType::NodeDataChanged,
+                state: KeeperState::SyncConnected,
+                path: "/cfg".to_owned(),
+            };
+            reply(&mut stream, -1, ErrorCode::Ok, &event);
+            reply(&mut stream, header.xid.0, ErrorCode::Ok, &());
+            request
+        });
+
+        let mut zk = ZooKeeper::connect(&addr, Duration(10_000)).unwrap();
+        zk.add_watch("/app", AddWatchMode::PersistentRecursive).unwrap();
+        zk.get_data("/cfg", true).unwrap();
+        journal.save(&zk).unwrap();
+        zk.close().unwrap();
+
+        let mut zk = ZooKeeper::connect(&addr, Duration(10_000)).unwrap();
+        assert_eq!(journal.restore(&mut zk).unwrap(), Some(Zxid(12)));
+        let events = zk.events().map(|e| e.path).collect::<Vec<_>>();
+        assert_eq!(events, vec!["/cfg"]);
+        assert!(zk.watches().paths(WatchKind::Data).is_empty());
+        assert_eq!(zk.watches().paths(WatchKind::PersistentRecursive), vec!["/app"]);
+
+        let request = server.join().unwrap();
+        assert_eq!(request.relative_zxid, Zxid(12));
+        assert_eq!(request.data_watches, vec!["/cfg"]);
+        assert_eq!(request.persistent_recursive_watches, vec!["/app"]);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,202 @@
+//! Discovery of an ensemble deployed as a Kubernetes StatefulSet.
+//!
+//! Pods of a StatefulSet are named `<statefulset>-<ordinal>`, and a headless service gives each of
+//! them a stable DNS name: `<pod>.<service>.<namespace>.svc.<cluster domain>`. Their IP address
+//! changes when they're rescheduled, so these names must be resolved again on every reconnection,
+//! which `ResolvingHostProvider` does. By convention (followed by the usual Helm charts and
+//! operators) the server id of a pod is its ordinal plus one.
+//!
+//! The members of the ensemble can be found by probing the DNS names of pods, or from the ensemble
+//! configuration in `/zookeeper/config` after a reconfiguration.
+
+use std::convert::TryFrom;
+use std::net::IpAddr;
+
+use super::host::{ConnectString, Resolver, ResolvingHostProvider, DEFAULT_PORT};
+use crate::proto::config::QuorumConfig;
+
+pub const DEFAULT_CLUSTER_DOMAIN: &str = "cluster.local";
+
+/// The pods of a StatefulSet that are members of the ensemble.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StatefulSetEnsemble {
+    statefulset: String,
+    service: String,
+    namespace: String,
+    cluster_domain: String,
+    port: u16,
+    first_id: i64,
+    ordinals: Vec<u32>,
+}
+
+impl StatefulSetEnsemble {
+    /// An ensemble of `replicas` pods of `statefulset`, named by the headless `service` of
+    /// `namespace`.
+    pub fn new(statefulset: &str, service: &str, namespace: &str, replicas: u32) -> StatefulSetEnsemble {
+        StatefulSetEnsemble {
+            statefulset: statefulset.to_owned(),
+            service: service.to_owned(),
+            namespace: namespace.to_owned(),
+            cluster_domain: DEFAULT_CLUSTER_DOMAIN.to_owned(),
+            port: DEFAULT_PORT,
+            first_id: 1,
+            ordinals: (0..replicas).collect(),
+        }
+    }
+
+    pub fn with_cluster_domain(mut self, cluster_domain: &str) -> Self {
+        self.cluster_domain = cluster_domain.to_owned();
+        self
+    }
+
+    /// Set the client port of servers.
+    pub fn with_port(mut self, port: u16) -> Self {
+        self.port = port;
+        self
+    }
+
+    /// Set the server id of the pod with ordinal 0.
+    pub fn with_first_id(mut self, first_id: i64) -> Self {
+        self.first_id = first_id;
+        self
+    }
+
+    /// Ordinals of the member pods
+    pub fn ordinals(&self) -> &[u32] {
+        &self.ordinals
+    }
+
+    /// DNS name of a pod, without the port
+    pub fn pod_name(&self, ordinal: u32) -> String {
+        format!(
+            "{}-{}.{}.{}.svc.{}",
+            self.statefulset, ordinal, self.service, self.namespace, self.cluster_domain
+        )
+    }
+
+    /// Name of the SRV records of the service's port named `port_name` (e.g. `client`), to find
+    /// ready pods with `SrvHostProvider` rather than probing their names.
+    pub fn srv_name(&self, port_name: &str) -> String {
+        format!(
+            "_{}._tcp.{}.{}.svc.{}",
+            port_name, self.service, self.namespace, self.cluster_domain
+        )
+    }
+
+    /// Member pods, as `host:port`
+    pub fn hosts(&self) -> Vec<String> {
+        self.ordinals
+            .iter()
+            .map(|o| format!("{}:{}", self.pod_name(*o), self.port))
+            .collect()
+    }
+
+    pub fn connect_string(&self, chroot: Option<&str>) -> ConnectString {
+        ConnectString {
+            hosts: self.hosts(),
+            chroot: chroot.map(str::to_owned),
+        }
+    }
+
+    /// A host provider that resolves the names of member pods on every reconnection.
+    pub fn host_provider(&self) -> ResolvingHostProvider {
+        ResolvingHostProvider::new(self.hosts())
+    }
+
+    /// Find the pods that exist among ordinals `0..max_replicas`, e.g. after the StatefulSet was
+    /// scaled: pods whose name can't be resolved are ignored. Members are left unchanged if no pod
+    /// is found. Returns whether members changed.
+    pub fn discover(&mut self, resolver: &dyn Resolver, max_replicas: u32) -> bool {
+        let ordinals = (0..max_replicas)
+            .filter(|o| {
+                let host = format!("{}:{}", self.pod_name(*o), self.port);
+                matches!(resolver.resolve(&host), Ok(addrs) if !addrs.is_empty())
+            })
+            .collect::<Vec<_>>();
+
+        self.set_ordinals(ordinals)
+    }
+
+    /// Update members from the ensemble configuration. A server is mapped to a pod from the host
+    /// name of its quorum address, or from its id if the address is an IP address. Servers with
+    /// the host name of another StatefulSet are ignored. Returns whether members changed.
+    pub fn update(&mut self, config: &QuorumConfig) -> bool {
+        let mut ordinals = config
+            .servers
+            .iter()
+            .filter_map(|server| {
+                let address = server.quorum_address.as_str();
+                let host = address.rsplit_once(':').map_or(address, |(host, _)| host);
+                let host = host.trim_matches(['[', ']']);
+                if host.parse::<IpAddr>().is_ok() {
+                    u32::try_from(server.id - self.first_id).ok()
+                } else {
+                    self.ordinal_of(host)
+                }
+            })
+            .collect::<Vec<_>>();
+        ordinals.sort_unstable();
+        ordinals.dedup();
+
+        self.set_ordinals(ordinals)
+    }
+
+    /// Ordinal of a pod, from its short or fully qualified name.
+    fn ordinal_of(&self, host: &str) -> Option<u32> {
+        let pod = host.split('.').next()?;
+        pod.strip_prefix(self.statefulset.as_str())?
+            .strip_prefix('-')?
+            .parse()
+            .ok()
+    }
+
+    fn set_ordinals(&mut self, ordinals: Vec<u32>) -> bool {
+        if ordinals.is_empty() || ordinals == self.ordinals {
+            return false;
+        }
+        self.ordinals = ordinals;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::host::HostProvider;
+    use std::net::SocketAddr;
+
+    #[test]
+    fn statefulset_members() {
+        let mut ensemble = StatefulSetEnsemble::new("zk", "zk-hs", "db", 3);
+        assert_eq!(
+            ensemble.connect_string(Some("/app")).hosts,
+            vec![
+                "zk-0.zk-hs.db.svc.cluster.local:2181",
+                "zk-1.zk-hs.db.svc.cluster.local:2181",
+                "zk-2.zk-hs.db.svc.cluster.local:2181",
+            ]
+        );
+        assert_eq!(ensemble.srv_name("client"), "_client._tcp.zk-hs.db.svc.cluster.local");
+
+        // Scaled up to 5 pods, one of which isn't scheduled yet
+        let resolver = |host: &str| match host {
+            "zk-3.zk-hs.db.svc.cluster.local:2181" => Err(std::io::ErrorKind::NotFound.into()),
+            _ => Ok(vec![SocketAddr::from(([10, 0, 0, 1], 2181))]),
+        };
+        assert!(ensemble.discover(&resolver, 5));
+        assert_eq!(ensemble.ordinals(), &[0, 1, 2, 4]);
+        assert!(!ensemble.discover(&resolver, 5));
+
+        let config = QuorumConfig::parse(
+            b"server.1=zk-0.zk-hs.db.svc.cluster.local:2888:3888;2181\n\
+              server.3=10.0.0.3:2888:3888;2181\n\
+              server.6=zk-4:2888:3888:observer;2181\n\
+              server.8=other-1:2888:3888;2181\n\
+              version=100000002\n",
+        )
+        .unwrap();
+        assert!(ensemble.update(&config));
+        assert_eq!(ensemble.ordinals(), &[0, 2, 4]);
+        assert_eq!(ensemble.host_provider().size(), 3);
+    }
+}
@@ -0,0 +1,22 @@
+//! ZooKeeper client.
+
+#[cfg(feature = "persistence")]
+pub mod bulk;
+pub mod cache;
+pub mod dns;
+pub mod host;
+pub mod interceptor;
+pub mod journal;
+#[cfg(feature = "kubernetes")]
+pub mod kubernetes;
+pub mod sasl;
+pub mod session;
+#[cfg(feature = "persistence")]
+pub mod subtree;
+pub mod sync;
+#[cfg(test)]
+pub(crate) mod testing;
+#[cfg(feature = "tls")]
+pub mod tls;
+pub mod watch;
+pub mod xid;
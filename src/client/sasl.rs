@@ -0,0 +1,188 @@
+//! SASL authentication of sessions.
+//!
+//! See [`ZooKeeperSaslClient.java`] in the Java client. Once the session is established, the
+//! client and the server exchange tokens in `GetSASLRequest` requests (`OpCode::Sasl`) and their
+//! `SetSASLResponse` until the mechanism completes. A failed authentication is a server error,
+//! usually `AuthFailed`, after which the server closes the session.
+//!
+//! This crate doesn't implement mechanisms: they're provided through the `SaslMechanism` trait,
+//! e.g. DIGEST-MD5 with the credentials of the server's JAAS configuration, or GSSAPI on top of a
+//! Kerberos library.
+//!
+//! [`ZooKeeperSaslClient.java`]: https://github.com/apache/zookeeper/blob/master/zookeeper-server/src/main/java/org/apache/zookeeper/client/ZooKeeperSaslClient.java
+
+use super::sync::{Transport, ZooKeeper};
+use crate::error::ClientError;
+use crate::proto::{GetSASLRequest, SetSASLResponse};
+
+/// Default service name of servers, the first part of their Kerberos principal
+pub const DEFAULT_SERVICE: &str = "zookeeper";
+
+/// Server name used by servers for DIGEST-MD5
+pub const DIGEST_MD5_SERVER_NAME: &str = "zk-sasl-md5";
+
+/// The client side of a SASL mechanism, like Java's `SaslClient`.
+pub trait SaslMechanism {
+    /// Mechanism name, e.g. `DIGEST-MD5` or `GSSAPI`
+    fn name(&self) -> &str;
+
+    /// Does the client send the first token? If it does, it's the response to an empty challenge.
+    fn has_initial_response(&self) -> bool;
+
+    /// Response to a challenge of the server, or `None` if there's nothing to send.
+    fn evaluate_challenge(&mut self, challenge: &[u8]) -> Result<Option<Vec<u8>>, ClientError>;
+
+    /// Has the exchange completed successfully?
+    fn is_complete(&self) -> bool;
+}
+
+impl<M: SaslMechanism + ?Sized> SaslMechanism for &mut M {
+    fn name(&self) -> &str {
+        (**self).name()
+    }
+
+    fn has_initial_response(&self) -> bool {
+        (**self).has_initial_response()
+    }
+
+    fn evaluate_challenge(&mut self, challenge: &[u8]) -> Result<Option<Vec<u8>>, ClientError> {
+        (**self).evaluate_challenge(challenge)
+    }
+
+    fn is_complete(&self) -> bool {
+        (**self).is_complete()
+    }
+}
+
+/// Drives the token exchange of a mechanism, independently of how requests are sent.
+pub struct SaslAuthenticator<M> {
+    mechanism: M,
+}
+
+impl<M: SaslMechanism> SaslAuthenticator<M> {
+    pub fn new(mechanism: M) -> SaslAuthenticator<M> {
+        SaslAuthenticator { mechanism }
+    }
+
+    /// The first request to send. Its token is empty if the server speaks first.
+    pub fn start(&mut self) -> Result<GetSASLRequest, ClientError> {
+        let token = if self.mechanism.has_initial_response() {
+            self.mechanism.evaluate_challenge(&[])?.unwrap_or_default()
+        } else {
+            Vec::new()
+        };
+        Ok(GetSASLRequest { token })
+    }
+
+    /// Handle the server's response to the previous request, and return the next request to send,
+    /// or `None` once authenticated.
+    pub fn on_response(&mut self, response: &SetSASLResponse) -> Result<Option<GetSASLRequest>, ClientError> {
+        if self.mechanism.is_complete() {
+            return Ok(None);
+        }
+
+        match self.mechanism.evaluate_challenge(&response.token)? {
+            Some(token) => Ok(Some(GetSASLRequest { token })),
+            None if self.mechanism.is_complete() => Ok(None),
+            None => Err(ClientError::Protocol(format!(
+                "{} has no response to the server's challenge",
+                self.mechanism.name()
+            ))),
+        }
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.mechanism.is_complete()
+    }
+
+    pub fn into_inner(self) -> M {
+        self.mechanism
+    }
+}
+
+impl<S: Transport> ZooKeeper<S> {
+    /// Authenticate the session with a SASL mechanism.
+    pub fn authenticate_sasl(&mut self, mechanism: &mut dyn SaslMechanism) -> Result<(), ClientError> {
+        let mut sasl = SaslAuthenticator::new(mechanism);
+        let mut request = sasl.start()?;
+        loop {
+            let response = self.call(&request)?;
+            match sasl.on_response(&response)? {
+                Some(next) => request = next,
+                None => return Ok(()),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::VecDeque;
+
+    /// Sends scripted tokens in turn, and completes once they have all been sent.
+    struct Scripted {
+        initial: bool,
+        tokens: VecDeque<Option<&'static [u8]>>,
+        challenges: Vec<Vec<u8>>,
+    }
+
+    impl Scripted {
+        fn new(initial: bool, tokens: Vec<Option<&'static [u8]>>) -> Scripted {
+            Scripted {
+                initial,
+                tokens: tokens.into(),
+                challenges: Vec::new(),
+            }
+        }
+    }
+
+    impl SaslMechanism for Scripted {
+        fn name(&self) -> &str {
+            "SCRIPTED"
+        }
+
+        fn has_initial_response(&self) -> bool {
+            self.initial
+        }
+
+        fn evaluate_challenge(&mut self, challenge: &[u8]) -> Result<Option<Vec<u8>>, ClientError> {
+            self.challenges.push(challenge.to_vec());
+            Ok(self.tokens.pop_front().flatten().map(<[u8]>::to_vec))
+        }
+
+        fn is_complete(&self) -> bool {
+            self.tokens.is_empty()
+        }
+    }
+
+    fn response(token: &[u8]) -> SetSASLResponse {
+        SetSASLResponse { token: token.to_vec() }
+    }
+
+    #[test]
+    fn token_exchange() {
+        // The server speaks first, like DIGEST-MD5
+        let mut sasl = SaslAuthenticator::new(Scripted::new(false, vec![Some(b"response")]));
+        assert!(sasl.start().unwrap().token.is_empty());
+        let request = sasl.on_response(&response(b"nonce")).unwrap().unwrap();
+        assert_eq!(request.token, b"response");
+        assert!(sasl.is_complete());
+        assert!(sasl.on_response(&response(b"rspauth")).unwrap().is_none());
+        assert_eq!(sasl.into_inner().challenges, vec![b"nonce".to_vec()]);
+
+        // The client speaks first, like GSSAPI
+        let mut sasl = SaslAuthenticator::new(Scripted::new(true, vec![Some(b"ticket"), Some(b"")]));
+        assert_eq!(sasl.start().unwrap().token, b"ticket");
+        assert!(sasl.on_response(&response(b"ok")).unwrap().unwrap().token.is_empty());
+        assert!(sasl.on_response(&response(b"")).unwrap().is_none());
+
+        // Nothing to answer before completion
+        let mut sasl = SaslAuthenticator::new(Scripted::new(false, vec![None, Some(b"late")]));
+        sasl.start().unwrap();
+        assert!(matches!(
+            sasl.on_response(&response(b"challenge")),
+            Err(ClientError::Protocol(_))
+        ));
+    }
+}
@@ -0,0 +1,192 @@
+//! A session that survives connection losses.
+//!
+//! See `ClientCnxn` in the Java client. A `Session` wraps a blocking client and the servers of the
+//! ensemble. When the connection is lost, it resumes the session on another server with its id,
+//! password and last zxid seen, and sets its watches again. The request that saw the connection
+//! loss fails with `ConnectionLoss`, since it may or may not have been processed: the application
+//! decides if it can be sent again.
+//!
+//! Changes of the connection state are delivered to watches and to the client's `events()`, as
+//! events with no path like the Java client does: `Disconnected` when the connection is lost,
+//! `SyncConnected` once the session is resumed, and `Expired` if it can't be. An expired session
+//! can't be used anymore.
+
+use std::net::{SocketAddr, TcpStream};
+
+use super::host::HostProvider;
+use super::sync::{Transport, ZooKeeper};
+use crate::error::ClientError;
+use crate::proto::{ErrorCode, KeeperState};
+use crate::Duration;
+
+/// A session that reconnects to the servers of a host provider.
+pub struct Session<S: Transport = TcpStream> {
+    zk: ZooKeeper<S>,
+    hosts: Box<dyn HostProvider>,
+    open: Box<dyn Fn(SocketAddr) -> Result<S, ClientError>>,
+    /// Server of the current connection
+    server: Option<SocketAddr>,
+    state: KeeperState,
+}
+
+impl Session {
+    /// Connect to one of the servers of a connect string. Chroots aren't supported.
+    pub fn connect(connect_string: &str, session_timeout: Duration) -> Result<Session, ClientError> {
+        let hosts = ZooKeeper::host_provider(connect_string)?;
+        Self::connect_with(Box::new(hosts), session_timeout, |addr| Ok(TcpStream::connect(addr)?))
+    }
+}
+
+impl<S: Transport> Session<S> {
+    /// Connect to one of the servers of a host provider, opening streams with `open`.
+    pub fn connect_with(
+        mut hosts: Box<dyn HostProvider>,
+        session_timeout: Duration,
+        open: impl Fn(SocketAddr) -> Result<S, ClientError> + 'static,
+    ) -> Result<Session<S>, ClientError> {
+        let zk = ZooKeeper::connect_each(hosts.as_mut(), session_timeout, &open)?;
+        Ok(Session {
+            server: zk.server_addr(),
+            zk,
+            hosts,
+            open: Box::new(open),
+            state: KeeperState::SyncConnected,
+        })
+    }
+
+    pub fn state(&self) -> KeeperState {
+        self.state
+    }
+
+    /// The client of the current connection, e.g. to read its events. Requests sent directly with
+    /// it don't reconnect: see `run`.
+    pub fn client(&mut self) -> &mut ZooKeeper<S> {
+        &mut self.zk
+    }
+
+    /// Run operations on the client, reconnecting first if the connection was lost. If they lose
+    /// the connection, the session is resumed on another server and they fail with `ConnectionLoss`.
+    pub fn run<T>(&mut self, op: impl FnOnce(&mut ZooKeeper<S>) -> Result<T, ClientError>) -> Result<T, ClientError> {
+        match self.state {
+            KeeperState::Expired => return Err(ErrorCode::SessionExpired.into()),
+            KeeperState::Disconnected => self.reconnect()?,
+            _ => {}
+        }
+
+        match op(&mut self.zk) {
+            Err(e) if e.is_connection_loss() => {
+                self.transition(KeeperState::Disconnected);
+                if let Some(addr) = self.server.take() {
+                    self.hosts.on_disconnected(addr);
+                }
+                // The next call tries again if no server is available
+                let _ = self.reconnect();
+                Err(ErrorCode::ConnectionLoss.into())
+            }
+            result => result,
+        }
+    }
+
+    /// Resume the session on the next servers of the host provider, trying each of them once.
+    pub fn reconnect(&mut self) -> Result<(), ClientError> {
+        for _ in 0..self.hosts.size() {
+            let addr = match self.hosts.next() {
+                Some(addr) => addr,
+                None => break,
+            };
+            let zk = &mut self.zk;
+            match (self.open)(addr).and_then(|stream| zk.resume(stream)) {
+                Ok(()) => {
+                    self.hosts.on_connected(addr);
+                    self.server = Some(addr);
+                    self.transition(KeeperState::SyncConnected);
+                    return Ok(());
+                }
+                Err(ClientError::Server(ErrorCode::SessionExpired)) => {
+                    self.transition(KeeperState::Expired);
+                    return Err(ErrorCode::SessionExpired.into());
+                }
+                Err(_) => self.hosts.on_disconnected(addr),
+            }
+        }
+        Err(ErrorCode::ConnectionLoss.into())
+    }
+
+    /// Close the session, which deletes its ephemeral nodes.
+    pub fn close(self) -> Result<(), ClientError> {
+        self.zk.close()
+    }
+
+    fn transition(&mut self, state: KeeperState) {
+        if state != self.state {
+            self.state = state;
+            self.zk.notify_state(state);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::host::StaticHostProvider;
+    use crate::client::testing::{handshake, reply};
+    use crate::client::xid::SET_WATCHES_XID;
+    use crate::proto::codec::{self, MAX_PACKET_LENGTH};
+    use crate::proto::*;
+    use crate::{SessionId, Zxid};
+    use serde::Deserialize;
+    use std::net::TcpListener;
+
+    #[test]
+    fn session_reconnection() {
+        let first = TcpListener::bind("127.0.0.1:0").unwrap();
+        let second = TcpListener::bind("127.0.0.1:0").unwrap();
+        let servers = vec![first.local_addr().unwrap(), second.local_addr().unwrap()];
+
+        let server = std::thread::spawn(move || {
+            // The first server sets a watch, and then drops the connection
+            let (mut stream, _) = first.accept().unwrap();
+            let request = handshake(&mut stream);
+            assert_eq!(request.session_id, SessionId(0));
+            codec::read_packet(&mut stream, MAX_PACKET_LENGTH).unwrap();
+            let children = GetChildrenResponse {
+                children: vec!["a".to_owned()],
+            };
+            reply(&mut stream, 1, ErrorCode::Ok, &children);
+            codec::read_packet(&mut stream, MAX_PACKET_LENGTH).unwrap();
+            drop(stream);
+
+            // The second one resumes the session and its watches
+            let (mut stream, _) = second.accept().unwrap();
+            let request = handshake(&mut stream);
+            assert_eq!(request.session_id, SessionId(42));
+            assert_eq!(request.passwd, vec![7; 16]);
+            assert_eq!(request.last_zxid_seen, Zxid(11));
+            let buf = codec::read_packet(&mut stream, MAX_PACKET_LENGTH).unwrap();
+            let (header, mut de) = codec::decode_request(&buf).unwrap();
+            assert_eq!(header.xid, SET_WATCHES_XID);
+            assert_eq!(SetWatches::deserialize(&mut de).unwrap().child_watches, vec!["/app"]);
+            reply(&mut stream, -8, ErrorCode::Ok, &());
+
+            codec::read_packet(&mut stream, MAX_PACKET_LENGTH).unwrap();
+            reply(&mut stream, 3, ErrorCode::Ok, &children);
+            let buf = codec::read_packet(&mut stream, MAX_PACKET_LENGTH).unwrap();
+            assert_eq!(codec::decode_request(&buf).unwrap().0.typ, OpCode::CloseSession);
+            reply(&mut stream, 4, ErrorCode::Ok, &());
+        });
+
+        let hosts = Box::new(StaticHostProvider::new_ordered(servers));
+        let mut session = Session::connect_with(hosts, Duration(10_000), |addr| Ok(TcpStream::connect(addr)?)).unwrap();
+        assert_eq!(session.run(|zk| zk.get_children("/app", true)).unwrap(), vec!["a"]);
+
+        let err = session.run(|zk| zk.get_children("/app", false)).unwrap_err();
+        assert_eq!(err.code(), Some(ErrorCode::ConnectionLoss));
+        assert_eq!(session.state(), KeeperState::SyncConnected);
+        let states = session.client().events().map(|e| e.state).collect::<Vec<_>>();
+        assert_eq!(states, vec![KeeperState::Disconnected, KeeperState::SyncConnected]);
+
+        assert_eq!(session.run(|zk| zk.get_children("/app", false)).unwrap(), vec!["a"]);
+        session.close().unwrap();
+        server.join().unwrap();
+    }
+}
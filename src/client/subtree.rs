@@ -0,0 +1,546 @@
+//! Export of a subtree from a live ensemble.
+//!
+//! `SubtreeExport` reads the nodes below a path, with their data, ACL and stat: the reverse of
+//! `client::bulk`. The nodes of each level are split between several clients, which send their
+//! requests in parallel, one thread per client. A rate limit can be set on top of this, to spare
+//! servers that also serve production traffic.
+//!
+//! The result can be written as a snapshot file, which `DataTree` and ZooKeeper servers can read.
+//! It also has the ancestors of the subtree's root, without their other children, since nodes
+//! can't be loaded without their parent.
+//!
+//! Nodes are read while they may be changed: the export isn't a point in time view of the
+//! subtree. Nodes deleted while being read are left out. `read_subtree_consistent` gets closer to
+//! one, with a single client: it syncs the server and reads again the nodes that changed after the
+//! sync. Servers don't tell the type of container and TTL nodes: they're exported as regular nodes.
+
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::Instant;
+
+use super::sync::{Transport, ZooKeeper};
+use super::watch::is_ancestor_or_self;
+use crate::error::{ClientError, ParseError, PersistenceError};
+use crate::path::ZkPath;
+use crate::persistence::snapshot::{ACLRef, DataNode, EphemeralInfo, SnapshotWriter, StatPersisted};
+use crate::proto::ErrorCode;
+use crate::{Stat, Zxid, ACL};
+
+/// Reads a subtree from a live ensemble.
+#[derive(Debug, Clone)]
+pub struct SubtreeExport {
+    /// Maximum number of requests per second, for all clients
+    max_rate: Option<u32>,
+    /// Number of times `read_subtree_consistent` reads again nodes that changed
+    max_retries: u32,
+}
+
+/// Nodes read by `SubtreeExport`.
+#[derive(Debug, Clone)]
+pub struct Subtree {
+    /// Nodes and their snapshot path (the root node's is ""), parents before their children
+    pub nodes: Vec<(String, DataNode)>,
+    /// ACLs referenced by the nodes
+    pub acls: HashMap<ACLRef, Vec<ACL>>,
+}
+
+/// A node read from a server
+struct NodeRead {
+    path: String,
+    data: Vec<u8>,
+    stat: Stat,
+    acl: Vec<ACL>,
+    children: Vec<String>,
+    /// Did the node keep the same stat between the requests that read it?
+    unchanged: bool,
+}
+
+impl Default for SubtreeExport {
+    fn default() -> SubtreeExport {
+        SubtreeExport {
+            max_rate: None,
+            max_retries: 5,
+        }
+    }
+}
+
+impl SubtreeExport {
+    pub fn new() -> SubtreeExport {
+        SubtreeExport::default()
+    }
+
+    /// Send at most `max_rate` requests per second, for all clients. Reading a node takes up to
+    /// three requests: data, ACL and children.
+    pub fn with_max_rate(mut self, max_rate: u32) -> Self {
+        self.max_rate = Some(max_rate.max(1));
+        self
+    }
+
+    /// Number of times `read_subtree_consistent` reads again the nodes that changed, before giving
+    /// up. Defaults to 5.
+    pub fn with_max_retries(mut self, max_retries: u32) -> Self {
+        self.max_retries = max_retries;
+        self
+    }
+
+    /// Read the subtree below `path`, splitting each level between `clients`.
+    pub fn export<S: Transport + Send>(
+        &self,
+        path: &str,
+        clients: &mut [ZooKeeper<S>],
+    ) -> Result<Subtree, ClientError> {
+        if clients.is_empty() {
+            return Err(ParseError::invalid("number of clients", 0).into());
+        }
+        let root = ZkPath::new(path)?;
+        let limiter = RateLimiter::new(self.max_rate);
+        let mut read = read_ancestors(&mut clients[0], &limiter, &root)?;
+
+        let mut level = vec![root.as_str().to_owned()];
+        while !level.is_empty() {
+            let per_client = level.len().div_ceil(clients.len());
+            let results = std::thread::scope(|scope| {
+                let workers = level
+                    .chunks(per_client)
+                    .zip(clients.iter_mut())
+                    .map(|(paths, zk)| {
+                        let limiter = &limiter;
+                        scope.spawn(move || {
+                            let mut nodes = Vec::new();
+                            for path in paths {
+                                nodes.extend(read_node(zk, limiter, path, true)?);
+                            }
+                            Ok::<_, ClientError>(nodes)
+                        })
+                    })
+                    .collect::<Vec<_>>();
+                workers
+                    .into_iter()
+                    .map(|w| w.join().expect("export thread panicked"))
+                    .collect::<Vec<_>>()
+            });
+
+            let mut next = Vec::new();
+            for nodes in results {
+                for node in nodes? {
+                    next.extend(child_paths(&node));
+                    read.push(node);
+                }
+            }
+            level = next;
+        }
+
+        if !read.iter().any(|node| node.path == root.as_str()) {
+            return Err(ErrorCode::NoNode.into());
+        }
+        Ok(Subtree::new(read))
+    }
+
+    /// Read the subtree below `path` as of a single zxid. The server is first synced with the
+    /// leader: nodes that changed after the sync, or between the requests that read them, are read
+    /// again with their subtree after another sync. Returns the nodes and the zxid of the last
+    /// sync, or an `Inconsistent` error if nodes still changed after `max_retries` rounds.
+    ///
+    /// Only one client is used, since other servers may be behind the synced one. This is a best
+    /// effort: nodes kept from an earlier round were unchanged at that round's zxid, but may have
+    /// changed before the last one. Ancestors of `path` aren't checked.
+    pub fn read_subtree_consistent<S: Transport>(
+        &self,
+        path: &str,
+        zk: &mut ZooKeeper<S>,
+    ) -> Result<(Subtree, Zxid), ClientError> {
+        let root = ZkPath::new(path)?;
+        let limiter = RateLimiter::new(self.max_rate);
+        let mut read = read_ancestors(zk, &limiter, &root)?;
+
+        // A parent's path sorts before its children's
+        let mut nodes = BTreeMap::new();
+        let mut pending = vec![root.as_str().to_owned()];
+        for _ in 0..=self.max_retries {
+            zk.sync(root.as_str())?;
+            let zxid = zk.last_zxid();
+
+            let mut changed = Vec::new();
+            while let Some(path) = pending.pop() {
+                match read_node(zk, &limiter, &path, true)? {
+                    Some(node) if node.unchanged && node.stat.mzxid <= zxid && node.stat.pzxid <= zxid => {
+                        pending.extend(child_paths(&node));
+                        nodes.insert(path, node);
+                    }
+                    Some(_) => changed.push(path),
+                    None if path == root.as_str() => return Err(ErrorCode::NoNode.into()),
+                    // Deleted after its parent listed it: the parent's children changed
+                    None => changed.push(ZkPath::new(&path)?.parent().map_or(path, ZkPath::into_string)),
+                }
+            }
+            if changed.is_empty() {
+                read.extend(nodes.into_values());
+                return Ok((Subtree::new(read), zxid));
+            }
+
+            nodes.retain(|path, _| !changed.iter().any(|c| is_ancestor_or_self(c, path)));
+            changed.sort();
+            changed.dedup();
+            pending = changed
+                .iter()
+                .filter(|path| !changed.iter().any(|c| c != *path && is_ancestor_or_self(c, path)))
+                .cloned()
+                .collect();
+        }
+
+        Err(ClientError::Inconsistent(format!(
+            "{} still changed after {} retries",
+            root, self.max_retries
+        )))
+    }
+}
+
+impl Subtree {
+    fn new(read: Vec<NodeRead>) -> Subtree {
+        let mut acls: Vec<(ACLRef, Vec<ACL>)> = Vec::new();
+        let mut nodes = Vec::with_capacity(read.len());
+        for node in read {
+            let acl_ref = match acls.iter().find(|(_, acl)| *acl == node.acl) {
+                Some((acl_ref, _)) => *acl_ref,
+                None => {
+                    let acl_ref = ACLRef(acls.len() as i64 + 1);
+                    acls.push((acl_ref, node.acl));
+                    acl_ref
+                }
+            };
+            let path = if node.path == "/" { String::new() } else { node.path };
+            let data_node = DataNode {
+                data: node.data,
+                acl: acl_ref,
+                stat: persisted_stat(&node.stat),
+            };
+            nodes.push((path, data_node));
+        }
+
+        Subtree {
+            nodes,
+            acls: acls.into_iter().collect(),
+        }
+    }
+
+    /// Most recent zxid of the nodes, e.g. to name a snapshot `snapshot.<zxid in hex>`.
+    pub fn zxid(&self) -> Zxid {
+        let zxids = self.nodes.iter().map(|(_, n)| n.stat.mzxid.max(n.stat.pzxid));
+        zxids.max().unwrap_or(Zxid(0))
+    }
+
+    /// Write the nodes as a snapshot file, without sessions.
+    pub fn write_snapshot(&self, path: impl AsRef<Path>) -> Result<(), PersistenceError> {
+        let mut writer = SnapshotWriter::create(path)?;
+        writer.sessions(&HashMap::new())?;
+        writer.acls(&self.acls)?;
+        for (path, node) in &self.nodes {
+            writer.node(path, node)?;
+        }
+        writer.finish(None)?;
+        Ok(())
+    }
+}
+
+/// Read the ancestors of `root`, parents first. They only have their data, ACL and stat.
+fn read_ancestors<S: Transport>(
+    zk: &mut ZooKeeper<S>,
+    limiter: &RateLimiter,
+    root: &ZkPath,
+) -> Result<Vec<NodeRead>, ClientError> {
+    let mut ancestors = Vec::new();
+    let mut parent = root.parent();
+    while let Some(path) = parent {
+        parent = path.parent();
+        ancestors.push(path);
+    }
+
+    let mut read = Vec::new();
+    for ancestor in ancestors.iter().rev() {
+        match read_node(zk, limiter, ancestor.as_str(), false)? {
+            Some(node) => read.push(node),
+            None => return Err(ErrorCode::NoNode.into()),
+        }
+    }
+    Ok(read)
+}
+
+/// Paths of the children of a node.
+fn child_paths(node: &NodeRead) -> impl Iterator<Item = String> + '_ {
+    let parent = if node.path == "/" { "" } else { node.path.as_str() };
+    node.children.iter().map(move |child| format!("{}/{}", parent, child))
+}
+
+/// Read a node and, if `children` is set, the names of its children. `None` if it was deleted.
+fn read_node<S: Transport>(
+    zk: &mut ZooKeeper<S>,
+    limiter: &RateLimiter,
+    path: &str,
+    children: bool,
+) -> Result<Option<NodeRead>, ClientError> {
+    let no_node = |e: &ClientError| e.code() == Some(ErrorCode::NoNode);
+
+    limiter.acquire();
+    let (data, stat) = match zk.get_data(path, false) {
+        Err(e) if no_node(&e) => return Ok(None),
+        result => result?,
+    };
+    limiter.acquire();
+    let (acl, acl_stat) = match zk.get_acl(path) {
+        Err(e) if no_node(&e) => return Ok(None),
+        result => result?,
+    };
+    let mut unchanged = same_stat(&stat, &acl_stat);
+    let children = if children && stat.num_children > 0 {
+        limiter.acquire();
+        let (children, children_stat) = match zk.get_children2(path, false) {
+            Err(e) if no_node(&e) => return Ok(None),
+            result => result?,
+        };
+        unchanged &= same_stat(&stat, &children_stat);
+        children
+    } else {
+        Vec::new()
+    };
+
+    Ok(Some(NodeRead {
+        path: path.to_owned(),
+        data,
+        stat,
+        acl,
+        children,
+        unchanged,
+    }))
+}
+
+/// Do two stats of a node show the same data, ACL and children?
+fn same_stat(a: &Stat, b: &Stat) -> bool {
+    a.mzxid == b.mzxid && a.pzxid == b.pzxid && a.version == b.version && a.aversion == b.aversion
+}
+
+/// The persisted form of a stat. Container and TTL nodes have no ephemeral owner in stats sent
+/// to clients, and become regular nodes.
+fn persisted_stat(stat: &Stat) -> StatPersisted {
+    StatPersisted {
+        czxid: stat.czxid,
+        mzxid: stat.mzxid,
+        ctime: stat.ctime,
+        mtime: stat.mtime,
+        version: stat.version,
+        cversion: stat.cversion,
+        aversion: stat.aversion,
+        ephemeral_info: EphemeralInfo(stat.ephemeral_owner.0),
+        pzxid: stat.pzxid,
+    }
+}
+
+/// Spaces requests evenly, across threads.
+struct RateLimiter {
+    interval: Option<std::time::Duration>,
+    /// Time at which the next request can be sent
+    next: Mutex<Instant>,
+}
+
+impl RateLimiter {
+    fn new(max_rate: Option<u32>) -> RateLimiter {
+        RateLimiter {
+            interval: max_rate.map(|rate| std::time::Duration::from_secs(1) / rate),
+            next: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Wait until a request can be sent.
+    fn acquire(&self) {
+        let interval = match self.interval {
+            Some(interval) => interval,
+            None => return,
+        };
+        let wait = {
+            let mut next = self.next.lock().unwrap_or_else(std::sync::PoisonError::into_inner);
+            let now = Instant::now();
+            let slot = (*next).max(now);
+            *next = slot + interval;
+            slot - now
+        };
+        if !wait.is_zero() {
+            std::thread::sleep(wait);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::testing::{handshake, reply_at};
+    use crate::persistence::datatree::DataTree;
+    use crate::persistence::snapshot::SnapshotFile;
+    use crate::persistence::testing::temp_dir;
+    use crate::proto::codec::{self, MAX_PACKET_LENGTH};
+    use crate::proto::*;
+    use crate::{Duration, Id, SessionId, Timestamp, Version, PERM_ALL, PERM_READ};
+    use serde::Deserialize;
+    use std::collections::BTreeMap;
+    use std::net::{TcpListener, TcpStream};
+    use std::sync::Arc;
+
+    fn stat(zxid: i64, num_children: i32) -> Stat {
+        Stat {
+            czxid: Zxid(zxid),
+            mzxid: Zxid(zxid),
+            ctime: Timestamp(0),
+            mtime: Timestamp(0),
+            version: Version(0),
+            cversion: Version(0),
+            aversion: Version(0),
+            ephemeral_owner: SessionId(0),
+            data_length: 0,
+            num_children,
+            pzxid: Zxid(zxid),
+        }
+    }
+
+    fn acl(perms: crate::Perms) -> Vec<ACL> {
+        vec![ACL {
+            perms,
+            id: Id::anyone(),
+        }]
+    }
+
+    type Tree = BTreeMap<String, (i64, Vec<ACL>, Vec<String>)>;
+
+    fn node(zxid: i64, perms: crate::Perms, children: &[&str]) -> (i64, Vec<ACL>, Vec<String>) {
+        (zxid, acl(perms), children.iter().map(|c| c.to_string()).collect())
+    }
+
+    /// Answers the requests of a session with the nodes of a tree: zxid, ACL and children. The
+    /// nth sync is at zxid 10 * n, and switches to the nth tree of `trees`.
+    fn handle(mut stream: TcpStream, trees: &[Tree]) {
+        handshake(&mut stream);
+
+        let mut syncs = 0;
+        while let Ok(buf) = codec::read_packet(&mut stream, MAX_PACKET_LENGTH) {
+            let (header, mut de) = codec::decode_request(&buf).unwrap();
+            let path = String::deserialize(&mut de).unwrap();
+            let xid = header.xid.0;
+            if header.typ == OpCode::Sync {
+                syncs += 1;
+                reply_at(&mut stream, xid, 10 * syncs, ErrorCode::Ok, &SyncResponse { path });
+                continue;
+            }
+
+            let tree = &trees[(syncs as usize).saturating_sub(1).min(trees.len() - 1)];
+            let (zxid, acl, children) = match tree.get(&path) {
+                Some(node) => node.clone(),
+                None => {
+                    reply_at(&mut stream, xid, 10 * syncs, ErrorCode::NoNode, &());
+                    continue;
+                }
+            };
+            let stat = stat(zxid, children.len() as i32);
+            match header.typ {
+                OpCode::GetData => {
+                    let data = path.into_bytes();
+                    reply_at(&mut stream, xid, zxid, ErrorCode::Ok, &GetDataResponse { data, stat })
+                }
+                OpCode::GetACL => reply_at(&mut stream, xid, zxid, ErrorCode::Ok, &GetACLResponse { acl, stat }),
+                OpCode::GetChildren2 => reply_at(
+                    &mut stream,
+                    xid,
+                    zxid,
+                    ErrorCode::Ok,
+                    &GetChildren2Response { children, stat },
+                ),
+                op => panic!("unexpected {:?}", op),
+            }
+        }
+    }
+
+    #[test]
+    fn subtree_export() {
+        let mut tree = BTreeMap::new();
+        tree.insert("/".to_owned(), node(0, PERM_ALL, &["app", "other"]));
+        tree.insert("/app".to_owned(), node(1, PERM_ALL, &["a", "b"]));
+        tree.insert("/app/a".to_owned(), node(2, PERM_READ, &["x"]));
+        tree.insert("/app/a/x".to_owned(), node(4, PERM_READ, &[]));
+        tree.insert("/app/b".to_owned(), node(3, PERM_ALL, &[]));
+        let tree = Arc::new(tree);
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let server = std::thread::spawn(move || {
+            let handlers = (0..2)
+                .map(|_| {
+                    let (stream, _) = listener.accept().unwrap();
+                    let tree = tree.clone();
+                    std::thread::spawn(move || handle(stream, std::slice::from_ref(&*tree)))
+                })
+                .collect::<Vec<_>>();
+            for handler in handlers {
+                handler.join().unwrap();
+            }
+        });
+
+        let mut clients = (0..2)
+            .map(|_| ZooKeeper::connect(&addr, Duration(10_000)).unwrap())
+            .collect::<Vec<_>>();
+        let subtree = SubtreeExport::new()
+            .with_max_rate(1000)
+            .export("/app", &mut clients)
+            .unwrap();
+        drop(clients);
+        server.join().unwrap();
+
+        // Parents come first, and the root has none of its other children
+        let paths = subtree.nodes.iter().map(|(p, _)| p.as_str()).collect::<Vec<_>>();
+        assert_eq!(paths, vec!["", "/app", "/app/a", "/app/b", "/app/a/x"]);
+        assert_eq!(subtree.acls.len(), 2);
+        assert_eq!(subtree.zxid(), Zxid(4));
+
+        let dir = temp_dir("subtree-export");
+        let path = dir.join("snapshot.4");
+        subtree.write_snapshot(&path).unwrap();
+        let tree = DataTree::from_snapshot(SnapshotFile::new(&path).unwrap()).unwrap();
+        assert_eq!(tree.children("/").unwrap(), vec!["app"]);
+        assert_eq!(tree.get("/app/a/x").unwrap().data, b"/app/a/x");
+        assert_eq!(tree.acl("/app/a").unwrap(), acl(PERM_READ));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn consistent_read() {
+        // Synced at zxid 10, /app/a then changes at zxid 12 and /app/b is deleted at zxid 13
+        let mut before = Tree::new();
+        before.insert("/".to_owned(), node(0, PERM_ALL, &["app"]));
+        before.insert("/app".to_owned(), node(1, PERM_ALL, &["a", "b"]));
+        before.insert("/app/a".to_owned(), node(12, PERM_ALL, &[]));
+        let mut after = before.clone();
+        after.insert("/app".to_owned(), node(13, PERM_ALL, &["a"]));
+        let trees = Arc::new(vec![before, after]);
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let server = std::thread::spawn(move || {
+            for _ in 0..2 {
+                let (stream, _) = listener.accept().unwrap();
+                handle(stream, &trees);
+            }
+        });
+
+        // Nodes that changed are read again after a second sync
+        let mut zk = ZooKeeper::connect(&addr, Duration(10_000)).unwrap();
+        let (subtree, zxid) = SubtreeExport::new().read_subtree_consistent("/app", &mut zk).unwrap();
+        drop(zk);
+        let paths = subtree.nodes.iter().map(|(p, _)| p.as_str()).collect::<Vec<_>>();
+        assert_eq!(paths, vec!["", "/app", "/app/a"]);
+        assert_eq!(zxid, Zxid(20));
+
+        let mut zk = ZooKeeper::connect(&addr, Duration(10_000)).unwrap();
+        let result = SubtreeExport::new()
+            .with_max_retries(0)
+            .read_subtree_consistent("/app", &mut zk);
+        assert!(matches!(result, Err(ClientError::Inconsistent(_))));
+        drop(zk);
+        server.join().unwrap();
+    }
+}
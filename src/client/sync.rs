@@ -0,0 +1,985 @@
+//! A blocking client, for programs that don't want an async runtime.
+//!
+//! Requests are sent one at a time on a `TcpStream`, possibly wrapped in TLS (see `client::tls`),
+//! and each call waits for its response.
+//! There is no background thread: watch notifications received while waiting for a response are
+//! queued and returned by `events()`, and the session is kept alive only while the client is in
+//! use. Long-lived clients that may be idle should regularly call `ping_if_idle()`, or wait for
+//! notifications with `wait_notifications()`, which pings the server when needed. A lost
+//! connection isn't recovered from: see `client::session` to resume the session on another server.
+//!
+//! Watches set with `watch: true` are delivered to `events()`. The `watch_*` operations deliver
+//! them to a `Watcher` instead, such as a callback or a channel (see `client::watch`).
+//!
+//! Server errors are returned as `ClientError::Server` with their `ErrorCode`. Bytes that follow
+//! the content of a reply are ignored, unless `with_strict_replies` is set.
+//!
+//! Interceptors added with `add_interceptor` are called around each request (see
+//! `client::interceptor`).
+
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::sync::Arc;
+
+use super::host::{ConnectString, HostProvider, StaticHostProvider};
+use super::interceptor::Interceptor;
+use super::watch::{WatchKind, WatchManager, Watcher};
+use super::xid::{XidAllocator, AUTH_XID, NOTIFICATION_XID, PING_XID, SET_WATCHES_XID};
+use crate::clock::{self, Clock};
+use crate::error::{ClientError, ParseError};
+use crate::path::{validate_path, ZkPath};
+use crate::proto::codec::{self, MAX_PACKET_LENGTH};
+use crate::proto::config::{QuorumConfig, CONFIG_NODE};
+use crate::proto::*;
+use crate::serde::{Deserializer, SliceRead};
+use crate::{
+    CreateMode, Duration, OptionalVersion, ServerVersion, SessionId, Stat, Timestamp, Version, Xid, Zxid, ACL,
+};
+
+/// A connection to a server: a TCP stream, or a wrapper such as a TLS stream.
+pub trait Transport: Read + Write {
+    /// The underlying TCP stream, to set socket options
+    fn tcp_stream(&self) -> &TcpStream;
+}
+
+impl Transport for TcpStream {
+    fn tcp_stream(&self) -> &TcpStream {
+        self
+    }
+}
+
+/// A blocking ZooKeeper client.
+pub struct ZooKeeper<S: Transport = TcpStream> {
+    stream: S,
+    session_id: SessionId,
+    passwd: Vec<u8>,
+    session_timeout: Duration,
+    xids: XidAllocator,
+    last_zxid: Zxid,
+    events: VecDeque<WatcherEvent>,
+    watches: WatchManager,
+    clock: Arc<dyn Clock>,
+    /// Time of the last request sent
+    last_sent: Timestamp,
+    /// Fail on replies with trailing bytes
+    strict: bool,
+    /// Release line of the servers, which operations are checked against
+    server_version: ServerVersion,
+    interceptors: Vec<Box<dyn Interceptor>>,
+    /// Number of notifications received
+    notifications: u64,
+}
+
+impl ZooKeeper {
+    /// Connect to one of the servers of a connect string, trying them in turn. Chroots aren't
+    /// supported.
+    pub fn connect(connect_string: &str, session_timeout: Duration) -> Result<ZooKeeper, ClientError> {
+        let mut hosts = Self::host_provider(connect_string)?;
+        Self::connect_with(&mut hosts, session_timeout)
+    }
+
+    /// The servers of a connect string, which must not have a chroot.
+    pub(crate) fn host_provider(connect_string: &str) -> Result<StaticHostProvider, ClientError> {
+        let connect = ConnectString::parse(connect_string)?;
+        if let Some(chroot) = &connect.chroot {
+            return Err(ClientError::Unsupported(format!(
+                "chroot {} in the sync client",
+                chroot
+            )));
+        }
+        Ok(StaticHostProvider::from_connect_string(&connect)?)
+    }
+
+    /// Connect to one of the servers of a host provider, trying each of them once.
+    pub fn connect_with(hosts: &mut dyn HostProvider, session_timeout: Duration) -> Result<ZooKeeper, ClientError> {
+        Self::connect_each(hosts, session_timeout, |addr| Ok(TcpStream::connect(addr)?))
+    }
+}
+
+impl<S: Transport> ZooKeeper<S> {
+    /// Open a stream with `open` to each server of `hosts` in turn, until a session is established.
+    pub(crate) fn connect_each(
+        hosts: &mut dyn HostProvider,
+        session_timeout: Duration,
+        open: impl Fn(SocketAddr) -> Result<S, ClientError>,
+    ) -> Result<ZooKeeper<S>, ClientError> {
+        let mut last_error = std::io::Error::new(std::io::ErrorKind::NotFound, "No server to connect to").into();
+        for _ in 0..hosts.size() {
+            let addr = match hosts.next() {
+                Some(addr) => addr,
+                None => break,
+            };
+            match open(addr).and_then(|s| Self::with_stream(s, session_timeout)) {
+                Ok(zk) => {
+                    hosts.on_connected(addr);
+                    return Ok(zk);
+                }
+                Err(e) => {
+                    hosts.on_disconnected(addr);
+                    last_error = e;
+                }
+            }
+        }
+        Err(last_error)
+    }
+
+    /// Establish a new session on a connected stream.
+    pub fn with_stream(mut stream: S, session_timeout: Duration) -> Result<ZooKeeper<S>, ClientError> {
+        let response = Self::handshake(&mut stream, &ConnectRequest::new_session(session_timeout))?;
+        if !response.is_session_valid() {
+            return Err(ClientError::SessionRefused);
+        }
+
+        let clock = clock::system();
+        Ok(ZooKeeper {
+            stream,
+            session_id: response.session_id,
+            passwd: response.passwd,
+            session_timeout: response.time_out,
+            xids: XidAllocator::new(),
+            last_zxid: Zxid(0),
+            events: VecDeque::new(),
+            watches: WatchManager::new(),
+            last_sent: clock.now(),
+            clock,
+            strict: false,
+            server_version: ServerVersion::LATEST,
+            interceptors: Vec::new(),
+            notifications: 0,
+        })
+    }
+
+    /// Resume the session on a new connected stream, e.g. to another server after a connection loss,
+    /// and set its watches again. Fails with `SessionExpired` if the server doesn't know the
+    /// session anymore.
+    pub fn resume(&mut self, mut stream: S) -> Result<(), ClientError> {
+        let request = ConnectRequest::resume(
+            self.session_id,
+            self.passwd.clone(),
+            self.last_zxid,
+            self.session_timeout,
+        );
+        let response = Self::handshake(&mut stream, &request)?;
+        if !response.is_session_valid() {
+            return Err(ErrorCode::SessionExpired.into());
+        }
+
+        self.stream = stream;
+        self.session_timeout = response.time_out;
+        self.last_sent = self.clock.now();
+        self.restore_watches()
+    }
+
+    /// Send a connect request, and read the response. Once accepted, responses are expected within
+    /// the negotiated timeout.
+    fn handshake(stream: &mut S, request: &ConnectRequest) -> Result<ConnectResponse, ClientError> {
+        let tcp = stream.tcp_stream();
+        tcp.set_nodelay(true)?;
+        tcp.set_read_timeout(Some(std::time::Duration::from_millis(request.time_out.0.max(1) as u64)))?;
+
+        stream.write_all(&codec::encode_packet(request)?)?;
+        let buf = codec::read_packet(stream, MAX_PACKET_LENGTH)?;
+        let response: ConnectResponse = crate::serde::from_slice(&buf)?;
+        if response.is_session_valid() {
+            let timeout = std::time::Duration::from_millis(response.time_out.0 as u64);
+            stream.tcp_stream().set_read_timeout(Some(timeout))?;
+        }
+        Ok(response)
+    }
+
+    /// Fail with `ClientError::TrailingBytes` on replies that have bytes left once read, instead
+    /// of ignoring them. This helps finding format differences with the server's version.
+    pub fn with_strict_replies(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
+    }
+
+    /// Set the release line of the servers. Operations they don't support then fail locally with
+    /// `Unimplemented`, instead of being sent: 3.4 servers close the connection on them. Servers
+    /// don't tell their version when connecting: it can be found with the `srvr` four letter word.
+    pub fn with_server_version(mut self, version: ServerVersion) -> Self {
+        self.server_version = version;
+        self
+    }
+
+    /// Set the clock used to decide when to ping the server.
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.last_sent = clock.now();
+        self.clock = clock;
+        self
+    }
+
+    /// Add an interceptor, called after the ones already added. See `client::interceptor`.
+    pub fn add_interceptor(&mut self, interceptor: impl Interceptor + 'static) {
+        self.interceptors.push(Box::new(interceptor));
+    }
+
+    pub fn session_id(&self) -> SessionId {
+        self.session_id
+    }
+
+    /// Session password, needed to resume the session
+    pub fn session_passwd(&self) -> &[u8] {
+        &self.passwd
+    }
+
+    /// Session timeout negotiated with the server
+    pub fn session_timeout(&self) -> Duration {
+        self.session_timeout
+    }
+
+    /// Address of the server, unless the connection is closed
+    pub fn server_addr(&self) -> Option<SocketAddr> {
+        self.stream.tcp_stream().peer_addr().ok()
+    }
+
+    /// Most recent zxid seen in a response
+    pub fn last_zxid(&self) -> Zxid {
+        self.last_zxid
+    }
+
+    /// Watch notifications received so far.
+    pub fn events(&mut self) -> impl Iterator<Item = WatcherEvent> + '_ {
+        self.events.drain(..)
+    }
+
+    /// Deliver a change of the connection state to the watches, and to the event queue, like the
+    /// notifications sent by servers.
+    pub(crate) fn notify_state(&mut self, state: KeeperState) {
+        let event = WatcherEvent {
+            typ: WatcherEventType::None,
+            state,
+            path: String::new(),
+        };
+        if self.watches.deliver(&event) {
+            self.events.push_back(event);
+        }
+    }
+
+    /// Watches set by this client that haven't been triggered yet.
+    pub fn watches(&self) -> &WatchManager {
+        &self.watches
+    }
+
+    /// Send a request and wait for its response.
+    pub fn call<R>(&mut self, request: &R) -> Result<R::Response, ClientError>
+    where
+        R: OpRequest + Serialize,
+        R::Response: DeserializeOwned,
+    {
+        let xid = self.xids.allocate();
+        self.exchange(xid, R::OP_CODE, request)
+    }
+
+    fn exchange<R, T>(&mut self, xid: Xid, op: OpCode, request: &R) -> Result<T, ClientError>
+    where
+        R: Serialize,
+        T: DeserializeOwned,
+    {
+        op.check_supported_by(self.server_version)?;
+        let header = RequestHeader { xid, typ: op };
+        let packet = if self.interceptors.is_empty() {
+            codec::encode_request(&header, request)?
+        } else {
+            let mut body = crate::serde::to_vec(request)?;
+            for interceptor in &mut self.interceptors {
+                interceptor.on_request(&header, &mut body)?;
+            }
+            codec::encode_request_bytes(&header, &body)?
+        };
+        self.stream.write_all(&packet)?;
+        self.last_sent = self.clock.now();
+
+        loop {
+            let buf = codec::read_packet(&mut self.stream, MAX_PACKET_LENGTH)?;
+            let (reply, mut de) = codec::decode_response(&buf)?;
+            if reply.zxid.0 > 0 {
+                self.last_zxid = self.last_zxid.max(reply.zxid);
+            }
+
+            if reply.xid == NOTIFICATION_XID {
+                self.notification(&buf, &mut de)?;
+                continue;
+            }
+            if reply.xid != xid {
+                return Err(ClientError::Protocol(format!(
+                    "Unexpected xid {} in reply to {}",
+                    reply.xid.0, xid.0
+                )));
+            }
+            for interceptor in &mut self.interceptors {
+                interceptor.on_response(&header, &reply, de.get_ref().remaining())?;
+            }
+            if reply.err != 0 {
+                return Err(match ErrorCode::from_code(reply.err) {
+                    Some(code) => code.into(),
+                    None => ClientError::UnknownErrorCode(reply.err),
+                });
+            }
+            let response = T::deserialize(&mut de)?;
+            self.check_consumed(&buf, &de)?;
+            return Ok(response);
+        }
+    }
+
+    /// Deliver a notification, whose body is read from `de`.
+    fn notification(&mut self, packet: &[u8], de: &mut Deserializer<SliceRead>) -> Result<(), ClientError> {
+        let event = WatcherEvent::deserialize(&mut *de)?;
+        self.check_consumed(packet, de)?;
+        self.notifications += 1;
+        if self.watches.deliver(&event) {
+            self.events.push_back(event);
+        }
+        Ok(())
+    }
+
+    fn check_consumed(&self, packet: &[u8], de: &Deserializer<SliceRead>) -> Result<(), ClientError> {
+        let remaining = de.get_ref().remaining().len();
+        if self.strict && remaining > 0 {
+            return Err(ClientError::TrailingBytes {
+                expected: packet.len(),
+                consumed: packet.len() - remaining,
+            });
+        }
+        Ok(())
+    }
+
+    /// Ping the server, which keeps the session alive.
+    pub fn ping(&mut self) -> Result<(), ClientError> {
+        self.exchange(PING_XID, OpCode::Ping, &())
+    }
+
+    /// Ping the server if nothing was sent for a third of the session timeout, like the Java
+    /// client does. Returns true if a ping was sent.
+    pub fn ping_if_idle(&mut self) -> Result<bool, ClientError> {
+        let idle = self.clock.elapsed(self.last_sent).as_millis();
+        if idle < (self.session_timeout.0 / 3).max(0) as u128 {
+            return Ok(false);
+        }
+        self.ping()?;
+        Ok(true)
+    }
+
+    /// Wait for watch notifications for up to `timeout`, without sending requests other than pings
+    /// that keep the session alive. Notifications are delivered like those received with responses.
+    /// Returns whether some were received.
+    pub fn wait_notifications(&mut self, timeout: std::time::Duration) -> Result<bool, ClientError> {
+        let received = self.notifications;
+        let deadline = std::time::Instant::now() + timeout;
+        let ping_interval = std::time::Duration::from_millis((self.session_timeout.0 / 3).max(0) as u64);
+        while self.notifications == received {
+            let now = std::time::Instant::now();
+            if now >= deadline {
+                break;
+            }
+            let until_ping = ping_interval.saturating_sub(self.clock.elapsed(self.last_sent));
+            if self.wait_readable((deadline - now).min(until_ping))? {
+                let buf = codec::read_packet(&mut self.stream, MAX_PACKET_LENGTH)?;
+                let (reply, mut de) = codec::decode_response(&buf)?;
+                if reply.zxid.0 > 0 {
+                    self.last_zxid = self.last_zxid.max(reply.zxid);
+                }
+                if reply.xid != NOTIFICATION_XID {
+                    return Err(ClientError::Protocol(format!(
+                        "Unexpected xid {} with no request in flight",
+                        reply.xid.0
+                    )));
+                }
+                self.notification(&buf, &mut de)?;
+            } else {
+                self.ping_if_idle()?;
+            }
+        }
+        Ok(self.notifications != received)
+    }
+
+    /// Wait for up to `timeout` until there are bytes to read.
+    fn wait_readable(&mut self, timeout: std::time::Duration) -> Result<bool, ClientError> {
+        let tcp = self.stream.tcp_stream();
+        tcp.set_read_timeout(Some(timeout.max(std::time::Duration::from_millis(1))))?;
+        let result = tcp.peek(&mut [0u8; 1]);
+        tcp.set_read_timeout(Some(std::time::Duration::from_millis(
+            self.session_timeout.0.max(1) as u64
+        )))?;
+        match result {
+            Ok(0) => Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into()),
+            Ok(_) => Ok(true),
+            Err(e) if matches!(e.kind(), std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut) => Ok(false),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Add authentication information to the session, e.g. `digest` and `user:password`.
+    pub fn add_auth(&mut self, scheme: &str, auth: &[u8]) -> Result<(), ClientError> {
+        self.exchange(AUTH_XID, AuthPacket::OP_CODE, &AuthPacket::new(scheme, auth))
+    }
+
+    /// Set the watches of this client again, e.g. on a new connection to the session. Servers then
+    /// send the notifications of changes that followed the last zxid seen by the client.
+    ///
+    /// They're set with a `SetWatches`, unless there are persistent watches that need a
+    /// `SetWatches2`, which only ZooKeeper 3.6+ understands.
+    pub fn restore_watches(&mut self) -> Result<(), ClientError> {
+        let request = match self.watches.set_watches(self.last_zxid) {
+            Some(request) => request,
+            None => return Ok(()),
+        };
+        self.send_set_watches(&request)
+    }
+
+    /// Set watches saved by another session, e.g. by this process before it restarted (see
+    /// `client::journal`). Their notifications are delivered to the event queue. Servers send the
+    /// notifications of changes that followed the request's `relative_zxid`.
+    pub fn set_watches(&mut self, request: &SetWatches2) -> Result<(), ClientError> {
+        let kinds = [
+            (WatchKind::Data, &request.data_watches),
+            (WatchKind::Exist, &request.exist_watches),
+            (WatchKind::Child, &request.child_watches),
+            (WatchKind::Persistent, &request.persistent_watches),
+            (WatchKind::PersistentRecursive, &request.persistent_recursive_watches),
+        ];
+        if kinds.iter().all(|(_, paths)| paths.is_empty()) {
+            return Ok(());
+        }
+        // Registered first, as the notifications of missed changes precede the reply
+        for (kind, paths) in kinds {
+            for path in paths {
+                self.watches.register_default(kind, path);
+            }
+        }
+        self.send_set_watches(request)
+    }
+
+    fn send_set_watches(&mut self, request: &SetWatches2) -> Result<(), ClientError> {
+        match request.to_set_watches() {
+            Some(request) => self.exchange(SET_WATCHES_XID, SetWatches::OP_CODE, &request),
+            None => self.exchange(SET_WATCHES_XID, SetWatches2::OP_CODE, request),
+        }
+    }
+
+    /// Close the session, which deletes its ephemeral nodes. Watchers are then notified with a
+    /// `Closed` state, even if the server couldn't be told.
+    pub fn close(mut self) -> Result<(), ClientError> {
+        let xid = self.xids.allocate();
+        let result = self.exchange(xid, OpCode::CloseSession, &CloseSessionRequest);
+        self.notify_state(KeeperState::Closed);
+        result
+    }
+
+    //----- Typed operations
+    //
+    // Paths are checked before being sent, to fail early like the Java client.
+
+    /// Create a node and returns its actual path, which differs from `path` for sequential nodes.
+    /// Container nodes are created with `create_container`.
+    pub fn create(&mut self, path: &str, data: &[u8], acl: Vec<ACL>, mode: CreateMode) -> Result<String, ClientError> {
+        if mode.is_container() {
+            return Ok(self.create_container(path, data, acl)?.0);
+        }
+        Self::check_no_ttl(&mode)?;
+        validate_path(path, mode.is_sequential())?;
+        let request = CreateRequest {
+            path: path.to_owned(),
+            data: data.to_vec(),
+            acl,
+            flags: mode,
+        };
+        Ok(self.call(&request)?.path)
+    }
+
+    /// Same as `create`, also returning the stat of the new node. Requires ZooKeeper 3.5+.
+    pub fn create2(
+        &mut self,
+        path: &str,
+        data: &[u8],
+        acl: Vec<ACL>,
+        mode: CreateMode,
+    ) -> Result<(String, Stat), ClientError> {
+        if mode.is_container() {
+            return self.create_container(path, data, acl);
+        }
+        Self::check_no_ttl(&mode)?;
+        validate_path(path, mode.is_sequential())?;
+        let request = Create2Request(CreateRequest {
+            path: path.to_owned(),
+            data: data.to_vec(),
+            acl,
+            flags: mode,
+        });
+        let response = self.call(&request)?;
+        Ok((response.path, response.stat))
+    }
+
+    /// Create a container node, which the server deletes once it has had children and they're all
+    /// deleted. Requires ZooKeeper 3.5+.
+    pub fn create_container(&mut self, path: &str, data: &[u8], acl: Vec<ACL>) -> Result<(String, Stat), ClientError> {
+        validate_path(path, false)?;
+        let response = self.call(&CreateContainerRequest::new(path, data.to_vec(), acl))?;
+        Ok((response.path, response.stat))
+    }
+
+    /// Create a node that the server deletes once it has no children and hasn't been modified
+    /// for `ttl` milliseconds. `mode` must be a TTL mode, and `ttl` within `(0, MAX_TTL]`. Requires
+    /// ZooKeeper 3.5+, with `zookeeper.extendedTypesEnabled` set on servers.
+    pub fn create_ttl(
+        &mut self,
+        path: &str,
+        data: &[u8],
+        acl: Vec<ACL>,
+        mode: CreateMode,
+        ttl: i64,
+    ) -> Result<(String, Stat), ClientError> {
+        validate_path(path, mode.is_sequential())?;
+        let response = self.call(&CreateTTLRequest::new(path, data.to_vec(), acl, mode, ttl)?)?;
+        Ok((response.path, response.stat))
+    }
+
+    /// TTL modes need a TTL, which only `create_ttl` sends.
+    fn check_no_ttl(mode: &CreateMode) -> Result<(), ClientError> {
+        if mode.is_ttl() {
+            return Err(ParseError::invalid("create mode without a TTL", format!("{:?}", mode)).into());
+        }
+        Ok(())
+    }
+
+    /// Delete a node. A `version` of -1 matches any version.
+    pub fn delete(&mut self, path: &str, version: OptionalVersion) -> Result<(), ClientError> {
+        self.call(&DeleteRequest {
+            path: ZkPath::new(path)?.into(),
+            version,
+        })
+    }
+
+    /// Stat of a node, or `None` if it doesn't exist.
+    pub fn exists(&mut self, path: &str, watch: bool) -> Result<Option<Stat>, ClientError> {
+        let stat = self.exists_request(path, watch)?;
+        if watch {
+            self.watches.register_default(Self::exists_kind(&stat), path);
+        }
+        Ok(stat)
+    }
+
+    /// Same as `exists`, with a watch delivered to `watcher` when the node is created, deleted or
+    /// changed.
+    pub fn watch_exists(&mut self, path: &str, watcher: impl Watcher + 'static) -> Result<Option<Stat>, ClientError> {
+        let stat = self.exists_request(path, true)?;
+        self.watches.register(Self::exists_kind(&stat), path, Box::new(watcher));
+        Ok(stat)
+    }
+
+    fn exists_request(&mut self, path: &str, watch: bool) -> Result<Option<Stat>, ClientError> {
+        let request = ExistsRequest {
+            path: ZkPath::new(path)?.into(),
+            watch,
+        };
+        match self.call(&request) {
+            Ok(response) => Ok(Some(response.stat)),
+            Err(ClientError::Server(ErrorCode::NoNode)) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Servers set a data watch on existing nodes, and an exist watch on missing ones.
+    fn exists_kind(stat: &Option<Stat>) -> WatchKind {
+        if stat.is_some() {
+            WatchKind::Data
+        } else {
+            WatchKind::Exist
+        }
+    }
+
+    pub fn get_data(&mut self, path: &str, watch: bool) -> Result<(Vec<u8>, Stat), ClientError> {
+        let response = self.get_data_request(path, watch)?;
+        if watch {
+            self.watches.register_default(WatchKind::Data, path);
+        }
+        Ok(response)
+    }
+
+    /// Same as `get_data`, with a watch delivered to `watcher` when the node is deleted or changed.
+    pub fn watch_data(&mut self, path: &str, watcher: impl Watcher + 'static) -> Result<(Vec<u8>, Stat), ClientError> {
+        let response = self.get_data_request(path, true)?;
+        self.watches.register(WatchKind::Data, path, Box::new(watcher));
+        Ok(response)
+    }
+
+    fn get_data_request(&mut self, path: &str, watch: bool) -> Result<(Vec<u8>, Stat), ClientError> {
+        let response = self.call(&GetDataRequest {
+            path: ZkPath::new(path)?.into(),
+            watch,
+        })?;
+        Ok((response.data, response.stat))
+    }
+
+    /// The ensemble configuration, read from `CONFIG_NODE` (`getConfig` in the Java client). Its
+    /// version is the one of the configuration, not of the node.
+    pub fn get_config(&mut self, watch: bool) -> Result<(QuorumConfig, Stat), ClientError> {
+        let (data, stat) = self.get_data(CONFIG_NODE, watch)?;
+        Ok((QuorumConfig::parse(&data)?, stat))
+    }
+
+    /// Same as `get_config`, with a watch delivered to `watcher` when the configuration changes.
+    pub fn watch_config(&mut self, watcher: impl Watcher + 'static) -> Result<(QuorumConfig, Stat), ClientError> {
+        let (data, stat) = self.watch_data(CONFIG_NODE, watcher)?;
+        Ok((QuorumConfig::parse(&data)?, stat))
+    }
+
+    pub fn set_data(&mut self, path: &str, data: &[u8], version: Version) -> Result<Stat, ClientError> {
+        let request = SetDataRequest {
+            path: ZkPath::new(path)?.into(),
+            data: data.to_vec(),
+            version,
+        };
+        Ok(self.call(&request)?.stat)
+    }
+
+    /// ACL of a node, with its stat.
+    pub fn get_acl(&mut self, path: &str) -> Result<(Vec<ACL>, Stat), ClientError> {
+        let response = self.call(&GetACLRequest {
+            path: ZkPath::new(path)?.into(),
+        })?;
+        Ok((response.acl, response.stat))
+    }
+
+    /// Names of the children of a node.
+    pub fn get_children(&mut self, path: &str, watch: bool) -> Result<Vec<String>, ClientError> {
+        let children = self.get_children_request(path, watch)?;
+        if watch {
+            self.watches.register_default(WatchKind::Child, path);
+        }
+        Ok(children)
+    }
+
+    /// Same as `get_children`, with a watch delivered to `watcher` when the node is deleted or its
+    /// children change.
+    pub fn watch_children(&mut self, path: &str, watcher: impl Watcher + 'static) -> Result<Vec<String>, ClientError> {
+        let children = self.get_children_request(path, true)?;
+        self.watches.register(WatchKind::Child, path, Box::new(watcher));
+        Ok(children)
+    }
+
+    /// Names of the children of a node, with its stat.
+    pub fn get_children2(&mut self, path: &str, watch: bool) -> Result<(Vec<String>, Stat), ClientError> {
+        let response = self.call(&GetChildren2Request {
+            path: ZkPath::new(path)?.into(),
+            watch,
+        })?;
+        if watch {
+            self.watches.register_default(WatchKind::Child, path);
+        }
+        Ok((response.children, response.stat))
+    }
+
+    /// Wait until the server has caught up with the leader, so that following reads see every
+    /// change committed before. `last_zxid()` is then the server's latest zxid.
+    pub fn sync(&mut self, path: &str) -> Result<(), ClientError> {
+        self.call(&SyncRequest {
+            path: ZkPath::new(path)?.into(),
+        })?;
+        Ok(())
+    }
+
+    /// Set a persistent watch on a node, delivered to `events()` until the session expires: it isn't
+    /// removed when triggered. Requires ZooKeeper 3.6+.
+    pub fn add_watch(&mut self, path: &str, mode: AddWatchMode) -> Result<(), ClientError> {
+        let kind = self.add_watch_request(path, mode)?;
+        self.watches.register_default(kind, path);
+        Ok(())
+    }
+
+    /// Same as `add_watch`, with the watch delivered to `watcher`.
+    pub fn add_watcher(
+        &mut self,
+        path: &str,
+        mode: AddWatchMode,
+        watcher: impl Watcher + 'static,
+    ) -> Result<(), ClientError> {
+        let kind = self.add_watch_request(path, mode)?;
+        self.watches.register(kind, path, Box::new(watcher));
+        Ok(())
+    }
+
+    fn add_watch_request(&mut self, path: &str, mode: AddWatchMode) -> Result<WatchKind, ClientError> {
+        self.call(&AddWatchRequest {
+            path: ZkPath::new(path)?.into(),
+            mode,
+        })?;
+        Ok(match mode {
+            AddWatchMode::Persistent => WatchKind::Persistent,
+            AddWatchMode::PersistentRecursive => WatchKind::PersistentRecursive,
+        })
+    }
+
+    fn get_children_request(&mut self, path: &str, watch: bool) -> Result<Vec<String>, ClientError> {
+        let response = self.call(&GetChildrenRequest {
+            path: ZkPath::new(path)?.into(),
+            watch,
+        })?;
+        Ok(response.children)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::testing::{accept, reply};
+    use crate::clock::MockClock;
+    use std::net::TcpListener;
+
+    /// Reads a request and returns its header.
+    fn read_request(stream: &mut TcpStream) -> RequestHeader {
+        let buf = codec::read_packet(stream, MAX_PACKET_LENGTH).unwrap();
+        codec::decode_request(&buf).unwrap().0
+    }
+
+    fn stat(version: i32) -> Stat {
+        Stat {
+            czxid: Zxid(1),
+            mzxid: Zxid(1),
+            ctime: Timestamp(0),
+            mtime: Timestamp(0),
+            version: Version(version),
+            cversion: Version(0),
+            aversion: Version(0),
+            ephemeral_owner: SessionId(0),
+            data_length: 0,
+            num_children: 0,
+            pzxid: Zxid(1),
+        }
+    }
+
+    #[test]
+    fn sync_client() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let server = std::thread::spawn(move || {
+            let mut stream = accept(&listener);
+
+            // A notification arrives before the response
+            assert_eq!(read_request(&mut stream).typ, OpCode::GetChildren);
+            let event = WatcherEvent {
+                typ: WatcherEventType::NodeChildrenChanged,
+                state: KeeperState::SyncConnected,
+                path: "/app".to_owned(),
+            };
+            reply(&mut stream, -1, ErrorCode::Ok, &event);
+            let children = GetChildrenResponse {
+                children: vec!["a".to_owned(), "b".to_owned()],
+            };
+            reply(&mut stream, 1, ErrorCode::Ok, &children);
+
+            assert_eq!(read_request(&mut stream).typ, OpCode::Exists);
+            reply(&mut stream, 2, ErrorCode::NoNode, &());
+
+            assert_eq!(read_request(&mut stream).typ, OpCode::Delete);
+            reply(&mut stream, 3, ErrorCode::NotEmpty, &());
+
+            assert_eq!(read_request(&mut stream).xid, PING_XID);
+            reply(&mut stream, -2, ErrorCode::Ok, &());
+
+            // Replies with a trailing field
+            for xid in 4..6 {
+                assert_eq!(read_request(&mut stream).typ, OpCode::GetChildren);
+                reply(
+                    &mut stream,
+                    xid,
+                    ErrorCode::Ok,
+                    &(GetChildrenResponse { children: vec![] }, 7),
+                );
+            }
+
+            assert_eq!(read_request(&mut stream).typ, OpCode::Exists);
+            reply(&mut stream, 6, ErrorCode::NoNode, &());
+
+            // Watches are set again, and a missed notification follows
+            let buf = codec::read_packet(&mut stream, MAX_PACKET_LENGTH).unwrap();
+            let (header, mut de) = codec::decode_request(&buf).unwrap();
+            assert_eq!((header.xid, header.typ), (SET_WATCHES_XID, OpCode::SetWatches));
+            let request = SetWatches::deserialize(&mut de).unwrap();
+            assert_eq!(request.relative_zxid, Zxid(16));
+            assert_eq!(request.exist_watches, vec!["/lock"]);
+            assert_eq!(request.child_watches, vec!["/app"]);
+            let event = WatcherEvent {
+                typ: WatcherEventType::NodeCreated,
+                state: KeeperState::SyncConnected,
+                path: "/lock".to_owned(),
+            };
+            reply(&mut stream, -1, ErrorCode::Ok, &event);
+            reply(&mut stream, -8, ErrorCode::Ok, &());
+
+            assert_eq!(read_request(&mut stream).typ, OpCode::CloseSession);
+            reply(&mut stream, 7, ErrorCode::Ok, &());
+        });
+
+        let clock = MockClock::new(Timestamp(0));
+        let mut zk = ZooKeeper::connect(&addr.to_string(), Duration(10_000))
+            .unwrap()
+            .with_clock(Arc::new(clock.clone()));
+        assert_eq!(zk.session_id(), SessionId(42));
+        assert_eq!(zk.session_timeout(), Duration(4000));
+
+        assert_eq!(zk.get_children("/app", true).unwrap(), vec!["a", "b"]);
+        assert_eq!(zk.last_zxid(), Zxid(11));
+        let events = zk.events().collect::<Vec<_>>();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].typ, WatcherEventType::NodeChildrenChanged);
+        assert_eq!(events[0].path, "/app");
+
+        assert!(zk.exists("/missing", false).unwrap().is_none());
+        let err = zk.delete("/app", OptionalVersion(-1)).unwrap_err();
+        assert_eq!(err.code(), Some(ErrorCode::NotEmpty));
+
+        clock.advance(std::time::Duration::from_millis(1000));
+        assert!(!zk.ping_if_idle().unwrap());
+        clock.advance(std::time::Duration::from_millis(400));
+        assert!(zk.ping_if_idle().unwrap());
+
+        assert!(zk.get_children("/app", false).unwrap().is_empty());
+        let mut zk = zk.with_strict_replies(true);
+        let err = zk.get_children("/app", false).unwrap_err();
+        assert!(matches!(
+            err,
+            ClientError::TrailingBytes {
+                expected: 24,
+                consumed: 20
+            }
+        ));
+
+        let (sender, receiver) = std::sync::mpsc::channel();
+        assert!(zk.watch_exists("/lock", sender).unwrap().is_none());
+        zk.restore_watches().unwrap();
+        assert_eq!(receiver.try_recv().unwrap().path, "/lock");
+        assert_eq!(zk.events().count(), 0);
+        assert_eq!(zk.watches().paths(WatchKind::Child), vec!["/app"]);
+        zk.close().unwrap();
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn typed_operations() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let server = std::thread::spawn(move || {
+            let mut stream = accept(&listener);
+
+            let buf = codec::read_packet(&mut stream, MAX_PACKET_LENGTH).unwrap();
+            let (header, mut de) = codec::decode_request(&buf).unwrap();
+            assert_eq!(header.typ, OpCode::GetData);
+            let request = GetDataRequest::deserialize(&mut de).unwrap();
+            assert_eq!((request.path.as_str(), request.watch), (CONFIG_NODE, true));
+            let config = GetDataResponse {
+                data: b"server.1=zk1:2888:3888;2181\nversion=100000003\n".to_vec(),
+                stat: stat(2),
+            };
+            reply(&mut stream, 1, ErrorCode::Ok, &config);
+
+            // Containers are created with their own operation, even with `create`
+            let creates = [OpCode::Create2, OpCode::CreateContainer, OpCode::CreateContainer];
+            for (xid, op) in (2..).zip(creates.iter()) {
+                let buf = codec::read_packet(&mut stream, MAX_PACKET_LENGTH).unwrap();
+                let (header, mut de) = codec::decode_request(&buf).unwrap();
+                assert_eq!(header.typ, *op);
+                let request = CreateRequest::deserialize(&mut de).unwrap();
+                let response = Create2Response {
+                    path: request.path,
+                    stat: stat(0),
+                };
+                reply(&mut stream, xid, ErrorCode::Ok, &response);
+            }
+
+            let buf = codec::read_packet(&mut stream, MAX_PACKET_LENGTH).unwrap();
+            let (header, mut de) = codec::decode_request(&buf).unwrap();
+            assert_eq!(header.typ, OpCode::CreateTTL);
+            let request = CreateTTLRequest::deserialize(&mut de).unwrap();
+            assert!(matches!(request.flags, CreateMode::PersistentWithTTL));
+            assert_eq!(request.ttl, 60_000);
+            let response = Create2Response {
+                path: request.path,
+                stat: stat(0),
+            };
+            reply(&mut stream, 5, ErrorCode::Ok, &response);
+
+            // Persistent watches are set again with a SetWatches2
+            assert_eq!(read_request(&mut stream).typ, OpCode::AddWatch);
+            reply(&mut stream, 6, ErrorCode::Ok, &ErrorResponse { err: ErrorCode::Ok });
+            let buf = codec::read_packet(&mut stream, MAX_PACKET_LENGTH).unwrap();
+            let (header, mut de) = codec::decode_request(&buf).unwrap();
+            assert_eq!((header.xid, header.typ), (SET_WATCHES_XID, OpCode::SetWatches2));
+            let request = SetWatches2::deserialize(&mut de).unwrap();
+            assert_eq!(request.data_watches, vec![CONFIG_NODE]);
+            assert_eq!(request.persistent_recursive_watches, vec!["/a"]);
+            reply(&mut stream, -8, ErrorCode::Ok, &());
+
+            assert_eq!(read_request(&mut stream).typ, OpCode::CloseSession);
+            reply(&mut stream, 7, ErrorCode::Ok, &());
+        });
+
+        let mut zk = ZooKeeper::connect(&addr.to_string(), Duration(10_000)).unwrap();
+        let (config, stat) = zk.get_config(true).unwrap();
+        assert_eq!(config.client_addresses().collect::<Vec<_>>(), vec!["2181"]);
+        assert_eq!(config.version, Some(Zxid(0x100000003)));
+        assert_eq!(stat.version, Version(2));
+        assert_eq!(zk.watches().paths(WatchKind::Data), vec![CONFIG_NODE]);
+
+        let acl = vec![ACL {
+            perms: crate::PERM_ALL,
+            id: crate::Id::anyone(),
+        }];
+        let (path, stat) = zk.create2("/a", b"", acl.clone(), CreateMode::Persistent).unwrap();
+        assert_eq!((path.as_str(), stat.version), ("/a", Version(0)));
+        assert_eq!(zk.create_container("/b", b"", acl.clone()).unwrap().0, "/b");
+        assert_eq!(zk.create("/c", b"", acl.clone(), CreateMode::Container).unwrap(), "/c");
+
+        // Invalid TTLs fail without sending a request
+        use CreateMode::{Persistent, PersistentWithTTL};
+        assert!(zk.create("/d", b"", acl.clone(), PersistentWithTTL).is_err());
+        assert!(zk.create_ttl("/d", b"", acl.clone(), PersistentWithTTL, 0).is_err());
+        assert!(zk.create_ttl("/d", b"", acl.clone(), Persistent, 1).is_err());
+        assert_eq!(
+            zk.create_ttl("/d", b"", acl, PersistentWithTTL, 60_000).unwrap().0,
+            "/d"
+        );
+
+        let (sender, receiver) = std::sync::mpsc::channel();
+        zk.add_watcher("/a", AddWatchMode::PersistentRecursive, sender).unwrap();
+        zk.restore_watches().unwrap();
+
+        // Watchers are told about the close
+        zk.close().unwrap();
+        assert_eq!(receiver.try_recv().unwrap().state, KeeperState::Closed);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn server_version() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let server = std::thread::spawn(move || {
+            let mut stream = accept(&listener);
+            assert_eq!(read_request(&mut stream).typ, OpCode::Create);
+            let response = CreateResponse { path: "/a".to_owned() };
+            reply(&mut stream, 2, ErrorCode::Ok, &response);
+        });
+
+        let mut zk = ZooKeeper::connect(&addr.to_string(), Duration(10_000))
+            .unwrap()
+            .with_server_version(ServerVersion::V3_4);
+        let acl = vec![ACL {
+            perms: crate::PERM_ALL,
+            id: crate::Id::anyone(),
+        }];
+
+        // Create2 isn't sent to a 3.4 server
+        let err = zk.create2("/a", b"", acl.clone(), CreateMode::Persistent).unwrap_err();
+        assert_eq!(err.code(), Some(ErrorCode::Unimplemented));
+        assert_eq!(zk.create("/a", b"", acl, CreateMode::Persistent).unwrap(), "/a");
+        server.join().unwrap();
+    }
+}
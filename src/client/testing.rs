@@ -0,0 +1,359 @@
+//! Helpers for tests that run a scripted server, or an in-memory one.
+
+use num_traits::ToPrimitive;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+
+use crate::proto::codec::{self, MAX_PACKET_LENGTH};
+use crate::proto::*;
+use crate::serde::{Deserializer, SliceRead};
+use crate::{Duration, SessionId, Stat, Timestamp, Version, Xid, Zxid};
+
+/// The response that accepts a session: id 42, with a 4 seconds timeout.
+pub fn connect_response() -> ConnectResponse {
+    ConnectResponse {
+        protocol_version: 0,
+        time_out: Duration(4000),
+        session_id: SessionId(42),
+        passwd: vec![7; 16],
+        read_only: Some(false),
+    }
+}
+
+/// Reads the connect request of a client, and accepts its session.
+pub fn handshake<S: Read + Write>(stream: &mut S) -> ConnectRequest {
+    let buf = codec::read_packet(stream, MAX_PACKET_LENGTH).unwrap();
+    let request = crate::serde::from_slice(&buf).unwrap();
+    stream
+        .write_all(&codec::encode_packet(&connect_response()).unwrap())
+        .unwrap();
+    stream.flush().unwrap();
+    request
+}
+
+/// Accepts a connection, and the session it asks for.
+pub fn accept(listener: &TcpListener) -> TcpStream {
+    let (mut stream, _) = listener.accept().unwrap();
+    handshake(&mut stream);
+    stream
+}
+
+/// Sends a reply at zxid `xid + 10`, so that the zxids seen by the client follow its requests.
+pub fn reply(stream: &mut impl Write, xid: i32, err: ErrorCode, body: &impl Serialize) {
+    reply_at(stream, xid, xid as i64 + 10, err, body);
+}
+
+/// Sends a reply at the given zxid.
+pub fn reply_at(stream: &mut impl Write, xid: i32, zxid: i64, err: ErrorCode, body: &impl Serialize) {
+    let header = ReplyHeader {
+        xid: Xid(xid),
+        zxid: Zxid(zxid),
+        err: err.to_i32().unwrap(),
+    };
+    stream
+        .write_all(&codec::encode_response(&header, body).unwrap())
+        .unwrap();
+}
+
+//----- In-memory server
+
+/// A server that keeps its nodes in memory, for tests with several clients. It answers the
+/// requests used by recipes, and sends the notifications of their watches. Ephemeral nodes are
+/// deleted when their session is closed, or its connection dropped.
+pub struct MemoryServer {
+    addr: String,
+}
+
+impl MemoryServer {
+    pub fn start() -> MemoryServer {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let state = Arc::new(Mutex::new(MemoryState::new()));
+        std::thread::spawn(move || {
+            for (n, stream) in listener.incoming().enumerate() {
+                let state = state.clone();
+                std::thread::spawn(move || serve_session(stream.unwrap(), n as i64 + 1, &state));
+            }
+        });
+        MemoryServer { addr }
+    }
+
+    pub fn addr(&self) -> &str {
+        &self.addr
+    }
+}
+
+fn serve_session(mut stream: TcpStream, session: i64, state: &Mutex<MemoryState>) {
+    codec::read_packet(&mut stream, MAX_PACKET_LENGTH).unwrap();
+    let response = ConnectResponse {
+        session_id: SessionId(session),
+        ..connect_response()
+    };
+    stream.write_all(&codec::encode_packet(&response).unwrap()).unwrap();
+    lock(state).streams.insert(session, stream.try_clone().unwrap());
+
+    // Until the session is closed, or the client dropped
+    while let Ok(buf) = codec::read_packet(&mut stream, MAX_PACKET_LENGTH) {
+        let (header, mut de) = codec::decode_request(&buf).unwrap();
+        let mut state = lock(state);
+        let response = state.apply(session, header.typ, &mut de);
+        let zxid = state.zxid;
+        let out = state.streams.get_mut(&session).unwrap();
+        let xid = header.xid.0;
+        match response {
+            Ok(MemoryResponse::Empty) => reply_at(out, xid, zxid, ErrorCode::Ok, &()),
+            Ok(MemoryResponse::Path(path)) => reply_at(out, xid, zxid, ErrorCode::Ok, &CreateResponse { path }),
+            Ok(MemoryResponse::Stat(stat)) => reply_at(out, xid, zxid, ErrorCode::Ok, &ExistsResponse { stat }),
+            Ok(MemoryResponse::Children(children)) => {
+                reply_at(out, xid, zxid, ErrorCode::Ok, &GetChildrenResponse { children })
+            }
+            Ok(MemoryResponse::Data(data, stat)) => {
+                reply_at(out, xid, zxid, ErrorCode::Ok, &GetDataResponse { data, stat })
+            }
+            Err(code) => reply_at(out, xid, zxid, code, &()),
+        }
+        if header.typ == OpCode::CloseSession {
+            break;
+        }
+    }
+    lock(state).close(session);
+}
+
+fn lock(state: &Mutex<MemoryState>) -> MutexGuard<'_, MemoryState> {
+    state.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+enum MemoryResponse {
+    Empty,
+    Path(String),
+    Stat(Stat),
+    Children(Vec<String>),
+    Data(Vec<u8>, Stat),
+}
+
+struct MemoryState {
+    zxid: i64,
+    nodes: BTreeMap<String, (Vec<u8>, Stat)>,
+    /// Sessions watching the data or the existence of a node
+    data_watches: HashMap<String, HashSet<i64>>,
+    child_watches: HashMap<String, HashSet<i64>>,
+    /// Streams of the sessions, to send replies and notifications
+    streams: HashMap<i64, TcpStream>,
+}
+
+impl MemoryState {
+    fn new() -> MemoryState {
+        let mut nodes = BTreeMap::new();
+        nodes.insert("/".to_owned(), (Vec::new(), Self::stat(0, 0)));
+        MemoryState {
+            zxid: 0,
+            nodes,
+            data_watches: HashMap::new(),
+            child_watches: HashMap::new(),
+            streams: HashMap::new(),
+        }
+    }
+
+    fn stat(zxid: i64, owner: i64) -> Stat {
+        Stat {
+            czxid: Zxid(zxid),
+            mzxid: Zxid(zxid),
+            ctime: Timestamp(0),
+            mtime: Timestamp(0),
+            version: Version(0),
+            cversion: Version(0),
+            aversion: Version(0),
+            ephemeral_owner: SessionId(owner),
+            data_length: 0,
+            num_children: 0,
+            pzxid: Zxid(zxid),
+        }
+    }
+
+    fn apply(
+        &mut self,
+        session: i64,
+        op: OpCode,
+        de: &mut Deserializer<SliceRead>,
+    ) -> Result<MemoryResponse, ErrorCode> {
+        let response = match op {
+            OpCode::Create => {
+                let request = CreateRequest::deserialize(de).unwrap();
+                let owner = if request.flags.is_ephemeral() { session } else { 0 };
+                MemoryResponse::Path(self.create(&request.path, request.data, owner, request.flags.is_sequential())?)
+            }
+            OpCode::Delete => {
+                let request = DeleteRequest::deserialize(de).unwrap();
+                self.delete(&request.path, request.version.0)?;
+                MemoryResponse::Empty
+            }
+            OpCode::Exists => {
+                let request = ExistsRequest::deserialize(de).unwrap();
+                if request.watch {
+                    watch(&mut self.data_watches, &request.path, session);
+                }
+                MemoryResponse::Stat(self.node(&request.path)?.1.clone())
+            }
+            OpCode::GetData => {
+                let request = GetDataRequest::deserialize(de).unwrap();
+                let (data, stat) = self.node(&request.path)?.clone();
+                if request.watch {
+                    watch(&mut self.data_watches, &request.path, session);
+                }
+                MemoryResponse::Data(data, stat)
+            }
+            OpCode::SetData => {
+                let request = SetDataRequest::deserialize(de).unwrap();
+                let zxid = self.zxid + 1;
+                let (data, stat) = self.nodes.get_mut(&request.path).ok_or(ErrorCode::NoNode)?;
+                if request.version.0 != -1 && request.version != stat.version {
+                    return Err(ErrorCode::BadVersion);
+                }
+                self.zxid = zxid;
+                *data = request.data;
+                stat.version.0 += 1;
+                stat.mzxid = Zxid(zxid);
+                stat.data_length = data.len() as i32;
+                let stat = stat.clone();
+                self.notify_data(&request.path, WatcherEventType::NodeDataChanged);
+                MemoryResponse::Stat(stat)
+            }
+            OpCode::GetChildren => {
+                let request = GetChildrenRequest::deserialize(de).unwrap();
+                self.node(&request.path)?;
+                if request.watch {
+                    watch(&mut self.child_watches, &request.path, session);
+                }
+                MemoryResponse::Children(self.children(&request.path))
+            }
+            OpCode::Ping => MemoryResponse::Empty,
+            OpCode::CloseSession => {
+                self.delete_ephemerals(session);
+                MemoryResponse::Empty
+            }
+            op => panic!("unexpected {:?}", op),
+        };
+        Ok(response)
+    }
+
+    fn node(&self, path: &str) -> Result<&(Vec<u8>, Stat), ErrorCode> {
+        self.nodes.get(path).ok_or(ErrorCode::NoNode)
+    }
+
+    fn children(&self, path: &str) -> Vec<String> {
+        let prefix = if path == "/" {
+            "/".to_owned()
+        } else {
+            format!("{}/", path)
+        };
+        self.nodes
+            .range(prefix.clone()..)
+            .take_while(|(p, _)| p.starts_with(&prefix))
+            .map(|(p, _)| p[prefix.len()..].to_owned())
+            .filter(|name| !name.is_empty() && !name.contains('/'))
+            .collect()
+    }
+
+    fn create(&mut self, path: &str, data: Vec<u8>, owner: i64, sequential: bool) -> Result<String, ErrorCode> {
+        let parent = parent(path);
+        let zxid = self.zxid + 1;
+        let parent_stat = &mut self.nodes.get_mut(parent).ok_or(ErrorCode::NoNode)?.1;
+        let path = match sequential {
+            true => format!("{}{:010}", path, parent_stat.cversion.0),
+            false => path.to_owned(),
+        };
+        if self.nodes.contains_key(&path) {
+            return Err(ErrorCode::NodeExists);
+        }
+
+        let parent_stat = &mut self.nodes.get_mut(parent).unwrap().1;
+        parent_stat.cversion.0 += 1;
+        parent_stat.num_children += 1;
+        parent_stat.pzxid = Zxid(zxid);
+        self.zxid = zxid;
+        let mut stat = Self::stat(zxid, owner);
+        stat.data_length = data.len() as i32;
+        self.nodes.insert(path.clone(), (data, stat));
+
+        self.notify_data(&path, WatcherEventType::NodeCreated);
+        self.notify_children(parent, WatcherEventType::NodeChildrenChanged);
+        Ok(path)
+    }
+
+    fn delete(&mut self, path: &str, version: i32) -> Result<(), ErrorCode> {
+        let stat = &self.node(path)?.1;
+        if version != -1 && version != stat.version.0 {
+            return Err(ErrorCode::BadVersion);
+        }
+        if stat.num_children > 0 {
+            return Err(ErrorCode::NotEmpty);
+        }
+
+        self.zxid += 1;
+        self.nodes.remove(path);
+        let parent = parent(path);
+        let parent_stat = &mut self.nodes.get_mut(parent).unwrap().1;
+        parent_stat.cversion.0 += 1;
+        parent_stat.num_children -= 1;
+        parent_stat.pzxid = Zxid(self.zxid);
+
+        self.notify_data(path, WatcherEventType::NodeDeleted);
+        self.notify_children(path, WatcherEventType::NodeDeleted);
+        self.notify_children(parent, WatcherEventType::NodeChildrenChanged);
+        Ok(())
+    }
+
+    fn close(&mut self, session: i64) {
+        self.streams.remove(&session);
+        self.delete_ephemerals(session);
+    }
+
+    fn delete_ephemerals(&mut self, session: i64) {
+        let ephemerals = self
+            .nodes
+            .iter()
+            .filter(|(_, (_, stat))| stat.ephemeral_owner == SessionId(session))
+            .map(|(path, _)| path.clone())
+            .collect::<Vec<_>>();
+        for path in ephemerals {
+            let _ = self.delete(&path, -1);
+        }
+    }
+
+    fn notify_data(&mut self, path: &str, typ: WatcherEventType) {
+        let sessions = self.data_watches.remove(path).unwrap_or_default();
+        self.notify(sessions, path, typ);
+    }
+
+    fn notify_children(&mut self, path: &str, typ: WatcherEventType) {
+        let sessions = self.child_watches.remove(path).unwrap_or_default();
+        self.notify(sessions, path, typ);
+    }
+
+    fn notify(&mut self, sessions: HashSet<i64>, path: &str, typ: WatcherEventType) {
+        let event = WatcherEvent {
+            typ,
+            state: KeeperState::SyncConnected,
+            path: path.to_owned(),
+        };
+        for session in sessions {
+            if let Some(stream) = self.streams.get_mut(&session) {
+                reply_at(stream, -1, -1, ErrorCode::Ok, &event);
+            }
+        }
+    }
+}
+
+fn watch(watches: &mut HashMap<String, HashSet<i64>>, path: &str, session: i64) {
+    watches.entry(path.to_owned()).or_default().insert(session);
+}
+
+fn parent(path: &str) -> &str {
+    match path.rfind('/') {
+        Some(0) | None => "/",
+        Some(i) => &path[..i],
+    }
+}
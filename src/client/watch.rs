@@ -0,0 +1,421 @@
+//! Watches registered by a client, and the delivery of their notifications.
+//!
+//! See `ZKWatchManager` in the Java client. Servers send a single notification for a path, even if
+//! it was watched several times: the client keeps track of who registered a watch, and delivers
+//! the notification to all of them. Watches are one-shot: they're removed once triggered, except
+//! persistent watches (ZK 3.6+) which stay set until the session expires.
+//!
+//! Watches are registered once the request that sets them succeeds. Servers forget the watches
+//! of a connection when it's closed: after a reconnection, `set_watches` builds the `SetWatches2`
+//! request that registers them again, and makes servers send the notifications missed since the
+//! last zxid seen by the client. Without persistent watches, it can be sent as a `SetWatches` that
+//! older servers understand.
+//!
+//! Notifications are delivered to a `Watcher`: a callback, or the `Sender` of a channel whose
+//! `Receiver` iterates on them. Async code can forward them to a channel of its runtime with a
+//! callback.
+//!
+//! Like the Java client, which keeps watchers in sets, a watcher is notified once per event even
+//! if it was registered several times: for the same path and kind, or with both a data and an
+//! exist watch on a path, which a `NodeCreated` event triggers together. Watchers are told apart
+//! with `Watcher::id`: wrap a watcher in a `SharedWatcher` to register it several times.
+
+use std::collections::{HashMap, HashSet};
+use std::sync::mpsc::Sender;
+use std::sync::{Arc, Mutex, PoisonError};
+
+use crate::proto::{KeeperState, SetWatches2, WatcherEvent, WatcherEventType};
+use crate::Zxid;
+
+/// Receives watch notifications.
+pub trait Watcher: Send {
+    fn process(&mut self, event: &WatcherEvent);
+
+    /// Identifies a watcher registered several times, that is notified only once per event.
+    /// `None` if it's distinct from all other watchers.
+    fn id(&self) -> Option<usize> {
+        None
+    }
+}
+
+impl<F: FnMut(&WatcherEvent) + Send> Watcher for F {
+    fn process(&mut self, event: &WatcherEvent) {
+        self(event)
+    }
+}
+
+impl Watcher for Sender<WatcherEvent> {
+    fn process(&mut self, event: &WatcherEvent) {
+        // The receiver may have been dropped, if it's no longer interested
+        let _ = self.send(event.clone());
+    }
+}
+
+/// A watcher that can be registered several times, by cloning it. All clones are the same watcher.
+#[derive(Clone)]
+pub struct SharedWatcher(Arc<Mutex<dyn Watcher>>);
+
+impl SharedWatcher {
+    pub fn new(watcher: impl Watcher + 'static) -> SharedWatcher {
+        SharedWatcher(Arc::new(Mutex::new(watcher)))
+    }
+}
+
+impl Watcher for SharedWatcher {
+    fn process(&mut self, event: &WatcherEvent) {
+        // A watcher that panicked can still be notified
+        self.0.lock().unwrap_or_else(PoisonError::into_inner).process(event)
+    }
+
+    fn id(&self) -> Option<usize> {
+        Some(Arc::as_ptr(&self.0) as *const () as usize)
+    }
+}
+
+/// The kind of a watch, from the request that registered it.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum WatchKind {
+    /// `GetData`, or `Exists` on an existing node
+    Data,
+    /// `Exists` on a missing node
+    Exist,
+    /// `GetChildren`
+    Child,
+    /// `AddWatch` in `Persistent` mode
+    Persistent,
+    /// `AddWatch` in `PersistentRecursive` mode
+    PersistentRecursive,
+}
+
+/// Who a watch is delivered to.
+enum Target {
+    /// The client's own event queue, for requests with `watch: true`
+    Default,
+    Watcher(Box<dyn Watcher>),
+}
+
+/// The watches of a session.
+#[derive(Default)]
+pub struct WatchManager {
+    data: HashMap<String, Vec<Target>>,
+    exist: HashMap<String, Vec<Target>>,
+    child: HashMap<String, Vec<Target>>,
+    persistent: HashMap<String, Vec<Target>>,
+    persistent_recursive: HashMap<String, Vec<Target>>,
+}
+
+impl WatchManager {
+    pub fn new() -> WatchManager {
+        WatchManager::default()
+    }
+
+    /// Register a watch whose notification is delivered to `watcher`. It is registered only once
+    /// per path if it has an `id`.
+    pub fn register(&mut self, kind: WatchKind, path: &str, watcher: Box<dyn Watcher>) {
+        let targets = self.watches(kind).entry(path.to_owned()).or_default();
+        let id = watcher.id();
+        let registered = |t: &Target| matches!(t, Target::Watcher(w) if w.id() == id);
+        if id.is_none() || !targets.iter().any(registered) {
+            targets.push(Target::Watcher(watcher));
+        }
+    }
+
+    /// Register a watch whose notification is delivered to the client's event queue. It is
+    /// registered only once per path.
+    pub fn register_default(&mut self, kind: WatchKind, path: &str) {
+        let targets = self.watches(kind).entry(path.to_owned()).or_default();
+        if !targets.iter().any(|t| matches!(t, Target::Default)) {
+            targets.push(Target::Default);
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+            && self.exist.is_empty()
+            && self.child.is_empty()
+            && self.persistent.is_empty()
+            && self.persistent_recursive.is_empty()
+    }
+
+    /// Paths with a watch of this kind, sorted.
+    pub fn paths(&self, kind: WatchKind) -> Vec<String> {
+        let mut paths = match kind {
+            WatchKind::Data => self.data.keys(),
+            WatchKind::Exist => self.exist.keys(),
+            WatchKind::Child => self.child.keys(),
+            WatchKind::Persistent => self.persistent.keys(),
+            WatchKind::PersistentRecursive => self.persistent_recursive.keys(),
+        }
+        .cloned()
+        .collect::<Vec<_>>();
+        paths.sort();
+        paths
+    }
+
+    /// Deliver a notification to the watchers it triggers, which are removed. Returns whether the
+    /// event must also be delivered to the client's event queue: state changes always are, and so
+    /// are notifications that trigger no watch, e.g. when they're received before the reply of the
+    /// request that set the watch.
+    ///
+    /// Persistent watches stay set. Recursive ones are also triggered by the changes of descendants,
+    /// except child list changes.
+    ///
+    /// Changes of the connection state are delivered to all watchers, which stay set. An expired
+    /// session loses all its watches.
+    pub fn deliver(&mut self, event: &WatcherEvent) -> bool {
+        let path = event.path.as_str();
+        let mut notified = HashSet::new();
+        let mut targets = Vec::new();
+        match event.typ {
+            WatcherEventType::None if event.state == KeeperState::Expired => {
+                for watches in self.all_watches() {
+                    targets.extend(watches.drain().flat_map(|(_, t)| t));
+                }
+                for mut target in targets {
+                    Self::notify(&mut target, &mut notified, event);
+                }
+                return true;
+            }
+            WatcherEventType::None => {
+                for watches in self.all_watches() {
+                    for target in watches.values_mut().flatten() {
+                        Self::notify(target, &mut notified, event);
+                    }
+                }
+                return true;
+            }
+            WatcherEventType::NodeCreated | WatcherEventType::NodeDataChanged => {
+                targets.extend(self.data.remove(path).unwrap_or_default());
+                targets.extend(self.exist.remove(path).unwrap_or_default());
+            }
+            WatcherEventType::NodeChildrenChanged => {
+                targets.extend(self.child.remove(path).unwrap_or_default());
+            }
+            WatcherEventType::NodeDeleted => {
+                targets.extend(self.data.remove(path).unwrap_or_default());
+                targets.extend(self.exist.remove(path).unwrap_or_default());
+                targets.extend(self.child.remove(path).unwrap_or_default());
+            }
+            WatcherEventType::DataWatchRemoved => {
+                targets.extend(self.data.remove(path).unwrap_or_default());
+                targets.extend(self.exist.remove(path).unwrap_or_default());
+            }
+            WatcherEventType::ChildWatchRemoved => {
+                targets.extend(self.child.remove(path).unwrap_or_default());
+            }
+        }
+
+        let (exact, recursive) = match event.typ {
+            WatcherEventType::NodeChildrenChanged => (true, false),
+            WatcherEventType::DataWatchRemoved | WatcherEventType::ChildWatchRemoved => (false, false),
+            _ => (true, true),
+        };
+        let persistent = self.persistent.get_mut(path).filter(|_| exact).into_iter().flatten();
+        let persistent_recursive = self
+            .persistent_recursive
+            .iter_mut()
+            .filter(|(watched, _)| recursive && is_ancestor_or_self(watched, path))
+            .flat_map(|(_, t)| t);
+
+        let mut triggered = !targets.is_empty();
+        let mut default = false;
+        for mut target in targets {
+            default |= Self::notify(&mut target, &mut notified, event);
+        }
+        for target in persistent.chain(persistent_recursive) {
+            triggered = true;
+            default |= Self::notify(target, &mut notified, event);
+        }
+        default || !triggered
+    }
+
+    /// The request that registers all watches again on a new connection, or `None` if there are
+    /// none. Servers send the notifications of changes that followed `relative_zxid`.
+    pub fn set_watches(&self, relative_zxid: Zxid) -> Option<SetWatches2> {
+        if self.is_empty() {
+            return None;
+        }
+        Some(SetWatches2 {
+            relative_zxid,
+            data_watches: self.paths(WatchKind::Data),
+            exist_watches: self.paths(WatchKind::Exist),
+            child_watches: self.paths(WatchKind::Child),
+            persistent_watches: self.paths(WatchKind::Persistent),
+            persistent_recursive_watches: self.paths(WatchKind::PersistentRecursive),
+        })
+    }
+
+    fn watches(&mut self, kind: WatchKind) -> &mut HashMap<String, Vec<Target>> {
+        match kind {
+            WatchKind::Data => &mut self.data,
+            WatchKind::Exist => &mut self.exist,
+            WatchKind::Child => &mut self.child,
+            WatchKind::Persistent => &mut self.persistent,
+            WatchKind::PersistentRecursive => &mut self.persistent_recursive,
+        }
+    }
+
+    fn all_watches(&mut self) -> [&mut HashMap<String, Vec<Target>>; 5] {
+        [
+            &mut self.data,
+            &mut self.exist,
+            &mut self.child,
+            &mut self.persistent,
+            &mut self.persistent_recursive,
+        ]
+    }
+
+    /// Notify a target, unless it's a watcher that has already been notified of this event.
+    /// Returns whether it's the default target.
+    fn notify(target: &mut Target, notified: &mut HashSet<usize>, event: &WatcherEvent) -> bool {
+        match target {
+            Target::Default => true,
+            Target::Watcher(watcher) => {
+                match watcher.id() {
+                    Some(id) if !notified.insert(id) => {}
+                    _ => watcher.process(event),
+                }
+                false
+            }
+        }
+    }
+}
+
+/// Is `path` the node `ancestor` or one of its descendants?
+pub(super) fn is_ancestor_or_self(ancestor: &str, path: &str) -> bool {
+    match path.strip_prefix(ancestor) {
+        Some(rest) => ancestor == "/" || rest.is_empty() || rest.starts_with('/'),
+        None => false,
+    }
+}
+
+impl std::fmt::Debug for WatchManager {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("WatchManager")
+            .field("data", &self.paths(WatchKind::Data))
+            .field("exist", &self.paths(WatchKind::Exist))
+            .field("child", &self.paths(WatchKind::Child))
+            .field("persistent", &self.paths(WatchKind::Persistent))
+            .field("persistent_recursive", &self.paths(WatchKind::PersistentRecursive))
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc::channel;
+
+    fn event(typ: WatcherEventType, path: &str) -> WatcherEvent {
+        WatcherEvent {
+            typ,
+            state: KeeperState::SyncConnected,
+            path: path.to_owned(),
+        }
+    }
+
+    #[test]
+    fn watch_delivery() {
+        let mut watches = WatchManager::new();
+        let (sender, receiver) = channel();
+        watches.register(WatchKind::Data, "/app", Box::new(sender.clone()));
+        watches.register(WatchKind::Child, "/app", Box::new(sender));
+        watches.register_default(WatchKind::Exist, "/lock");
+        watches.register_default(WatchKind::Exist, "/lock");
+
+        let (paths, created) = channel();
+        let callback = move |e: &WatcherEvent| paths.send(e.path.clone()).unwrap();
+        watches.register(WatchKind::Exist, "/lock", Box::new(callback));
+
+        let request = watches.set_watches(Zxid(42)).unwrap();
+        assert_eq!(request.relative_zxid, Zxid(42));
+        assert_eq!(request.data_watches, vec!["/app"]);
+        assert_eq!(request.exist_watches, vec!["/lock"]);
+        assert_eq!(request.child_watches, vec!["/app"]);
+
+        // Data changes don't trigger child watches, and watches are one-shot
+        assert!(!watches.deliver(&event(WatcherEventType::NodeDataChanged, "/app")));
+        assert!(watches.deliver(&event(WatcherEventType::NodeDataChanged, "/app")));
+        assert_eq!(receiver.try_iter().count(), 1);
+        assert_eq!(watches.paths(WatchKind::Child), vec!["/app"]);
+
+        assert!(watches.deliver(&event(WatcherEventType::NodeCreated, "/lock")));
+        assert_eq!(created.try_iter().collect::<Vec<_>>(), vec!["/lock"]);
+
+        // Connection state changes notify watchers, which are kept
+        let disconnected = WatcherEvent {
+            typ: WatcherEventType::None,
+            state: KeeperState::Disconnected,
+            path: String::new(),
+        };
+        assert!(watches.deliver(&disconnected));
+        assert_eq!(
+            receiver.try_iter().map(|e| e.state).collect::<Vec<_>>(),
+            vec![KeeperState::Disconnected]
+        );
+        assert_eq!(watches.paths(WatchKind::Child), vec!["/app"]);
+
+        // Expiration notifies and clears all watches
+        let expired = WatcherEvent {
+            typ: WatcherEventType::None,
+            state: KeeperState::Expired,
+            path: String::new(),
+        };
+        assert!(watches.deliver(&expired));
+        assert_eq!(
+            receiver.try_iter().map(|e| e.state).collect::<Vec<_>>(),
+            vec![KeeperState::Expired]
+        );
+        assert!(watches.is_empty());
+        assert!(watches.set_watches(Zxid(42)).is_none());
+    }
+
+    #[test]
+    fn watch_deduplication() {
+        let mut watches = WatchManager::new();
+        let (sender, receiver) = channel();
+        let shared = SharedWatcher::new(sender.clone());
+        watches.register(WatchKind::Data, "/app", Box::new(shared.clone()));
+        watches.register(WatchKind::Data, "/app", Box::new(shared.clone()));
+        watches.register(WatchKind::Exist, "/app", Box::new(shared.clone()));
+        watches.register(WatchKind::Child, "/app", Box::new(shared));
+        assert_eq!(watches.data["/app"].len(), 1);
+
+        // Distinct watchers are all notified, a shared one once per event
+        watches.register(WatchKind::Exist, "/app", Box::new(sender));
+        assert!(!watches.deliver(&event(WatcherEventType::NodeCreated, "/app")));
+        assert_eq!(receiver.try_iter().count(), 2);
+        assert!(!watches.deliver(&event(WatcherEventType::NodeDeleted, "/app")));
+        assert_eq!(receiver.try_iter().count(), 1);
+        assert!(watches.is_empty());
+    }
+
+    #[test]
+    fn persistent_watches() {
+        let mut watches = WatchManager::new();
+        let (sender, receiver) = channel();
+        watches.register(WatchKind::Persistent, "/app", Box::new(sender.clone()));
+        watches.register(WatchKind::PersistentRecursive, "/app", Box::new(sender));
+        watches.register_default(WatchKind::PersistentRecursive, "/");
+
+        // Changes of the node trigger both, which stay set
+        for _ in 0..2 {
+            assert!(watches.deliver(&event(WatcherEventType::NodeDataChanged, "/app")));
+            assert_eq!(receiver.try_iter().count(), 2);
+        }
+
+        // Changes of descendants only trigger recursive watches, except child list changes
+        watches.deliver(&event(WatcherEventType::NodeCreated, "/app/a"));
+        assert_eq!(receiver.try_iter().count(), 1);
+        watches.deliver(&event(WatcherEventType::NodeChildrenChanged, "/app/a"));
+        watches.deliver(&event(WatcherEventType::NodeChildrenChanged, "/app"));
+        assert_eq!(receiver.try_iter().count(), 1);
+        watches.deliver(&event(WatcherEventType::NodeCreated, "/application"));
+        assert_eq!(receiver.try_iter().count(), 0);
+
+        let request = watches.set_watches(Zxid(42)).unwrap();
+        assert_eq!(request.persistent_watches, vec!["/app"]);
+        assert_eq!(request.persistent_recursive_watches, vec!["/", "/app"]);
+        assert!(request.to_set_watches().is_none());
+    }
+}
@@ -0,0 +1,172 @@
+//! Request ids.
+//!
+//! Clients number their requests with an xid that the server copies in the reply. Xids of regular
+//! requests are positive and wrap around at `i32::MAX`; negative ones are reserved for special
+//! packets (see `ClientCnxn.java`).
+//!
+//! `XidAllocator` hands out xids, and `CorrelationMap` keeps track of the requests waiting for a
+//! reply, so that clients and proxies can match replies to their request and find requests that
+//! never got one.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use crate::clock::{self, Clock};
+use crate::{Timestamp, Xid};
+
+/// Xid of watch notifications sent by the server
+pub const NOTIFICATION_XID: Xid = Xid(-1);
+/// Xid of pings
+pub const PING_XID: Xid = Xid(-2);
+/// Xid of authentication packets
+pub const AUTH_XID: Xid = Xid(-4);
+/// Xid of the requests that set watches again after a reconnection
+pub const SET_WATCHES_XID: Xid = Xid(-8);
+
+/// Allocates the xids of regular requests: 1, 2, ... `i32::MAX`, then 1 again.
+#[derive(Debug, Clone, Default)]
+pub struct XidAllocator {
+    last: i32,
+}
+
+impl XidAllocator {
+    pub fn new() -> XidAllocator {
+        XidAllocator::default()
+    }
+
+    /// An allocator whose first xid follows `last`, e.g. to continue the numbering of a session.
+    /// Reserved xids are ignored, and allocation then starts at 1.
+    pub fn after(last: Xid) -> XidAllocator {
+        XidAllocator { last: last.0.max(0) }
+    }
+
+    /// The next xid
+    pub fn allocate(&mut self) -> Xid {
+        self.last = if self.last == i32::MAX { 1 } else { self.last + 1 };
+        Xid(self.last)
+    }
+}
+
+/// Requests waiting for their reply, by xid, with the time they were sent.
+///
+/// Xids are allocated by the map so that a wrapped around xid is never given to a request that is
+/// still pending. Requests that are never answered (e.g. lost by a proxy or a faulty server) would
+/// stay here forever: `leaked` removes those that have been waiting for too long.
+pub struct CorrelationMap<T> {
+    xids: XidAllocator,
+    pending: HashMap<Xid, (Timestamp, T)>,
+    clock: Arc<dyn Clock>,
+}
+
+impl<T> Default for CorrelationMap<T> {
+    fn default() -> Self {
+        CorrelationMap::new()
+    }
+}
+
+impl<T> CorrelationMap<T> {
+    pub fn new() -> CorrelationMap<T> {
+        CorrelationMap {
+            xids: XidAllocator::new(),
+            pending: HashMap::new(),
+            clock: clock::system(),
+        }
+    }
+
+    /// Allocate xids with `xids` instead of starting from 1.
+    pub fn with_allocator(mut self, xids: XidAllocator) -> Self {
+        self.xids = xids;
+        self
+    }
+
+    /// Set the clock used to timestamp requests.
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Add a request, and return the xid to send it with.
+    pub fn insert(&mut self, request: T) -> Xid {
+        // Terminates as there can't be i32::MAX pending requests in memory
+        let mut xid = self.xids.allocate();
+        while self.pending.contains_key(&xid) {
+            xid = self.xids.allocate();
+        }
+        self.pending.insert(xid, (self.clock.now(), request));
+        xid
+    }
+
+    /// Remove the request answered by a reply with this xid, if it's pending.
+    pub fn remove(&mut self, xid: Xid) -> Option<T> {
+        self.pending.remove(&xid).map(|(_, request)| request)
+    }
+
+    /// Remove the requests that have been waiting for their reply for more than `timeout`, oldest
+    /// first.
+    pub fn leaked(&mut self, timeout: std::time::Duration) -> Vec<(Xid, T)> {
+        let clock = &self.clock;
+        let mut leaked = self
+            .pending
+            .iter()
+            .filter(|(_, (sent, _))| clock.elapsed(*sent) > timeout)
+            .map(|(xid, (sent, _))| (*sent, *xid))
+            .collect::<Vec<_>>();
+        leaked.sort();
+
+        leaked
+            .into_iter()
+            .filter_map(|(_, xid)| self.remove(xid).map(|request| (xid, request)))
+            .collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.pending.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::MockClock;
+    use std::time::Duration;
+
+    #[test]
+    fn wrap_around() {
+        let mut xids = XidAllocator::after(Xid(i32::MAX - 1));
+        assert_eq!(xids.allocate(), Xid(i32::MAX));
+        assert_eq!(xids.allocate(), Xid(1));
+        assert_eq!(XidAllocator::after(PING_XID).allocate(), Xid(1));
+
+        // Xids still pending are skipped
+        let mut map = CorrelationMap::new().with_allocator(XidAllocator::after(Xid(i32::MAX - 1)));
+        assert_eq!(map.insert("a"), Xid(i32::MAX));
+        assert_eq!(map.insert("b"), Xid(1));
+        map.xids = XidAllocator::after(Xid(i32::MAX - 1));
+        assert_eq!(map.insert("c"), Xid(2));
+        assert_eq!(map.remove(Xid(1)), Some("b"));
+        assert_eq!(map.remove(Xid(1)), None);
+        assert_eq!(map.len(), 2);
+    }
+
+    #[test]
+    fn leaked_requests() {
+        let clock = MockClock::new(Timestamp(1000));
+        let mut map = CorrelationMap::new().with_clock(Arc::new(clock.clone()));
+
+        let first = map.insert("first");
+        clock.advance(Duration::from_secs(5));
+        let second = map.insert("second");
+        let third = map.insert("third");
+        assert_eq!(map.remove(second), Some("second"));
+
+        clock.advance(Duration::from_secs(5));
+        assert_eq!(map.leaked(Duration::from_secs(6)), vec![(first, "first")]);
+        clock.advance(Duration::from_secs(5));
+        assert_eq!(map.leaked(Duration::from_secs(6)), vec![(third, "third")]);
+        assert!(map.is_empty());
+    }
+}
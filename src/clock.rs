@@ -0,0 +1,82 @@
+//! Sources of time.
+//!
+//! Everything that reads the current time (client pings, TTL expiry, replay progress) does so
+//! through a `Clock`, so that tests and simulations can control time with a `MockClock` instead of
+//! waiting for it to pass.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::Timestamp;
+
+pub trait Clock: Send + Sync {
+    /// Current time, in milliseconds since the epoch like ZooKeeper's `ctime` and `mtime`.
+    fn now(&self) -> Timestamp;
+
+    /// Time elapsed since `since`, or zero if `since` is in the future.
+    fn elapsed(&self, since: Timestamp) -> std::time::Duration {
+        std::time::Duration::from_millis(self.now().0.saturating_sub(since.0))
+    }
+}
+
+/// The system's wall clock.
+#[derive(Debug, Copy, Clone, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Timestamp {
+        let since_epoch = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        Timestamp(since_epoch.as_millis() as u64)
+    }
+}
+
+/// The default clock, shared by the components that don't have one set.
+pub fn system() -> Arc<dyn Clock> {
+    Arc::new(SystemClock)
+}
+
+/// A clock that only moves when told to. Clones share the same time, so that a test can keep a
+/// clone to move the time of the components it gave the clock to.
+#[derive(Debug, Clone, Default)]
+pub struct MockClock(Arc<AtomicU64>);
+
+impl MockClock {
+    pub fn new(start: Timestamp) -> MockClock {
+        MockClock(Arc::new(AtomicU64::new(start.0)))
+    }
+
+    pub fn set(&self, time: Timestamp) {
+        self.0.store(time.0, Ordering::SeqCst);
+    }
+
+    pub fn advance(&self, duration: std::time::Duration) {
+        self.0.fetch_add(duration.as_millis() as u64, Ordering::SeqCst);
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> Timestamp {
+        Timestamp(self.0.load(Ordering::SeqCst))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mock_clock() {
+        let clock = MockClock::new(Timestamp(1000));
+        let shared: Arc<dyn Clock> = Arc::new(clock.clone());
+
+        clock.advance(std::time::Duration::from_secs(2));
+        assert_eq!(shared.now(), Timestamp(3000));
+        assert_eq!(shared.elapsed(Timestamp(1500)), std::time::Duration::from_millis(1500));
+        assert_eq!(shared.elapsed(Timestamp(5000)), std::time::Duration::default());
+
+        clock.set(Timestamp(10));
+        assert_eq!(shared.now(), Timestamp(10));
+        assert!(SystemClock.now() > Timestamp(1_500_000_000_000));
+    }
+}
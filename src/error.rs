@@ -0,0 +1,294 @@
+//! Errors returned by this crate.
+//!
+//! Each area has its own error type, so that callers can match on what went wrong:
+//! - `CodecError`: encoding and decoding of the jute wire format,
+//! - `ParseError`: invalid user input such as paths, patterns, queries or connect strings,
+//! - `PersistenceError`: snapshot and txnlog files, and the data trees built from them,
+//! - `ClientError`: exchanges with a ZooKeeper server,
+//! - `RecipeError`: coordination recipes built on the client,
+//! - `BackupError`: backup repositories.
+//!
+//! All of them are `#[non_exhaustive]`, and errors of lower layers (I/O, encoding, parsing) are
+//! wrapped, so that they're available as their `source()`.
+
+use std::fmt::Display;
+use thiserror::Error;
+
+pub use crate::serde::CodecError;
+
+#[cfg(feature = "client")]
+use crate::proto::ErrorCode;
+#[cfg(feature = "persistence")]
+use crate::Zxid;
+
+/// An invalid value given to a parser or a constructor.
+#[derive(Debug, Error)]
+#[non_exhaustive]
+pub enum ParseError {
+    /// A value that isn't in the expected syntax or range. `what` is the kind of value.
+    #[error("Invalid {what}: {value}")]
+    Invalid { what: &'static str, value: String },
+
+    #[error("Invalid regular expression: {0}")]
+    Regex(#[from] regex::Error),
+
+    #[error("Invalid UTF-8 text: {0}")]
+    Utf8(#[from] std::str::Utf8Error),
+
+    #[error("Invalid number: {0}")]
+    Int(#[from] std::num::ParseIntError),
+}
+
+impl ParseError {
+    pub fn invalid(what: &'static str, value: impl Display) -> ParseError {
+        ParseError::Invalid {
+            what,
+            value: value.to_string(),
+        }
+    }
+}
+
+/// Errors of snapshot and txnlog files, and of the operations that read them.
+#[cfg(feature = "persistence")]
+#[derive(Debug, Error)]
+#[non_exhaustive]
+pub enum PersistenceError {
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+
+    #[error("Encoding error: {0}")]
+    Codec(#[from] CodecError),
+
+    #[error(transparent)]
+    Parse(#[from] ParseError),
+
+    #[error("Wrong magic number")]
+    WrongMagic,
+
+    #[error("Wrong version number {0}")]
+    WrongVersion(i32),
+
+    /// The last record of a file was only partially written
+    #[error("Last transaction was partial")]
+    Partial,
+
+    /// A record's checksum doesn't match its content. `zxid` is the one found in the record, if
+    /// it's long enough to have one (it may be corrupted too). `detected` is the algorithm that
+    /// matches, if there is one, which means that the file was read with the wrong algorithm.
+    #[error(
+        "{algorithm} checksum mismatch{}{}",
+        zxid.map(|z| format!(" in txn {:x}", z.0)).unwrap_or_default(),
+        detected.map(|d| format!(" (matches {})", d)).unwrap_or_default()
+    )]
+    ChecksumMismatch {
+        algorithm: &'static str,
+        zxid: Option<Zxid>,
+        detected: Option<&'static str>,
+    },
+
+    /// Data that doesn't follow the file format
+    #[error("Corrupted file: {0}")]
+    Corrupted(String),
+
+    /// A data tree digest that doesn't match the one computed from the data nodes
+    #[error("Digest mismatch at txn {:x}: expected {expected:x}, computed {actual:x}", zxid.0)]
+    DigestMismatch { zxid: Zxid, expected: i64, actual: i64 },
+
+    /// A txn whose operation code is unknown to this crate, e.g. written by a more recent server.
+    /// The following txns can still be read.
+    #[error("Unknown operation code {code} in txn {:x}", zxid.0)]
+    UnknownOpCode { zxid: Zxid, code: i32 },
+
+    /// A stream that can't be read any further after a previous error
+    #[error("Stream already errored out")]
+    Errored,
+
+    /// A file, node or other item that doesn't exist
+    #[error("{0}")]
+    NotFound(String),
+
+    /// Transactions that can't be applied to a data tree
+    #[error("{0}")]
+    Inconsistent(String),
+
+    #[error("Cancelled")]
+    Cancelled,
+
+    /// A file format that requires a feature that isn't enabled
+    #[error("Unsupported: {0}")]
+    Unsupported(String),
+
+    #[cfg(feature = "arrow")]
+    #[error("Arrow error: {0}")]
+    Arrow(#[from] arrow_schema::ArrowError),
+
+    #[cfg(feature = "parquet")]
+    #[error("Parquet error: {0}")]
+    Parquet(#[from] parquet::errors::ParquetError),
+}
+
+/// Errors of the exchanges with a ZooKeeper server.
+#[cfg(feature = "client")]
+#[derive(Debug, Error)]
+#[non_exhaustive]
+pub enum ClientError {
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+
+    #[error("Encoding error: {0}")]
+    Codec(#[from] CodecError),
+
+    #[error(transparent)]
+    Parse(#[from] ParseError),
+
+    /// An error code returned by the server
+    #[error("Server error: {0}")]
+    Server(ErrorCode),
+
+    /// An error code that isn't known to this crate
+    #[error("Unknown error code {0}")]
+    UnknownErrorCode(i32),
+
+    /// Data sent by the server that doesn't follow the protocol
+    #[error("Protocol error: {0}")]
+    Protocol(String),
+
+    /// A packet of `expected` bytes that has bytes left once its content has been read, e.g. fields
+    /// added by a more recent server. Only returned in strict mode.
+    #[error("Packet of {expected} bytes has trailing bytes after {consumed} bytes")]
+    TrailingBytes { expected: usize, consumed: usize },
+
+    #[error("Server refused the session")]
+    SessionRefused,
+
+    #[error("Unsupported: {0}")]
+    Unsupported(String),
+
+    /// Nodes that kept changing while being read
+    #[error("Inconsistent read: {0}")]
+    Inconsistent(String),
+
+    #[cfg(feature = "tls")]
+    #[error("TLS error: {0}")]
+    Tls(#[from] rustls::Error),
+}
+
+#[cfg(feature = "client")]
+impl ClientError {
+    /// The server error code, if this is a server error.
+    pub fn code(&self) -> Option<ErrorCode> {
+        match self {
+            ClientError::Server(code) => Some(*code),
+            _ => None,
+        }
+    }
+
+    /// Was the connection to the server lost? The request may or may not have been processed.
+    pub fn is_connection_loss(&self) -> bool {
+        matches!(self, ClientError::Io(_) | ClientError::Server(ErrorCode::ConnectionLoss))
+    }
+}
+
+#[cfg(feature = "client")]
+impl From<ErrorCode> for ClientError {
+    fn from(code: ErrorCode) -> Self {
+        ClientError::Server(code)
+    }
+}
+
+/// Errors of the coordination recipes.
+#[cfg(feature = "client")]
+#[derive(Debug, Error)]
+#[non_exhaustive]
+pub enum RecipeError {
+    #[error(transparent)]
+    Client(#[from] ClientError),
+
+    #[error(transparent)]
+    Parse(#[from] ParseError),
+
+    /// The wait for a lease, a barrier or a latch was longer than its timeout
+    #[error("Timed out")]
+    Timeout,
+
+    /// Nodes of a recipe that don't have the expected content, e.g. written by another program
+    #[error("Invalid recipe state: {0}")]
+    InvalidState(String),
+}
+
+#[cfg(feature = "client")]
+impl From<ErrorCode> for RecipeError {
+    fn from(code: ErrorCode) -> Self {
+        RecipeError::Client(code.into())
+    }
+}
+
+/// Errors of backup repositories.
+#[cfg(feature = "backup")]
+#[derive(Debug, Error)]
+#[non_exhaustive]
+pub enum BackupError {
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+
+    #[error(transparent)]
+    Persistence(#[from] PersistenceError),
+
+    #[error("Invalid manifest: {0}")]
+    Json(#[from] serde_json::Error),
+
+    #[error("Unsupported backup manifest version {version} (latest supported is {latest})")]
+    ManifestVersion { version: u32, latest: u32 },
+
+    /// A manifest that can't be read, with the reason as the source
+    #[error("Invalid manifest {}: {source}", path.display())]
+    InvalidManifest {
+        path: std::path::PathBuf,
+        source: Box<BackupError>,
+    },
+
+    /// Data keys that can't be wrapped, unwrapped or used
+    #[error("Encryption error: {0}")]
+    Encryption(String),
+
+    #[error("Backup {0} is encrypted")]
+    Encrypted(String),
+
+    #[error("Backup {0} already exists")]
+    AlreadyExists(String),
+
+    /// A snapshot, backup or restore point that doesn't exist
+    #[error("{0}")]
+    NotFound(String),
+
+    #[error("No new txns since backup {0}")]
+    NoNewTxns(String),
+
+    /// Txnlogs needed by an incremental backup were deleted
+    #[error("Txnlogs following zxid {:x} have been purged", .0.0)]
+    Purged(Zxid),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::error::Error;
+
+    #[test]
+    fn error_sources() {
+        let err = crate::path::PathMatcher::regex("(").unwrap_err();
+        assert!(matches!(err, ParseError::Regex(_)));
+        assert!(err.source().is_some());
+
+        let err = crate::tenant::Tenant::new("t", "a").unwrap_err();
+        assert_eq!(err.to_string(), "Invalid tenant root: a");
+        assert!(err.source().is_none());
+
+        #[cfg(feature = "persistence")]
+        {
+            let err = PersistenceError::from(CodecError::Eof);
+            assert_eq!(err.to_string(), "Encoding error: unexpected end of input");
+            assert_eq!(err.source().unwrap().to_string(), "unexpected end of input");
+        }
+    }
+}
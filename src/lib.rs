@@ -4,21 +4,84 @@ extern crate strum_macros;
 #[macro_use]
 extern crate num_derive;
 
-#[macro_use]
-extern crate failure;
-
 pub mod proto;
 pub mod serde;
+#[cfg(feature = "persistence")]
 pub mod persistence;
-
+#[cfg(feature = "client")]
+pub mod client;
+#[cfg(feature = "client")]
+pub mod recipes;
+pub mod path;
+pub mod acl;
+pub mod auth;
+pub mod tenant;
+#[cfg(feature = "backup")]
+pub mod backup;
+pub mod clock;
+pub mod error;
+
+use named_type_derive::NamedType;
 use serde_derive::Deserialize;
 use serde_derive::Serialize;
 
+/// A ZooKeeper release line. Used to know which operations and persistence formats are supported
+/// by a server.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum ServerVersion {
+    V3_4,
+    V3_5,
+    V3_6,
+    V3_7,
+}
+
+impl ServerVersion {
+    /// The most recent release line known to this crate
+    pub const LATEST: ServerVersion = ServerVersion::V3_7;
+
+    /// Parse a version string such as `3.5.5-390fe37ea45dee01bf87dc1c042b5e3dcce88653`. Releases
+    /// more recent than `LATEST` are considered as `LATEST`.
+    pub fn parse(version: &str) -> Option<ServerVersion> {
+        let mut parts = version.trim().split(&['.', '-'][..]);
+        let major = parts.next()?.parse::<u32>().ok()?;
+        let minor = parts.next()?.parse::<u32>().ok()?;
+
+        match (major, minor) {
+            (3, 4) => Some(ServerVersion::V3_4),
+            (3, 5) => Some(ServerVersion::V3_5),
+            (3, 6) => Some(ServerVersion::V3_6),
+            (3, m) if m > 6 => Some(Self::LATEST),
+            (m, _) if m > 3 => Some(Self::LATEST),
+            _ => None,
+        }
+    }
+
+    /// Find the server version in the output of the `srvr` four letter word.
+    pub fn from_srvr_output(output: &str) -> Option<ServerVersion> {
+        let version = output
+            .lines()
+            .find_map(|l| l.trim().strip_prefix("Zookeeper version:"))?;
+        Self::parse(version)
+    }
+}
+
 /// ZooKeeper transaction id
 #[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
 #[derive(Serialize, Deserialize)]
 pub struct Zxid(pub i64);
 
+impl Zxid {
+    /// Epoch of the leader that assigned this zxid, i.e. its high 32 bits
+    pub fn epoch(&self) -> i64 {
+        self.0 >> 32
+    }
+
+    /// Counter of this zxid in its epoch, i.e. its low 32 bits
+    pub fn counter(&self) -> i64 {
+        self.0 & 0xFFFF_FFFF
+    }
+}
+
 #[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
 #[derive(Serialize, Deserialize)]
 pub struct Timestamp(pub u64);
@@ -36,7 +99,7 @@ pub const ANY_VERSION: Version = Version(-1);
 #[derive(Serialize, Deserialize)]
 pub struct OptionalVersion(pub i32);
 
-#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
 #[derive(Serialize, Deserialize)]
 pub struct SessionId(pub i64);
 
@@ -44,7 +107,7 @@ pub struct SessionId(pub i64);
 ///
 /// It starts at 1, but can be negative for server-generated notifications (see
 /// `FinalRequestProcessor` in ZK server)
-#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
 #[derive(Serialize, Deserialize)]
 pub struct Xid(pub i32);
 
@@ -77,6 +140,9 @@ pub const PERM_ALL: Perms = Perms(PERM_READ.0 | PERM_WRITE.0 | PERM_CREATE.0 | P
 // See CreateMode.java
 #[derive(Debug)]
 #[derive(Serialize, Deserialize)]
+#[derive(ToPrimitive)]
+#[derive(IntoStaticStr, EnumIter)]
+#[derive(NamedType)]
 pub enum CreateMode {
     Persistent = 0,
     Ephemeral = 1,
@@ -87,6 +153,9 @@ pub enum CreateMode {
     PersistentSequentialWithTTL = 6,
 }
 
+/// Maximum time to live of a TTL node, in milliseconds (see `EphemeralType.java`)
+pub const MAX_TTL: i64 = 0x0000_00FF_FFFF_FFFF;
+
 use CreateMode::*;
 impl CreateMode {
     pub fn is_ephemeral(&self) -> bool {
@@ -120,14 +189,28 @@ impl CreateMode {
 
 //----- Data
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 #[derive(Serialize, Deserialize)]
 pub struct Id {
     pub scheme: String,
     pub id: String,
 }
 
-#[derive(Debug)]
+impl Id {
+    pub fn new(scheme: &str, id: &str) -> Id {
+        Id {
+            scheme: scheme.to_owned(),
+            id: id.to_owned(),
+        }
+    }
+
+    /// The `world:anyone` identity, i.e. everybody
+    pub fn anyone() -> Id {
+        Id::new("world", "anyone")
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 #[derive(Serialize, Deserialize)]
 pub struct ACL {
     pub perms: Perms,
@@ -135,7 +218,7 @@ pub struct ACL {
 }
 
 /// Information shared with the client
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 #[derive(Serialize, Deserialize)]
 pub struct Stat {
     /// Created zxid
@@ -189,4 +272,29 @@ pub mod test {
 
         let _v = OpCode::iter().map(|v| (v, 0)).collect::<Vec<_>>();
     }
+
+    #[test]
+    pub fn test_server_version() {
+        use super::proto::OpCode;
+        use super::ServerVersion;
+
+        assert_eq!(ServerVersion::parse("3.4.14-4c25d480e66aadd371de8bd2fd8da255ac140bcf"), Some(ServerVersion::V3_4));
+        assert_eq!(ServerVersion::parse("3.5.5"), Some(ServerVersion::V3_5));
+        assert_eq!(ServerVersion::parse("3.6.3"), Some(ServerVersion::V3_6));
+        assert_eq!(ServerVersion::parse("3.9.1"), Some(ServerVersion::LATEST));
+        assert_eq!(ServerVersion::parse("3.3.6"), None);
+        assert_eq!(ServerVersion::parse("foo"), None);
+
+        let srvr = "Zookeeper version: 3.5.5-390fe37ea45dee01bf87dc1c042b5e3dcce88653, built on 05/03/2019 12:07 GMT\n\
+                    Mode: standalone\n";
+        assert_eq!(ServerVersion::from_srvr_output(srvr), Some(ServerVersion::V3_5));
+
+        assert!(OpCode::Create.is_supported_by(ServerVersion::V3_4));
+        assert!(!OpCode::CreateContainer.is_supported_by(ServerVersion::V3_4));
+        assert!(OpCode::CreateContainer.is_supported_by(ServerVersion::V3_5));
+        assert_eq!(
+            OpCode::SetWatches2.check_supported_by(ServerVersion::V3_5),
+            Err(super::proto::ErrorCode::Unimplemented)
+        );
+    }
 }
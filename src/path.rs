@@ -0,0 +1,370 @@
+//! Node paths, and path matching used to select nodes or transactions by path.
+//!
+//! `ZkPath` is a path that follows the rules of the ZooKeeper server. Matchers are compiled once
+//! and can then be used in hot loops over snapshot nodes or txnlogs.
+
+use std::convert::TryFrom;
+use std::fmt;
+use std::str::FromStr;
+
+use regex::Regex;
+use serde_derive::{Deserialize, Serialize};
+
+use crate::error::ParseError;
+
+//----- Node paths
+
+/// A valid node path.
+///
+/// Request structs keep their paths as `String`, so that data read from the wire or from txnlogs
+/// is never rejected. A `ZkPath` converts into a `String` to build requests.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[derive(Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct ZkPath(String);
+
+impl ZkPath {
+    pub fn new(path: &str) -> Result<ZkPath, ParseError> {
+        validate_path(path, false)?;
+        Ok(ZkPath(path.to_owned()))
+    }
+
+    pub fn root() -> ZkPath {
+        ZkPath("/".to_owned())
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    pub fn is_root(&self) -> bool {
+        self.0 == "/"
+    }
+
+    /// The parent path, or `None` for the root.
+    pub fn parent(&self) -> Option<ZkPath> {
+        match self.0.rfind('/')? {
+            _ if self.is_root() => None,
+            0 => Some(ZkPath::root()),
+            idx => Some(ZkPath(self.0[..idx].to_owned())),
+        }
+    }
+
+    /// The last segment of the path, which is empty for the root.
+    pub fn basename(&self) -> &str {
+        self.0.rfind('/').map_or("", |idx| &self.0[idx + 1..])
+    }
+
+    /// The path of a child named `name`.
+    pub fn child(&self, name: &str) -> Result<ZkPath, ParseError> {
+        if name.contains('/') {
+            return Err(ParseError::invalid("node name", name));
+        }
+        let path = if self.is_root() {
+            format!("/{}", name)
+        } else {
+            format!("{}/{}", self.0, name)
+        };
+        validate_path(&path, false)?;
+        Ok(ZkPath(path))
+    }
+
+    pub fn into_string(self) -> String {
+        self.0
+    }
+}
+
+impl fmt::Display for ZkPath {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl AsRef<str> for ZkPath {
+    fn as_ref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl FromStr for ZkPath {
+    type Err = ParseError;
+
+    fn from_str(path: &str) -> Result<ZkPath, ParseError> {
+        ZkPath::new(path)
+    }
+}
+
+impl TryFrom<&str> for ZkPath {
+    type Error = ParseError;
+
+    fn try_from(path: &str) -> Result<ZkPath, ParseError> {
+        ZkPath::new(path)
+    }
+}
+
+impl TryFrom<String> for ZkPath {
+    type Error = ParseError;
+
+    fn try_from(path: String) -> Result<ZkPath, ParseError> {
+        validate_path(&path, false)?;
+        Ok(ZkPath(path))
+    }
+}
+
+impl From<ZkPath> for String {
+    fn from(path: ZkPath) -> String {
+        path.0
+    }
+}
+
+/// Check a path with the rules of `PathUtils.validatePath` in the Java client and server. Paths
+/// of sequential nodes are checked with a sequence number appended, so they can end with '/'.
+pub fn validate_path(path: &str, sequential: bool) -> Result<(), ParseError> {
+    let invalid = |reason: &'static str| Err(ParseError::invalid(reason, path));
+
+    if !path.starts_with('/') {
+        return invalid("path, not starting with '/'");
+    }
+    if path.len() == 1 {
+        return Ok(());
+    }
+    if path.ends_with('/') && !sequential {
+        return invalid("path, ending with '/'");
+    }
+
+    // A sequential path ending with '/' has its last name made of the sequence number
+    let names = path[1..].strip_suffix('/').filter(|_| sequential).unwrap_or(&path[1..]);
+    for name in names.split('/') {
+        match name {
+            "" => return invalid("path, with an empty node name"),
+            "." | ".." => return invalid("path, relative"),
+            _ => {}
+        }
+    }
+
+    // Java strings are UTF-16: characters outside of the BMP are surrogates, which are forbidden
+    let forbidden =
+        |c: char| matches!(c, '\u{0}'..='\u{1f}' | '\u{7f}'..='\u{9f}' | '\u{e000}'..='\u{f8ff}' | '\u{fff0}'..);
+    if path.chars().any(forbidden) {
+        return invalid("path, with a forbidden character");
+    }
+
+    Ok(())
+}
+
+//----- Path matchers
+
+/// Matches ZooKeeper node paths.
+#[derive(Debug, Clone)]
+pub enum PathMatcher {
+    /// A single path
+    Exact(String),
+    /// A path and all its descendants
+    Prefix(String),
+    /// A glob pattern (see `Glob`)
+    Glob(Glob),
+    /// A regular expression, matching the whole path
+    Regex(Regex),
+}
+
+impl PathMatcher {
+    pub fn exact(path: &str) -> PathMatcher {
+        PathMatcher::Exact(path.to_owned())
+    }
+
+    pub fn prefix(path: &str) -> PathMatcher {
+        PathMatcher::Prefix(path.trim_end_matches('/').to_owned())
+    }
+
+    pub fn glob(pattern: &str) -> Result<PathMatcher, ParseError> {
+        Ok(PathMatcher::Glob(Glob::new(pattern)?))
+    }
+
+    pub fn regex(regex: &str) -> Result<PathMatcher, ParseError> {
+        // Anchor the regex so that it matches the whole path
+        let regex = Regex::new(&format!("^(?:{})$", regex))?;
+        Ok(PathMatcher::Regex(regex))
+    }
+
+    /// Parse a matcher expression: `re:<regex>` is a regex, paths ending with `/**` are prefixes,
+    /// paths containing `*` or `?` are globs, and others are exact paths.
+    pub fn parse(expr: &str) -> Result<PathMatcher, ParseError> {
+        if let Some(regex) = expr.strip_prefix("re:") {
+            Self::regex(regex)
+        } else if let Some(prefix) = expr.strip_suffix("/**").filter(|p| !p.contains(&['*', '?'][..])) {
+            Ok(Self::prefix(prefix))
+        } else if expr.contains(&['*', '?'][..]) {
+            Self::glob(expr)
+        } else {
+            Ok(Self::exact(expr))
+        }
+    }
+
+    pub fn matches(&self, path: &str) -> bool {
+        match self {
+            PathMatcher::Exact(p) => path == p,
+            PathMatcher::Prefix(p) => match path.strip_prefix(p.as_str()) {
+                Some(rest) => rest.is_empty() || rest.starts_with('/') || p.is_empty(),
+                None => false,
+            },
+            PathMatcher::Glob(g) => g.matches(path),
+            PathMatcher::Regex(r) => r.is_match(path),
+        }
+    }
+}
+
+/// A glob pattern on paths. It matches whole path segments: `*` matches any part of a segment,
+/// `?` any character and `**` any number of segments (including none).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Glob {
+    segments: Vec<Segment>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Segment {
+    /// `**`
+    Any,
+    /// A segment without wildcards
+    Literal(String),
+    /// A segment with `*` or `?` wildcards
+    Pattern(String),
+}
+
+impl Glob {
+    pub fn new(pattern: &str) -> Result<Glob, ParseError> {
+        if !pattern.starts_with('/') {
+            return Err(ParseError::invalid("path pattern, not starting with '/'", pattern));
+        }
+
+        let segments = pattern
+            .split('/')
+            .filter(|s| !s.is_empty())
+            .map(|s| {
+                if s == "**" {
+                    Segment::Any
+                } else if s.contains(&['*', '?'][..]) {
+                    Segment::Pattern(s.to_owned())
+                } else {
+                    Segment::Literal(s.to_owned())
+                }
+            })
+            .collect();
+
+        Ok(Glob { segments })
+    }
+
+    pub fn matches(&self, path: &str) -> bool {
+        let segments = path.split('/').filter(|s| !s.is_empty()).collect::<Vec<_>>();
+        match_segments(&self.segments, &segments)
+    }
+}
+
+fn match_segments(pattern: &[Segment], path: &[&str]) -> bool {
+    match (pattern.split_first(), path.split_first()) {
+        (None, _) => path.is_empty(),
+        (Some((Segment::Any, rest)), _) => (0..=path.len()).any(|i| match_segments(rest, &path[i..])),
+        (Some((Segment::Literal(l), rest)), Some((first, path_rest))) => l == first && match_segments(rest, path_rest),
+        (Some((Segment::Pattern(p), rest)), Some((first, path_rest))) => {
+            match_glob(p.as_bytes(), first.as_bytes()) && match_segments(rest, path_rest)
+        }
+        (Some(_), None) => false,
+    }
+}
+
+/// Match a single segment against a glob with `*` and `?` wildcards.
+fn match_glob(glob: &[u8], text: &[u8]) -> bool {
+    match glob.split_first() {
+        None => text.is_empty(),
+        Some((b'*', rest)) => (0..=text.len()).any(|i| match_glob(rest, &text[i..])),
+        Some((b'?', rest)) => !text.is_empty() && match_glob(rest, &text[1..]),
+        Some((c, rest)) => text.first() == Some(c) && match_glob(rest, &text[1..]),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matchers() {
+        let m = PathMatcher::exact("/a/b");
+        assert!(m.matches("/a/b"));
+        assert!(!m.matches("/a/b/c"));
+
+        let m = PathMatcher::prefix("/a/b/");
+        assert!(m.matches("/a/b"));
+        assert!(m.matches("/a/b/c"));
+        assert!(!m.matches("/a/bc"));
+        assert!(PathMatcher::prefix("/").matches("/a"));
+
+        let m = PathMatcher::regex("/a/[0-9]+").unwrap();
+        assert!(m.matches("/a/12"));
+        assert!(!m.matches("/a/12/b"));
+        assert!(PathMatcher::regex("(").is_err());
+    }
+
+    #[test]
+    fn globs() {
+        let g = Glob::new("/services/**").unwrap();
+        assert!(g.matches("/services"));
+        assert!(g.matches("/services/a/b"));
+        assert!(!g.matches("/other/a"));
+
+        let g = Glob::new("/services/*/config").unwrap();
+        assert!(g.matches("/services/foo/config"));
+        assert!(!g.matches("/services/foo/bar/config"));
+
+        let g = Glob::new("/**/lock-?").unwrap();
+        assert!(g.matches("/a/b/lock-1"));
+        assert!(g.matches("/lock-2"));
+        assert!(!g.matches("/a/lock-10"));
+
+        assert!(Glob::new("a").is_err());
+    }
+
+    #[test]
+    fn zk_paths() {
+        let path = ZkPath::new("/app/config").unwrap();
+        assert_eq!(path.basename(), "config");
+        assert_eq!(path.parent().unwrap().as_str(), "/app");
+        assert_eq!(path.parent().unwrap().parent(), Some(ZkPath::root()));
+        assert_eq!(ZkPath::root().parent(), None);
+        assert_eq!(
+            ZkPath::root().child("app").unwrap().child("x").unwrap().as_str(),
+            "/app/x"
+        );
+        assert!(path.child("a/b").is_err());
+        assert!(path.child("..").is_err());
+        assert_eq!(String::from(path), "/app/config");
+
+        for invalid in &[
+            "",
+            "app",
+            "/app/",
+            "//app",
+            "/app//x",
+            "/.",
+            "/a/../b",
+            "/a\u{0}",
+            "/a\u{e000}",
+            "/😀",
+        ] {
+            assert!(ZkPath::new(invalid).is_err(), "{:?}", invalid);
+        }
+        for valid in &["/", "/.a", "/a..", "/zookeeper/quota", "/é"] {
+            assert!(ZkPath::new(valid).is_ok(), "{:?}", valid);
+        }
+        assert!(validate_path("/lock-", true).is_ok());
+        assert!(validate_path("/locks/", true).is_ok());
+        assert!(validate_path("/locks//", true).is_err());
+        assert!(ZkPath::try_from("/a/".to_owned()).is_err());
+    }
+
+    #[test]
+    fn parse() {
+        assert!(matches!(PathMatcher::parse("/a").unwrap(), PathMatcher::Exact(_)));
+        assert!(matches!(PathMatcher::parse("/a/**").unwrap(), PathMatcher::Prefix(_)));
+        assert!(matches!(PathMatcher::parse("/*/**").unwrap(), PathMatcher::Glob(_)));
+        assert!(matches!(PathMatcher::parse("re:/a.*").unwrap(), PathMatcher::Regex(_)));
+    }
+}
@@ -0,0 +1,186 @@
+//! Allocation audit: counts heap allocations while decoding records, to find the record types that
+//! allocate the most and guard against regressions.
+//!
+//! Counting requires `CountingAllocator` to be the global allocator of the program:
+//!
+//! ```ignore
+//! #[global_allocator]
+//! static ALLOC: zookeepers::persistence::alloc_audit::CountingAllocator =
+//!     zookeepers::persistence::alloc_audit::CountingAllocator;
+//! ```
+//!
+//! Allocations are counted per thread, so that measures aren't disturbed by other threads.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::path::Path;
+
+use super::txnlog::TxnlogFile;
+use crate::error::PersistenceError;
+
+/// A global allocator that counts the allocations of each thread, and delegates to `System`.
+pub struct CountingAllocator;
+
+thread_local! {
+    static COUNTS: Cell<AllocStats> = const { Cell::new(AllocStats { allocations: 0, bytes: 0 }) };
+}
+
+fn record(size: usize) {
+    // Fails during thread teardown
+    let _ = COUNTS.try_with(|counts| {
+        let mut stats = counts.get();
+        stats.allocations += 1;
+        stats.bytes += size as u64;
+        counts.set(stats);
+    });
+}
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        record(layout.size());
+        System.alloc(layout)
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        record(layout.size());
+        System.alloc_zeroed(layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        record(new_size);
+        System.realloc(ptr, layout, new_size)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+/// Allocations counted by `CountingAllocator`. Reallocations count as allocations of their new size.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct AllocStats {
+    pub allocations: u64,
+    pub bytes: u64,
+}
+
+impl std::ops::Sub for AllocStats {
+    type Output = AllocStats;
+
+    fn sub(self, other: AllocStats) -> AllocStats {
+        AllocStats {
+            allocations: self.allocations - other.allocations,
+            bytes: self.bytes - other.bytes,
+        }
+    }
+}
+
+/// Allocations of the current thread since it started. Always zero if `CountingAllocator` isn't
+/// the global allocator.
+pub fn current() -> AllocStats {
+    COUNTS.with(Cell::get)
+}
+
+/// Count the allocations of a function on the current thread.
+pub fn measure<T>(f: impl FnOnce() -> T) -> (T, AllocStats) {
+    let before = current();
+    let result = f();
+    (result, current() - before)
+}
+
+/// Allocations of a type of record.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RecordAllocs {
+    pub kind: &'static str,
+    pub records: u64,
+    pub allocs: AllocStats,
+}
+
+impl RecordAllocs {
+    pub fn per_record(&self) -> f64 {
+        self.allocs.allocations as f64 / self.records as f64
+    }
+}
+
+/// Decode all transactions of a txnlog file, and return allocations per operation type, the
+/// most allocating per record first.
+pub fn audit_txnlog(path: impl AsRef<Path>) -> Result<Vec<RecordAllocs>, PersistenceError> {
+    let mut txns = TxnlogFile::new(path)?;
+    let mut kinds: HashMap<&'static str, RecordAllocs> = HashMap::new();
+
+    loop {
+        let (txn, allocs) = measure(|| txns.next());
+        let txn = match txn {
+            None => break,
+            Some(txn) => txn?,
+        };
+
+        let kind = <&'static str>::from(&txn.op);
+        let entry = kinds.entry(kind).or_insert(RecordAllocs {
+            kind,
+            records: 0,
+            allocs: AllocStats::default(),
+        });
+        entry.records += 1;
+        entry.allocs.allocations += allocs.allocations;
+        entry.allocs.bytes += allocs.bytes;
+    }
+
+    let mut result = kinds.into_values().collect::<Vec<_>>();
+    result.sort_by(|a, b| {
+        b.per_record()
+            .partial_cmp(&a.per_record())
+            .unwrap()
+            .then(a.kind.cmp(b.kind))
+    });
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::persistence::testing::*;
+
+    #[global_allocator]
+    static ALLOC: CountingAllocator = CountingAllocator;
+
+    #[test]
+    fn txnlog_allocations() {
+        let dir = temp_dir("alloc-audit");
+        let log = write_txnlog(
+            &dir,
+            1,
+            &[
+                txn_body(1, 10, 1, &create_op("/app", "foo", false)),
+                txn_body(2, 10, 5, &path_op("/app", Some("bar"))),
+                txn_body(3, 10, -11, &[]),
+                txn_body(4, 11, -11, &[]),
+            ],
+        );
+
+        let (_, allocs) = measure(|| vec![0u8; 100]);
+        assert_eq!(
+            allocs,
+            AllocStats {
+                allocations: 1,
+                bytes: 100
+            }
+        );
+
+        let audit = audit_txnlog(&log).unwrap();
+        assert_eq!(audit.len(), 3);
+        assert_eq!(audit.iter().map(|r| r.records).sum::<u64>(), 4);
+
+        // Regression guards: a create has a path, data and ACL, a close session has no payload
+        let allocs = |kind| audit.iter().find(|r| r.kind == kind).unwrap().per_record();
+        assert!(allocs("Create") > allocs("CloseSession"));
+        assert!(
+            allocs("CloseSession") <= 2.0,
+            "CloseSession: {}",
+            allocs("CloseSession")
+        );
+        assert!(allocs("Create") <= 6.0, "Create: {}", allocs("Create"));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,602 @@
+//! Analysis of transaction logs.
+
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::path::Path;
+
+use super::txnlog::{CreateTxn, MultiTxnOperation, Txn, TxnOperation, TxnlogFile};
+use crate::error::PersistenceError;
+use crate::path::PathMatcher;
+use crate::SessionId;
+use crate::Timestamp;
+use crate::Zxid;
+
+//----- Path history
+
+/// Find all transactions that touched paths matched by `matcher` in the txnlogs of a directory,
+/// oldest first: creations, data and ACL changes, deletions, and multi transactions containing
+/// any of them.
+///
+/// Ephemeral nodes are deleted when their session closes, without a transaction containing their
+/// path: close session transactions of sessions owning matching ephemeral nodes are therefore
+/// also returned. This is only possible for ephemeral nodes created in the logs that are read.
+pub fn history(
+    dir: impl AsRef<Path>,
+    matcher: PathMatcher,
+) -> Result<History<impl Iterator<Item = Result<Txn, PersistenceError>>>, PersistenceError> {
+    let files = TxnlogFile::txnlog_paths(dir)?
+        .into_iter()
+        .map(TxnlogFile::new)
+        .collect::<Result<Vec<_>, _>>()?;
+
+    Ok(History::new(files.into_iter().flatten(), matcher))
+}
+
+/// Iterator on the transactions that touched some paths. See `history`.
+pub struct History<I> {
+    txns: I,
+    matcher: PathMatcher,
+    /// Owners of live ephemeral nodes that match
+    ephemerals: HashMap<String, SessionId>,
+}
+
+impl<I: Iterator<Item = Result<Txn, PersistenceError>>> History<I> {
+    pub fn new(txns: I, matcher: PathMatcher) -> History<I> {
+        History {
+            txns,
+            matcher,
+            ephemerals: HashMap::new(),
+        }
+    }
+
+    fn touches(&mut self, txn: &Txn) -> bool {
+        let session = txn.header.client_id;
+
+        if let TxnOperation::CloseSession = txn.op {
+            let count = self.ephemerals.len();
+            self.ephemerals.retain(|_, owner| *owner != session);
+            return self.ephemerals.len() != count;
+        }
+
+        if !txn.op.matches(&self.matcher) {
+            return false;
+        }
+
+        // Track ephemeral nodes
+        match &txn.op {
+            TxnOperation::Create(t) | TxnOperation::Create2(t) => self.track_create(t, session),
+            TxnOperation::Delete(t) => {
+                self.ephemerals.remove(&t.path);
+            }
+            TxnOperation::Multi(multi) => {
+                for op in &multi.txns {
+                    match op {
+                        MultiTxnOperation::Create(t) | MultiTxnOperation::Create2(t) => self.track_create(t, session),
+                        MultiTxnOperation::Delete(t) => {
+                            self.ephemerals.remove(&t.path);
+                        }
+                        _ => {}
+                    }
+                }
+            }
+            _ => {}
+        }
+
+        true
+    }
+
+    fn track_create(&mut self, txn: &CreateTxn, session: SessionId) {
+        if txn.ephemeral && self.matcher.matches(&txn.path) {
+            self.ephemerals.insert(txn.path.clone(), session);
+        }
+    }
+}
+
+impl<I: Iterator<Item = Result<Txn, PersistenceError>>> Iterator for History<I> {
+    type Item = Result<Txn, PersistenceError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            match self.txns.next()? {
+                Err(e) => return Some(Err(e)),
+                Ok(txn) => {
+                    if self.touches(&txn) {
+                        return Some(Ok(txn));
+                    }
+                }
+            }
+        }
+    }
+}
+
+//----- Write heatmap
+
+/// Number of writes on a path prefix during a time interval.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HeatmapCell {
+    /// Start of the time interval
+    pub time: Timestamp,
+    pub prefix: String,
+    pub writes: u64,
+}
+
+/// Aggregates writes by path prefix and time interval. Paths are truncated to their first `depth`
+/// segments, and each path in a multi transaction counts as a write.
+#[derive(Debug, Clone)]
+pub struct Heatmap {
+    depth: usize,
+    interval: u64,
+    cells: BTreeMap<(u64, String), u64>,
+}
+
+impl Heatmap {
+    pub fn new(depth: usize, interval: std::time::Duration) -> Heatmap {
+        Heatmap {
+            depth,
+            interval: (interval.as_millis() as u64).max(1),
+            cells: BTreeMap::new(),
+        }
+    }
+
+    /// Truncate a path to its first `depth` segments
+    pub fn prefix<'a>(&self, path: &'a str) -> &'a str {
+        match path.match_indices('/').nth(self.depth) {
+            Some((idx, _)) => &path[..idx],
+            None => path,
+        }
+    }
+
+    pub fn add(&mut self, txn: &Txn) {
+        let time = txn.header.time.0 / self.interval * self.interval;
+        for path in txn.op.paths() {
+            let prefix = self.prefix(path).to_owned();
+            *self.cells.entry((time, prefix)).or_insert(0) += 1;
+        }
+    }
+
+    /// Cells ordered by time and prefix
+    pub fn cells(&self) -> impl Iterator<Item = HeatmapCell> + '_ {
+        self.cells.iter().map(|((time, prefix), writes)| HeatmapCell {
+            time: Timestamp(*time),
+            prefix: prefix.clone(),
+            writes: *writes,
+        })
+    }
+}
+
+//----- Clock skew
+
+/// Analyze the transaction times of the txnlogs of a directory. See `ClockSkew`.
+pub fn clock_skew(dir: impl AsRef<Path>, threshold: std::time::Duration) -> Result<ClockSkew, PersistenceError> {
+    let mut skew = ClockSkew::new(threshold);
+    for path in TxnlogFile::txnlog_paths(dir)? {
+        for txn in TxnlogFile::new(path)? {
+            skew.add(&txn?);
+        }
+    }
+    Ok(skew)
+}
+
+/// A transaction whose time is suspect compared to the previous transaction.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClockAnomaly {
+    pub zxid: Zxid,
+    pub time: Timestamp,
+    pub previous_zxid: Zxid,
+    pub previous_time: Timestamp,
+    /// Time elapsed since the previous transaction, in milliseconds. Negative if time went
+    /// backwards.
+    pub delta: i64,
+}
+
+impl ClockAnomaly {
+    /// Is this the first transaction of an epoch, i.e. of a new leader?
+    pub fn is_epoch_change(&self) -> bool {
+        self.zxid.epoch() != self.previous_zxid.epoch()
+    }
+}
+
+/// Transaction times of an epoch.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EpochClock {
+    pub epoch: i64,
+    pub first_zxid: Zxid,
+    pub last_zxid: Zxid,
+    pub first_time: Timestamp,
+    pub last_time: Timestamp,
+    pub txns: u64,
+    /// Anomalies in this epoch, including on its first transaction
+    pub anomalies: u64,
+}
+
+impl EpochClock {
+    /// An epoch is suspect if its leader's clock went backwards, or if it was skewed compared
+    /// to the previous leader's.
+    pub fn is_suspect(&self) -> bool {
+        self.anomalies > 0
+    }
+}
+
+/// Detects clock issues from transaction times, which are set by the leader when it receives a
+/// request.
+///
+/// Times should increase with zxids. A transaction whose time is before the previous one's shows
+/// a leader clock that went backwards, or a new leader whose clock is behind the previous
+/// leader's. A new epoch that starts more than `threshold` after the end of the previous one shows
+/// either a long election or a new leader whose clock is ahead. Both skew the expiry of TTL and
+/// container nodes, which is based on these times.
+#[derive(Debug, Clone)]
+pub struct ClockSkew {
+    threshold: i64,
+    last: Option<(Zxid, Timestamp)>,
+    anomalies: Vec<ClockAnomaly>,
+    epochs: Vec<EpochClock>,
+}
+
+impl ClockSkew {
+    pub fn new(threshold: std::time::Duration) -> ClockSkew {
+        ClockSkew {
+            threshold: threshold.as_millis() as i64,
+            last: None,
+            anomalies: Vec::new(),
+            epochs: Vec::new(),
+        }
+    }
+
+    /// Add a transaction. Transactions must be added in zxid order.
+    pub fn add(&mut self, txn: &Txn) {
+        let zxid = txn.header.zxid;
+        let time = txn.header.time;
+
+        let new_epoch = match self.epochs.last() {
+            Some(epoch) => epoch.epoch != zxid.epoch(),
+            None => true,
+        };
+        if new_epoch {
+            self.epochs.push(EpochClock {
+                epoch: zxid.epoch(),
+                first_zxid: zxid,
+                last_zxid: zxid,
+                first_time: time,
+                last_time: time,
+                txns: 0,
+                anomalies: 0,
+            });
+        }
+        let epoch = self.epochs.last_mut().unwrap();
+        epoch.last_zxid = zxid;
+        epoch.last_time = time;
+        epoch.txns += 1;
+
+        if let Some((previous_zxid, previous_time)) = self.last {
+            let delta = time.0 as i64 - previous_time.0 as i64;
+            if delta < 0 || (new_epoch && delta > self.threshold) {
+                epoch.anomalies += 1;
+                self.anomalies.push(ClockAnomaly {
+                    zxid,
+                    time,
+                    previous_zxid,
+                    previous_time,
+                    delta,
+                });
+            }
+        }
+        self.last = Some((zxid, time));
+    }
+
+    /// Anomalies, in zxid order
+    pub fn anomalies(&self) -> &[ClockAnomaly] {
+        &self.anomalies
+    }
+
+    /// All epochs, in zxid order
+    pub fn epochs(&self) -> &[EpochClock] {
+        &self.epochs
+    }
+
+    /// Epochs with anomalies
+    pub fn suspect_epochs(&self) -> impl Iterator<Item = &EpochClock> {
+        self.epochs.iter().filter(|e| e.is_suspect())
+    }
+}
+
+//----- Leadership timeline
+
+/// Reconstruct the epochs of the txnlogs of a directory. See `Timeline`.
+pub fn leadership_timeline(dir: impl AsRef<Path>, window: std::time::Duration) -> Result<Timeline, PersistenceError> {
+    let mut timeline = Timeline::new(window);
+    for path in TxnlogFile::txnlog_paths(dir)? {
+        for txn in TxnlogFile::new(path)? {
+            timeline.add(&txn?);
+        }
+    }
+    Ok(timeline)
+}
+
+/// An epoch, during which a single leader was elected.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Epoch {
+    pub epoch: i64,
+    pub first_zxid: Zxid,
+    pub last_zxid: Zxid,
+    /// Time of the first transaction
+    pub start: Timestamp,
+    /// Time of the last transaction
+    pub end: Timestamp,
+    pub txns: u64,
+    pub sessions_created: u64,
+    pub sessions_closed: u64,
+    /// Sessions created in the window that follows the start of the epoch
+    pub burst_created: u64,
+    /// Sessions closed in the window that follows the start of the epoch
+    pub burst_closed: u64,
+}
+
+/// The change from one epoch to the next, i.e. a leader election.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LeadershipChange {
+    pub from: Epoch,
+    pub to: Epoch,
+}
+
+impl LeadershipChange {
+    /// Time between the last transaction of the previous leader and the first of the new one, in
+    /// milliseconds. It includes the election, and is negative if the clocks of leaders are skewed
+    /// (see `ClockSkew`).
+    pub fn gap(&self) -> i64 {
+        self.to.start.0 as i64 - self.from.end.0 as i64
+    }
+}
+
+/// Reconstructs the timeline of epochs from transactions, to report leadership changes.
+///
+/// Epochs are the high 32 bits of zxids, and a new one starts with each leader election. Clients
+/// reconnect to the new leader after an election: sessions that expired meanwhile are closed,
+/// and clients that gave up create new ones. Session creations and closings in the `window` that
+/// follows the start of an epoch are counted as its burst, to be compared with the rest of the
+/// epoch when looking for the impact of an election.
+#[derive(Debug, Clone)]
+pub struct Timeline {
+    window: u64,
+    epochs: Vec<Epoch>,
+}
+
+impl Timeline {
+    pub fn new(window: std::time::Duration) -> Timeline {
+        Timeline {
+            window: window.as_millis() as u64,
+            epochs: Vec::new(),
+        }
+    }
+
+    /// Add a transaction. Transactions must be added in zxid order.
+    pub fn add(&mut self, txn: &Txn) {
+        let zxid = txn.header.zxid;
+        let time = txn.header.time;
+
+        let new_epoch = match self.epochs.last() {
+            Some(epoch) => epoch.epoch != zxid.epoch(),
+            None => true,
+        };
+        if new_epoch {
+            self.epochs.push(Epoch {
+                epoch: zxid.epoch(),
+                first_zxid: zxid,
+                last_zxid: zxid,
+                start: time,
+                end: time,
+                txns: 0,
+                sessions_created: 0,
+                sessions_closed: 0,
+                burst_created: 0,
+                burst_closed: 0,
+            });
+        }
+        let epoch = self.epochs.last_mut().unwrap();
+        epoch.last_zxid = zxid;
+        epoch.end = time;
+        epoch.txns += 1;
+
+        let in_burst = time.0.saturating_sub(epoch.start.0) <= self.window;
+        match txn.op {
+            TxnOperation::CreateSession(_) => {
+                epoch.sessions_created += 1;
+                epoch.burst_created += in_burst as u64;
+            }
+            TxnOperation::CloseSession => {
+                epoch.sessions_closed += 1;
+                epoch.burst_closed += in_burst as u64;
+            }
+            _ => {}
+        }
+    }
+
+    /// All epochs, in zxid order
+    pub fn epochs(&self) -> &[Epoch] {
+        &self.epochs
+    }
+
+    /// Leadership changes, in zxid order
+    pub fn changes(&self) -> impl Iterator<Item = LeadershipChange> + '_ {
+        self.epochs.windows(2).map(|pair| LeadershipChange {
+            from: pair[0].clone(),
+            to: pair[1].clone(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::persistence::testing::*;
+
+    #[test]
+    fn path_history() {
+        let dir = temp_dir("path-history");
+
+        write_txnlog(
+            &dir,
+            1,
+            &[
+                txn_body(1, 10, 1, &create_op("/app", "", false)),
+                txn_body(2, 10, 1, &create_op("/app/lock", "", true)),
+                txn_body(3, 20, 5, &path_op("/app", Some("x"))),
+                txn_body(4, 20, 1, &create_op("/other", "", true)),
+            ],
+        );
+        write_txnlog(
+            &dir,
+            5,
+            &[
+                txn_body(5, 20, -11, &[]),
+                txn_body(6, 10, -11, &[]),
+                txn_body(7, 30, 2, &path_op("/app", None)),
+            ],
+        );
+
+        let txns = history(&dir, PathMatcher::prefix("/app"))
+            .unwrap()
+            .map(|r| r.unwrap().header.zxid)
+            .collect::<Vec<_>>();
+
+        // Session 20 owned an ephemeral node, but not in /app
+        assert_eq!(txns, vec![Zxid(1), Zxid(2), Zxid(3), Zxid(6), Zxid(7)]);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn write_heatmap() {
+        let dir = temp_dir("write-heatmap");
+
+        write_txnlog(
+            &dir,
+            1,
+            &[
+                txn_body(1, 10, 1, &create_op("/app", "", false)),
+                txn_body(2, 10, 1, &create_op("/app/a", "", true)),
+                txn_body(3, 20, 5, &path_op("/app/a/b", Some("x"))),
+                txn_body(9, 20, 5, &path_op("/other", Some("x"))),
+                txn_body(10, 20, -11, &[]),
+            ],
+        );
+
+        // Txn times are zxid * 1000
+        let mut heatmap = Heatmap::new(1, std::time::Duration::from_secs(5));
+        for txn in TxnlogFile::new(dir.join("log.1")).unwrap() {
+            heatmap.add(&txn.unwrap());
+        }
+
+        let cells = heatmap
+            .cells()
+            .map(|c| (c.time.0, c.prefix, c.writes))
+            .collect::<Vec<_>>();
+        assert_eq!(cells, vec![(0, "/app".to_owned(), 3), (5000, "/other".to_owned(), 1)]);
+
+        assert_eq!(Heatmap::new(0, std::time::Duration::from_secs(1)).prefix("/a/b"), "");
+        assert_eq!(
+            Heatmap::new(2, std::time::Duration::from_secs(1)).prefix("/a/b/c"),
+            "/a/b"
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn clock_anomalies() {
+        let dir = temp_dir("clock-anomalies");
+
+        let txn = |epoch: i64, counter: i64, time: u64| {
+            let mut body = txn_body(epoch << 32 | counter, 10, 5, &path_op("/app", Some("x")));
+            body[20..28].copy_from_slice(&time.to_be_bytes());
+            body
+        };
+        write_txnlog(
+            &dir,
+            1 << 32,
+            &[
+                txn(1, 1, 1000),
+                txn(1, 2, 2000),
+                // Leader clock went backwards
+                txn(1, 3, 1500),
+                // New leader with a clock ahead
+                txn(2, 1, 200_000),
+                txn(2, 2, 201_000),
+                // New leader with a clock behind
+                txn(3, 1, 150_000),
+                txn(3, 2, 151_000),
+                txn(4, 1, 170_000),
+            ],
+        );
+
+        let skew = clock_skew(&dir, std::time::Duration::from_secs(60)).unwrap();
+
+        let anomalies = skew
+            .anomalies()
+            .iter()
+            .map(|a| (a.zxid.epoch(), a.zxid.counter(), a.delta, a.is_epoch_change()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            anomalies,
+            vec![(1, 3, -500, false), (2, 1, 198_500, true), (3, 1, -51_000, true)]
+        );
+
+        assert_eq!(skew.epochs().len(), 4);
+        assert_eq!(skew.epochs()[0].txns, 3);
+        assert_eq!(
+            skew.suspect_epochs().map(|e| e.epoch).collect::<Vec<_>>(),
+            vec![1, 2, 3]
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn leadership_changes() {
+        let dir = temp_dir("leadership-changes");
+
+        let txn = |epoch: i64, counter: i64, op: i32, time: u64| {
+            let payload = if op == -10 { vec![0, 0, 0x75, 0x30] } else { Vec::new() };
+            let mut body = txn_body(epoch << 32 | counter, counter, op, &payload);
+            body[20..28].copy_from_slice(&time.to_be_bytes());
+            body
+        };
+        write_txnlog(
+            &dir,
+            1 << 32,
+            &[txn(1, 1, -10, 1000), txn(1, 2, -10, 2000), txn(1, 3, -11, 60_000)],
+        );
+        write_txnlog(
+            &dir,
+            2 << 32,
+            &[
+                // Expired sessions are closed, and clients reconnect
+                txn(2, 1, -11, 75_000),
+                txn(2, 2, -11, 75_001),
+                txn(2, 3, -10, 76_000),
+                txn(2, 4, -10, 200_000),
+            ],
+        );
+
+        let timeline = leadership_timeline(&dir, std::time::Duration::from_secs(10)).unwrap();
+        let epochs = timeline.epochs();
+        assert_eq!(epochs.len(), 2);
+        assert_eq!(
+            (epochs[0].txns, epochs[0].sessions_created, epochs[0].sessions_closed),
+            (3, 2, 1)
+        );
+        assert_eq!(
+            (epochs[1].first_zxid, epochs[1].last_zxid),
+            (Zxid(2 << 32 | 1), Zxid(2 << 32 | 4))
+        );
+        assert_eq!((epochs[1].burst_created, epochs[1].burst_closed), (1, 2));
+        assert_eq!(epochs[1].sessions_created, 2);
+
+        let changes = timeline.changes().collect::<Vec<_>>();
+        assert_eq!(changes.len(), 1);
+        assert_eq!((changes[0].from.epoch, changes[0].to.epoch), (1, 2));
+        assert_eq!(changes[0].gap(), 15_000);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
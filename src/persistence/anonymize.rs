@@ -0,0 +1,276 @@
+//! Deterministic anonymization of snapshots and transactions, to share data directories in bug
+//! reports.
+//!
+//! Path segments, node data and digest identities are replaced with pseudonyms derived from a
+//! secret key: a given input always has the same pseudonym, so that the tree structure and the
+//! relations between snapshots and transactions are preserved. Sizes, stats, zxids and sessions
+//! are unchanged.
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::collections::HashMap;
+use std::path::Path;
+
+use super::snapshot::{ACLRef, DataNode, InitState, SnapshotFile};
+use super::transform::{rewrite, Transform};
+use super::txnlog::{MultiTxnOperation, Txn, TxnOperation};
+use crate::error::PersistenceError;
+use crate::{Id, ACL};
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Characters of pseudonyms for paths and text data
+const ALPHABET: &[u8] = b"abcdefghijklmnopqrstuvwxyz0123456789";
+
+/// Characters of pseudonyms for password hashes in digest identities
+const BASE64_ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// Path segments that are kept as is, as ZooKeeper itself creates them.
+const RESERVED_SEGMENTS: &[&str] = &["zookeeper", "quota", "config", "zookeeper_limits", "zookeeper_stats"];
+
+/// Length of the counter appended to the name of sequential nodes
+const SEQUENCE_LEN: usize = 10;
+
+/// Rewrites paths, data and digest identities with keyed pseudonyms.
+///
+/// Pseudonyms have the same length as the original values, so short path segments may collide.
+pub struct Anonymizer {
+    key: Vec<u8>,
+}
+
+impl Anonymizer {
+    /// Create an anonymizer with a secret key. The same key must be used for all files of a data
+    /// directory to keep them consistent, and should not be shared with the anonymized data.
+    pub fn new(key: &[u8]) -> Anonymizer {
+        Anonymizer { key: key.to_vec() }
+    }
+
+    /// `len` pseudo-random bytes derived from the key, a domain and an input value.
+    fn keystream(&self, domain: &str, input: &[u8], len: usize) -> Vec<u8> {
+        let mut result = Vec::with_capacity(len);
+        let mut counter = 0u32;
+        while result.len() < len {
+            let mut mac = HmacSha256::new_from_slice(&self.key).expect("HMAC accepts keys of any length");
+            mac.update(domain.as_bytes());
+            mac.update(&[0]);
+            mac.update(&counter.to_be_bytes());
+            mac.update(input);
+            result.extend_from_slice(&mac.finalize().into_bytes());
+            counter += 1;
+        }
+        result.truncate(len);
+        result
+    }
+
+    fn text(&self, domain: &str, input: &[u8], alphabet: &[u8]) -> String {
+        self.keystream(domain, input, input.len())
+            .into_iter()
+            .map(|b| alphabet[b as usize % alphabet.len()] as char)
+            .collect()
+    }
+
+    /// Pseudonym of a path segment. Reserved names are kept, as well as the counter of
+    /// sequential nodes so that their ordering is preserved.
+    pub fn segment(&self, segment: &str) -> String {
+        if RESERVED_SEGMENTS.contains(&segment) {
+            return segment.to_owned();
+        }
+
+        let bytes = segment.as_bytes();
+        if bytes.len() > SEQUENCE_LEN && bytes[bytes.len() - SEQUENCE_LEN..].iter().all(u8::is_ascii_digit) {
+            let (name, sequence) = segment.split_at(segment.len() - SEQUENCE_LEN);
+            return self.text("segment", name.as_bytes(), ALPHABET) + sequence;
+        }
+
+        self.text("segment", bytes, ALPHABET)
+    }
+
+    /// Pseudonym of a path, segment by segment.
+    pub fn path(&self, path: &str) -> String {
+        path.split('/').map(|s| self.segment(s)).collect::<Vec<_>>().join("/")
+    }
+
+    /// Pseudonym of node data. UTF-8 text is replaced with alphanumeric text, other data with
+    /// random bytes.
+    pub fn data(&self, data: &[u8]) -> Vec<u8> {
+        if std::str::from_utf8(data).is_ok() {
+            self.text("data", data, ALPHABET).into_bytes()
+        } else {
+            self.keystream("data", data, data.len())
+        }
+    }
+
+    /// Pseudonym of an identity. Only `digest` identities are changed, as they contain user names
+    /// and password hashes.
+    pub fn id(&self, id: &Id) -> Id {
+        if id.scheme != "digest" {
+            return id.clone();
+        }
+
+        let (user, hash) = match id.id.find(':') {
+            Some(idx) => (&id.id[..idx], &id.id[idx + 1..]),
+            None => (&id.id[..], ""),
+        };
+        let user = self.text("user", user.as_bytes(), ALPHABET);
+        let hash = self.text("password", hash.as_bytes(), BASE64_ALPHABET);
+        Id::new("digest", &format!("{}:{}", user, hash))
+    }
+
+    /// Pseudonyms of the identities of an ACL.
+    pub fn acl(&self, acl: &[ACL]) -> Vec<ACL> {
+        acl.iter()
+            .map(|entry| ACL {
+                perms: entry.perms,
+                id: self.id(&entry.id),
+            })
+            .collect()
+    }
+
+    /// Anonymize a data node. Its ACL is a reference to the ACL cache, anonymized separately.
+    pub fn node(&self, node: &mut DataNode) {
+        node.data = self.data(&node.data);
+    }
+
+    fn rewrite(&self, path: &mut String, data: Option<&mut Vec<u8>>, acl: Option<&mut Vec<ACL>>) {
+        *path = self.path(path);
+        if let Some(data) = data {
+            *data = self.data(data);
+        }
+        if let Some(acl) = acl {
+            *acl = self.acl(acl);
+        }
+    }
+
+    /// Anonymize a transaction. Its digest is removed, since it doesn't match the anonymized tree.
+    pub fn txn(&self, txn: &mut Txn) {
+        use TxnOperation::*;
+        match &mut txn.op {
+            CreateSession(_) | CloseSession | Error(_) => {}
+            Create(t) | Create2(t) => self.rewrite(&mut t.path, Some(&mut t.data), Some(&mut t.acl)),
+            CreateTTL(t) => self.rewrite(&mut t.path, Some(&mut t.data), Some(&mut t.acl)),
+            CreateContainer(t) => self.rewrite(&mut t.path, Some(&mut t.data), Some(&mut t.acl)),
+            Delete(t) | DeleteContainer(t) => self.rewrite(&mut t.path, None, None),
+            Reconfig(t) | SetData(t) => self.rewrite(&mut t.path, Some(&mut t.data), None),
+            SetACL(t) => self.rewrite(&mut t.path, None, Some(&mut t.acl)),
+            Multi(multi) => {
+                for op in &mut multi.txns {
+                    use MultiTxnOperation as M;
+                    match op {
+                        M::Create(t) | M::Create2(t) => self.rewrite(&mut t.path, Some(&mut t.data), Some(&mut t.acl)),
+                        M::CreateTTL(t) => self.rewrite(&mut t.path, Some(&mut t.data), Some(&mut t.acl)),
+                        M::CreateContainer(t) => self.rewrite(&mut t.path, Some(&mut t.data), Some(&mut t.acl)),
+                        M::Delete(t) | M::DeleteContainer(t) => self.rewrite(&mut t.path, None, None),
+                        M::SetData(t) => self.rewrite(&mut t.path, Some(&mut t.data), None),
+                        M::Check(t) => self.rewrite(&mut t.path, None, None),
+                        M::Error(_) => {}
+                    }
+                }
+            }
+        }
+        txn.digest = None;
+    }
+
+    /// Write an anonymized copy of a snapshot. The copy has no digest, since it doesn't match the
+    /// anonymized tree.
+    pub fn snapshot(
+        &self,
+        snapshot: SnapshotFile<InitState>,
+        output: impl AsRef<Path>,
+    ) -> Result<(), PersistenceError> {
+        rewrite(snapshot, self, output)?;
+        Ok(())
+    }
+}
+
+impl Transform for &Anonymizer {
+    fn acls(&mut self, acls: &mut HashMap<ACLRef, Vec<ACL>>) {
+        for acl in acls.values_mut() {
+            *acl = self.acl(acl);
+        }
+    }
+
+    fn node(&mut self, path: String, mut node: DataNode) -> Option<(String, DataNode)> {
+        Anonymizer::node(self, &mut node);
+        Some((self.path(&path), node))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::persistence::testing::*;
+    use crate::PERM_ALL;
+
+    #[test]
+    fn pseudonyms() {
+        let anonymizer = Anonymizer::new(b"secret");
+
+        let path = anonymizer.path("/app/locks/lock-0000000042");
+        assert_ne!(path, "/app/locks/lock-0000000042");
+        assert_eq!(path.len(), "/app/locks/lock-0000000042".len());
+        assert!(path.starts_with(&anonymizer.path("/app/locks")));
+        assert!(path.ends_with("0000000042"));
+        assert_eq!(path, anonymizer.path("/app/locks/lock-0000000042"));
+        assert_ne!(path, Anonymizer::new(b"other").path("/app/locks/lock-0000000042"));
+
+        assert_eq!(anonymizer.path(""), "");
+        assert_eq!(anonymizer.path("/zookeeper/quota"), "/zookeeper/quota");
+
+        let data = anonymizer.data(b"password=foo");
+        assert_eq!(data.len(), 12);
+        assert!(std::str::from_utf8(&data).is_ok());
+        assert_eq!(anonymizer.data(&[0xFF, 0xFE]).len(), 2);
+
+        let id = anonymizer.id(&Id::new("digest", "admin:abc="));
+        assert_eq!(id.scheme, "digest");
+        assert_eq!(id.id.len(), 10);
+        assert_eq!(id.id.find(':'), Some(5));
+        assert!(!id.id.starts_with("admin"));
+        assert_eq!(anonymizer.id(&Id::anyone()), Id::anyone());
+    }
+
+    #[test]
+    fn anonymize_snapshot() {
+        let acl = vec![ACL {
+            perms: PERM_ALL,
+            id: Id::new("digest", "admin:xxx"),
+        }];
+        let input = write_snapshot(
+            "anonymize",
+            &[(1, 10_000)],
+            &[(1, acl.clone())],
+            &[
+                ("", node("", -1, 0, 0)),
+                ("/app", node("secret", 1, 0, 1)),
+                ("/app/a", node("", 1, 1, 2)),
+            ],
+        );
+        let output = input.with_file_name("snapshot.2");
+
+        let anonymizer = Anonymizer::new(b"secret");
+        anonymizer
+            .snapshot(SnapshotFile::new(&input).unwrap(), &output)
+            .unwrap();
+
+        let (sessions, snap) = SnapshotFile::new(&output)
+            .unwrap()
+            .sessions()
+            .unwrap()
+            .session_map()
+            .unwrap();
+        assert_eq!(sessions.len(), 1);
+        let (acls, nodes) = snap.acl_map().unwrap();
+        assert_eq!(acls[&crate::persistence::snapshot::ACLRef(1)], anonymizer.acl(&acl));
+
+        let nodes = nodes.collect::<Result<Vec<_>, _>>().unwrap();
+        let paths = nodes.iter().map(|(p, _)| p.clone()).collect::<Vec<_>>();
+        assert_eq!(
+            paths,
+            vec!["".to_owned(), anonymizer.path("/app"), anonymizer.path("/app/a")]
+        );
+        assert_eq!(nodes[1].1.data, anonymizer.data(b"secret"));
+        assert_eq!(nodes[2].1.stat, node("", 1, 1, 2).stat);
+
+        remove_snapshot(&input);
+    }
+}
@@ -0,0 +1,115 @@
+//! Offline ACL audit of a snapshot.
+//!
+//! Checks all data nodes against an `AclPolicy`, and reports common security issues: nodes that
+//! anybody can write to, nodes that nobody can administer, and the digest identities in use.
+
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+
+use super::snapshot::{ACLRef, InitState, SnapshotFile};
+use crate::acl::{AclDrift, AclPolicy};
+use crate::error::PersistenceError;
+use crate::{Id, ACL, PERM_ADMIN, PERM_ALL, PERM_WRITE};
+
+#[derive(Debug, Default)]
+pub struct AclAuditReport {
+    /// Nodes whose ACLs differ from the policy
+    pub drifts: Vec<AclDrift>,
+    /// Nodes where `world:anyone` has write permission
+    pub world_writable: Vec<String>,
+    /// Nodes where no identity has admin permission
+    pub missing_admin: Vec<String>,
+    /// Users of `digest` identities, with the number of nodes they appear on
+    pub digest_users: BTreeMap<String, usize>,
+}
+
+/// Audit the ACLs of all data nodes in a snapshot.
+pub fn audit_acls(snapshot: SnapshotFile<InitState>, policy: &AclPolicy) -> Result<AclAuditReport, PersistenceError> {
+    let (mut acls, nodes) = snapshot.sessions()?.acl_map()?;
+
+    acls.entry(ACLRef::OPEN_ACL_UNSAFE).or_insert_with(|| {
+        vec![ACL {
+            perms: PERM_ALL,
+            id: Id::anyone(),
+        }]
+    });
+
+    audit_nodes(&acls, nodes, policy)
+}
+
+fn audit_nodes(
+    acls: &HashMap<ACLRef, Vec<ACL>>,
+    nodes: impl Iterator<Item = Result<(String, super::snapshot::DataNode), PersistenceError>>,
+    policy: &AclPolicy,
+) -> Result<AclAuditReport, PersistenceError> {
+    let mut report = AclAuditReport::default();
+
+    for r in nodes {
+        let (path, node) = r?;
+        let acl = acls.get(&node.acl).ok_or_else(|| {
+            PersistenceError::Inconsistent(format!("Unknown ACL reference {} on {}", node.acl.0, path))
+        })?;
+
+        if let Some(drift) = policy.check(&path, acl) {
+            report.drifts.push(drift);
+        }
+
+        let anyone = Id::anyone();
+        if acl.iter().any(|e| e.id == anyone && e.perms.has(PERM_WRITE)) {
+            report.world_writable.push(path.clone());
+        }
+
+        if !acl.iter().any(|e| e.perms.has(PERM_ADMIN)) {
+            report.missing_admin.push(path.clone());
+        }
+
+        for entry in acl.iter().filter(|e| e.id.scheme == "digest") {
+            // Digest ids are "user:base64(sha1(user:password))"
+            let user = entry.id.id.split(':').next().unwrap_or_default();
+            *report.digest_users.entry(user.to_owned()).or_insert(0) += 1;
+        }
+    }
+
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::persistence::testing::*;
+    use crate::PERM_READ;
+
+    #[test]
+    fn audit_snapshot() {
+        let admin = ACL {
+            perms: PERM_ALL,
+            id: Id::new("digest", "admin:xxx"),
+        };
+        let read = ACL {
+            perms: PERM_READ,
+            id: Id::anyone(),
+        };
+
+        let path = write_snapshot(
+            "audit-acls",
+            &[],
+            &[(1, vec![admin.clone()]), (2, vec![read.clone()])],
+            &[
+                ("", node("", -1, 0, 0)),
+                ("/app", node("", 1, 0, 1)),
+                ("/app/public", node("", 2, 0, 2)),
+            ],
+        );
+
+        let policy = AclPolicy::new().rule("/app/**", vec![admin.clone()]).unwrap();
+        let report = audit_acls(SnapshotFile::new(&path).unwrap(), &policy).unwrap();
+
+        assert_eq!(report.drifts.len(), 1);
+        assert_eq!(report.drifts[0].path, "/app/public");
+        assert_eq!(report.world_writable, vec![""]);
+        assert_eq!(report.missing_admin, vec!["/app/public"]);
+        assert_eq!(report.digest_users.get("admin"), Some(&1));
+
+        remove_snapshot(&path);
+    }
+}
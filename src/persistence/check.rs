@@ -0,0 +1,250 @@
+//! Consistency checks on snapshots.
+
+use std::collections::HashMap;
+
+use super::snapshot::{DataNode, InitState, SnapshotFile};
+use crate::error::PersistenceError;
+use crate::Duration;
+use crate::SessionId;
+use crate::Version;
+use crate::Zxid;
+
+/// Result of `check_ephemerals`.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct EphemeralReport {
+    /// Ephemeral nodes owned by a session that isn't in the session table, as (path, owner)
+    pub orphans: Vec<(String, SessionId)>,
+    /// Sessions that own no ephemeral node, with their timeout. This is normal for most clients,
+    /// but sessions that stay in this list across snapshots taken far apart may be leaked by
+    /// their application.
+    pub idle_sessions: Vec<(SessionId, Duration)>,
+    /// Number of ephemeral nodes owned by each session
+    pub owned: HashMap<SessionId, usize>,
+}
+
+/// Check that the owners of ephemeral nodes exist in the session table of a snapshot, and find
+/// sessions that own no ephemeral node.
+pub fn check_ephemerals(snapshot: SnapshotFile<InitState>) -> Result<EphemeralReport, PersistenceError> {
+    let (sessions, acls) = snapshot.sessions()?.session_map()?;
+
+    let mut report = EphemeralReport::default();
+
+    for r in acls.data_nodes()? {
+        let (path, node) = r?;
+        if let Some(owner) = node.stat.ephemeral_info.owner() {
+            if sessions.contains_key(&owner) {
+                *report.owned.entry(owner).or_insert(0) += 1;
+            } else {
+                report.orphans.push((path, owner));
+            }
+        }
+    }
+
+    report.idle_sessions = sessions
+        .into_iter()
+        .filter(|(id, _)| !report.owned.contains_key(id))
+        .collect();
+    report.idle_sessions.sort();
+
+    Ok(report)
+}
+
+//----- Stat invariants
+
+/// A broken invariant on a node's stat.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StatViolation {
+    /// The parent node doesn't exist
+    MissingParent,
+    /// Last modification is before creation
+    ModifiedBeforeCreated { czxid: Zxid, mzxid: Zxid },
+    /// Last children change is before creation, or before the creation of a child
+    PzxidTooLow { pzxid: Zxid, min: Zxid },
+    /// Children version is lower than the number of children (each creation increments it)
+    CVersionTooLow { cversion: Version, children: usize },
+}
+
+struct NodeInfo {
+    czxid: Zxid,
+    mzxid: Zxid,
+    pzxid: Zxid,
+    cversion: Version,
+    children: usize,
+    max_child_czxid: Zxid,
+}
+
+/// Is this one of the nodes ZooKeeper creates at startup, without updating their parent's stat?
+fn is_system_node(path: &str) -> bool {
+    path == "/zookeeper" || path.starts_with("/zookeeper/")
+}
+
+fn parent(path: &str) -> Option<&str> {
+    path.rfind('/').map(|idx| &path[..idx])
+}
+
+/// Check the structural invariants of the stats of a complete tree, such as the data nodes of a
+/// snapshot. Returns violations as (path, violation), sorted by path.
+///
+/// Snapshots are fuzzy (they are written while transactions are applied), so a snapshot on its
+/// own can have transient violations that replaying the following transactions will fix.
+pub fn check_stats<I>(nodes: I) -> Result<Vec<(String, StatViolation)>, PersistenceError>
+where
+    I: IntoIterator<Item = Result<(String, DataNode), PersistenceError>>,
+{
+    let mut tree = HashMap::new();
+    for r in nodes {
+        let (path, node) = r?;
+        let stat = node.stat;
+        tree.insert(
+            path,
+            NodeInfo {
+                czxid: stat.czxid,
+                mzxid: stat.mzxid,
+                pzxid: stat.pzxid,
+                cversion: stat.cversion,
+                children: 0,
+                max_child_czxid: Zxid(i64::MIN),
+            },
+        );
+    }
+
+    let mut violations = Vec::new();
+
+    // Count children
+    let paths = tree.keys().cloned().collect::<Vec<_>>();
+    for path in &paths {
+        let parent = match parent(path) {
+            Some(p) => p,
+            None => continue, // root
+        };
+        let czxid = tree[path].czxid;
+        match tree.get_mut(parent) {
+            None => violations.push((path.clone(), StatViolation::MissingParent)),
+            Some(_) if is_system_node(path) => {}
+            Some(info) => {
+                info.children += 1;
+                info.max_child_czxid = info.max_child_czxid.max(czxid);
+            }
+        }
+    }
+
+    for (path, info) in &tree {
+        if info.mzxid < info.czxid {
+            violations.push((
+                path.clone(),
+                StatViolation::ModifiedBeforeCreated {
+                    czxid: info.czxid,
+                    mzxid: info.mzxid,
+                },
+            ));
+        }
+
+        let min_pzxid = info.czxid.max(info.max_child_czxid);
+        if info.pzxid < min_pzxid {
+            violations.push((
+                path.clone(),
+                StatViolation::PzxidTooLow {
+                    pzxid: info.pzxid,
+                    min: min_pzxid,
+                },
+            ));
+        }
+
+        if (info.cversion.0 as i64) < info.children as i64 {
+            violations.push((
+                path.clone(),
+                StatViolation::CVersionTooLow {
+                    cversion: info.cversion,
+                    children: info.children,
+                },
+            ));
+        }
+    }
+
+    violations.sort_by(|a, b| a.0.cmp(&b.0));
+    Ok(violations)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::persistence::testing::*;
+
+    #[test]
+    fn ephemeral_owners() {
+        let path = write_snapshot(
+            "check-ephemerals",
+            &[(1, 10_000), (2, 20_000)],
+            &[],
+            &[
+                ("", node("", -1, 0, 0)),
+                ("/a", node("", -1, 1, 1)),
+                ("/b", node("", -1, 1, 2)),
+                ("/c", node("", -1, 3, 3)),
+                // Container
+                ("/d", node("", -1, i64::MIN, 4)),
+            ],
+        );
+
+        let report = check_ephemerals(SnapshotFile::new(&path).unwrap()).unwrap();
+
+        assert_eq!(report.orphans, vec![("/c".to_owned(), SessionId(3))]);
+        assert_eq!(report.idle_sessions, vec![(SessionId(2), Duration(20_000))]);
+        assert_eq!(report.owned.get(&SessionId(1)), Some(&2));
+
+        remove_snapshot(&path);
+    }
+
+    #[test]
+    fn stat_invariants() {
+        let mut root = node("", -1, 0, 0);
+        root.stat.cversion = Version(1);
+        root.stat.pzxid = Zxid(1);
+
+        let mut a = node("", -1, 0, 1);
+        a.stat.pzxid = Zxid(2); // child b at 3
+        a.stat.cversion = Version(1); // 2 children
+
+        let mut c = node("", -1, 0, 4);
+        c.stat.mzxid = Zxid(3);
+
+        let nodes = vec![
+            ("".to_owned(), root),
+            ("/zookeeper".to_owned(), node("", -1, 0, 0)),
+            ("/a".to_owned(), a),
+            ("/a/b".to_owned(), node("", -1, 0, 3)),
+            ("/a/c".to_owned(), c),
+            ("/x/y".to_owned(), node("", -1, 0, 5)),
+        ];
+
+        let violations = check_stats(nodes.into_iter().map(Ok)).unwrap();
+
+        assert_eq!(
+            violations,
+            vec![
+                (
+                    "/a".to_owned(),
+                    StatViolation::PzxidTooLow {
+                        pzxid: Zxid(2),
+                        min: Zxid(4)
+                    }
+                ),
+                (
+                    "/a".to_owned(),
+                    StatViolation::CVersionTooLow {
+                        cversion: Version(1),
+                        children: 2
+                    }
+                ),
+                (
+                    "/a/c".to_owned(),
+                    StatViolation::ModifiedBeforeCreated {
+                        czxid: Zxid(4),
+                        mzxid: Zxid(3)
+                    }
+                ),
+                ("/x/y".to_owned(), StatViolation::MissingParent),
+            ]
+        );
+    }
+}
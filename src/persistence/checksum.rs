@@ -0,0 +1,159 @@
+//! Checksums of txnlog records and snapshots.
+//!
+//! ZooKeeper uses Adler-32, but forks may use other algorithms: readers can be configured with
+//! any implementation of `Checksum`, and `detect` finds which known algorithm produced a value.
+
+/// A checksum algorithm.
+pub trait Checksum {
+    /// Name of the algorithm, for reporting
+    fn name(&self) -> &'static str;
+
+    /// Compute the checksum of `bytes`. Values are stored as 64 bits even for 32 bits algorithms.
+    fn compute(&self, bytes: &[u8]) -> u64;
+}
+
+/// Adler-32, used by ZooKeeper (`java.util.zip.Adler32`)
+#[derive(Debug, Copy, Clone, Default)]
+pub struct Adler32;
+
+impl Checksum for Adler32 {
+    fn name(&self) -> &'static str {
+        "Adler-32"
+    }
+
+    fn compute(&self, bytes: &[u8]) -> u64 {
+        Self::update(1, bytes)
+    }
+}
+
+impl Adler32 {
+    /// Update a checksum with more bytes, to compute the checksum of a stream. The initial value
+    /// is 1.
+    pub fn update(value: u64, bytes: &[u8]) -> u64 {
+        const MOD: u32 = 65521;
+        // Largest number of bytes that can be summed before b overflows
+        const CHUNK: usize = 5552;
+
+        let mut a = value as u32 & 0xFFFF;
+        let mut b = (value as u32) >> 16;
+        for chunk in bytes.chunks(CHUNK) {
+            for &byte in chunk {
+                a += u32::from(byte);
+                b += a;
+            }
+            a %= MOD;
+            b %= MOD;
+        }
+
+        u64::from((b << 16) | a)
+    }
+}
+
+/// Lookup table of a reflected CRC-32 polynomial
+const fn crc32_table(poly: u32) -> [u32; 256] {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut j = 0;
+        while j < 8 {
+            crc = if crc & 1 == 1 { (crc >> 1) ^ poly } else { crc >> 1 };
+            j += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+/// Update a CRC-32 `value` (zero initially) with more bytes.
+fn crc32_update(table: &[u32; 256], value: u64, bytes: &[u8]) -> u64 {
+    let crc = bytes.iter().fold(!(value as u32), |crc, &byte| {
+        table[((crc ^ u32::from(byte)) & 0xFF) as usize] ^ (crc >> 8)
+    });
+    u64::from(!crc)
+}
+
+/// CRC-32, as in `java.util.zip.CRC32`. ZooKeeper uses it for data tree digests.
+#[derive(Debug, Copy, Clone, Default)]
+pub struct Crc32;
+
+impl Crc32 {
+    const TABLE: [u32; 256] = crc32_table(0xEDB8_8320);
+
+    /// Update a checksum with more bytes, to compute the checksum of a stream. The initial value
+    /// is 0.
+    pub fn update(value: u64, bytes: &[u8]) -> u64 {
+        crc32_update(&Self::TABLE, value, bytes)
+    }
+}
+
+impl Checksum for Crc32 {
+    fn name(&self) -> &'static str {
+        "CRC-32"
+    }
+
+    fn compute(&self, bytes: &[u8]) -> u64 {
+        Self::update(0, bytes)
+    }
+}
+
+/// CRC-32C (Castagnoli), as in `java.util.zip.CRC32C`
+#[derive(Debug, Copy, Clone, Default)]
+pub struct Crc32c;
+
+impl Crc32c {
+    const TABLE: [u32; 256] = crc32_table(0x82F6_3B78);
+}
+
+impl Checksum for Crc32c {
+    fn name(&self) -> &'static str {
+        "CRC-32C"
+    }
+
+    fn compute(&self, bytes: &[u8]) -> u64 {
+        crc32_update(&Self::TABLE, 0, bytes)
+    }
+}
+
+/// All known algorithms, ZooKeeper's default first
+pub const ALGORITHMS: &[&(dyn Checksum + Sync)] = &[&Adler32, &Crc32c, &Crc32];
+
+/// Find the algorithm that computes `expected` for `bytes`, if any.
+pub fn detect(bytes: &[u8], expected: u64) -> Option<&'static (dyn Checksum + Sync)> {
+    ALGORITHMS.iter().cloned().find(|algo| algo.compute(bytes) == expected)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn known_values() {
+        assert_eq!(Adler32.compute(b""), 1);
+        assert_eq!(Adler32.compute(b"Wikipedia"), 0x11E6_0398);
+        assert_eq!(Adler32::update(Adler32::update(1, b"Wiki"), b"pedia"), 0x11E6_0398);
+        assert_eq!(
+            Adler32.compute(&[0xFF; 100_000]),
+            u64::from(adler32_naive(&[0xFF; 100_000]))
+        );
+
+        assert_eq!(Crc32c.compute(b""), 0);
+        assert_eq!(Crc32c.compute(b"123456789"), 0xE306_9283);
+        assert_eq!(Crc32.compute(b"123456789"), 0xCBF4_3926);
+        assert_eq!(Crc32::update(Crc32::update(0, b"1234"), b"56789"), 0xCBF4_3926);
+
+        assert_eq!(detect(b"Wikipedia", 0x11E6_0398).map(|c| c.name()), Some("Adler-32"));
+        assert_eq!(detect(b"123456789", 0xE306_9283).map(|c| c.name()), Some("CRC-32C"));
+        assert_eq!(detect(b"123456789", 0xCBF4_3926).map(|c| c.name()), Some("CRC-32"));
+        assert!(detect(b"123456789", 0).is_none());
+    }
+
+    fn adler32_naive(bytes: &[u8]) -> u32 {
+        let (a, b) = bytes.iter().fold((1u32, 0u32), |(a, b), &byte| {
+            let a = (a + u32::from(byte)) % 65521;
+            (a, (b + a) % 65521)
+        });
+        (b << 16) | a
+    }
+}
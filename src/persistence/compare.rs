@@ -0,0 +1,242 @@
+//! Comparison of the data directories of ensemble members, to find where they diverge.
+//!
+//! Transactions are aligned by zxid across all servers. Snapshots are compared node by node,
+//! ignoring changes more recent than the oldest snapshot since other servers may not have
+//! snapshotted them yet.
+
+use std::collections::BTreeSet;
+use std::collections::HashMap;
+use std::iter::Peekable;
+use std::path::Path;
+
+use super::snapshot::{DataNode, InitState, SnapshotFile};
+use super::txnlog::{Txn, TxnlogFile};
+use crate::error::PersistenceError;
+use crate::Version;
+use crate::Zxid;
+
+fn parent(path: &str) -> Option<&str> {
+    path.rfind('/').map(|idx| &path[..idx])
+}
+
+/// A transaction that isn't identical on all servers.
+#[derive(Debug)]
+pub struct TxnDivergence {
+    pub zxid: Zxid,
+    /// Distinct versions of the transaction
+    pub txns: Vec<Txn>,
+    /// For each server, the index of its version in `txns`, or `None` if it doesn't have it.
+    pub servers: Vec<Option<usize>>,
+}
+
+/// A node that isn't identical on all servers.
+#[derive(Debug)]
+pub struct NodeDivergence {
+    pub path: String,
+    /// Distinct versions of the node
+    pub nodes: Vec<DataNode>,
+    /// For each server, the index of its version in `nodes`, or `None` if it doesn't have it.
+    pub servers: Vec<Option<usize>>,
+}
+
+/// Group identical values, returning the distinct values and the group of each input value.
+fn group<T: PartialEq>(values: Vec<Option<T>>) -> (Vec<T>, Vec<Option<usize>>) {
+    let mut distinct: Vec<T> = Vec::new();
+    let mut indices = Vec::new();
+    for value in values {
+        indices.push(value.map(|v| match distinct.iter().position(|d| *d == v) {
+            Some(idx) => idx,
+            None => {
+                distinct.push(v);
+                distinct.len() - 1
+            }
+        }));
+    }
+    (distinct, indices)
+}
+
+/// Compare the transactions after `from` in the data directories of several servers. Servers that
+/// are behind others will have missing transactions at the end.
+pub fn compare_txnlogs(dirs: &[impl AsRef<Path>], from: Zxid) -> Result<Vec<TxnDivergence>, PersistenceError> {
+    let mut logs = dirs
+        .iter()
+        .map(|dir| TxnlogFile::find_txnlog(dir, from).map(Iterator::peekable))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    compare_txns(&mut logs)
+}
+
+fn compare_txns<I>(logs: &mut [Peekable<I>]) -> Result<Vec<TxnDivergence>, PersistenceError>
+where
+    I: Iterator<Item = Result<Txn, PersistenceError>>,
+{
+    let mut result = Vec::new();
+
+    loop {
+        // Smallest zxid at the head of logs
+        let mut zxid = None;
+        for log in logs.iter_mut() {
+            match log.peek() {
+                None => {}
+                Some(Ok(txn)) => zxid = Some(zxid.map_or(txn.header.zxid, |z: Zxid| z.min(txn.header.zxid))),
+                Some(Err(_)) => return Err(log.next().unwrap().unwrap_err()),
+            }
+        }
+
+        let zxid = match zxid {
+            Some(zxid) => zxid,
+            None => return Ok(result),
+        };
+
+        let txns = logs
+            .iter_mut()
+            .map(|log| match log.peek() {
+                Some(Ok(txn)) if txn.header.zxid == zxid => log.next().and_then(Result::ok),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+
+        let (txns, servers) = group(txns);
+        if txns.len() > 1 || servers.contains(&None) {
+            result.push(TxnDivergence { zxid, txns, servers });
+        }
+    }
+}
+
+/// Compare the data nodes of snapshots from several servers. Nodes created, modified or deleted
+/// after the zxid of the oldest snapshot are ignored, as well as stat fields updated by changes
+/// on children after that zxid.
+pub fn compare_snapshots(snapshots: Vec<SnapshotFile<InitState>>) -> Result<Vec<NodeDivergence>, PersistenceError> {
+    let min_zxid = match snapshots.iter().map(SnapshotFile::zxid).min() {
+        Some(zxid) => zxid,
+        None => return Ok(Vec::new()),
+    };
+
+    let mut trees = Vec::new();
+    for snapshot in snapshots {
+        let (_, nodes) = snapshot.sessions()?.acl_map()?;
+        trees.push(nodes.collect::<Result<HashMap<_, _>, _>>()?);
+    }
+
+    let paths = trees.iter().flat_map(HashMap::keys).cloned().collect::<BTreeSet<_>>();
+
+    let mut result = Vec::new();
+    'paths: for path in paths {
+        let mut nodes = Vec::new();
+        for tree in &trees {
+            let node = tree.get(&path);
+            if node.is_none() {
+                match parent(&path).map(|p| tree.get(p)) {
+                    // Parent is missing too: only report the topmost missing node
+                    Some(None) => continue 'paths,
+                    // The deletion zxid is unknown, but it's at most the parent's pzxid
+                    Some(Some(parent)) if parent.stat.pzxid > min_zxid => continue 'paths,
+                    _ => {}
+                }
+            }
+            nodes.push(node.cloned());
+        }
+
+        // Changes not yet in the oldest snapshot
+        if nodes
+            .iter()
+            .flatten()
+            .any(|n| n.stat.czxid > min_zxid || n.stat.mzxid > min_zxid)
+        {
+            continue;
+        }
+
+        // Children changed recently: cversion and pzxid may legitimately differ
+        if nodes.iter().flatten().any(|n| n.stat.pzxid > min_zxid) {
+            for node in nodes.iter_mut().flatten() {
+                node.stat.pzxid = min_zxid;
+                node.stat.cversion = Version(0);
+            }
+        }
+
+        let (nodes, servers) = group(nodes);
+        if nodes.len() > 1 || servers.contains(&None) {
+            result.push(NodeDivergence { path, nodes, servers });
+        }
+    }
+
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::persistence::testing::*;
+    use crate::persistence::txnlog::{TxnHeader, TxnOperation};
+    use crate::{SessionId, Timestamp, Xid};
+
+    fn txn(zxid: i64, session: i64) -> Result<Txn, PersistenceError> {
+        Ok(Txn {
+            header: TxnHeader {
+                client_id: SessionId(session),
+                cxid: Xid(0),
+                zxid: Zxid(zxid),
+                time: Timestamp(0),
+            },
+            op: TxnOperation::CloseSession,
+            digest: None,
+        })
+    }
+
+    #[test]
+    fn divergent_txns() {
+        let mut logs = vec![
+            vec![txn(1, 1), txn(2, 1), txn(3, 1)].into_iter().peekable(),
+            vec![txn(1, 1), txn(2, 2), txn(3, 1)].into_iter().peekable(),
+            vec![txn(1, 1), txn(3, 1)].into_iter().peekable(),
+        ];
+
+        let result = compare_txns(&mut logs).unwrap();
+        assert_eq!(result.len(), 1);
+        assert_eq!(result[0].zxid, Zxid(2));
+        assert_eq!(result[0].txns.len(), 2);
+        assert_eq!(result[0].servers, vec![Some(0), Some(1), None]);
+    }
+
+    #[test]
+    fn divergent_snapshots() {
+        let snap1 = write_snapshot_at(
+            "compare-1",
+            5,
+            &[],
+            &[],
+            &[
+                ("", node("", -1, 0, 0)),
+                ("/a", node("x", -1, 0, 1)),
+                ("/b", node("x", -1, 0, 2)),
+                ("/c", node("x", -1, 0, 3)),
+                ("/c/d", node("x", -1, 0, 3)),
+            ],
+        );
+        let mut root = node("", -1, 0, 0);
+        root.stat.pzxid = Zxid(10);
+        let snap2 = write_snapshot_at(
+            "compare-2",
+            20,
+            &[],
+            &[],
+            &[
+                ("", root),
+                ("/a", node("y", -1, 0, 1)),
+                // Created after the oldest snapshot
+                ("/e", node("x", -1, 0, 10)),
+            ],
+        );
+
+        // The second snapshot is more recent, so /b and /c may have been deleted since the first
+        // one, as the root's pzxid shows.
+        let snapshots = vec![SnapshotFile::new(&snap1).unwrap(), SnapshotFile::new(&snap2).unwrap()];
+
+        let result = compare_snapshots(snapshots).unwrap();
+        assert_eq!(result.iter().map(|d| d.path.as_str()).collect::<Vec<_>>(), vec!["/a"]);
+        assert_eq!(result[0].servers, vec![Some(0), Some(1)]);
+
+        remove_snapshot(&snap1);
+        remove_snapshot(&snap2);
+    }
+}
@@ -0,0 +1,379 @@
+//! Compressed snapshots.
+//!
+//! ZooKeeper 3.6+ compresses snapshots when `zookeeper.snapshot.compression.method` is `gz` or
+//! `snappy`, and adds the method as a suffix of the file name, e.g. `snapshot.1a2b.gz` (see
+//! `SnapStream.java`). Snapshot checksums are computed on the uncompressed content.
+//!
+//! Each method requires its feature, `gzip` or `snappy`. Snappy snapshots are written with
+//! snappy-java's `SnappyOutputStream`, whose format isn't the standard snappy framing format: a
+//! header is followed by blocks that are compressed separately and prefixed with their length.
+
+use std::io::{self, Read, Write};
+use std::path::Path;
+
+use crate::error::PersistenceError;
+
+/// Compression method of a snapshot file.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Compression {
+    None,
+    Gzip,
+    Snappy,
+}
+
+impl Compression {
+    /// The compression method of a snapshot file, given by its suffix.
+    pub fn from_path(path: impl AsRef<Path>) -> Compression {
+        match path.as_ref().extension().and_then(|ext| ext.to_str()) {
+            Some("gz") => Compression::Gzip,
+            Some("snappy") => Compression::Snappy,
+            _ => Compression::None,
+        }
+    }
+
+    /// Suffix of the names of snapshot files compressed with this method.
+    pub fn suffix(&self) -> &'static str {
+        match self {
+            Compression::None => "",
+            Compression::Gzip => ".gz",
+            Compression::Snappy => ".snappy",
+        }
+    }
+
+    /// Does `bytes`, the start of a file, look like data compressed with this method? Like
+    /// `SnapStream.isValidSnapshot`, this only checks the magic number of compressed files.
+    pub fn has_magic(&self, bytes: &[u8]) -> bool {
+        match self {
+            Compression::None => true,
+            Compression::Gzip => bytes.starts_with(&[0x1f, 0x8b]),
+            Compression::Snappy => bytes.starts_with(&SNAPPY_MAGIC),
+        }
+    }
+
+    /// Decompress `input`.
+    pub fn reader<R: Read>(&self, input: R) -> Result<Decompressor<R>, PersistenceError> {
+        let decoder = match self {
+            Compression::None => Decoder::None(input),
+            #[cfg(feature = "gzip")]
+            Compression::Gzip => Decoder::Gzip(io::BufReader::new(flate2::read::GzDecoder::new(input))),
+            #[cfg(feature = "snappy")]
+            Compression::Snappy => Decoder::Snappy(SnappyReader::new(input)?),
+            #[allow(unreachable_patterns)]
+            _ => return Err(self.unsupported()),
+        };
+        Ok(Decompressor { decoder })
+    }
+
+    /// Compress what is written to `out`.
+    pub(crate) fn writer<W: Write>(&self, out: W) -> Result<Compressor<W>, PersistenceError> {
+        Ok(match self {
+            Compression::None => Compressor::None(out),
+            #[cfg(feature = "gzip")]
+            Compression::Gzip => Compressor::Gzip(flate2::write::GzEncoder::new(out, flate2::Compression::default())),
+            #[cfg(feature = "snappy")]
+            Compression::Snappy => Compressor::Snappy(SnappyWriter::new(out)?),
+            #[allow(unreachable_patterns)]
+            _ => return Err(self.unsupported()),
+        })
+    }
+
+    #[allow(dead_code)]
+    fn unsupported(&self) -> PersistenceError {
+        let feature = match self {
+            Compression::Gzip => "gzip",
+            _ => "snappy",
+        };
+        PersistenceError::Unsupported(format!("{} snapshots require the `{}` feature", feature, feature))
+    }
+}
+
+/// A reader that decompresses its input.
+pub struct Decompressor<R: Read> {
+    decoder: Decoder<R>,
+}
+
+enum Decoder<R: Read> {
+    None(R),
+    #[cfg(feature = "gzip")]
+    Gzip(io::BufReader<flate2::read::GzDecoder<R>>),
+    #[cfg(feature = "snappy")]
+    Snappy(SnappyReader<R>),
+}
+
+impl<R: Read> Read for Decompressor<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match &mut self.decoder {
+            Decoder::None(input) => input.read(buf),
+            #[cfg(feature = "gzip")]
+            Decoder::Gzip(input) => input.read(buf),
+            #[cfg(feature = "snappy")]
+            Decoder::Snappy(input) => input.read(buf),
+        }
+    }
+}
+
+/// A writer that compresses its output. `finish` must be called to write the end of the data.
+pub(crate) enum Compressor<W: Write> {
+    None(W),
+    #[cfg(feature = "gzip")]
+    Gzip(flate2::write::GzEncoder<W>),
+    #[cfg(feature = "snappy")]
+    Snappy(SnappyWriter<W>),
+}
+
+impl<W: Write> Compressor<W> {
+    /// Write the end of the compressed data, and return the output.
+    pub fn finish(self) -> io::Result<W> {
+        match self {
+            Compressor::None(out) => Ok(out),
+            #[cfg(feature = "gzip")]
+            Compressor::Gzip(out) => out.finish(),
+            #[cfg(feature = "snappy")]
+            Compressor::Snappy(out) => out.finish(),
+        }
+    }
+}
+
+impl<W: Write> Write for Compressor<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Compressor::None(out) => out.write(buf),
+            #[cfg(feature = "gzip")]
+            Compressor::Gzip(out) => out.write(buf),
+            #[cfg(feature = "snappy")]
+            Compressor::Snappy(out) => out.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Compressor::None(out) => out.flush(),
+            #[cfg(feature = "gzip")]
+            Compressor::Gzip(out) => out.flush(),
+            #[cfg(feature = "snappy")]
+            Compressor::Snappy(out) => out.flush(),
+        }
+    }
+}
+
+//----- Snappy
+
+/// Magic number of `SnappyCodec.java`, followed by its version and minimum compatible version
+const SNAPPY_MAGIC: [u8; 8] = [0x82, b'S', b'N', b'A', b'P', b'P', b'Y', 0];
+#[cfg(feature = "snappy")]
+const SNAPPY_VERSION: i32 = 1;
+
+/// Uncompressed size of blocks, same as `SnappyOutputStream.java`
+#[cfg(feature = "snappy")]
+const SNAPPY_BLOCK_SIZE: usize = 32 * 1024;
+
+#[cfg(feature = "snappy")]
+fn invalid_data(msg: impl ToString) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+/// Reads the format of `SnappyInputStream.java`.
+#[cfg(feature = "snappy")]
+struct SnappyReader<R: Read> {
+    input: R,
+    block: Vec<u8>,
+    /// Position in `block` of the next byte to read
+    position: usize,
+}
+
+#[cfg(feature = "snappy")]
+impl<R: Read> SnappyReader<R> {
+    fn new(mut input: R) -> io::Result<Self> {
+        let mut header = [0u8; 16];
+        input.read_exact(&mut header)?;
+        if !header.starts_with(&SNAPPY_MAGIC) {
+            return Err(invalid_data("Not a snappy stream"));
+        }
+
+        Ok(SnappyReader {
+            input,
+            block: Vec::new(),
+            position: 0,
+        })
+    }
+
+    /// Read and decompress the next block. Returns false at the end of the input.
+    fn next_block(&mut self) -> io::Result<bool> {
+        let mut len = [0u8; 4];
+        loop {
+            // End of input, or a partial length
+            let read = self.input.by_ref().take(4).read(&mut len)?;
+            if read == 0 {
+                return Ok(false);
+            }
+            self.input.read_exact(&mut len[read..])?;
+
+            // Concatenated streams each have a header
+            if len != SNAPPY_MAGIC[..4] {
+                break;
+            }
+            let mut header = [0u8; 12];
+            self.input.read_exact(&mut header)?;
+            if header[..4] != SNAPPY_MAGIC[4..] {
+                return Err(invalid_data("Invalid snappy stream header"));
+            }
+        }
+
+        // Don't trust the length to preallocate the buffer, in case the file is corrupted
+        let len = u32::from_be_bytes(len) as u64;
+        let mut compressed = Vec::new();
+        self.input.by_ref().take(len).read_to_end(&mut compressed)?;
+        if compressed.len() as u64 != len {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+
+        self.block = snap::raw::Decoder::new()
+            .decompress_vec(&compressed)
+            .map_err(invalid_data)?;
+        self.position = 0;
+        Ok(true)
+    }
+}
+
+#[cfg(feature = "snappy")]
+impl<R: Read> Read for SnappyReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.position == self.block.len() {
+            if !self.next_block()? {
+                return Ok(0);
+            }
+        }
+
+        let len = buf.len().min(self.block.len() - self.position);
+        buf[..len].copy_from_slice(&self.block[self.position..self.position + len]);
+        self.position += len;
+        Ok(len)
+    }
+}
+
+/// Writes the format of `SnappyOutputStream.java`.
+#[cfg(feature = "snappy")]
+pub(crate) struct SnappyWriter<W: Write> {
+    out: W,
+    block: Vec<u8>,
+}
+
+#[cfg(feature = "snappy")]
+impl<W: Write> SnappyWriter<W> {
+    fn new(mut out: W) -> io::Result<Self> {
+        out.write_all(&SNAPPY_MAGIC)?;
+        out.write_all(&SNAPPY_VERSION.to_be_bytes())?; // version
+        out.write_all(&SNAPPY_VERSION.to_be_bytes())?; // minimum compatible version
+        Ok(SnappyWriter {
+            out,
+            block: Vec::with_capacity(SNAPPY_BLOCK_SIZE),
+        })
+    }
+
+    fn write_block(&mut self) -> io::Result<()> {
+        if self.block.is_empty() {
+            return Ok(());
+        }
+        let compressed = snap::raw::Encoder::new()
+            .compress_vec(&self.block)
+            .map_err(invalid_data)?;
+        self.out.write_all(&(compressed.len() as u32).to_be_bytes())?;
+        self.out.write_all(&compressed)?;
+        self.block.clear();
+        Ok(())
+    }
+
+    fn finish(mut self) -> io::Result<W> {
+        self.write_block()?;
+        Ok(self.out)
+    }
+}
+
+#[cfg(feature = "snappy")]
+impl<W: Write> Write for SnappyWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = buf.len().min(SNAPPY_BLOCK_SIZE - self.block.len());
+        self.block.extend_from_slice(&buf[..len]);
+        if self.block.len() == SNAPPY_BLOCK_SIZE {
+            self.write_block()?;
+        }
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.write_block()?;
+        self.out.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compressed_snapshots() {
+        use crate::persistence::snapshot::{SnapshotFile, SnapshotWriter};
+        use crate::persistence::testing::{node, temp_dir};
+        use std::collections::HashMap;
+
+        let dir = temp_dir("compressed-snapshots");
+        let nodes = vec![
+            ("".to_owned(), node("", -1, 0, 0)),
+            ("/a".to_owned(), node("x", 1, 2, 5)),
+        ];
+
+        for compression in &[Compression::None, Compression::Gzip, Compression::Snappy] {
+            let path = dir.join(format!("snapshot.5{}", compression.suffix()));
+            assert_eq!(Compression::from_path(&path), *compression);
+
+            let supported = cfg!(feature = "gzip") || *compression != Compression::Gzip;
+            let supported = supported && (cfg!(feature = "snappy") || *compression != Compression::Snappy);
+            let writer = SnapshotWriter::create(&path);
+            if !supported {
+                assert!(matches!(writer, Err(PersistenceError::Unsupported(_))));
+                continue;
+            }
+
+            let mut writer = writer.unwrap();
+            writer.sessions(&HashMap::new()).unwrap();
+            writer.acls(&HashMap::new()).unwrap();
+            for (path, node) in &nodes {
+                writer.node(path, node).unwrap();
+            }
+            writer.finish(None).unwrap();
+
+            assert!(SnapshotFile::is_valid_snapshot(&path).unwrap());
+            let snap = SnapshotFile::new(&path).unwrap();
+            assert_eq!(snap.zxid().0, 5);
+            let (_, mut snap) = snap.sessions().unwrap().acl_map().unwrap();
+            assert_eq!(snap.by_ref().collect::<Result<Vec<_>, _>>().unwrap(), nodes);
+            assert_eq!(snap.finish().unwrap(), None);
+
+            std::fs::remove_file(&path).unwrap();
+        }
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(feature = "snappy")]
+    #[test]
+    fn snappy_java_format() {
+        let mut bytes = SNAPPY_MAGIC.to_vec();
+        bytes.extend_from_slice(&[0, 0, 0, 1, 0, 0, 0, 1]); // versions
+        bytes.extend_from_slice(&[0, 0, 0, 5, 3, 0x08, b'a', b'b', b'c']); // "abc" literal
+                                                                           // Concatenated stream
+        bytes.extend_from_slice(&bytes.clone());
+
+        let mut data = String::new();
+        Compression::Snappy
+            .reader(&bytes[..])
+            .unwrap()
+            .read_to_string(&mut data)
+            .unwrap();
+        assert_eq!(data, "abcabc");
+
+        let mut writer = Compression::Snappy.writer(Vec::new()).unwrap();
+        writer.write_all(b"abc").unwrap();
+        assert_eq!(writer.finish().unwrap(), bytes[..bytes.len() / 2]);
+    }
+}
@@ -0,0 +1,578 @@
+//! In-memory data tree, rebuilt from a snapshot and the transactions that follow it.
+//!
+//! `DataTree::load` does what a ZooKeeper server does when it starts: it reads the most recent
+//! valid snapshot of a data directory and replays the transactions that follow it. The resulting
+//! tree can then be inspected offline, with the stat, data and ACL of each node.
+//!
+//! Snapshots are fuzzy: they're written while transactions are applied, so some transactions that
+//! follow a snapshot's zxid may already be in it. Like ZooKeeper, a replay ignores the
+//! transactions that fail because of this, e.g. creating a node that already exists.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::path::Path;
+
+use super::replay::Replay;
+use super::snapshot::{
+    ACLRef, DataNode, EphemeralType, InitState, SnapshotFile, StatPersisted, MAX_SNAPSHOT_CANDIDATES,
+};
+use super::txnlog::{CreateTxn, MultiTxnOperation, Txn, TxnHeader, TxnOperation};
+use crate::clock::Clock;
+use crate::error::{ParseError, PersistenceError};
+use crate::{Duration, Id, SessionId, Timestamp, Version, Zxid, ACL, PERM_ALL};
+
+/// Split a path into its parent path and node name. The root node's path is empty.
+fn split(path: &str) -> Option<(&str, &str)> {
+    path.rfind('/').map(|idx| (&path[..idx], &path[idx + 1..]))
+}
+
+/// Path of a node in snapshots, where the root node is "" rather than "/".
+fn node_path(path: &str) -> &str {
+    if path == "/" {
+        ""
+    } else {
+        path
+    }
+}
+
+/// The nodes, ACL cache and sessions of a ZooKeeper server.
+#[derive(Debug, Clone)]
+pub struct DataTree {
+    /// Nodes by path, which lists parents before their children
+    nodes: BTreeMap<String, DataNode>,
+    /// Child names by parent path
+    children: HashMap<String, BTreeSet<String>>,
+    acls: HashMap<ACLRef, Vec<ACL>>,
+    sessions: HashMap<SessionId, Duration>,
+    /// Paths of ephemeral nodes by owner
+    ephemerals: HashMap<SessionId, BTreeSet<String>>,
+    zxid: Zxid,
+}
+
+impl Default for DataTree {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl DataTree {
+    /// An empty tree, with only a root node.
+    pub fn new() -> DataTree {
+        let mut tree = DataTree {
+            nodes: BTreeMap::new(),
+            children: HashMap::new(),
+            acls: HashMap::new(),
+            sessions: HashMap::new(),
+            ephemerals: HashMap::new(),
+            zxid: Zxid(0),
+        };
+        let root = new_node(&[], ACLRef::OPEN_ACL_UNSAFE, EphemeralType::Void, Zxid(0), Timestamp(0));
+        tree.insert(String::new(), root);
+        tree
+    }
+
+    /// Load the most recent snapshot of `snap_dir` whose checksums are correct, and replay the
+    /// transactions of `log_dir` that follow it.
+    pub fn load(snap_dir: impl AsRef<Path>, log_dir: impl AsRef<Path>) -> Result<DataTree, PersistenceError> {
+        let snapshot =
+            SnapshotFile::find_verified_snapshot(&snap_dir, MAX_SNAPSHOT_CANDIDATES, false)?.ok_or_else(|| {
+                PersistenceError::NotFound(format!("No valid snapshot in {}", snap_dir.as_ref().display()))
+            })?;
+        let mut tree = Self::from_snapshot(snapshot)?;
+        tree.replay(Replay::new(log_dir, tree.zxid)?)?;
+        Ok(tree)
+    }
+
+    /// Read a snapshot. The tree's zxid is the snapshot's zxid.
+    pub fn from_snapshot(snapshot: SnapshotFile<InitState>) -> Result<DataTree, PersistenceError> {
+        let zxid = snapshot.zxid();
+        let (sessions, snapshot) = snapshot.sessions()?.session_map()?;
+        let (acls, mut snapshot) = snapshot.acl_map()?;
+
+        let mut tree = DataTree {
+            nodes: BTreeMap::new(),
+            children: HashMap::new(),
+            acls,
+            sessions,
+            ephemerals: HashMap::new(),
+            zxid,
+        };
+        for r in snapshot.by_ref() {
+            let (path, node) = r?;
+            tree.insert(path, node);
+        }
+        snapshot.finish()?;
+
+        Ok(tree)
+    }
+
+    /// Apply the transactions that follow the tree's zxid. Transactions that can't be applied to
+    /// the tree are ignored, since they're included in the snapshot it was read from.
+    pub fn replay(
+        &mut self,
+        txns: impl IntoIterator<Item = Result<Txn, PersistenceError>>,
+    ) -> Result<(), PersistenceError> {
+        for txn in txns {
+            let txn = txn?;
+            if txn.header.zxid > self.zxid {
+                // Failures are expected with fuzzy snapshots
+                let _ = self.apply(&txn);
+            }
+        }
+        Ok(())
+    }
+
+    /// Apply a transaction. Fails if the nodes it modifies don't exist, or if it creates an
+    /// existing node. The operations of a multi transaction are all applied, and the first
+    /// failure is returned.
+    pub fn apply(&mut self, txn: &Txn) -> Result<(), PersistenceError> {
+        use TxnOperation::*;
+        let header = &txn.header;
+        self.zxid = self.zxid.max(header.zxid);
+
+        match &txn.op {
+            CreateSession(t) => {
+                self.sessions.insert(header.client_id, t.time_out);
+                Ok(())
+            }
+            CloseSession => {
+                self.close_session(header.client_id, header.zxid);
+                Ok(())
+            }
+            Create(t) | Create2(t) => self.create_txn(t, header),
+            CreateTTL(t) => self.create(
+                &t.path,
+                &t.data,
+                &t.acl,
+                EphemeralType::TTL(t.ttl),
+                t.parent_c_version,
+                header,
+            ),
+            CreateContainer(t) => self.create(
+                &t.path,
+                &t.data,
+                &t.acl,
+                EphemeralType::Container,
+                t.parent_c_version,
+                header,
+            ),
+            Delete(t) | DeleteContainer(t) => self.delete(&t.path, header.zxid),
+            Reconfig(t) | SetData(t) => self.set_data(&t.path, &t.data, t.version, header),
+            SetACL(t) => self.set_acl(&t.path, &t.acl, t.version),
+            Error(_) => Ok(()),
+            Multi(multi) => {
+                let mut result = Ok(());
+                for op in &multi.txns {
+                    let r = self.apply_multi_op(op, header);
+                    if result.is_ok() {
+                        result = r;
+                    }
+                }
+                result
+            }
+        }
+    }
+
+    fn apply_multi_op(&mut self, op: &MultiTxnOperation, header: &TxnHeader) -> Result<(), PersistenceError> {
+        use MultiTxnOperation::*;
+        match op {
+            Create(t) | Create2(t) => self.create_txn(t, header),
+            CreateTTL(t) => self.create(
+                &t.path,
+                &t.data,
+                &t.acl,
+                EphemeralType::TTL(t.ttl),
+                t.parent_c_version,
+                header,
+            ),
+            CreateContainer(t) => self.create(
+                &t.path,
+                &t.data,
+                &t.acl,
+                EphemeralType::Container,
+                t.parent_c_version,
+                header,
+            ),
+            Delete(t) | DeleteContainer(t) => self.delete(&t.path, header.zxid),
+            SetData(t) => self.set_data(&t.path, &t.data, t.version, header),
+            Error(_) | Check(_) => Ok(()),
+        }
+    }
+
+    /// Zxid of the last transaction applied, or of the snapshot the tree was read from.
+    pub fn zxid(&self) -> Zxid {
+        self.zxid
+    }
+
+    pub fn node_count(&self) -> usize {
+        self.nodes.len()
+    }
+
+    /// A node. The root node's path is either "/" or "".
+    pub fn get(&self, path: &str) -> Option<&DataNode> {
+        self.nodes.get(node_path(path))
+    }
+
+    /// The ACL of a node, resolved from the ACL cache.
+    pub fn acl(&self, path: &str) -> Option<Vec<ACL>> {
+        let node = self.get(path)?;
+        match self.acls.get(&node.acl) {
+            Some(acl) => Some(acl.clone()),
+            None if node.acl == ACLRef::OPEN_ACL_UNSAFE => Some(vec![ACL {
+                perms: PERM_ALL,
+                id: Id::anyone(),
+            }]),
+            None => None,
+        }
+    }
+
+    /// Names of the children of a node, in lexicographic order.
+    pub fn children(&self, path: &str) -> Option<Vec<&str>> {
+        let path = node_path(path);
+        self.nodes.get(path)?;
+        Some(match self.children.get(path) {
+            Some(children) => children.iter().map(String::as_str).collect(),
+            None => Vec::new(),
+        })
+    }
+
+    /// All nodes with their path, parents before their children.
+    pub fn nodes(&self) -> impl Iterator<Item = (&str, &DataNode)> {
+        self.nodes.iter().map(|(path, node)| (path.as_str(), node))
+    }
+
+    /// The ACL cache. It also contains ACLs that are no longer used by any node.
+    pub fn acls(&self) -> &HashMap<ACLRef, Vec<ACL>> {
+        &self.acls
+    }
+
+    /// Open sessions, with their timeout.
+    pub fn sessions(&self) -> &HashMap<SessionId, Duration> {
+        &self.sessions
+    }
+
+    /// Paths of the ephemeral nodes owned by a session.
+    pub fn ephemerals(&self, session: SessionId) -> Vec<&str> {
+        match self.ephemerals.get(&session) {
+            Some(paths) => paths.iter().map(String::as_str).collect(),
+            None => Vec::new(),
+        }
+    }
+
+    /// Paths of the TTL nodes that have expired: nodes without children that haven't been
+    /// modified for longer than their TTL (see `ContainerManager.getCandidates()`).
+    pub fn expired_ttl_nodes(&self, clock: &dyn Clock) -> Vec<&str> {
+        self.nodes
+            .iter()
+            .filter(|(path, node)| match node.stat.ephemeral_info.ephemeral_type() {
+                EphemeralType::TTL(ttl) => {
+                    ttl > 0
+                        && self.children.get(path.as_str()).into_iter().all(BTreeSet::is_empty)
+                        && clock.elapsed(node.stat.mtime).as_millis() > ttl as u128
+                }
+                _ => false,
+            })
+            .map(|(path, _)| path.as_str())
+            .collect()
+    }
+
+    fn insert(&mut self, path: String, node: DataNode) {
+        if let Some((parent, name)) = split(&path) {
+            self.children
+                .entry(parent.to_owned())
+                .or_default()
+                .insert(name.to_owned());
+        }
+        if let Some(owner) = node.stat.ephemeral_info.owner() {
+            self.ephemerals.entry(owner).or_default().insert(path.clone());
+        }
+        self.nodes.insert(path, node);
+    }
+
+    fn remove(&mut self, path: &str) -> Option<DataNode> {
+        let node = self.nodes.remove(path)?;
+        if let Some((parent, name)) = split(path) {
+            if let Some(children) = self.children.get_mut(parent) {
+                children.remove(name);
+            }
+        }
+        if let Some(owner) = node.stat.ephemeral_info.owner() {
+            if let Some(paths) = self.ephemerals.get_mut(&owner) {
+                paths.remove(path);
+            }
+        }
+        self.children.remove(path);
+        Some(node)
+    }
+
+    fn node_mut(&mut self, path: &str) -> Result<&mut DataNode, PersistenceError> {
+        self.nodes
+            .get_mut(path)
+            .ok_or_else(|| PersistenceError::Inconsistent(format!("No node at '{}'", path)))
+    }
+
+    /// Reference of an ACL in the ACL cache, adding it if needed.
+    ///
+    /// See `ReferenceCountedACLCache.convertAcls`.
+    fn acl_ref(&mut self, acl: &[ACL]) -> ACLRef {
+        if let Some((acl_ref, _)) = self.acls.iter().find(|(_, cached)| cached.as_slice() == acl) {
+            return *acl_ref;
+        }
+        let acl_ref = ACLRef(self.acls.keys().map(|r| r.0).max().unwrap_or(0) + 1);
+        self.acls.insert(acl_ref, acl.to_vec());
+        acl_ref
+    }
+
+    fn create_txn(&mut self, t: &CreateTxn, header: &TxnHeader) -> Result<(), PersistenceError> {
+        let ephemeral = if t.ephemeral {
+            EphemeralType::Normal(header.client_id)
+        } else {
+            EphemeralType::Void
+        };
+        self.create(&t.path, &t.data, &t.acl, ephemeral, t.parent_c_version, header)
+    }
+
+    /// See `DataTree.createNode`.
+    fn create(
+        &mut self,
+        path: &str,
+        data: &[u8],
+        acl: &[ACL],
+        ephemeral: EphemeralType,
+        parent_cversion: Version,
+        header: &TxnHeader,
+    ) -> Result<(), PersistenceError> {
+        let (parent_path, _) = split(path).ok_or_else(|| ParseError::invalid("path", path))?;
+        let parent = self
+            .nodes
+            .get_mut(parent_path)
+            .ok_or_else(|| PersistenceError::Inconsistent(format!("No parent node for '{}'", path)))?;
+
+        // Updated even if the node exists, as it may have been deleted and created again since
+        // the snapshot was taken (see `FileTxnSnapLog.processTransaction`)
+        let parent_cversion = if parent_cversion.0 == -1 {
+            Version(parent.stat.cversion.0 + 1)
+        } else {
+            parent_cversion
+        };
+        if parent_cversion > parent.stat.cversion {
+            parent.stat.cversion = parent_cversion;
+            parent.stat.pzxid = header.zxid;
+        }
+
+        if self.nodes.contains_key(path) {
+            return Err(PersistenceError::Inconsistent(format!(
+                "Node '{}' already exists",
+                path
+            )));
+        }
+        let acl = self.acl_ref(acl);
+        self.insert(
+            path.to_owned(),
+            new_node(data, acl, ephemeral, header.zxid, header.time),
+        );
+        Ok(())
+    }
+
+    /// See `DataTree.deleteNode`.
+    fn delete(&mut self, path: &str, zxid: Zxid) -> Result<(), PersistenceError> {
+        let (parent_path, _) =
+            split(path).ok_or_else(|| PersistenceError::Inconsistent("Can't delete the root node".to_owned()))?;
+        self.remove(path)
+            .ok_or_else(|| PersistenceError::Inconsistent(format!("No node at '{}'", path)))?;
+        if let Some(parent) = self.nodes.get_mut(parent_path) {
+            parent.stat.pzxid = parent.stat.pzxid.max(zxid);
+        }
+        Ok(())
+    }
+
+    fn set_data(
+        &mut self,
+        path: &str,
+        data: &[u8],
+        version: Version,
+        header: &TxnHeader,
+    ) -> Result<(), PersistenceError> {
+        let node = self.node_mut(path)?;
+        node.data = data.to_vec();
+        node.stat.version = version;
+        node.stat.mzxid = header.zxid;
+        node.stat.mtime = header.time;
+        Ok(())
+    }
+
+    fn set_acl(&mut self, path: &str, acl: &[ACL], version: Version) -> Result<(), PersistenceError> {
+        // Don't add the ACL to the cache if there's no node
+        self.node_mut(path)?;
+        let acl = self.acl_ref(acl);
+        let node = self.node_mut(path)?;
+        node.acl = acl;
+        node.stat.aversion = version;
+        Ok(())
+    }
+
+    /// Remove a session and delete its ephemeral nodes.
+    fn close_session(&mut self, session: SessionId, zxid: Zxid) {
+        self.sessions.remove(&session);
+        for path in self.ephemerals.remove(&session).unwrap_or_default() {
+            let _ = self.delete(&path, zxid);
+        }
+    }
+}
+
+fn new_node(data: &[u8], acl: ACLRef, ephemeral: EphemeralType, zxid: Zxid, time: Timestamp) -> DataNode {
+    DataNode {
+        data: data.to_vec(),
+        acl,
+        stat: StatPersisted {
+            czxid: zxid,
+            mzxid: zxid,
+            ctime: time,
+            mtime: time,
+            version: Version(0),
+            cversion: Version(0),
+            aversion: Version(0),
+            ephemeral_info: ephemeral.into(),
+            pzxid: zxid,
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::MockClock;
+    use crate::persistence::testing::*;
+    use crate::persistence::txnlog::{CreateTTLTxn, DeleteTxn, MultiTxn, SetACLTxn, SetDataTxn};
+    use crate::{Xid, PERM_READ};
+
+    fn txn(zxid: i64, op: TxnOperation) -> Txn {
+        Txn {
+            header: TxnHeader {
+                client_id: SessionId(10),
+                cxid: Xid(0),
+                zxid: Zxid(zxid),
+                time: Timestamp(zxid as u64 * 1000),
+            },
+            op,
+            digest: None,
+        }
+    }
+
+    #[test]
+    fn load_data_tree() {
+        let acl = vec![ACL {
+            perms: PERM_READ,
+            id: Id::anyone(),
+        }];
+        let snapshot = write_snapshot_at(
+            "load-data-tree",
+            3,
+            &[(10, 3000), (11, 3000)],
+            &[(1, acl.clone())],
+            &[
+                ("", node("", -1, 0, 0)),
+                ("/app", node("v0", 1, 0, 1)),
+                ("/app/lock", node("", 1, 11, 3)),
+            ],
+        );
+        let dir = snapshot.parent().unwrap();
+
+        write_txnlog(
+            dir,
+            3,
+            &[
+                // Already in the snapshot
+                txn_body(3, 11, 1, &create_op("/app/lock", "", true)),
+                // Fuzzy snapshot: the node was created while the snapshot was written
+                txn_body(4, 11, 1, &create_op("/app/lock", "", true)),
+                txn_body(5, 10, 1, &create_op("/app/a", "x", true)),
+                txn_body(6, 10, 5, &path_op("/app", Some("v1"))),
+                txn_body(7, 10, 1, &create_op("/app/b", "", false)),
+                txn_body(8, 10, 2, &path_op("/app/b", None)),
+                txn_body(9, 11, -11, &[]),
+            ],
+        );
+
+        let tree = DataTree::load(dir, dir).unwrap();
+        assert_eq!(tree.zxid(), Zxid(9));
+        assert_eq!(tree.sessions().keys().collect::<Vec<_>>(), vec![&SessionId(10)]);
+        assert_eq!(tree.children("/").unwrap(), vec!["app"]);
+        assert_eq!(tree.children("/app").unwrap(), vec!["a"]);
+        assert_eq!(tree.ephemerals(SessionId(10)), vec!["/app/a"]);
+        assert!(tree.ephemerals(SessionId(11)).is_empty());
+
+        let app = tree.get("/app").unwrap();
+        assert_eq!(app.data, b"v1");
+        assert_eq!((app.stat.version, app.stat.mzxid), (Version(1), Zxid(6)));
+        assert_eq!(app.stat.pzxid, Zxid(9));
+        assert_eq!(tree.acl("/app").unwrap(), acl);
+        assert_eq!(tree.acl("/").unwrap()[0].perms, PERM_ALL);
+
+        let a = tree.get("/app/a").unwrap();
+        assert_eq!((a.stat.czxid, a.stat.ctime), (Zxid(5), Timestamp(5000)));
+        assert_eq!(a.stat.ephemeral_info.owner(), Some(SessionId(10)));
+        // Empty ACL of the test txns, added to the ACL cache
+        assert_eq!(a.acl, ACLRef(2));
+
+        remove_snapshot(&snapshot);
+    }
+
+    #[test]
+    fn apply_txns() {
+        let mut tree = DataTree::new();
+        let acl = vec![ACL {
+            perms: PERM_ALL,
+            id: Id::new("digest", "admin:xxx"),
+        }];
+
+        let create = |path: &str, ttl| {
+            MultiTxnOperation::CreateTTL(CreateTTLTxn {
+                path: path.to_owned(),
+                data: vec![],
+                acl: vec![],
+                parent_c_version: Version(-1),
+                ttl,
+            })
+        };
+        let multi = TxnOperation::Multi(MultiTxn {
+            txns: vec![create("/a", 1000), create("/a/b", 2000), create("/c/d", 0)],
+        });
+        assert!(tree.apply(&txn(1, multi)).is_err());
+        assert_eq!(tree.children("/a").unwrap(), vec!["b"]);
+        assert_eq!(tree.get("/").unwrap().stat.cversion, Version(1));
+        assert_eq!(
+            tree.get("/a/b").unwrap().stat.ephemeral_info.ephemeral_type(),
+            EphemeralType::TTL(2000)
+        );
+
+        let set_acl = TxnOperation::SetACL(SetACLTxn {
+            path: "/a".to_owned(),
+            acl: acl.clone(),
+            version: Version(1),
+        });
+        tree.apply(&txn(2, set_acl)).unwrap();
+        assert_eq!(tree.acl("/a").unwrap(), acl);
+        assert_eq!(tree.get("/a").unwrap().stat.aversion, Version(1));
+
+        let set_data = TxnOperation::SetData(SetDataTxn {
+            path: "/missing".to_owned(),
+            data: vec![],
+            version: Version(1),
+        });
+        assert!(tree.apply(&txn(3, set_data)).is_err());
+        assert_eq!(tree.zxid(), Zxid(3));
+
+        let delete = |path: &str| TxnOperation::Delete(DeleteTxn { path: path.to_owned() });
+        tree.apply(&txn(4, delete("/a/b"))).unwrap();
+        assert!(tree.apply(&txn(5, delete("/a/b"))).is_err());
+        assert!(tree.children("/a").unwrap().is_empty());
+        assert_eq!(tree.get("/a").unwrap().stat.pzxid, Zxid(4));
+        assert_eq!(tree.nodes().map(|(path, _)| path).collect::<Vec<_>>(), vec!["", "/a"]);
+
+        // "/a" was last modified when created at zxid 1, and has a TTL of 1s
+        let clock = MockClock::new(Timestamp(2000));
+        assert!(tree.expired_ttl_nodes(&clock).is_empty());
+        clock.advance(std::time::Duration::from_millis(1));
+        assert_eq!(tree.expired_ttl_nodes(&clock), vec!["/a"]);
+    }
+}
@@ -0,0 +1,257 @@
+//! Whole-file digests, to detect bit rot in archived snapshots and txnlogs.
+//!
+//! A `FileDigest` holds the SHA-256 of a file and of each of its chunks, so that corruption can
+//! be narrowed down to the chunks that changed. Digests are stored as a text manifest:
+//!
+//! ```text
+//! zookeepers-digest 1
+//! size 150000000
+//! chunk-size 67108864
+//! sha256 <hex digest of the file>
+//! chunk 0 <hex digest>
+//! chunk 1 <hex digest>
+//! chunk 2 <hex digest>
+//! ```
+
+use serde_derive::Deserialize;
+use serde_derive::Serialize;
+use sha2::{Digest, Sha256};
+use std::fmt::Write;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+use crate::error::{ParseError, PersistenceError};
+
+/// Default chunk size: 64 MiB
+pub const CHUNK_SIZE: u64 = 64 * 1024 * 1024;
+
+/// First line of digest manifests, with the format version
+const MANIFEST_HEADER: &str = "zookeepers-digest 1";
+
+const READ_BUFFER_SIZE: usize = 1024 * 1024;
+
+/// SHA-256 digests of a file and of its chunks.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Deserialize, Serialize)]
+pub struct FileDigest {
+    pub size: u64,
+    pub chunk_size: u64,
+    /// Hex-encoded SHA-256 of the whole file
+    pub sha256: String,
+    /// Hex-encoded SHA-256 of each chunk. The last chunk can be shorter than `chunk_size`.
+    pub chunks: Vec<String>,
+}
+
+/// Compute the digest of a file, with 64 MiB chunks.
+pub fn file_digest(path: impl AsRef<Path>) -> Result<FileDigest, PersistenceError> {
+    FileDigest::compute(File::open(path)?, CHUNK_SIZE)
+}
+
+pub(crate) fn to_hex(bytes: &[u8]) -> String {
+    let mut hex = String::with_capacity(bytes.len() * 2);
+    for byte in bytes {
+        write!(hex, "{:02x}", byte).unwrap();
+    }
+    hex
+}
+
+pub(crate) fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if hex.len() & 1 == 1 || !hex.is_ascii() {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+        .collect()
+}
+
+fn is_sha256_hex(s: &str) -> bool {
+    s.len() == 64 && s.bytes().all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b))
+}
+
+impl FileDigest {
+    /// Compute the digest of a stream, read to its end.
+    pub fn compute(mut input: impl Read, chunk_size: u64) -> Result<FileDigest, PersistenceError> {
+        let chunk_size = chunk_size.max(1);
+        let mut file_hash = Sha256::new();
+        let mut chunk_hash = Sha256::new();
+        let mut chunk_len = 0u64;
+        let mut size = 0u64;
+        let mut chunks = Vec::new();
+
+        let mut buf = vec![0u8; READ_BUFFER_SIZE];
+        loop {
+            let len = match input.read(&mut buf) {
+                Ok(0) => break,
+                Ok(len) => len,
+                Err(ref e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e.into()),
+            };
+
+            let mut bytes = &buf[..len];
+            file_hash.update(bytes);
+            size += len as u64;
+
+            while !bytes.is_empty() {
+                let n = (chunk_size - chunk_len).min(bytes.len() as u64) as usize;
+                chunk_hash.update(&bytes[..n]);
+                chunk_len += n as u64;
+                bytes = &bytes[n..];
+
+                if chunk_len == chunk_size {
+                    chunks.push(to_hex(&chunk_hash.finalize_reset()));
+                    chunk_len = 0;
+                }
+            }
+        }
+
+        if chunk_len > 0 {
+            chunks.push(to_hex(&chunk_hash.finalize()));
+        }
+
+        Ok(FileDigest {
+            size,
+            chunk_size,
+            sha256: to_hex(&file_hash.finalize()),
+            chunks,
+        })
+    }
+
+    /// Indices of the chunks that differ in `actual`, including chunks that only exist in one of
+    /// the two digests. Both digests should have the same chunk size.
+    pub fn changed_chunks(&self, actual: &FileDigest) -> Vec<usize> {
+        let count = self.chunks.len().max(actual.chunks.len());
+        (0..count)
+            .filter(|i| self.chunks.get(*i) != actual.chunks.get(*i))
+            .collect()
+    }
+
+    /// Check a file against this digest, returning the indices of corrupted chunks. The file is
+    /// intact if none are returned.
+    pub fn verify(&self, path: impl AsRef<Path>) -> Result<Vec<usize>, PersistenceError> {
+        let actual = FileDigest::compute(File::open(path)?, self.chunk_size)?;
+        let mut changed = self.changed_chunks(&actual);
+        if changed.is_empty() && (actual.size != self.size || actual.sha256 != self.sha256) {
+            // Only possible if the manifest itself is inconsistent
+            changed = (0..self.chunks.len()).collect();
+        }
+        Ok(changed)
+    }
+
+    /// Write this digest as a text manifest.
+    pub fn to_manifest(&self) -> String {
+        let mut text = String::new();
+        writeln!(text, "{}", MANIFEST_HEADER).unwrap();
+        writeln!(text, "size {}", self.size).unwrap();
+        writeln!(text, "chunk-size {}", self.chunk_size).unwrap();
+        writeln!(text, "sha256 {}", self.sha256).unwrap();
+        for (i, chunk) in self.chunks.iter().enumerate() {
+            writeln!(text, "chunk {} {}", i, chunk).unwrap();
+        }
+        text
+    }
+
+    /// Read a digest from a text manifest.
+    pub fn from_manifest(text: &str) -> Result<FileDigest, ParseError> {
+        let mut lines = text.lines().filter(|line| !line.trim().is_empty());
+
+        match lines.next() {
+            Some(MANIFEST_HEADER) => {}
+            Some(line) => return Err(ParseError::invalid("digest manifest header", line)),
+            None => return Err(ParseError::invalid("digest manifest", "empty")),
+        }
+
+        let mut field = |key: &str| -> Result<String, ParseError> {
+            let line = lines
+                .next()
+                .ok_or_else(|| ParseError::invalid("digest manifest, missing field", key))?;
+            match line.split_once(' ') {
+                Some((k, value)) if k == key => Ok(value.to_owned()),
+                _ => Err(ParseError::invalid("digest manifest field", format!("expected '{}', found '{}'", key, line))),
+            }
+        };
+
+        let size = field("size")?.parse::<u64>()?;
+        let chunk_size = field("chunk-size")?.parse::<u64>()?;
+        let sha256 = field("sha256")?;
+        if chunk_size == 0 {
+            return Err(ParseError::invalid("digest manifest chunk size", chunk_size));
+        }
+        if !is_sha256_hex(&sha256) {
+            return Err(ParseError::invalid("SHA-256 digest", sha256));
+        }
+
+        let mut chunks = Vec::new();
+        for line in lines {
+            let mut parts = line.split(' ');
+            match (parts.next(), parts.next(), parts.next(), parts.next()) {
+                (Some("chunk"), Some(index), Some(digest), None)
+                    if index.parse::<usize>().ok() == Some(chunks.len()) && is_sha256_hex(digest) =>
+                {
+                    chunks.push(digest.to_owned());
+                }
+                _ => return Err(ParseError::invalid("digest manifest chunk", line)),
+            }
+        }
+
+        let expected = size.div_ceil(chunk_size);
+        if chunks.len() as u64 != expected {
+            return Err(ParseError::invalid(
+                "digest manifest chunk count",
+                format!("{}, expected {}", chunks.len(), expected),
+            ));
+        }
+
+        Ok(FileDigest {
+            size,
+            chunk_size,
+            sha256,
+            chunks,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::persistence::testing::temp_dir;
+
+    #[test]
+    fn chunked_digest() {
+        let dir = temp_dir("chunked-digest");
+        let path = dir.join("log.1");
+        let mut bytes = (0..2500u32).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        std::fs::write(&path, &bytes).unwrap();
+
+        let digest = FileDigest::compute(File::open(&path).unwrap(), 1000).unwrap();
+        assert_eq!(digest.size, 2500);
+        assert_eq!(digest.chunks.len(), 3);
+        assert_eq!(digest.sha256, to_hex(&Sha256::digest(&bytes)));
+        assert_eq!(digest.chunks[2], to_hex(&Sha256::digest(&bytes[2000..])));
+        assert_eq!(file_digest(&path).unwrap().chunks, vec![digest.sha256.clone()]);
+
+        let manifest = digest.to_manifest();
+        assert!(manifest.starts_with("zookeepers-digest 1\nsize 2500\nchunk-size 1000\n"));
+        assert_eq!(FileDigest::from_manifest(&manifest).unwrap(), digest);
+        assert!(FileDigest::from_manifest(&manifest.replace("chunk 2", "chunk 3")).is_err());
+        assert!(FileDigest::from_manifest(&manifest.replace("size 2500", "size 3500")).is_err());
+
+        assert_eq!(digest.verify(&path).unwrap(), Vec::<usize>::new());
+
+        // Flip a bit in the second chunk
+        bytes[1500] ^= 1;
+        std::fs::write(&path, &bytes).unwrap();
+        assert_eq!(digest.verify(&path).unwrap(), vec![1]);
+
+        // Truncation
+        std::fs::write(&path, &bytes[..1000]).unwrap();
+        assert_eq!(digest.verify(&path).unwrap(), vec![1, 2]);
+
+        let empty = FileDigest::compute(&[][..], CHUNK_SIZE).unwrap();
+        assert!(empty.chunks.is_empty());
+        assert_eq!(FileDigest::from_manifest(&empty.to_manifest()).unwrap(), empty);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,295 @@
+//! Conversion of transactions and snapshot data nodes to Arrow record batches, that can be handed
+//! over to analytics engines without further serialization.
+
+use arrow_array::builder::{BinaryBuilder, Int32Builder, Int64Builder, StringBuilder, TimestampMillisecondBuilder};
+use arrow_array::{ArrayRef, RecordBatch};
+use arrow_schema::{DataType, Field, Schema, SchemaRef, TimeUnit};
+use std::path::Path;
+use std::sync::Arc;
+
+use super::redact::Redactor;
+use super::{Redact, TxnRecord};
+use crate::error::PersistenceError;
+use crate::persistence::snapshot::{DataNode, InitState, SnapshotFile};
+use crate::persistence::txnlog::{Txn, TxnlogFile};
+
+/// A data node and its path
+type Node = (String, DataNode);
+
+/// Default number of rows in a record batch.
+pub const DEFAULT_BATCH_SIZE: usize = 8192;
+
+fn timestamp() -> DataType {
+    DataType::Timestamp(TimeUnit::Millisecond, None)
+}
+
+/// Schema of transaction batches, whose columns are the fields of `TxnRecord`.
+pub fn txn_schema() -> SchemaRef {
+    Arc::new(Schema::new(vec![
+        Field::new("zxid", DataType::Int64, false),
+        Field::new("time", timestamp(), false),
+        Field::new("session", DataType::Int64, false),
+        Field::new("cxid", DataType::Int32, false),
+        Field::new("multi_index", DataType::Int32, true),
+        Field::new("op", DataType::Utf8, false),
+        Field::new("path", DataType::Utf8, true),
+        Field::new("data", DataType::Binary, true),
+    ]))
+}
+
+/// Schema of data node batches: the path, data, ACL reference and stat of nodes. The ephemeral
+/// owner is null for persistent nodes.
+pub fn node_schema() -> SchemaRef {
+    Arc::new(Schema::new(vec![
+        Field::new("path", DataType::Utf8, false),
+        Field::new("data", DataType::Binary, false),
+        Field::new("acl", DataType::Int64, false),
+        Field::new("czxid", DataType::Int64, false),
+        Field::new("mzxid", DataType::Int64, false),
+        Field::new("pzxid", DataType::Int64, false),
+        Field::new("ctime", timestamp(), false),
+        Field::new("mtime", timestamp(), false),
+        Field::new("version", DataType::Int32, false),
+        Field::new("cversion", DataType::Int32, false),
+        Field::new("aversion", DataType::Int32, false),
+        Field::new("ephemeral_owner", DataType::Int64, true),
+    ]))
+}
+
+/// Convert transaction records to a record batch.
+pub fn txn_batch(records: &[TxnRecord]) -> Result<RecordBatch, PersistenceError> {
+    let mut zxid = Int64Builder::with_capacity(records.len());
+    let mut time = TimestampMillisecondBuilder::with_capacity(records.len());
+    let mut session = Int64Builder::with_capacity(records.len());
+    let mut cxid = Int32Builder::with_capacity(records.len());
+    let mut multi_index = Int32Builder::with_capacity(records.len());
+    let mut op = StringBuilder::new();
+    let mut path = StringBuilder::new();
+    let mut data = BinaryBuilder::new();
+
+    for record in records {
+        zxid.append_value(record.zxid);
+        time.append_value(record.time as i64);
+        session.append_value(record.session);
+        cxid.append_value(record.cxid);
+        multi_index.append_option(record.multi_index);
+        op.append_value(record.op);
+        path.append_option(record.path.as_ref());
+        data.append_option(record.data.as_ref());
+    }
+
+    let columns: Vec<ArrayRef> = vec![
+        Arc::new(zxid.finish()),
+        Arc::new(time.finish()),
+        Arc::new(session.finish()),
+        Arc::new(cxid.finish()),
+        Arc::new(multi_index.finish()),
+        Arc::new(op.finish()),
+        Arc::new(path.finish()),
+        Arc::new(data.finish()),
+    ];
+
+    Ok(RecordBatch::try_new(txn_schema(), columns)?)
+}
+
+/// Convert data nodes to a record batch.
+pub fn node_batch(nodes: &[Node]) -> Result<RecordBatch, PersistenceError> {
+    let mut path = StringBuilder::new();
+    let mut data = BinaryBuilder::new();
+    let mut acl = Int64Builder::with_capacity(nodes.len());
+    let mut czxid = Int64Builder::with_capacity(nodes.len());
+    let mut mzxid = Int64Builder::with_capacity(nodes.len());
+    let mut pzxid = Int64Builder::with_capacity(nodes.len());
+    let mut ctime = TimestampMillisecondBuilder::with_capacity(nodes.len());
+    let mut mtime = TimestampMillisecondBuilder::with_capacity(nodes.len());
+    let mut version = Int32Builder::with_capacity(nodes.len());
+    let mut cversion = Int32Builder::with_capacity(nodes.len());
+    let mut aversion = Int32Builder::with_capacity(nodes.len());
+    let mut ephemeral_owner = Int64Builder::with_capacity(nodes.len());
+
+    for (node_path, node) in nodes {
+        let stat = &node.stat;
+        path.append_value(node_path);
+        data.append_value(&node.data);
+        acl.append_value(node.acl.0);
+        czxid.append_value(stat.czxid.0);
+        mzxid.append_value(stat.mzxid.0);
+        pzxid.append_value(stat.pzxid.0);
+        ctime.append_value(stat.ctime.0 as i64);
+        mtime.append_value(stat.mtime.0 as i64);
+        version.append_value(stat.version.0);
+        cversion.append_value(stat.cversion.0);
+        aversion.append_value(stat.aversion.0);
+        ephemeral_owner.append_option(stat.ephemeral_info.owner().map(|s| s.0));
+    }
+
+    let columns: Vec<ArrayRef> = vec![
+        Arc::new(path.finish()),
+        Arc::new(data.finish()),
+        Arc::new(acl.finish()),
+        Arc::new(czxid.finish()),
+        Arc::new(mzxid.finish()),
+        Arc::new(pzxid.finish()),
+        Arc::new(ctime.finish()),
+        Arc::new(mtime.finish()),
+        Arc::new(version.finish()),
+        Arc::new(cversion.finish()),
+        Arc::new(aversion.finish()),
+        Arc::new(ephemeral_owner.finish()),
+    ];
+
+    Ok(RecordBatch::try_new(node_schema(), columns)?)
+}
+
+/// Iterator grouping items in record batches of a given size.
+pub struct Batches<I, T> {
+    items: I,
+    size: usize,
+    convert: fn(&[T]) -> Result<RecordBatch, PersistenceError>,
+    redactor: Option<Redactor>,
+}
+
+impl<I, T> Batches<I, T> {
+    fn new(items: I, size: usize, convert: fn(&[T]) -> Result<RecordBatch, PersistenceError>) -> Batches<I, T> {
+        Batches {
+            items,
+            size: size.max(1),
+            convert,
+            redactor: None,
+        }
+    }
+
+    /// Redact data before conversion.
+    pub fn redact(mut self, redactor: Redactor) -> Batches<I, T> {
+        self.redactor = Some(redactor);
+        self
+    }
+}
+
+impl<I: Iterator<Item = Result<T, PersistenceError>>, T: Redact> Iterator for Batches<I, T> {
+    type Item = Result<RecordBatch, PersistenceError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut batch = Vec::with_capacity(self.size);
+        while batch.len() < self.size {
+            match self.items.next() {
+                None => break,
+                Some(Err(e)) => return Some(Err(e)),
+                Some(Ok(mut item)) => {
+                    if let Some(redactor) = &self.redactor {
+                        item.redact(redactor);
+                    }
+                    batch.push(item);
+                }
+            }
+        }
+
+        if batch.is_empty() {
+            None
+        } else {
+            Some((self.convert)(&batch))
+        }
+    }
+}
+
+/// Convert transactions to record batches of `batch_size` records.
+pub fn txn_batches<I>(
+    txns: I,
+    batch_size: usize,
+) -> Batches<impl Iterator<Item = Result<TxnRecord, PersistenceError>>, TxnRecord>
+where
+    I: IntoIterator<Item = Result<Txn, PersistenceError>>,
+{
+    let records = txns.into_iter().flat_map(|r| match r {
+        Ok(txn) => TxnRecord::from_txn(&txn).into_iter().map(Ok).collect::<Vec<_>>(),
+        Err(e) => vec![Err(e)],
+    });
+
+    Batches::new(records, batch_size, txn_batch)
+}
+
+/// Read txnlog files as record batches of `batch_size` records.
+pub fn txnlog_to_arrow(
+    paths: impl IntoIterator<Item = impl AsRef<Path>>,
+    batch_size: usize,
+) -> Result<Batches<impl Iterator<Item = Result<TxnRecord, PersistenceError>>, TxnRecord>, PersistenceError> {
+    let files = paths.into_iter().map(TxnlogFile::new).collect::<Result<Vec<_>, _>>()?;
+
+    Ok(txn_batches(files.into_iter().flatten(), batch_size))
+}
+
+/// Read the data nodes of a snapshot as record batches of `batch_size` nodes.
+pub fn snapshot_to_arrow(
+    snapshot: SnapshotFile<InitState>,
+    batch_size: usize,
+) -> Result<Batches<impl Iterator<Item = Result<Node, PersistenceError>>, Node>, PersistenceError> {
+    let (_, nodes) = snapshot.sessions()?.acl_map()?;
+
+    Ok(Batches::new(nodes, batch_size, node_batch))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::persistence::export::redact::Replacement;
+    use crate::persistence::testing::*;
+    use arrow_array::cast::AsArray;
+    use arrow_array::types::Int64Type;
+    use arrow_array::Array;
+
+    #[test]
+    fn txnlog_batches() {
+        let dir = temp_dir("arrow-txnlog");
+        let log = write_txnlog(
+            &dir,
+            1,
+            &[
+                txn_body(1, 10, 1, &create_op("/app", "foo", false)),
+                txn_body(2, 10, 5, &path_op("/app", Some("bar"))),
+                txn_body(3, 10, -11, &[]),
+            ],
+        );
+
+        let batches = txnlog_to_arrow(vec![log], 2)
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+
+        assert_eq!(
+            batches.iter().map(RecordBatch::num_rows).collect::<Vec<_>>(),
+            vec![2, 1]
+        );
+        assert_eq!(batches[0].schema(), txn_schema());
+        assert_eq!(batches[0].column(6).as_string::<i32>().value(1), "/app");
+        assert!(batches[1].column(6).is_null(0));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn snapshot_batches() {
+        let path = write_snapshot(
+            "arrow-snapshot",
+            &[(1, 10_000)],
+            &[],
+            &[("", node("", -1, 0, 0)), ("/a", node("x", -1, 1, 1))],
+        );
+
+        let redactor = Redactor::new(Replacement::Placeholder(b"-".to_vec()))
+            .path("/a")
+            .unwrap();
+        let batches = snapshot_to_arrow(SnapshotFile::new(&path).unwrap(), 10)
+            .unwrap()
+            .redact(redactor)
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+
+        assert_eq!(batches.len(), 1);
+        let owners = batches[0].column(11).as_primitive::<Int64Type>();
+        assert!(owners.is_null(0));
+        assert_eq!(owners.value(1), 1);
+        assert_eq!(batches[0].column(1).as_binary::<i32>().value(1), b"-");
+
+        remove_snapshot(&path);
+    }
+}
@@ -0,0 +1,151 @@
+//! Export of transactions to formats that analytics tools can query.
+//!
+//! Transactions are flattened to `TxnRecord`s, which have a stable schema: operations of multi
+//! transactions are exported as separate records sharing the same zxid.
+//!
+//! Exporters accept a `redact::Redactor` to hide sensitive node data.
+
+#[cfg(feature = "arrow")]
+pub mod arrow;
+#[cfg(feature = "parquet")]
+pub mod parquet;
+pub mod redact;
+
+use self::redact::Redactor;
+use super::snapshot::DataNode;
+use super::txnlog::{MultiTxnOperation, Txn, TxnOperation};
+
+/// Exported items whose data can be redacted.
+pub trait Redact {
+    fn redact(&mut self, redactor: &Redactor);
+}
+
+impl Redact for TxnRecord {
+    fn redact(&mut self, redactor: &Redactor) {
+        if let Some(data) = &mut self.data {
+            redactor.redact(self.path.as_deref(), data);
+        }
+    }
+}
+
+impl Redact for (String, DataNode) {
+    fn redact(&mut self, redactor: &Redactor) {
+        redactor.redact(Some(&self.0), &mut self.1.data);
+    }
+}
+
+/// A flattened transaction, or operation of a multi transaction.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TxnRecord {
+    pub zxid: i64,
+    /// Milliseconds since the epoch
+    pub time: u64,
+    pub session: i64,
+    pub cxid: i32,
+    /// Position of the operation in a multi transaction
+    pub multi_index: Option<i32>,
+    /// Name of the operation, as in `OpCode`
+    pub op: &'static str,
+    pub path: Option<String>,
+    /// Payload of creations, data changes and reconfigurations
+    pub data: Option<Vec<u8>>,
+}
+
+impl TxnRecord {
+    /// Flatten a transaction to one record, or one record per operation for multi transactions.
+    pub fn from_txn(txn: &Txn) -> Vec<TxnRecord> {
+        let record = |multi_index, op, path: Option<&str>, data: Option<&[u8]>| TxnRecord {
+            zxid: txn.header.zxid.0,
+            time: txn.header.time.0,
+            session: txn.header.client_id.0,
+            cxid: txn.header.cxid.0,
+            multi_index,
+            op,
+            path: path.map(str::to_owned),
+            data: data.map(<[u8]>::to_vec),
+        };
+
+        use TxnOperation::*;
+        let single = match &txn.op {
+            CreateSession(_) => record(None, "CreateSession", None, None),
+            CloseSession => record(None, "CloseSession", None, None),
+            Create(t) => record(None, "Create", Some(&t.path), Some(&t.data)),
+            Create2(t) => record(None, "Create2", Some(&t.path), Some(&t.data)),
+            CreateTTL(t) => record(None, "CreateTTL", Some(&t.path), Some(&t.data)),
+            CreateContainer(t) => record(None, "CreateContainer", Some(&t.path), Some(&t.data)),
+            Delete(t) => record(None, "Delete", Some(&t.path), None),
+            DeleteContainer(t) => record(None, "DeleteContainer", Some(&t.path), None),
+            Reconfig(t) => record(None, "Reconfig", Some(&t.path), Some(&t.data)),
+            SetData(t) => record(None, "SetData", Some(&t.path), Some(&t.data)),
+            SetACL(t) => record(None, "SetACL", Some(&t.path), None),
+            Error(_) => record(None, "Error", None, None),
+            Multi(multi) => {
+                return multi
+                    .txns
+                    .iter()
+                    .enumerate()
+                    .map(|(i, op)| {
+                        let i = Some(i as i32);
+                        use MultiTxnOperation as M;
+                        match op {
+                            M::Create(t) => record(i, "Create", Some(&t.path), Some(&t.data)),
+                            M::Create2(t) => record(i, "Create2", Some(&t.path), Some(&t.data)),
+                            M::CreateTTL(t) => record(i, "CreateTTL", Some(&t.path), Some(&t.data)),
+                            M::CreateContainer(t) => record(i, "CreateContainer", Some(&t.path), Some(&t.data)),
+                            M::Delete(t) => record(i, "Delete", Some(&t.path), None),
+                            M::DeleteContainer(t) => record(i, "DeleteContainer", Some(&t.path), None),
+                            M::SetData(t) => record(i, "SetData", Some(&t.path), Some(&t.data)),
+                            M::Error(_) => record(i, "Error", None, None),
+                            M::Check(t) => record(i, "Check", Some(&t.path), None),
+                        }
+                    })
+                    .collect();
+            }
+        };
+
+        vec![single]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::persistence::testing::*;
+    use crate::persistence::txnlog::TxnlogFile;
+
+    #[test]
+    fn flatten_txns() {
+        let dir = temp_dir("flatten-txns");
+        let path = write_txnlog(
+            &dir,
+            1,
+            &[
+                txn_body(1, 10, 1, &create_op("/app", "foo", false)),
+                txn_body(2, 10, -11, &[]),
+            ],
+        );
+
+        let records = TxnlogFile::new(path)
+            .unwrap()
+            .flat_map(|txn| TxnRecord::from_txn(&txn.unwrap()))
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            records[0],
+            TxnRecord {
+                zxid: 1,
+                time: 1000,
+                session: 10,
+                cxid: 0,
+                multi_index: None,
+                op: "Create",
+                path: Some("/app".to_owned()),
+                data: Some(b"foo".to_vec()),
+            }
+        );
+        assert_eq!(records[1].op, "CloseSession");
+        assert_eq!(records[1].path, None);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
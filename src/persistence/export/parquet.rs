@@ -0,0 +1,84 @@
+//! Streaming export of transactions to Parquet files.
+
+use parquet::arrow::ArrowWriter;
+use parquet::file::properties::WriterProperties;
+use std::io::Write;
+
+use super::arrow::{txn_batches, txn_schema};
+use super::redact::Redactor;
+use crate::error::PersistenceError;
+use crate::persistence::txnlog::Txn;
+
+/// Write transactions to a Parquet file, in row groups of `batch_size` records so that logs of any
+/// size can be exported, redacting data if a redactor is provided. Returns the number of records
+/// written.
+pub fn write_txns<W, I>(
+    txns: I,
+    output: W,
+    batch_size: usize,
+    redactor: Option<&Redactor>,
+) -> Result<u64, PersistenceError>
+where
+    W: Write + Send,
+    I: IntoIterator<Item = Result<Txn, PersistenceError>>,
+{
+    let props = WriterProperties::builder()
+        .set_max_row_group_size(batch_size.max(1))
+        .build();
+    let mut writer = ArrowWriter::try_new(output, txn_schema(), Some(props))?;
+    let mut count = 0;
+
+    let mut batches = txn_batches(txns, batch_size);
+    if let Some(redactor) = redactor {
+        batches = batches.redact(redactor.clone());
+    }
+
+    for batch in batches {
+        let batch = batch?;
+        writer.write(&batch)?;
+        count += batch.num_rows() as u64;
+    }
+
+    writer.close()?;
+    Ok(count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::persistence::testing::*;
+    use crate::persistence::txnlog::TxnlogFile;
+    use parquet::file::reader::{FileReader, SerializedFileReader};
+
+    #[test]
+    fn export_parquet() {
+        let dir = temp_dir("export-parquet");
+        let log = write_txnlog(
+            &dir,
+            1,
+            &[
+                txn_body(1, 10, 1, &create_op("/app", "foo", false)),
+                txn_body(2, 10, 5, &path_op("/app", Some("bar"))),
+                txn_body(3, 10, -11, &[]),
+            ],
+        );
+
+        let output = dir.join("txns.parquet");
+        let count = write_txns(
+            TxnlogFile::new(log).unwrap(),
+            std::fs::File::create(&output).unwrap(),
+            2,
+            None,
+        )
+        .unwrap();
+        assert_eq!(count, 3);
+
+        let reader = SerializedFileReader::new(std::fs::File::open(&output).unwrap()).unwrap();
+        let metadata = reader.metadata();
+        assert_eq!(metadata.file_metadata().num_rows(), 3);
+        assert_eq!(metadata.num_row_groups(), 2);
+        assert_eq!(metadata.file_metadata().schema_descr().num_columns(), 8);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,139 @@
+//! Redaction of node data in exports.
+//!
+//! Snapshots and transactions often contain credentials and other secrets. A `Redactor` replaces
+//! the data of nodes selected by path or by content with a hash or a placeholder, so that exports
+//! can be shared safely.
+
+use regex::bytes::Regex;
+use sha2::{Digest, Sha256};
+use std::fmt::Write;
+use std::sync::Arc;
+
+use crate::error::ParseError;
+use crate::path::PathMatcher;
+
+/// A case-insensitive pattern for data that looks like it contains credentials, such as
+/// `password=...` or `"apiKey": ...`. Use it with `Redactor::content`.
+pub const CREDENTIALS_PATTERN: &str = r#"(?i)(password|passwd|secret|token|api[_-]?key|credentials?)"?\s*[=:]"#;
+
+/// What redacted data is replaced with.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Replacement {
+    /// `sha256:` followed by the hex-encoded SHA-256 of the data. Identical values can still be
+    /// correlated, but low-entropy values can be brute-forced.
+    Hash,
+    /// A fixed value
+    Placeholder(Vec<u8>),
+}
+
+type Detector = Arc<dyn Fn(&[u8]) -> bool + Send + Sync>;
+
+/// Selects the data to redact, by path or content, and how to replace it.
+#[derive(Clone)]
+pub struct Redactor {
+    paths: Vec<PathMatcher>,
+    detectors: Vec<Detector>,
+    replacement: Replacement,
+}
+
+impl std::fmt::Debug for Redactor {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("Redactor")
+            .field("paths", &self.paths)
+            .field("detectors", &self.detectors.len())
+            .field("replacement", &self.replacement)
+            .finish()
+    }
+}
+
+impl Redactor {
+    /// A redactor that selects nothing until paths or detectors are added.
+    pub fn new(replacement: Replacement) -> Redactor {
+        Redactor {
+            paths: Vec::new(),
+            detectors: Vec::new(),
+            replacement,
+        }
+    }
+
+    /// Redact the data of nodes matching a path expression (see `PathMatcher::parse`).
+    pub fn path(mut self, expr: &str) -> Result<Redactor, ParseError> {
+        self.paths.push(PathMatcher::parse(expr)?);
+        Ok(self)
+    }
+
+    /// Redact data matching a regular expression.
+    pub fn content(self, regex: &str) -> Result<Redactor, ParseError> {
+        let regex = Regex::new(regex)?;
+        Ok(self.detector(move |data| regex.is_match(data)))
+    }
+
+    /// Redact data selected by a custom detector.
+    pub fn detector(mut self, detector: impl Fn(&[u8]) -> bool + Send + Sync + 'static) -> Redactor {
+        self.detectors.push(Arc::new(detector));
+        self
+    }
+
+    /// Should the data of a node be redacted? Empty data never is, as there's nothing to hide.
+    pub fn matches(&self, path: Option<&str>, data: &[u8]) -> bool {
+        if data.is_empty() {
+            return false;
+        }
+        path.is_some_and(|p| self.paths.iter().any(|m| m.matches(p))) || self.detectors.iter().any(|d| d(data))
+    }
+
+    /// Replace data in place if it should be redacted, returning `true` if it was.
+    pub fn redact(&self, path: Option<&str>, data: &mut Vec<u8>) -> bool {
+        if !self.matches(path, data) {
+            return false;
+        }
+
+        *data = match &self.replacement {
+            Replacement::Placeholder(placeholder) => placeholder.clone(),
+            Replacement::Hash => {
+                let mut hash = String::from("sha256:");
+                for byte in Sha256::digest(&data[..]) {
+                    write!(hash, "{:02x}", byte).unwrap();
+                }
+                hash.into_bytes()
+            }
+        };
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn redact_data() {
+        let redactor = Redactor::new(Replacement::Placeholder(b"<redacted>".to_vec()))
+            .path("/app/secrets/**")
+            .unwrap()
+            .content(CREDENTIALS_PATTERN)
+            .unwrap();
+
+        let mut data = b"foo".to_vec();
+        assert!(redactor.redact(Some("/app/secrets/db"), &mut data));
+        assert_eq!(data, b"<redacted>");
+
+        let mut data = b"{\"apiKey\": \"xyz\"}".to_vec();
+        assert!(redactor.redact(Some("/app/config"), &mut data));
+
+        let mut data = b"user=bob".to_vec();
+        assert!(!redactor.redact(Some("/app/config"), &mut data));
+        assert_eq!(data, b"user=bob");
+
+        let mut data = Vec::new();
+        assert!(!redactor.redact(Some("/app/secrets/empty"), &mut data));
+
+        let redactor = Redactor::new(Replacement::Hash).path("/a").unwrap();
+        let mut data = b"abc".to_vec();
+        redactor.redact(Some("/a"), &mut data);
+        assert_eq!(
+            String::from_utf8(data).unwrap(),
+            "sha256:ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+    }
+}
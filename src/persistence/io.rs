@@ -0,0 +1,279 @@
+//! File reading options for snapshots and txnlogs.
+//!
+//! Scanning multi-GB files fills the page cache with pages that won't be read again, evicting the
+//! working set of other processes such as the ZooKeeper server itself. With the `unix` feature,
+//! `ReadOptions` can give the kernel hints to avoid this. Hints are ignored on platforms without
+//! `posix_fadvise`.
+//!
+//! With the `mmap` feature, files can also be memory-mapped instead of read through a buffer,
+//! which avoids a system call per buffer refill on fast storage.
+
+use std::fs::File;
+use std::io::{BufReader, Read, Seek, SeekFrom};
+use std::path::Path;
+
+/// Default size of read buffers, the same as `std::io::BufReader`
+pub const DEFAULT_BUFFER_SIZE: usize = 8 * 1024;
+
+/// Read pages are dropped from the cache every time this number of bytes has been read
+const DROP_CACHE_INTERVAL: u64 = 16 * 1024 * 1024;
+
+/// Options to open snapshots and txnlogs.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct ReadOptions {
+    /// Size of the read buffer
+    pub buffer_size: usize,
+    /// Tell the kernel that the file will be read sequentially, to increase read-ahead
+    /// (`POSIX_FADV_SEQUENTIAL`)
+    pub sequential: bool,
+    /// Drop pages from the page cache once they have been read (`POSIX_FADV_DONTNEED`)
+    pub drop_cache: bool,
+    /// Memory-map the file. Reads then copy straight from the mapping, and the read buffer is only
+    /// used for reads smaller than `buffer_size`. Pages of a mapped file are not dropped from the
+    /// page cache.
+    ///
+    /// The file must not be truncated while it is mapped, which would crash the process: only use
+    /// this on files that are no longer written to, e.g. snapshots and txnlogs older than the
+    /// current one.
+    #[cfg(feature = "mmap")]
+    pub mmap: bool,
+}
+
+impl Default for ReadOptions {
+    fn default() -> Self {
+        ReadOptions {
+            buffer_size: DEFAULT_BUFFER_SIZE,
+            sequential: false,
+            drop_cache: false,
+            #[cfg(feature = "mmap")]
+            mmap: false,
+        }
+    }
+}
+
+impl ReadOptions {
+    /// Options for scans of large files: a 1 MiB buffer, and both kernel hints.
+    pub fn scan() -> ReadOptions {
+        ReadOptions {
+            buffer_size: 1024 * 1024,
+            sequential: true,
+            drop_cache: true,
+            #[cfg(feature = "mmap")]
+            mmap: false,
+        }
+    }
+
+    /// Options for scans of large files that are memory-mapped. See `mmap` for restrictions.
+    #[cfg(feature = "mmap")]
+    pub fn mapped() -> ReadOptions {
+        ReadOptions {
+            buffer_size: 1,
+            sequential: true,
+            drop_cache: false,
+            mmap: true,
+        }
+    }
+
+    /// Open a file with these options.
+    pub fn open(&self, path: impl AsRef<Path>) -> std::io::Result<BufReader<ScanReader>> {
+        let file = File::open(path)?;
+
+        #[cfg(feature = "mmap")]
+        {
+            if self.mmap {
+                let reader = ScanReader {
+                    input: Input::Mapped(std::io::Cursor::new(map(&file, self.sequential)?)),
+                    drop_cache: false,
+                    position: 0,
+                    dropped: 0,
+                };
+                return Ok(BufReader::with_capacity(self.buffer_size.max(1), reader));
+            }
+        }
+
+        if self.sequential {
+            advise(&file, 0, 0, Advice::Sequential)?;
+        }
+
+        let reader = ScanReader {
+            input: Input::File(file),
+            drop_cache: self.drop_cache,
+            position: 0,
+            dropped: 0,
+        };
+        Ok(BufReader::with_capacity(self.buffer_size.max(1), reader))
+    }
+}
+
+/// Memory-map a file. See `ReadOptions::mmap` for restrictions.
+#[cfg(feature = "mmap")]
+#[cfg_attr(not(unix), allow(unused_variables))]
+pub(crate) fn map(file: &File, sequential: bool) -> std::io::Result<memmap2::Mmap> {
+    // Safety: see the restrictions on `mmap`
+    let map = unsafe { memmap2::Mmap::map(file)? };
+    #[cfg(unix)]
+    {
+        if sequential && !map.is_empty() {
+            map.advise(memmap2::Advice::Sequential)?;
+        }
+    }
+    Ok(map)
+}
+
+/// A file read sequentially, that can drop read pages from the page cache.
+#[derive(Debug)]
+pub struct ScanReader {
+    input: Input,
+    drop_cache: bool,
+    position: u64,
+    /// Pages before this position have been dropped
+    dropped: u64,
+}
+
+#[derive(Debug)]
+enum Input {
+    File(File),
+    #[cfg(feature = "mmap")]
+    Mapped(std::io::Cursor<memmap2::Mmap>),
+}
+
+impl Read for ScanReader {
+    #[cfg_attr(not(feature = "mmap"), allow(clippy::infallible_destructuring_match))]
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let file = match &mut self.input {
+            Input::File(file) => file,
+            #[cfg(feature = "mmap")]
+            Input::Mapped(map) => return map.read(buf),
+        };
+
+        let len = file.read(buf)?;
+        self.position += len as u64;
+
+        let pending = self.position - self.dropped;
+        if self.drop_cache && (pending >= DROP_CACHE_INTERVAL || (len == 0 && pending > 0)) {
+            advise(file, self.dropped, pending, Advice::DontNeed)?;
+            self.dropped = self.position;
+        }
+
+        Ok(len)
+    }
+}
+
+impl Seek for ScanReader {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let position = match &mut self.input {
+            Input::File(file) => file.seek(pos)?,
+            #[cfg(feature = "mmap")]
+            Input::Mapped(map) => map.seek(pos)?,
+        };
+        self.position = position;
+        self.dropped = self.dropped.min(position);
+        Ok(position)
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+enum Advice {
+    Sequential,
+    DontNeed,
+}
+
+#[cfg(all(
+    feature = "unix",
+    any(target_os = "linux", target_os = "android", target_os = "freebsd")
+))]
+fn advise(file: &File, offset: u64, len: u64, advice: Advice) -> std::io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    let advice = match advice {
+        Advice::Sequential => libc::POSIX_FADV_SEQUENTIAL,
+        Advice::DontNeed => libc::POSIX_FADV_DONTNEED,
+    };
+
+    let result = unsafe { libc::posix_fadvise(file.as_raw_fd(), offset as libc::off_t, len as libc::off_t, advice) };
+    if result == 0 {
+        Ok(())
+    } else {
+        Err(std::io::Error::from_raw_os_error(result))
+    }
+}
+
+#[cfg(not(all(
+    feature = "unix",
+    any(target_os = "linux", target_os = "android", target_os = "freebsd")
+)))]
+fn advise(_file: &File, _offset: u64, _len: u64, _advice: Advice) -> std::io::Result<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::persistence::testing::*;
+    use crate::persistence::txnlog::TxnlogFile;
+    use crate::ServerVersion;
+
+    #[test]
+    fn read_options() {
+        let dir = temp_dir("read-options");
+        let bodies = (1..=100)
+            .map(|zxid| txn_body(zxid, 10, 5, &path_op("/app", Some("data"))))
+            .collect::<Vec<_>>();
+        let log = write_txnlog(&dir, 1, &bodies);
+
+        let options = ReadOptions {
+            buffer_size: 7,
+            ..ReadOptions::scan()
+        };
+        let txns = TxnlogFile::with_options(&log, ServerVersion::LATEST, &options)
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        let expected = TxnlogFile::new(&log).unwrap().collect::<Result<Vec<_>, _>>().unwrap();
+        assert_eq!(txns.len(), 100);
+        assert_eq!(txns, expected);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(feature = "mmap")]
+    #[test]
+    fn mapped_reads() {
+        use crate::persistence::snapshot::SnapshotFile;
+
+        let dir = temp_dir("mapped-reads");
+        let bodies = (1..=100)
+            .map(|zxid| txn_body(zxid, 10, 5, &path_op("/app", Some("data"))))
+            .collect::<Vec<_>>();
+        let log = write_txnlog(&dir, 1, &bodies);
+
+        let txns = TxnlogFile::with_options(&log, ServerVersion::LATEST, &ReadOptions::mapped())
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        let expected = TxnlogFile::new(&log).unwrap().collect::<Result<Vec<_>, _>>().unwrap();
+        assert_eq!(txns.len(), 100);
+        assert_eq!(txns, expected);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let snapshot = write_snapshot(
+            "mapped-snapshot",
+            &[(1, 3000)],
+            &[],
+            &[("", node("", -1, 0, 0)), ("/app", node("x", -1, 1, 2))],
+        );
+        let nodes = SnapshotFile::with_options(&snapshot, &ReadOptions::mapped())
+            .unwrap()
+            .sessions()
+            .unwrap()
+            .acl_map()
+            .unwrap()
+            .1
+            .map(|n| n.unwrap().0)
+            .collect::<Vec<_>>();
+        assert_eq!(nodes, vec!["".to_owned(), "/app".to_owned()]);
+
+        remove_snapshot(&snapshot);
+    }
+}
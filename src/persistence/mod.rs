@@ -5,8 +5,31 @@ use serde_derive::Serialize;
 
 use std::path::Path;
 
+#[cfg(feature = "alloc-audit")]
+pub mod alloc_audit;
+pub mod analysis;
+pub mod anonymize;
+pub mod audit;
+pub mod check;
+pub mod checksum;
+pub mod compare;
+pub mod compression;
+pub mod datatree;
+pub mod digest;
+pub mod export;
+pub mod io;
+pub mod query;
+pub mod replay;
 pub mod snapshot;
+pub mod transform;
 pub mod txnlog;
+pub mod txnsnaplog;
+
+#[cfg(test)]
+pub(crate) mod testing;
+
+pub use digest::file_digest;
+pub use txnsnaplog::FileTxnSnapLog;
 
 use crate::Zxid;
 
@@ -22,7 +45,12 @@ pub const TXNLOG_MAGIC: i32 = 0x5a4b_4c47; // "ZKLG"
 pub const SNAP_MAGIC: i32 = 0x5a4b_534e; // "ZKSN"
 
 pub fn zxid_from_path(path: impl AsRef<Path>) -> Option<Zxid> {
-    let path = path.as_ref();
+    let mut path = path.as_ref();
+
+    // Compressed snapshots have the compression suffix after the zxid
+    if compression::Compression::from_path(path) != compression::Compression::None {
+        path = Path::new(path.file_stem()?);
+    }
 
     let ext = path.extension()?.to_str()?;
     let value = i64::from_str_radix(ext, 16).ok()?;
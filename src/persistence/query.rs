@@ -0,0 +1,348 @@
+//! A small query language to select data nodes in snapshots.
+//!
+//! A query is a path pattern optionally followed by predicates in brackets, e.g.
+//! `/services/**[data ~= 'v2' and version > 3]`.
+//!
+//! Path patterns are globs (see `crate::path::Glob`): `*` matches any part of a segment, `?` any
+//! character and `**` any number of segments (including none).
+//!
+//! Predicates compare a field to a value, and are joined with `and`:
+//! - `data` and `data_length` are the node's data and its length,
+//! - `version`, `cversion`, `aversion`, `czxid`, `mzxid`, `pzxid`, `ctime`, `mtime` are stat fields,
+//! - `ephemeral_owner` is the owning session of an ephemeral node (zero otherwise).
+//!
+//! Operators are `=`, `!=`, `<`, `<=`, `>`, `>=` and `~=` (data contains a string). Values are
+//! strings in single quotes or integers, in decimal or hexadecimal (`0x` prefix).
+
+use super::snapshot::DataNode;
+use crate::error::{ParseError, PersistenceError};
+use crate::path::Glob;
+
+/// A parsed query.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Query {
+    pattern: Glob,
+    predicates: Vec<Predicate>,
+}
+
+/// Parse a query.
+pub fn select(query: &str) -> Result<Query, ParseError> {
+    Query::parse(query)
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Field {
+    Data,
+    DataLength,
+    Version,
+    CVersion,
+    AVersion,
+    Czxid,
+    Mzxid,
+    Pzxid,
+    Ctime,
+    Mtime,
+    EphemeralOwner,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Op {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Contains,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Value {
+    Str(String),
+    Int(i64),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Predicate {
+    field: Field,
+    op: Op,
+    value: Value,
+}
+
+impl Query {
+    pub fn parse(query: &str) -> Result<Query, ParseError> {
+        let query = query.trim();
+        let (path, predicates) = match query.find('[') {
+            Some(idx) => {
+                let preds = query[idx + 1..]
+                    .strip_suffix(']')
+                    .ok_or_else(|| ParseError::invalid("query, missing ']'", query))?;
+                (&query[..idx], parse_predicates(preds)?)
+            }
+            None => (query, Vec::new()),
+        };
+
+        let pattern = Glob::new(path.trim())?;
+
+        Ok(Query { pattern, predicates })
+    }
+
+    /// Does a path match this query's path pattern?
+    pub fn matches_path(&self, path: &str) -> bool {
+        self.pattern.matches(path)
+    }
+
+    /// Does a data node match this query?
+    pub fn matches(&self, path: &str, node: &DataNode) -> bool {
+        self.matches_path(path) && self.predicates.iter().all(|p| p.matches(node))
+    }
+
+    /// Filter the data nodes of a snapshot (see `SnapshotFile<DataNodesState>`), keeping errors.
+    pub fn filter<'a, I>(&'a self, nodes: I) -> impl Iterator<Item = Result<(String, DataNode), PersistenceError>> + 'a
+    where
+        I: IntoIterator<Item = Result<(String, DataNode), PersistenceError>> + 'a,
+    {
+        nodes.into_iter().filter(move |r| match r {
+            Ok((path, node)) => self.matches(path, node),
+            Err(_) => true,
+        })
+    }
+}
+
+fn parse_predicates(text: &str) -> Result<Vec<Predicate>, ParseError> {
+    let mut tokens = tokenize(text)?.into_iter();
+    let mut result = Vec::new();
+
+    loop {
+        let field = match tokens.next() {
+            Some(Token::Word(w)) => Field::parse(&w)?,
+            other => {
+                return Err(ParseError::invalid(
+                    "query, expecting a field name",
+                    format!("{:?}", other),
+                ))
+            }
+        };
+        let op = match tokens.next() {
+            Some(Token::Op(op)) => op,
+            other => {
+                return Err(ParseError::invalid(
+                    "query, expecting an operator",
+                    format!("{:?}", other),
+                ))
+            }
+        };
+        let value = match tokens.next() {
+            Some(Token::Str(s)) => Value::Str(s),
+            Some(Token::Word(w)) => Value::Int(parse_int(&w)?),
+            other => return Err(ParseError::invalid("query, expecting a value", format!("{:?}", other))),
+        };
+
+        let predicate = Predicate { field, op, value };
+        predicate.check()?;
+        result.push(predicate);
+
+        match tokens.next() {
+            None => return Ok(result),
+            Some(Token::Word(ref w)) if w == "and" => {}
+            other => return Err(ParseError::invalid("query, expecting 'and'", format!("{:?}", other))),
+        }
+    }
+}
+
+fn parse_int(s: &str) -> Result<i64, ParseError> {
+    let result = match s.strip_prefix("0x") {
+        Some(hex) => i64::from_str_radix(hex, 16),
+        None => s.parse::<i64>(),
+    };
+    result.map_err(|_| ParseError::invalid("number", s))
+}
+
+#[derive(Debug, PartialEq)]
+enum Token {
+    Word(String),
+    Str(String),
+    Op(Op),
+}
+
+fn tokenize(text: &str) -> Result<Vec<Token>, ParseError> {
+    let mut tokens = Vec::new();
+    let mut chars = text.chars().peekable();
+
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+        } else if c == '\'' {
+            chars.next();
+            let mut s = String::new();
+            loop {
+                match chars.next() {
+                    Some('\'') => break,
+                    Some(c) => s.push(c),
+                    None => return Err(ParseError::invalid("query, unterminated string", text)),
+                }
+            }
+            tokens.push(Token::Str(s));
+        } else if "=!<>~".contains(c) {
+            chars.next();
+            let eq = chars.peek() == Some(&'=');
+            if eq {
+                chars.next();
+            }
+            let op = match (c, eq) {
+                ('=', false) => Op::Eq,
+                ('!', true) => Op::Ne,
+                ('<', false) => Op::Lt,
+                ('<', true) => Op::Le,
+                ('>', false) => Op::Gt,
+                ('>', true) => Op::Ge,
+                ('~', true) => Op::Contains,
+                _ => return Err(ParseError::invalid("query operator", text)),
+            };
+            tokens.push(Token::Op(op));
+        } else if c.is_alphanumeric() || c == '_' || c == '-' {
+            let mut s = String::new();
+            while let Some(&c) = chars.peek() {
+                if c.is_alphanumeric() || c == '_' || c == '-' {
+                    s.push(c);
+                    chars.next();
+                } else {
+                    break;
+                }
+            }
+            tokens.push(Token::Word(s));
+        } else {
+            return Err(ParseError::invalid(
+                "query, unexpected character",
+                format!("'{}' in {}", c, text),
+            ));
+        }
+    }
+
+    Ok(tokens)
+}
+
+impl Field {
+    fn parse(name: &str) -> Result<Field, ParseError> {
+        let field = match name {
+            "data" => Field::Data,
+            "data_length" => Field::DataLength,
+            "version" => Field::Version,
+            "cversion" => Field::CVersion,
+            "aversion" => Field::AVersion,
+            "czxid" => Field::Czxid,
+            "mzxid" => Field::Mzxid,
+            "pzxid" => Field::Pzxid,
+            "ctime" => Field::Ctime,
+            "mtime" => Field::Mtime,
+            "ephemeral_owner" => Field::EphemeralOwner,
+            _ => return Err(ParseError::invalid("field", name)),
+        };
+        Ok(field)
+    }
+
+    fn int_value(self, node: &DataNode) -> i64 {
+        let stat = &node.stat;
+        match self {
+            Field::Data => 0,
+            Field::DataLength => node.data.len() as i64,
+            Field::Version => i64::from(stat.version.0),
+            Field::CVersion => i64::from(stat.cversion.0),
+            Field::AVersion => i64::from(stat.aversion.0),
+            Field::Czxid => stat.czxid.0,
+            Field::Mzxid => stat.mzxid.0,
+            Field::Pzxid => stat.pzxid.0,
+            Field::Ctime => stat.ctime.0 as i64,
+            Field::Mtime => stat.mtime.0 as i64,
+            Field::EphemeralOwner => stat.ephemeral_info.owner().map_or(0, |s| s.0),
+        }
+    }
+}
+
+impl Predicate {
+    /// Check that the field, operator and value types are consistent.
+    fn check(&self) -> Result<(), ParseError> {
+        let valid = match (self.field, &self.value) {
+            (Field::Data, Value::Str(_)) => matches!(self.op, Op::Eq | Op::Ne | Op::Contains),
+            (Field::Data, Value::Int(_)) => false,
+            (_, Value::Int(_)) => self.op != Op::Contains,
+            (_, Value::Str(_)) => false,
+        };
+        if valid {
+            Ok(())
+        } else {
+            Err(ParseError::invalid("predicate", format!("{:?}", self)))
+        }
+    }
+
+    fn matches(&self, node: &DataNode) -> bool {
+        match &self.value {
+            Value::Str(s) => {
+                let data = node.data.as_slice();
+                let s = s.as_bytes();
+                match self.op {
+                    Op::Eq => data == s,
+                    Op::Ne => data != s,
+                    Op::Contains => s.is_empty() || data.windows(s.len()).any(|w| w == s),
+                    _ => false,
+                }
+            }
+            Value::Int(v) => {
+                let field = self.field.int_value(node);
+                match self.op {
+                    Op::Eq => field == *v,
+                    Op::Ne => field != *v,
+                    Op::Lt => field < *v,
+                    Op::Le => field <= *v,
+                    Op::Gt => field > *v,
+                    Op::Ge => field >= *v,
+                    Op::Contains => false,
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::persistence::snapshot::{ACLRef, EphemeralInfo, StatPersisted};
+    use crate::{Timestamp, Version, Zxid};
+
+    fn node(data: &str, version: i32) -> DataNode {
+        DataNode {
+            data: data.as_bytes().to_vec(),
+            acl: ACLRef(-1),
+            stat: StatPersisted {
+                czxid: Zxid(1),
+                mzxid: Zxid(2),
+                ctime: Timestamp(0),
+                mtime: Timestamp(0),
+                version: Version(version),
+                cversion: Version(0),
+                aversion: Version(0),
+                ephemeral_info: EphemeralInfo(0),
+                pzxid: Zxid(1),
+            },
+        }
+    }
+
+    #[test]
+    fn predicates() {
+        let q = select("/services/**[data ~= 'v2' and version >= 0x3]").unwrap();
+        assert!(q.matches("/services/foo", &node("api-v2", 3)));
+        assert!(!q.matches("/services/foo", &node("api-v2", 2)));
+        assert!(!q.matches("/services/foo", &node("api-v1", 3)));
+        assert!(!q.matches("/other", &node("api-v2", 3)));
+
+        let q = select("/*[data = 'x' and data_length = 1]").unwrap();
+        assert!(q.matches("/a", &node("x", 0)));
+
+        assert!(select("/a[data > 'x']").is_err());
+        assert!(select("/a[version ~= 1]").is_err());
+        assert!(select("/a[foo = 1]").is_err());
+        assert!(select("/a[version = 1").is_err());
+        assert!(select("/a[version = 1 or version = 2]").is_err());
+        assert!(select("a").is_err());
+    }
+}
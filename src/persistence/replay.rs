@@ -0,0 +1,303 @@
+//! Replay of transaction logs, with progress reporting and cancellation.
+//!
+//! Restoring a data directory can take a long time with large logs: `Replay` iterates on the
+//! transactions following a snapshot like `TxnlogFile::find_txnlog`, periodically reporting its
+//! progress to a callback, and stops when its `CancellationToken` is cancelled.
+
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use super::txnlog::Txn;
+use super::txnlog::TxnlogFile;
+use crate::clock::{self, Clock};
+use crate::error::PersistenceError;
+use crate::ServerVersion;
+use crate::Timestamp;
+use crate::Zxid;
+
+/// A token to cooperatively stop a replay or a `TxnlogTail` from another thread.
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    pub fn new() -> CancellationToken {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Progress of a replay.
+#[derive(Debug, Clone)]
+pub struct ReplayProgress {
+    /// File being read
+    pub file: PathBuf,
+    /// Bytes read in all files
+    pub bytes: u64,
+    /// Total size of all files. Txnlog files are preallocated, so this is an upper bound.
+    pub total_bytes: u64,
+    /// Transactions read so far
+    pub txns: u64,
+    /// Zxid of the last transaction read
+    pub zxid: Option<Zxid>,
+    /// Time elapsed since the start of the replay
+    pub elapsed: std::time::Duration,
+}
+
+impl ReplayProgress {
+    /// Estimated remaining time, based on the bytes read so far. Since txnlog files are
+    /// preallocated this is pessimistic for the last file.
+    pub fn eta(&self) -> Option<std::time::Duration> {
+        if self.bytes == 0 {
+            return None;
+        }
+        let remaining = self.total_bytes.saturating_sub(self.bytes);
+        Some(self.elapsed.mul_f64(remaining as f64 / self.bytes as f64))
+    }
+}
+
+type ProgressCallback = Box<dyn FnMut(&ReplayProgress) + Send>;
+
+/// Default number of transactions between two progress reports
+pub const DEFAULT_PROGRESS_INTERVAL: u64 = 10_000;
+
+/// Iterator on the transactions included in or after a snapshot. Progress is reported every
+/// `progress_interval` transactions and at the end of each file.
+pub struct Replay {
+    paths: std::vec::IntoIter<PathBuf>,
+    version: ServerVersion,
+    snapshot_zxid: Zxid,
+    current: Option<TxnlogFile>,
+    /// Bytes in the files that have been fully read
+    done_bytes: u64,
+    progress: ReplayProgress,
+    progress_interval: u64,
+    on_progress: Option<ProgressCallback>,
+    cancel: CancellationToken,
+    clock: Arc<dyn Clock>,
+    start: Timestamp,
+    finished: bool,
+}
+
+impl Replay {
+    pub fn new(dir: impl AsRef<Path>, snapshot_zxid: Zxid) -> Result<Replay, PersistenceError> {
+        Self::with_version(dir, snapshot_zxid, ServerVersion::LATEST)
+    }
+
+    pub fn with_version(
+        dir: impl AsRef<Path>,
+        snapshot_zxid: Zxid,
+        version: ServerVersion,
+    ) -> Result<Replay, PersistenceError> {
+        let paths = TxnlogFile::find_txnlog_paths(dir, snapshot_zxid)?;
+
+        let mut total_bytes = 0;
+        for path in &paths {
+            total_bytes += std::fs::metadata(path)?.len();
+        }
+
+        let clock = clock::system();
+        Ok(Replay {
+            paths: paths.into_iter(),
+            version,
+            snapshot_zxid,
+            current: None,
+            done_bytes: 0,
+            progress: ReplayProgress {
+                file: PathBuf::new(),
+                bytes: 0,
+                total_bytes,
+                txns: 0,
+                zxid: None,
+                elapsed: std::time::Duration::default(),
+            },
+            progress_interval: DEFAULT_PROGRESS_INTERVAL,
+            on_progress: None,
+            cancel: CancellationToken::new(),
+            start: clock.now(),
+            clock,
+            finished: false,
+        })
+    }
+
+    /// Set the progress callback.
+    pub fn on_progress(mut self, callback: impl FnMut(&ReplayProgress) + Send + 'static) -> Self {
+        self.on_progress = Some(Box::new(callback));
+        self
+    }
+
+    /// Set the number of transactions between two progress reports.
+    pub fn progress_interval(mut self, txns: u64) -> Self {
+        self.progress_interval = txns.max(1);
+        self
+    }
+
+    /// Set the token used to cancel this replay.
+    pub fn cancellation_token(mut self, token: CancellationToken) -> Self {
+        self.cancel = token;
+        self
+    }
+
+    /// Set the clock used to measure the elapsed time.
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.start = clock.now();
+        self.clock = clock;
+        self
+    }
+
+    /// Current progress
+    pub fn progress(&self) -> &ReplayProgress {
+        &self.progress
+    }
+
+    fn report(&mut self) {
+        self.progress.bytes = self.done_bytes + self.current.as_ref().map_or(0, TxnlogFile::position);
+        self.progress.elapsed = self.clock.elapsed(self.start);
+        if let Some(callback) = &mut self.on_progress {
+            callback(&self.progress);
+        }
+    }
+
+    fn read_next(&mut self) -> Result<Option<Txn>, PersistenceError> {
+        loop {
+            if self.cancel.is_cancelled() {
+                return Err(PersistenceError::Cancelled);
+            }
+
+            let file = match &mut self.current {
+                Some(file) => file,
+                None => match self.paths.next() {
+                    None => return Ok(None),
+                    Some(path) => {
+                        let file = TxnlogFile::with_version(&path, self.version)?;
+                        self.progress.file = path;
+                        self.current.get_or_insert(file)
+                    }
+                },
+            };
+
+            match file.next().transpose()? {
+                // Transactions in the first file that are before the snapshot
+                Some(txn) if txn.header.zxid < self.snapshot_zxid => {}
+                Some(txn) => {
+                    self.progress.txns += 1;
+                    self.progress.zxid = Some(txn.header.zxid);
+                    if self.progress.txns.is_multiple_of(self.progress_interval) {
+                        self.report();
+                    }
+                    return Ok(Some(txn));
+                }
+                None => {
+                    self.done_bytes += std::fs::metadata(&self.progress.file)?.len();
+                    self.current = None;
+                    self.report();
+                }
+            }
+        }
+    }
+}
+
+impl Iterator for Replay {
+    type Item = Result<Txn, PersistenceError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.finished {
+            return None;
+        }
+        let result = self.read_next().transpose();
+        // Stop after the end or an error
+        self.finished = !matches!(result, Some(Ok(_)));
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::persistence::checksum::{Adler32, Checksum};
+    use byteorder::{BigEndian, WriteBytesExt};
+    use std::sync::Mutex;
+
+    /// A txnlog with `count` close session transactions, starting at `zxid`
+    fn write_txnlog(path: &Path, zxid: i64, count: i64) {
+        let mut bytes = Vec::new();
+        bytes.write_i32::<BigEndian>(crate::persistence::TXNLOG_MAGIC).unwrap();
+        bytes.write_i32::<BigEndian>(2).unwrap();
+        bytes.write_i64::<BigEndian>(-1).unwrap();
+
+        for i in zxid..zxid + count {
+            let mut record = Vec::new();
+            record.write_i64::<BigEndian>(1).unwrap(); // session
+            record.write_i32::<BigEndian>(0).unwrap(); // cxid
+            record.write_i64::<BigEndian>(i).unwrap(); // zxid
+            record.write_i64::<BigEndian>(0).unwrap(); // time
+            record.write_i32::<BigEndian>(-11).unwrap(); // close session
+
+            bytes.write_u64::<BigEndian>(Adler32.compute(&record)).unwrap();
+            bytes.write_u32::<BigEndian>(record.len() as u32).unwrap();
+            bytes.extend_from_slice(&record);
+            bytes.push(b'B');
+        }
+        bytes.extend_from_slice(&[0; 12]);
+        std::fs::write(path, &bytes).unwrap();
+    }
+
+    #[test]
+    fn replay_progress() {
+        let dir = std::env::temp_dir().join("zookeepers-replay-progress");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+
+        write_txnlog(&dir.join("log.1"), 1, 10);
+        write_txnlog(&dir.join("log.b"), 11, 10);
+
+        let reports = Arc::new(Mutex::new(Vec::new()));
+        let reports2 = reports.clone();
+
+        let replay = Replay::new(&dir, Zxid(5))
+            .unwrap()
+            .progress_interval(4)
+            .on_progress(move |p| reports2.lock().unwrap().push((p.txns, p.bytes, p.total_bytes)));
+
+        let zxids = replay.map(|r| r.unwrap().header.zxid.0).collect::<Vec<_>>();
+        assert_eq!(zxids, (5..=20).collect::<Vec<_>>());
+
+        let reports = reports.lock().unwrap();
+        // Every 4 txns, and at the end of each file
+        assert_eq!(
+            reports.iter().map(|r| r.0).collect::<Vec<_>>(),
+            vec![4, 6, 8, 12, 16, 16]
+        );
+        let last = reports.last().unwrap();
+        assert_eq!(last.1, last.2);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn replay_cancel() {
+        let dir = std::env::temp_dir().join("zookeepers-replay-cancel");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+
+        write_txnlog(&dir.join("log.1"), 1, 10);
+
+        let token = CancellationToken::new();
+        let mut replay = Replay::new(&dir, Zxid(1)).unwrap().cancellation_token(token.clone());
+
+        assert!(replay.next().unwrap().is_ok());
+        token.cancel();
+        assert!(replay.next().unwrap().is_err());
+        assert!(replay.next().is_none());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,25 +1,42 @@
 use serde::Deserialize;
+use serde::Serialize;
 use serde_derive::Deserialize;
 use serde_derive::Serialize;
 
 use crate::Duration;
+use crate::ServerVersion;
 use crate::SessionId;
 use crate::Zxid;
 use crate::ACL;
 use crate::Version;
 use crate::Timestamp;
 
-use failure::Error;
+use super::checksum::{Adler32, Checksum, Crc32};
+use super::compression::{Compression, Compressor, Decompressor};
+use super::io::{ReadOptions, ScanReader};
+use super::FileHeader;
+use crate::serde::de::{Deserializer, JuteRead, SliceRead};
+use crate::serde::Serializer;
+use crate::error::{ParseError, PersistenceError};
 use std::fs::File;
 use std::io::BufReader;
+use std::io::BufWriter;
+use std::io::{Read, Seek, SeekFrom, Write};
 use std::iter::Iterator;
 use std::path::Path;
+use std::path::PathBuf;
 
 use std::collections::HashMap;
 
-#[derive(Debug, PartialEq, Eq, Hash)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
 #[derive(Deserialize, Serialize)]
-pub struct ACLRef(i64);
+pub struct ACLRef(pub i64);
+
+impl ACLRef {
+    /// Reference used for `OPEN_ACL_UNSAFE`, which isn't stored in the ACL cache (see
+    /// `ReferenceCountedACLCache.java`)
+    pub const OPEN_ACL_UNSAFE: ACLRef = ACLRef(-1);
+}
 
 #[derive(Debug)]
 #[derive(Deserialize, Serialize)]
@@ -39,10 +56,62 @@ pub struct ACLCacheEntry {
 /// See EphemeralType.java
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
 #[derive(Serialize, Deserialize)]
-pub struct EphemeralInfo(i64);
+pub struct EphemeralInfo(pub i64);
+
+/// Decoded `EphemeralInfo`
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum EphemeralType {
+    /// Regular node
+    Void,
+    /// Ephemeral node, owned by a session
+    Normal(SessionId),
+    /// Container node
+    Container,
+    /// TTL node, with its time to live in milliseconds
+    TTL(i64),
+}
+
+impl EphemeralInfo {
+    const CONTAINER: i64 = i64::MIN;
+    /// High byte set for extended types, followed by a 16 bits type (zero for TTL)
+    const EXTENDED_MASK: i64 = 0xFF00_0000_0000_0000u64 as i64;
+    const EXTENDED_TYPE_MASK: i64 = 0x00FF_FF00_0000_0000;
+
+    pub fn ephemeral_type(&self) -> EphemeralType {
+        let value = self.0;
+        if value == 0 {
+            EphemeralType::Void
+        } else if value == Self::CONTAINER {
+            EphemeralType::Container
+        } else if value & Self::EXTENDED_MASK == Self::EXTENDED_MASK && value & Self::EXTENDED_TYPE_MASK == 0 {
+            EphemeralType::TTL(value & crate::MAX_TTL)
+        } else {
+            EphemeralType::Normal(SessionId(value))
+        }
+    }
+
+    /// The session owning this node, if it's an ephemeral node
+    pub fn owner(&self) -> Option<SessionId> {
+        match self.ephemeral_type() {
+            EphemeralType::Normal(id) => Some(id),
+            _ => None,
+        }
+    }
+}
+
+impl From<EphemeralType> for EphemeralInfo {
+    fn from(ephemeral_type: EphemeralType) -> EphemeralInfo {
+        match ephemeral_type {
+            EphemeralType::Void => EphemeralInfo(0),
+            EphemeralType::Normal(id) => EphemeralInfo(id.0),
+            EphemeralType::Container => EphemeralInfo(Self::CONTAINER),
+            EphemeralType::TTL(ttl) => EphemeralInfo(Self::EXTENDED_MASK | (ttl & crate::MAX_TTL)),
+        }
+    }
+}
 
 /// Enhanced stats
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 #[derive(Serialize, Deserialize)]
 pub struct StatPersisted {
     /// created zxid
@@ -65,13 +134,32 @@ pub struct StatPersisted {
     pub pzxid: Zxid,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 #[derive(Deserialize, Serialize)]
 pub struct DataNode {
     #[serde(with = "serde_bytes")]
-    data: Vec<u8>,
-    acl: ACLRef,
-    stat: StatPersisted,
+    pub data: Vec<u8>,
+    pub acl: ACLRef,
+    pub stat: StatPersisted,
+}
+
+/// A data node that borrows its data from a snapshot read with `SnapshotFile::from_slice`.
+#[derive(Debug, Clone, PartialEq)]
+#[derive(Deserialize)]
+pub struct DataNodeRef<'a> {
+    pub data: &'a [u8],
+    pub acl: ACLRef,
+    pub stat: StatPersisted,
+}
+
+impl DataNodeRef<'_> {
+    pub fn into_owned(self) -> DataNode {
+        DataNode {
+            data: self.data.to_vec(),
+            acl: self.acl,
+            stat: self.stat,
+        }
+    }
 }
 
 /// A ZooKeeper snapshot file. After the initial header, it is composed of 3 sections:
@@ -82,79 +170,266 @@ pub struct DataNode {
 /// Each section is implemented as type state implementing iterator for the type related to that
 /// section (sessions, acls, data nodes).
 ///
+/// Snapshots are read from a file, or from their content with `from_slice` (see also
+/// `MappedSnapshot`). Data nodes read from a slice borrow their path and data from it instead of
+/// being copied. Files with a compression suffix are decompressed (see `compression`).
+///
 /// See [`SnapshotFormatter.java`] and [`SerializeUtils.java`] for details.
 ///
 /// [`SnapshotFormatter.java`]: https://github.com/apache/zookeeper/blob/master/zookeeper-server/src/main/java/org/apache/zookeeper/server/SnapshotFormatter.java
 /// [`SerializeUtils.java`]: https://github.com/apache/zookeeper/blob/master/zookeeper-server/src/main/java/org/apache/zookeeper/server/util/SerializeUtils.java
 ///
-pub struct SnapshotFile<S> {
-    deser: crate::serde::Deserializer<BufReader<File>>,
+pub struct SnapshotFile<S, R = ChecksumReader<Decompressor<BufReader<ScanReader>>>> {
+    deser: Deserializer<R>,
+    version: ServerVersion,
+    /// Recompute the data tree digest to compare it with the snapshot's
+    check_digest: bool,
     count: usize,
     errored: bool,
     state: S,
 }
 
+impl<S, R> SnapshotFile<S, R> {
+    /// The server version this file is read for
+    pub fn version(&self) -> ServerVersion {
+        self.version
+    }
+}
+
+/// Computes the Adler-32 of everything read through it, to check the checksums of snapshot files.
+pub struct ChecksumReader<R: Read> {
+    input: R,
+    checksum: u64,
+}
+
+impl<R: Read> Read for ChecksumReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let len = self.input.read(buf)?;
+        self.checksum = Adler32::update(self.checksum, &buf[..len]);
+        Ok(len)
+    }
+}
+
+/// Compare a checksum read from a snapshot with the Adler-32 of what precedes it.
+fn check_checksum(stored: i64, computed: u64) -> Result<(), PersistenceError> {
+    if stored as u64 != computed {
+        return Err(PersistenceError::ChecksumMismatch {
+            algorithm: Adler32.name(),
+            zxid: None,
+            detected: None,
+        });
+    }
+    Ok(())
+}
+
 //--------------------------------------------------------------------------------------------------
 // Part 1: header
 
+/// Maximum number of snapshots considered when looking for a valid one (same as `FileSnap.java`)
+pub const MAX_SNAPSHOT_CANDIDATES: usize = 100;
+
 pub struct InitState {
     zxid: Zxid,
+    path: PathBuf,
 }
 
 impl SnapshotFile<InitState> {
     /// Find the most recent snapshot in a directory
-    pub fn most_recent_snapshot(dir: impl AsRef<Path>) -> Result<Option<SnapshotFile<InitState>>, Error> {
-        let mut snapshot_paths = std::fs::read_dir(dir)?
+    pub fn most_recent_snapshot(dir: impl AsRef<Path>) -> Result<Option<SnapshotFile<InitState>>, PersistenceError> {
+        Self::snapshot_paths(dir)?.into_iter().next().map(Self::new).transpose()
+    }
+
+    /// Find the most recent valid snapshot in a directory, looking at most at the `max_candidates`
+    /// most recent ones. A snapshot is valid if it is complete (see `is_valid_snapshot`) and has a
+    /// correct header.
+    ///
+    /// Use `path()` on the result to know which snapshot was actually selected.
+    ///
+    pub fn find_valid_snapshot(
+        dir: impl AsRef<Path>,
+        max_candidates: usize,
+    ) -> Result<Option<SnapshotFile<InitState>>, PersistenceError> {
+        for path in Self::snapshot_paths(dir)?.into_iter().take(max_candidates) {
+            // Like ZooKeeper, snapshots that can't be read are skipped
+            if !Self::is_valid_snapshot(&path).unwrap_or(false) {
+                continue;
+            }
+            if let Ok(snap) = Self::new(&path) {
+                return Ok(Some(snap));
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Same as `find_valid_snapshot`, also reading each candidate entirely to check its checksums,
+    /// and its digest if `check_digest` is set (see `with_digest_check`). Like `FileSnap.java`,
+    /// candidates that fail are skipped. The selected snapshot is returned unread.
+    pub fn find_verified_snapshot(
+        dir: impl AsRef<Path>,
+        max_candidates: usize,
+        check_digest: bool,
+    ) -> Result<Option<SnapshotFile<InitState>>, PersistenceError> {
+        for path in Self::snapshot_paths(dir)?.into_iter().take(max_candidates) {
+            if !Self::is_valid_snapshot(&path).unwrap_or(false) {
+                continue;
+            }
+            let verified = Self::new(&path).and_then(|snap| snap.with_digest_check(check_digest).verify());
+            if verified.is_err() {
+                continue;
+            }
+            if let Ok(snap) = Self::new(&path) {
+                return Ok(Some(snap));
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Read the whole snapshot to check its checksums, and its digest if `with_digest_check` is
+    /// set. Returns the digest, if there is one.
+    pub fn verify(self) -> Result<Option<SnapshotDigest>, PersistenceError> {
+        self.sessions()?.acls()?.data_nodes()?.finish()
+    }
+
+    /// Paths of the snapshots in a directory, most recent first.
+    pub fn snapshot_paths(dir: impl AsRef<Path>) -> Result<Vec<PathBuf>, PersistenceError> {
+        let mut zxid_paths = std::fs::read_dir(dir)?
             .filter_map(|r| r.ok())
             .map(|entry| entry.path())
-            .filter(|path| path.starts_with("snapshot."))
+            .filter(|path| {
+                path.file_name()
+                    .and_then(|n| n.to_str())
+                    .unwrap_or_default()
+                    .starts_with("snapshot.")
+            })
+            .filter_map(|path| super::zxid_from_path(&path).map(|zxid| (zxid, path)))
             .collect::<Vec<_>>();
 
-        snapshot_paths.sort();
+        zxid_paths.sort_by(|(zxid1, _), (zxid2, _)| zxid2.cmp(zxid1));
 
-        snapshot_paths.pop().map(|path| Self::new(path)).transpose()
+        Ok(zxid_paths.into_iter().map(|(_, path)| path).collect())
     }
 
-    pub fn new(path: impl AsRef<Path>) -> Result<SnapshotFile<InitState>, Error> {
+    /// Quick check that a snapshot file is complete, without reading it entirely: a snapshot always
+    /// ends with the "/" path (see `Util.isValidSnapshot()` in ZK server). Like ZooKeeper, only the
+    /// magic number of compressed snapshots is checked.
+    pub fn is_valid_snapshot(path: impl AsRef<Path>) -> Result<bool, PersistenceError> {
         let path = path.as_ref();
 
-        let zxid =
-            super::zxid_from_path(path).ok_or_else(|| format_err!("Can't parse version in path {}", path.display()))?;
+        if super::zxid_from_path(path).is_none() {
+            return Ok(false);
+        }
+
+        let mut file = File::open(path)?;
+        let compression = Compression::from_path(path);
+        if compression != Compression::None {
+            let mut magic = Vec::new();
+            file.take(8).read_to_end(&mut magic)?;
+            return Ok(compression.has_magic(&magic));
+        }
 
-        let file = BufReader::new(File::open(path)?);
+        if file.metadata()?.len() < 10 {
+            return Ok(false);
+        }
+
+        let mut trailer = [0u8; 5];
+        file.seek(SeekFrom::End(-5))?;
+        file.read_exact(&mut trailer)?;
+
+        // 32 bits length followed by "/"
+        Ok(trailer == [0, 0, 0, 1, b'/'])
+    }
 
-        let mut deser = crate::serde::de::from_reader(file);
-        let header = super::FileHeader::deserialize(&mut deser)?;
+    pub fn new(path: impl AsRef<Path>) -> Result<SnapshotFile<InitState>, PersistenceError> {
+        Self::with_options(path, &ReadOptions::default())
+    }
+
+    /// Same as `new`, with options for the read buffer and kernel hints.
+    pub fn with_options(
+        path: impl AsRef<Path>,
+        options: &ReadOptions,
+    ) -> Result<SnapshotFile<InitState>, PersistenceError> {
+        let path = path.as_ref();
+        let input = Compression::from_path(path).reader(options.open(path)?)?;
+        let file = ChecksumReader { input, checksum: 1 };
+        Self::read_header(path, crate::serde::de::from_reader(file))
+    }
+}
+
+impl<'a> SnapshotFile<InitState, SliceRead<'a>> {
+    /// Read a snapshot from its content. `path` is only used to get the snapshot's zxid.
+    pub fn from_slice(path: impl AsRef<Path>, bytes: &'a [u8]) -> Result<Self, PersistenceError> {
+        Self::read_header(path.as_ref(), crate::serde::de::from_slice(bytes))
+    }
+}
+
+impl<'de, R: JuteRead<'de>> SnapshotFile<InitState, R> {
+    fn read_header(path: &Path, mut deser: Deserializer<R>) -> Result<Self, PersistenceError> {
+        let zxid =
+            super::zxid_from_path(path).ok_or_else(|| ParseError::invalid("snapshot path", path.display()))?;
+
+        let header = FileHeader::deserialize(&mut deser)?;
 
         if header.magic != super::SNAP_MAGIC {
-            return Err(failure::err_msg("Wrong magic number"));
+            return Err(PersistenceError::WrongMagic);
         }
 
         if header.version != 2 {
-            return Err(failure::err_msg("Wrong version number"));
+            return Err(PersistenceError::WrongVersion(header.version));
         }
 
         Ok(SnapshotFile {
             deser,
+            version: ServerVersion::LATEST,
+            check_digest: false,
             count: 0,
             errored: false,
-            state: InitState { zxid },
+            state: InitState {
+                zxid,
+                path: path.to_path_buf(),
+            },
         })
     }
 
+    /// Read this snapshot as written by a given server version. Features that didn't exist in this
+    /// version (e.g. digests before 3.6) are rejected. Defaults to `ServerVersion::LATEST`.
+    pub fn for_version(mut self, version: ServerVersion) -> Self {
+        self.version = version;
+        self
+    }
+
+    /// Recompute the digest of the data tree while reading data nodes, and check that it matches
+    /// the snapshot's digest, if it has one (see `SnapshotFile::<DataNodesState>::digest`).
+    ///
+    /// ZooKeeper writes snapshots while transactions are applied, and the digest is the one of the
+    /// tree once written. It can only match if the tree wasn't modified in the meantime, as is the
+    /// case of snapshots written when a server starts or by `SnapshotWriter`.
+    pub fn with_digest_check(mut self, check: bool) -> Self {
+        self.check_digest = check;
+        self
+    }
+
     /// The transaction id for this snapshot
     pub fn zxid(&self) -> Zxid {
         self.state.zxid
     }
 
+    /// Path of this snapshot file
+    pub fn path(&self) -> &Path {
+        &self.state.path
+    }
+
     /// Transition to session information
-    pub fn sessions(self) -> Result<SnapshotFile<SessionsState>, Error> {
+    pub fn sessions(self) -> Result<SnapshotFile<SessionsState, R>, PersistenceError> {
         SnapshotFile::new_sessions(self)
     }
 }
 
 /// Generic implementation of Iterator::next
-fn next_item<'de, T: Deserialize<'de>, S>(snap: &mut SnapshotFile<S>) -> Option<Result<T, Error>> {
+fn next_item<'de, T: Deserialize<'de>, S, R: JuteRead<'de>>(
+    snap: &mut SnapshotFile<S, R>,
+) -> Option<Result<T, PersistenceError>> {
     if snap.count == 0 || snap.errored {
         return None;
     }
@@ -171,13 +446,21 @@ fn next_item<'de, T: Deserialize<'de>, S>(snap: &mut SnapshotFile<S>) -> Option<
 //--------------------------------------------------------------------------------------------------
 // Part 2: sessions
 
+/// All sessions, and the ACL cache entries that follow them
+type SessionMap<R> = (HashMap<SessionId, Duration>, SnapshotFile<ACLCacheState, R>);
+
+/// All ACL cache entries, and the data nodes that follow them
+type AclMap<R> = (HashMap<ACLRef, Vec<ACL>>, SnapshotFile<DataNodesState, R>);
+
 pub struct SessionsState {}
 
-impl SnapshotFile<SessionsState> {
-    fn new_sessions<T>(mut prev: SnapshotFile<T>) -> Result<Self, Error> {
+impl<'de, R: JuteRead<'de>> SnapshotFile<SessionsState, R> {
+    fn new_sessions<T>(mut prev: SnapshotFile<T, R>) -> Result<Self, PersistenceError> {
         let count = <i32>::deserialize(&mut prev.deser)? as usize;
         Ok(SnapshotFile {
             deser: prev.deser,
+            version: prev.version,
+            check_digest: prev.check_digest,
             count,
             errored: false,
             state: SessionsState {},
@@ -186,21 +469,31 @@ impl SnapshotFile<SessionsState> {
 
     /// Transition to ACL cache entries. It will skip any session states that have not been
     /// read yet.
-    pub fn acls(mut self) -> Result<SnapshotFile<ACLCacheState>, Error> {
+    pub fn acls(mut self) -> Result<SnapshotFile<ACLCacheState, R>, PersistenceError> {
         // drain iterator
         self.last();
 
         if self.errored {
-            return Err(failure::err_msg("Stream already errored out"));
+            return Err(PersistenceError::Errored);
         }
 
-        SnapshotFile::<ACLCacheState>::new_acl_cache(self)
+        SnapshotFile::<ACLCacheState, R>::new_acl_cache(self)
     }
 
     /// Reads all ACL cache entries, return them as a map and transition to data nodes
-    pub fn acl_map(self) -> Result<(HashMap<ACLRef, Vec<ACL>>, SnapshotFile<DataNodesState>), Error> {
+    pub fn acl_map(self) -> Result<AclMap<R>, PersistenceError> {
         self.acls()?.read_acl_map()
     }
+
+    /// Reads all remaining sessions, return them as a map of session id to timeout and transition
+    /// to ACL cache entries.
+    pub fn session_map(mut self) -> Result<SessionMap<R>, PersistenceError> {
+        let sessions: HashMap<_, _> = self
+            .map(|r| r.map(|session| (session.id, session.timeout)))
+            .collect::<Result<_, _>>()?;
+
+        Ok((sessions, self.acls()?))
+    }
 }
 
 /// Iterate on the sessions contained in this snapshot
@@ -208,8 +501,8 @@ impl SnapshotFile<SessionsState> {
 /// Note: implemented on `&mut SnapshotFile` so that we can use functions that consume the iterator
 /// while still being able to use the object to move to the next state.
 ///
-impl Iterator for &mut SnapshotFile<SessionsState> {
-    type Item = Result<Session, Error>;
+impl<'de, R: JuteRead<'de>> Iterator for &mut SnapshotFile<SessionsState, R> {
+    type Item = Result<Session, PersistenceError>;
 
     fn next(&mut self) -> Option<Self::Item> {
         next_item(self)
@@ -221,18 +514,25 @@ impl Iterator for &mut SnapshotFile<SessionsState> {
 
 pub struct ACLCacheState {}
 
-impl SnapshotFile<ACLCacheState> {
-    fn new_acl_cache<T>(mut prev: SnapshotFile<T>) -> Result<SnapshotFile<ACLCacheState>, Error> {
+impl<'de, R: JuteRead<'de>> SnapshotFile<ACLCacheState, R> {
+    fn new_acl_cache<T>(mut prev: SnapshotFile<T, R>) -> Result<Self, PersistenceError> {
         let count = <i32>::deserialize(&mut prev.deser)? as usize;
         Ok(SnapshotFile {
             deser: prev.deser,
+            version: prev.version,
+            check_digest: prev.check_digest,
             count,
             errored: false,
             state: ACLCacheState {},
         })
     }
 
-    fn read_acl_map(mut self) -> Result<(HashMap<ACLRef, Vec<ACL>>, SnapshotFile<DataNodesState>), Error> {
+    /// Reads all remaining ACL cache entries, return them as a map and transition to data nodes
+    pub fn acl_map(self) -> Result<AclMap<R>, PersistenceError> {
+        self.read_acl_map()
+    }
+
+    fn read_acl_map(mut self) -> Result<AclMap<R>, PersistenceError> {
 
         let all_acls: HashMap<_, _> = self
             .map(|r| r.map(|entry| (entry.entry_id, entry.acl)))
@@ -242,20 +542,20 @@ impl SnapshotFile<ACLCacheState> {
     }
 
     /// Transition to data nodes. It will skip any ACL cache entries that have not been read yet.
-    pub fn data_nodes(mut self) -> Result<SnapshotFile<DataNodesState>, Error> {
+    pub fn data_nodes(mut self) -> Result<SnapshotFile<DataNodesState, R>, PersistenceError> {
         // drain iterator
         self.last();
 
         if self.errored {
-            return Err(failure::err_msg("Stream already errored out"));
+            return Err(PersistenceError::Errored);
         }
 
-        SnapshotFile::<DataNodesState>::new_data_nodes(self)
+        SnapshotFile::<DataNodesState, R>::new_data_nodes(self)
     }
 }
 
-impl Iterator for &mut SnapshotFile<ACLCacheState> {
-    type Item = Result<ACLCacheEntry, Error>;
+impl<'de, R: JuteRead<'de>> Iterator for &mut SnapshotFile<ACLCacheState, R> {
+    type Item = Result<ACLCacheEntry, PersistenceError>;
 
     fn next(&mut self) -> Option<Self::Item> {
         next_item(self)
@@ -265,31 +565,37 @@ impl Iterator for &mut SnapshotFile<ACLCacheState> {
 //--------------------------------------------------------------------------------------------------
 // Part 4: data nodes
 
-pub struct DataNodesState {}
+pub struct DataNodesState {
+    /// Digest of the data nodes read so far, if `check_digest` is set
+    tree_digest: i64,
+}
 
-impl SnapshotFile<DataNodesState> {
-    fn new_data_nodes<T>(prev: SnapshotFile<T>) -> Result<SnapshotFile<DataNodesState>, Error> {
+impl<'de, R: JuteRead<'de>> SnapshotFile<DataNodesState, R> {
+    fn new_data_nodes<T>(prev: SnapshotFile<T, R>) -> Result<Self, PersistenceError> {
         // We don't have a count of entries for this section. This is a series of (path, data) and
         // the section ends when we see a "/" path.
 
         Ok(SnapshotFile {
             deser: prev.deser,
+            version: prev.version,
+            check_digest: prev.check_digest,
             count: 1,
             errored: false,
-            state: DataNodesState {},
+            state: DataNodesState { tree_digest: 0 },
         })
     }
-}
-
-impl Iterator for SnapshotFile<DataNodesState> {
-    type Item = Result<(String, DataNode), Error>;
 
-    fn next(&mut self) -> Option<Self::Item> {
+    /// Generic implementation of Iterator::next, for owned or borrowed paths and nodes
+    fn next_node<P, N>(&mut self) -> Option<Result<(P, N), PersistenceError>>
+    where
+        P: Deserialize<'de> + AsRef<str>,
+        N: Deserialize<'de> + NodeContent,
+    {
         if self.count == 0 || self.errored {
             return None;
         }
 
-        let path = match <String>::deserialize(&mut self.deser) {
+        let path = match P::deserialize(&mut self.deser) {
             Ok(p) => p,
             Err(e) => {
                 self.errored = true;
@@ -297,12 +603,12 @@ impl Iterator for SnapshotFile<DataNodesState> {
             }
         };
 
-        if &path == "/" {
+        if path.as_ref() == "/" {
             self.count = 0;
             return None;
         }
 
-        let data = match <DataNode>::deserialize(&mut self.deser) {
+        let data = match N::deserialize(&mut self.deser) {
             Ok(d) => d,
             Err(e) => {
                 self.errored = true;
@@ -310,13 +616,389 @@ impl Iterator for SnapshotFile<DataNodesState> {
             }
         };
 
+        if self.check_digest {
+            let digest = SnapshotDigest::node_digest(path.as_ref(), data.data(), data.stat());
+            self.state.tree_digest = self.state.tree_digest.wrapping_add(digest);
+        }
+
         Some(Ok((path, data)))
     }
+
+    /// Read the trailer that follows data nodes, once they have all been read. `checksum` is the
+    /// Adler-32 of everything read so far.
+    fn read_trailer(&mut self, checksum: u64) -> Result<(), PersistenceError> {
+        if self.errored {
+            return Err(PersistenceError::Errored);
+        }
+
+        // Checksum of the previous sections, followed by "/"
+        check_checksum(<i64>::deserialize(&mut self.deser)?, checksum)?;
+        if <String>::deserialize(&mut self.deser)? != "/" {
+            return Err(PersistenceError::Corrupted("Missing snapshot trailer".to_owned()));
+        }
+        Ok(())
+    }
+
+    /// Read the digest in what follows the trailer, check it if needed, and transition to the
+    /// digest state. `checksum` is the Adler-32 of everything before `rest`.
+    fn new_digest(self, rest: &[u8], checksum: u64) -> Result<SnapshotFile<DigestState, R>, PersistenceError> {
+        let digest = if rest.is_empty() {
+            None
+        } else if self.version < ServerVersion::V3_6 {
+            return Err(PersistenceError::Corrupted(
+                "Unexpected data after end of snapshot: digests require ZooKeeper 3.6+".to_owned(),
+            ));
+        } else {
+            // Digest, followed by another checksum and "/"
+            let mut deser = crate::serde::de::from_slice(rest);
+            let digest = SnapshotDigest::deserialize(&mut deser)?;
+            let checksum = Adler32::update(checksum, deser.get_ref().consumed());
+            check_checksum(<i64>::deserialize(&mut deser)?, checksum)?;
+            if <String>::deserialize(&mut deser)? != "/" {
+                return Err(PersistenceError::Corrupted("Missing digest trailer".to_owned()));
+            }
+            Some(digest)
+        };
+
+        let computed = if self.check_digest {
+            Some(self.state.tree_digest)
+        } else {
+            None
+        };
+
+        if let (Some(digest), Some(computed)) = (digest, computed) {
+            if digest.version != SnapshotDigest::VERSION {
+                return Err(PersistenceError::Unsupported(format!("digest version {}", digest.version)));
+            }
+            if digest.tree_digest != computed {
+                return Err(PersistenceError::DigestMismatch {
+                    zxid: digest.zxid,
+                    expected: digest.tree_digest,
+                    actual: computed,
+                });
+            }
+        }
+
+        Ok(SnapshotFile {
+            deser: self.deser,
+            version: self.version,
+            check_digest: self.check_digest,
+            count: 0,
+            errored: false,
+            state: DigestState { digest, computed },
+        })
+    }
+}
+
+impl SnapshotFile<DataNodesState> {
+    /// Transition to the data tree digest that ends the snapshot, skipping any data nodes that have
+    /// not been read yet. Fails if the digest is checked and doesn't match (see
+    /// `with_digest_check`).
+    pub fn digest(mut self) -> Result<SnapshotFile<DigestState>, PersistenceError> {
+        // drain iterator
+        self.by_ref().last();
+        self.read_trailer(self.deser.get_ref().checksum)?;
+
+        let checksum = self.deser.get_ref().checksum;
+        let mut rest = Vec::new();
+        self.deser.get_mut().read_to_end(&mut rest)?;
+        self.new_digest(&rest, checksum)
+    }
+
+    /// Read the end of the snapshot, skipping any data nodes that have not been read yet, and
+    /// return the data tree digest if there is one (ZooKeeper 3.6+).
+    pub fn finish(self) -> Result<Option<SnapshotDigest>, PersistenceError> {
+        Ok(self.digest()?.digest())
+    }
+}
+
+impl<'a> SnapshotFile<DataNodesState, SliceRead<'a>> {
+    /// Same as `digest` for snapshots read from a slice.
+    pub fn digest(mut self) -> Result<SnapshotFile<DigestState, SliceRead<'a>>, PersistenceError> {
+        // drain iterator
+        self.by_ref().last();
+        let consumed = self.deser.get_ref().consumed();
+        let checksum = Adler32::update(1, consumed);
+        self.read_trailer(checksum)?;
+
+        let trailer = &self.deser.get_ref().consumed()[consumed.len()..];
+        let checksum = Adler32::update(checksum, trailer);
+        let rest = self.deser.get_ref().remaining();
+        self.new_digest(rest, checksum)
+    }
+
+    /// Same as `finish` for snapshots read from a slice.
+    pub fn finish(self) -> Result<Option<SnapshotDigest>, PersistenceError> {
+        Ok(self.digest()?.digest())
+    }
+}
+
+/// Data tree digest at the end of a snapshot
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[derive(Deserialize, Serialize)]
+pub struct SnapshotDigest {
+    /// Zxid of the last transaction included in the digest
+    pub zxid: Zxid,
+    /// Version of the digest algorithm
+    pub version: i32,
+    pub tree_digest: i64,
+}
+
+impl SnapshotDigest {
+    /// Version of the digest algorithm implemented by `node_digest`
+    pub const VERSION: i32 = 2;
+
+    /// Digest of a data node, that is added to the other nodes' to compute the digest of a data
+    /// tree (see `DigestCalculator.java`). Nodes under `/zookeeper/` (quotas) aren't included, and
+    /// neither are times, which differ between servers.
+    pub fn node_digest(path: &str, data: &[u8], stat: &StatPersisted) -> i64 {
+        if path.starts_with("/zookeeper/") {
+            return 0;
+        }
+
+        let mut fields = Vec::with_capacity(44);
+        fields.extend_from_slice(&stat.czxid.0.to_be_bytes());
+        fields.extend_from_slice(&stat.mzxid.0.to_be_bytes());
+        fields.extend_from_slice(&stat.pzxid.0.to_be_bytes());
+        fields.extend_from_slice(&stat.version.0.to_be_bytes());
+        fields.extend_from_slice(&stat.cversion.0.to_be_bytes());
+        fields.extend_from_slice(&stat.aversion.0.to_be_bytes());
+        fields.extend_from_slice(&stat.ephemeral_info.0.to_be_bytes());
+
+        let crc = Crc32::update(Crc32::update(0, path.as_bytes()), data);
+        Crc32::update(crc, &fields) as i64
+    }
+}
+
+/// Content of data nodes that is part of the digest
+trait NodeContent {
+    fn data(&self) -> &[u8];
+    fn stat(&self) -> &StatPersisted;
+}
+
+impl NodeContent for DataNode {
+    fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    fn stat(&self) -> &StatPersisted {
+        &self.stat
+    }
+}
+
+impl NodeContent for DataNodeRef<'_> {
+    fn data(&self) -> &[u8] {
+        self.data
+    }
+
+    fn stat(&self) -> &StatPersisted {
+        &self.stat
+    }
+}
+
+impl Iterator for SnapshotFile<DataNodesState> {
+    type Item = Result<(String, DataNode), PersistenceError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_node()
+    }
+}
+
+/// Data nodes of a snapshot read from a slice, that borrow their path and data from it.
+impl<'a> Iterator for SnapshotFile<DataNodesState, SliceRead<'a>> {
+    type Item = Result<(&'a str, DataNodeRef<'a>), PersistenceError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_node()
+    }
+}
+
+//--------------------------------------------------------------------------------------------------
+// Part 5: digest
+
+pub struct DigestState {
+    digest: Option<SnapshotDigest>,
+    computed: Option<i64>,
+}
+
+impl<R> SnapshotFile<DigestState, R> {
+    /// The data tree digest written at the end of the snapshot (ZooKeeper 3.6+)
+    pub fn digest(&self) -> Option<SnapshotDigest> {
+        self.state.digest
+    }
+
+    /// The digest of the data nodes that were read, if the digest is checked
+    pub fn computed_digest(&self) -> Option<i64> {
+        self.state.computed
+    }
+}
+
+/// A memory-mapped snapshot file, to read data nodes without copying them: they borrow their path
+/// and data from the mapping.
+///
+/// The file must not be truncated while it is mapped, which would crash the process (see
+/// `ReadOptions::mmap`). This is the case of snapshots, that are never written to once complete.
+#[cfg(feature = "mmap")]
+pub struct MappedSnapshot {
+    path: PathBuf,
+    map: memmap2::Mmap,
+}
+
+#[cfg(feature = "mmap")]
+impl MappedSnapshot {
+    pub fn open(path: impl AsRef<Path>) -> Result<MappedSnapshot, PersistenceError> {
+        let path = path.as_ref();
+        let map = super::io::map(&File::open(path)?, true)?;
+        Ok(MappedSnapshot {
+            path: path.to_path_buf(),
+            map,
+        })
+    }
+
+    /// Path of this snapshot file
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Read this snapshot
+    pub fn snapshot(&self) -> Result<SnapshotFile<InitState, SliceRead<'_>>, PersistenceError> {
+        SnapshotFile::from_slice(&self.path, &self.map)
+    }
+}
+
+//--------------------------------------------------------------------------------------------------
+// Writing snapshots
+
+/// Writes snapshot files, section by section in the same order as they're read: sessions, ACL
+/// cache entries, data nodes, and an optional digest.
+///
+/// Files are written in the format of ZooKeeper 3.6, which older versions can read if there's no
+/// digest. Only ZooKeeper 3.6+ can read compressed snapshots.
+pub struct SnapshotWriter<W: Write> {
+    ser: Serializer<ChecksumWriter<Compressor<W>>>,
+}
+
+/// Computes the Adler-32 of everything written through it.
+struct ChecksumWriter<W: Write> {
+    out: W,
+    checksum: u64,
+}
+
+impl<W: Write> Write for ChecksumWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let len = self.out.write(buf)?;
+        self.checksum = Adler32::update(self.checksum, &buf[..len]);
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.out.flush()
+    }
+}
+
+impl SnapshotWriter<BufWriter<File>> {
+    /// Create a snapshot file. Its name should be `snapshot.<zxid in hex>` for ZooKeeper to find it,
+    /// followed by a compression suffix (e.g. `.gz`) to compress it.
+    pub fn create(path: impl AsRef<Path>) -> Result<Self, PersistenceError> {
+        let path = path.as_ref();
+        let compression = Compression::from_path(path);
+        // Check that the compression is supported before creating the file
+        compression.writer(std::io::sink())?;
+        Self::with_compression(BufWriter::new(File::create(path)?), compression)
+    }
+}
+
+impl<W: Write> SnapshotWriter<W> {
+    /// Write the file header. Sections must then be written in order.
+    pub fn new(out: W) -> Result<Self, PersistenceError> {
+        Self::with_compression(out, Compression::None)
+    }
+
+    /// Same as `new`, compressing the snapshot.
+    pub fn with_compression(out: W, compression: Compression) -> Result<Self, PersistenceError> {
+        let out = compression.writer(out)?;
+        let mut writer = SnapshotWriter {
+            ser: crate::serde::ser::to_writer(ChecksumWriter { out, checksum: 1 }),
+        };
+        // ZooKeeper doesn't use the dbid of snapshots, and always writes -1
+        writer.write(&FileHeader {
+            magic: super::SNAP_MAGIC,
+            version: 2,
+            dbid: -1,
+        })?;
+        Ok(writer)
+    }
+
+    fn write(&mut self, value: &impl Serialize) -> Result<(), PersistenceError> {
+        value.serialize(&mut self.ser)?;
+        Ok(())
+    }
+
+    /// Write the session section, ordered by session id.
+    pub fn sessions(&mut self, sessions: &HashMap<SessionId, Duration>) -> Result<(), PersistenceError> {
+        let mut sessions = sessions
+            .iter()
+            .map(|(&id, &timeout)| Session { id, timeout })
+            .collect::<Vec<_>>();
+        sessions.sort_by_key(|session| session.id);
+
+        self.write(&(sessions.len() as i32))?;
+        for session in &sessions {
+            self.write(session)?;
+        }
+        Ok(())
+    }
+
+    /// Write the ACL cache section, ordered by reference.
+    pub fn acls(&mut self, acls: &HashMap<ACLRef, Vec<ACL>>) -> Result<(), PersistenceError> {
+        let mut acls = acls
+            .iter()
+            .map(|(&entry_id, acl)| ACLCacheEntry {
+                entry_id,
+                acl: acl.clone(),
+            })
+            .collect::<Vec<_>>();
+        acls.sort_by_key(|entry| entry.entry_id.0);
+
+        self.write(&(acls.len() as i32))?;
+        for entry in &acls {
+            self.write(entry)?;
+        }
+        Ok(())
+    }
+
+    /// Write a data node. Parents must be written before their children.
+    pub fn node(&mut self, path: &str, node: &DataNode) -> Result<(), PersistenceError> {
+        self.write(&path)?;
+        self.write(node)
+    }
+
+    /// End the data nodes section, write the trailer and digest, and return the underlying writer.
+    pub fn finish(mut self, digest: Option<SnapshotDigest>) -> Result<W, PersistenceError> {
+        self.write(&"/")?;
+        self.write_checksum()?;
+
+        if let Some(digest) = digest {
+            self.write(&digest)?;
+            self.write_checksum()?;
+        }
+
+        let mut out = self.ser.into_inner().out.finish()?;
+        out.flush()?;
+        Ok(out)
+    }
+
+    fn write_checksum(&mut self) -> Result<(), PersistenceError> {
+        let checksum = self.ser.get_ref().checksum as i64;
+        self.write(&checksum)?;
+        self.write(&"/")
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::persistence::testing::snapshot_bytes;
 
     #[test]
     fn read_snapshot() {
@@ -398,4 +1080,247 @@ mod tests {
         assert!(false);
     }
 
+    #[test]
+    fn session_map() {
+        let dir = std::env::temp_dir().join("zookeepers-session-map");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let path = dir.join("snapshot.10");
+        std::fs::write(&path, snapshot_bytes(&[(1, 30_000), (2, 10_000)], &[], &[])).unwrap();
+
+        let (sessions, snap) = SnapshotFile::new(&path).unwrap().sessions().unwrap().session_map().unwrap();
+        assert_eq!(sessions.len(), 2);
+        assert_eq!(sessions.get(&SessionId(1)), Some(&Duration(30_000)));
+        assert_eq!(sessions.get(&SessionId(2)), Some(&Duration(10_000)));
+
+        let (acls, mut snap) = snap.acl_map().unwrap();
+        assert!(acls.is_empty());
+        assert!(snap.next().is_none());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn ephemeral_type() {
+        assert_eq!(EphemeralInfo(0).ephemeral_type(), EphemeralType::Void);
+        assert_eq!(EphemeralInfo(0x1234).ephemeral_type(), EphemeralType::Normal(SessionId(0x1234)));
+        assert_eq!(EphemeralInfo(0x1234).owner(), Some(SessionId(0x1234)));
+        assert_eq!(EphemeralInfo(i64::MIN).ephemeral_type(), EphemeralType::Container);
+        assert_eq!(EphemeralInfo(i64::MIN).owner(), None);
+
+        let ttl = 0xFF00_0000_0000_0000u64 as i64 | 60_000;
+        assert_eq!(EphemeralInfo(ttl).ephemeral_type(), EphemeralType::TTL(60_000));
+
+        for info in &[0, 0x1234, i64::MIN, ttl] {
+            assert_eq!(EphemeralInfo::from(EphemeralInfo(*info).ephemeral_type()).0, *info);
+        }
+    }
+
+    #[test]
+    fn find_valid_snapshot() {
+        let dir = std::env::temp_dir().join("zookeepers-find-valid-snapshot");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let bytes = snapshot_bytes(&[], &[], &[]);
+
+        std::fs::write(dir.join("snapshot.10"), &bytes).unwrap();
+        // Truncated, more recent snapshot
+        std::fs::write(dir.join("snapshot.20"), &bytes[..bytes.len() - 10]).unwrap();
+        // Not a snapshot
+        std::fs::write(dir.join("log.30"), &bytes).unwrap();
+
+        let paths = SnapshotFile::snapshot_paths(&dir).unwrap();
+        assert_eq!(paths, vec![dir.join("snapshot.20"), dir.join("snapshot.10")]);
+
+        let snap = SnapshotFile::most_recent_snapshot(&dir).unwrap().unwrap();
+        assert_eq!(snap.zxid(), Zxid(0x20));
+
+        // Can't be read
+        std::fs::create_dir(dir.join("snapshot.30")).unwrap();
+
+        let snap = SnapshotFile::find_valid_snapshot(&dir, MAX_SNAPSHOT_CANDIDATES).unwrap().unwrap();
+        assert_eq!(snap.zxid(), Zxid(0x10));
+        assert_eq!(snap.path(), dir.join("snapshot.10").as_path());
+
+        assert!(SnapshotFile::find_valid_snapshot(&dir, 2).unwrap().is_none());
+
+        // A complete snapshot with a bad checksum is only skipped once verified
+        let mut corrupted = bytes.clone();
+        corrupted[10] ^= 0xff; // dbid
+        std::fs::write(dir.join("snapshot.40"), &corrupted).unwrap();
+        let snap = SnapshotFile::find_valid_snapshot(&dir, MAX_SNAPSHOT_CANDIDATES).unwrap().unwrap();
+        assert_eq!(snap.zxid(), Zxid(0x40));
+        assert!(matches!(snap.verify(), Err(PersistenceError::ChecksumMismatch { .. })));
+        let snap = SnapshotFile::find_verified_snapshot(&dir, MAX_SNAPSHOT_CANDIDATES, false).unwrap().unwrap();
+        assert_eq!(snap.zxid(), Zxid(0x10));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn snapshot_digest() {
+        use byteorder::{BigEndian, WriteBytesExt};
+
+        let dir = std::env::temp_dir().join("zookeepers-snapshot-digest");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let mut bytes = snapshot_bytes(&[], &[], &[]);
+        std::fs::write(dir.join("snapshot.10"), &bytes).unwrap();
+
+        bytes.write_i64::<BigEndian>(0x10).unwrap(); // zxid
+        bytes.write_i32::<BigEndian>(2).unwrap(); // digest version
+        bytes.write_i64::<BigEndian>(1234).unwrap(); // digest
+        let checksum = Adler32::update(1, &bytes);
+        bytes.write_i64::<BigEndian>(checksum as i64).unwrap();
+        bytes.extend_from_slice(&[0, 0, 0, 1, b'/']);
+        std::fs::write(dir.join("snapshot.20"), &bytes).unwrap();
+
+        let read_digest = |name: &str, version: ServerVersion| {
+            SnapshotFile::new(dir.join(name))?
+                .for_version(version)
+                .sessions()?
+                .acls()?
+                .data_nodes()?
+                .finish()
+        };
+
+        assert_eq!(read_digest("snapshot.10", ServerVersion::V3_4).unwrap(), None);
+        assert_eq!(read_digest("snapshot.10", ServerVersion::V3_6).unwrap(), None);
+        assert_eq!(
+            read_digest("snapshot.20", ServerVersion::V3_6).unwrap(),
+            Some(SnapshotDigest {
+                zxid: Zxid(0x10),
+                version: 2,
+                tree_digest: 1234
+            })
+        );
+        assert!(read_digest("snapshot.20", ServerVersion::V3_5).is_err());
+
+        // No data nodes: the computed digest is 0
+        let check_digest = |name: &str| {
+            SnapshotFile::new(dir.join(name))?
+                .with_digest_check(true)
+                .sessions()?
+                .acl_map()?
+                .1
+                .digest()
+        };
+        let snap = check_digest("snapshot.10").unwrap();
+        assert_eq!((snap.digest(), snap.computed_digest()), (None, Some(0)));
+        assert!(matches!(
+            check_digest("snapshot.20").err(),
+            Some(PersistenceError::DigestMismatch {
+                expected: 1234,
+                actual: 0,
+                ..
+            })
+        ));
+        let snap = SnapshotFile::find_verified_snapshot(&dir, MAX_SNAPSHOT_CANDIDATES, true).unwrap().unwrap();
+        assert_eq!(snap.zxid(), Zxid(0x10));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn borrowed_nodes() {
+        use crate::persistence::testing::node;
+
+        let nodes = [("", node("", -1, 0, 0)), ("/app", node("xyz", -1, 1, 2))];
+        let bytes = snapshot_bytes(&[(1, 3000)], &[], &nodes);
+
+        let mut snap = SnapshotFile::from_slice("snapshot.2", &bytes)
+            .unwrap()
+            .sessions()
+            .unwrap()
+            .acl_map()
+            .unwrap()
+            .1;
+        let read = snap.by_ref().collect::<Result<Vec<_>, _>>().unwrap();
+        assert_eq!(read.len(), 2);
+        assert_eq!(read[1].0, "/app");
+        assert_eq!(read[1].1.data, b"xyz");
+        assert_eq!(read[1].1.clone().into_owned(), nodes[1].1);
+        // Borrowed, not copied
+        let offset = read[1].1.data.as_ptr() as usize - bytes.as_ptr() as usize;
+        assert_eq!(&bytes[offset..offset + 3], b"xyz");
+        assert_eq!(snap.finish().unwrap(), None);
+
+        #[cfg(feature = "mmap")]
+        {
+            let path = crate::persistence::testing::write_snapshot("borrowed-nodes", &[(1, 3000)], &[], &nodes);
+            let map = MappedSnapshot::open(&path).unwrap();
+            let snap = map.snapshot().unwrap();
+            assert_eq!(snap.zxid(), Zxid(1));
+            let paths = snap
+                .sessions()
+                .unwrap()
+                .acl_map()
+                .unwrap()
+                .1
+                .map(|n| n.unwrap().0)
+                .collect::<Vec<_>>();
+            assert_eq!(paths, vec!["", "/app"]);
+            crate::persistence::testing::remove_snapshot(&path);
+        }
+    }
+
+    #[test]
+    fn write_snapshot() {
+        use crate::persistence::testing::node;
+        use crate::{Id, PERM_ALL};
+        use byteorder::ByteOrder;
+
+        let dir = crate::persistence::testing::temp_dir("write-snapshot");
+        let path = dir.join("snapshot.5");
+
+        let sessions = vec![(SessionId(2), Duration(20_000)), (SessionId(1), Duration(10_000))]
+            .into_iter()
+            .collect::<HashMap<_, _>>();
+        let acl = vec![ACL {
+            perms: PERM_ALL,
+            id: Id::new("digest", "admin:xxx"),
+        }];
+        let acls = vec![(ACLRef(1), acl)].into_iter().collect::<HashMap<_, _>>();
+        let nodes = vec![("".to_owned(), node("", -1, 0, 0)), ("/a".to_owned(), node("x", 1, 2, 5))];
+        let digest = SnapshotDigest {
+            zxid: Zxid(5),
+            version: 2,
+            tree_digest: nodes
+                .iter()
+                .map(|(path, node)| SnapshotDigest::node_digest(path, &node.data, &node.stat))
+                .fold(0i64, i64::wrapping_add),
+        };
+
+        let mut writer = SnapshotWriter::create(&path).unwrap();
+        writer.sessions(&sessions).unwrap();
+        writer.acls(&acls).unwrap();
+        for (path, node) in &nodes {
+            writer.node(path, node).unwrap();
+        }
+        writer.finish(Some(digest)).unwrap();
+
+        let (read_sessions, snap) = SnapshotFile::new(&path).unwrap().sessions().unwrap().session_map().unwrap();
+        assert_eq!(read_sessions, sessions);
+        let (read_acls, mut snap) = snap.acl_map().unwrap();
+        assert_ne!(digest.tree_digest, 0);
+        assert_eq!(read_acls, acls);
+        assert_eq!(snap.by_ref().collect::<Result<Vec<_>, _>>().unwrap(), nodes);
+        assert_eq!(snap.finish().unwrap(), Some(digest));
+        assert!(SnapshotFile::is_valid_snapshot(&path).unwrap());
+
+        let snap = SnapshotFile::new(&path).unwrap().with_digest_check(true);
+        let (_, snap) = snap.sessions().unwrap().acl_map().unwrap();
+        assert_eq!(snap.digest().unwrap().computed_digest(), Some(digest.tree_digest));
+
+        // First checksum covers everything before it
+        let bytes = std::fs::read(&path).unwrap();
+        let end = bytes.len() - 5 - 8 - 20 - 5 - 8;
+        let checksum = byteorder::BigEndian::read_i64(&bytes[end..]);
+        assert_eq!(checksum as u64, Adler32::update(1, &bytes[..end]));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
 }
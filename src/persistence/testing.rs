@@ -0,0 +1,172 @@
+//! Helpers to write small snapshots in tests.
+
+use byteorder::{BigEndian, WriteBytesExt};
+use std::path::Path;
+
+use super::snapshot::{ACLRef, DataNode, EphemeralInfo, StatPersisted};
+use crate::{Timestamp, Version, Zxid, ACL};
+
+/// A data node with the given data, ACL reference and ephemeral owner, created at `zxid`.
+pub fn node(data: &str, acl: i64, ephemeral_owner: i64, zxid: i64) -> DataNode {
+    DataNode {
+        data: data.as_bytes().to_vec(),
+        acl: ACLRef(acl),
+        stat: StatPersisted {
+            czxid: Zxid(zxid),
+            mzxid: Zxid(zxid),
+            ctime: Timestamp(0),
+            mtime: Timestamp(0),
+            version: Version(0),
+            cversion: Version(0),
+            aversion: Version(0),
+            ephemeral_info: EphemeralInfo(ephemeral_owner),
+            pzxid: Zxid(zxid),
+        },
+    }
+}
+
+fn write_string(bytes: &mut Vec<u8>, s: &str) {
+    bytes.write_i32::<BigEndian>(s.len() as i32).unwrap();
+    bytes.extend_from_slice(s.as_bytes());
+}
+
+/// Serialize a snapshot with sessions as (id, timeout), ACL cache entries and data nodes.
+pub fn snapshot_bytes(sessions: &[(i64, i32)], acls: &[(i64, Vec<ACL>)], nodes: &[(&str, DataNode)]) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    bytes.write_i32::<BigEndian>(super::SNAP_MAGIC).unwrap();
+    bytes.write_i32::<BigEndian>(2).unwrap(); // version
+    bytes.write_i64::<BigEndian>(-1).unwrap(); // dbid
+
+    bytes.write_i32::<BigEndian>(sessions.len() as i32).unwrap();
+    for (id, timeout) in sessions {
+        bytes.write_i64::<BigEndian>(*id).unwrap();
+        bytes.write_i32::<BigEndian>(*timeout).unwrap();
+    }
+
+    bytes.write_i32::<BigEndian>(acls.len() as i32).unwrap();
+    for (id, acl) in acls {
+        bytes.write_i64::<BigEndian>(*id).unwrap();
+        bytes.write_i32::<BigEndian>(acl.len() as i32).unwrap();
+        for entry in acl {
+            bytes.write_u32::<BigEndian>(entry.perms.0).unwrap();
+            write_string(&mut bytes, &entry.id.scheme);
+            write_string(&mut bytes, &entry.id.id);
+        }
+    }
+
+    for (path, node) in nodes {
+        write_string(&mut bytes, path);
+        bytes.write_i32::<BigEndian>(node.data.len() as i32).unwrap();
+        bytes.extend_from_slice(&node.data);
+        bytes.write_i64::<BigEndian>(node.acl.0).unwrap();
+        let stat = &node.stat;
+        bytes.write_i64::<BigEndian>(stat.czxid.0).unwrap();
+        bytes.write_i64::<BigEndian>(stat.mzxid.0).unwrap();
+        bytes.write_u64::<BigEndian>(stat.ctime.0).unwrap();
+        bytes.write_u64::<BigEndian>(stat.mtime.0).unwrap();
+        bytes.write_i32::<BigEndian>(stat.version.0).unwrap();
+        bytes.write_i32::<BigEndian>(stat.cversion.0).unwrap();
+        bytes.write_i32::<BigEndian>(stat.aversion.0).unwrap();
+        bytes.write_i64::<BigEndian>(stat.ephemeral_info.0).unwrap();
+        bytes.write_i64::<BigEndian>(stat.pzxid.0).unwrap();
+    }
+    write_string(&mut bytes, "/"); // end of data nodes
+
+    let checksum = super::checksum::Adler32::update(1, &bytes);
+    bytes.write_i64::<BigEndian>(checksum as i64).unwrap();
+    write_string(&mut bytes, "/");
+    bytes
+}
+
+/// Write a snapshot in a fresh temporary directory, and return the snapshot path.
+pub fn write_snapshot(
+    name: &str,
+    sessions: &[(i64, i32)],
+    acls: &[(i64, Vec<ACL>)],
+    nodes: &[(&str, DataNode)],
+) -> std::path::PathBuf {
+    write_snapshot_at(name, 1, sessions, acls, nodes)
+}
+
+/// Same as `write_snapshot`, with the snapshot's zxid.
+pub fn write_snapshot_at(
+    name: &str,
+    zxid: i64,
+    sessions: &[(i64, i32)],
+    acls: &[(i64, Vec<ACL>)],
+    nodes: &[(&str, DataNode)],
+) -> std::path::PathBuf {
+    let dir = temp_dir(name);
+    let path = dir.join(format!("snapshot.{:x}", zxid));
+    std::fs::write(&path, snapshot_bytes(sessions, acls, nodes)).unwrap();
+    path
+}
+
+/// Remove the directory created by `write_snapshot`.
+pub fn remove_snapshot(path: &Path) {
+    std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+}
+
+/// Serialize a txn record body: header, op code and operation bytes.
+pub fn txn_body(zxid: i64, session: i64, op_code: i32, op: &[u8]) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    bytes.write_i64::<BigEndian>(session).unwrap();
+    bytes.write_i32::<BigEndian>(0).unwrap(); // cxid
+    bytes.write_i64::<BigEndian>(zxid).unwrap();
+    bytes.write_u64::<BigEndian>(zxid as u64 * 1000).unwrap(); // time
+    bytes.write_i32::<BigEndian>(op_code).unwrap();
+    bytes.extend_from_slice(op);
+    bytes
+}
+
+/// Serialize the operation of a create txn, with an empty ACL.
+pub fn create_op(path: &str, data: &str, ephemeral: bool) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    write_string(&mut bytes, path);
+    write_string(&mut bytes, data);
+    bytes.write_i32::<BigEndian>(0).unwrap(); // acl
+    bytes.push(ephemeral as u8);
+    bytes.write_i32::<BigEndian>(0).unwrap(); // parent cversion
+    bytes
+}
+
+/// Serialize the operation of a set data or delete txn (path and data, data being empty for deletes).
+pub fn path_op(path: &str, data: Option<&str>) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    write_string(&mut bytes, path);
+    if let Some(data) = data {
+        write_string(&mut bytes, data);
+        bytes.write_i32::<BigEndian>(1).unwrap(); // version
+    }
+    bytes
+}
+
+/// Write a txnlog file with the given txn bodies, returning its path.
+pub fn write_txnlog(dir: &Path, zxid: i64, bodies: &[Vec<u8>]) -> std::path::PathBuf {
+    use super::checksum::{Adler32, Checksum};
+
+    let mut bytes = Vec::new();
+    bytes.write_i32::<BigEndian>(super::TXNLOG_MAGIC).unwrap();
+    bytes.write_i32::<BigEndian>(2).unwrap(); // version
+    bytes.write_i64::<BigEndian>(-1).unwrap(); // dbid
+
+    for body in bodies {
+        bytes.write_u64::<BigEndian>(Adler32.compute(body)).unwrap();
+        bytes.write_u32::<BigEndian>(body.len() as u32).unwrap();
+        bytes.extend_from_slice(body);
+        bytes.push(b'B');
+    }
+    bytes.extend_from_slice(&[0; 12]); // end of log
+
+    let path = dir.join(format!("log.{:x}", zxid));
+    std::fs::write(&path, bytes).unwrap();
+    path
+}
+
+/// Create a fresh temporary directory.
+pub fn temp_dir(name: &str) -> std::path::PathBuf {
+    let dir = std::env::temp_dir().join(format!("zookeepers-{}", name));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
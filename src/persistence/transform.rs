@@ -0,0 +1,517 @@
+//! Transformations of snapshots, written to new snapshot files.
+//!
+//! Transformed snapshots have no digest, since changing the tree invalidates it.
+
+use std::collections::HashMap;
+use std::path::Path;
+
+use super::snapshot::{ACLRef, DataNode, EphemeralType, InitState, SnapshotFile, SnapshotWriter};
+use crate::error::{ParseError, PersistenceError};
+use crate::proto::{CreateRequest, DeleteRequest};
+use crate::{CreateMode, Id, OptionalVersion, ACL, PERM_ALL};
+
+/// A transformation applied by `rewrite`. It is implemented by closures that only change data nodes.
+pub trait Transform {
+    /// Change the ACL cache before data nodes are rewritten. Nodes can reference new entries.
+    fn acls(&mut self, _acls: &mut HashMap<ACLRef, Vec<ACL>>) {}
+
+    /// Change a data node and its path, or return `None` to remove it.
+    fn node(&mut self, path: String, node: DataNode) -> Option<(String, DataNode)>;
+
+    /// Nodes to write after the node that was just transformed, such as nodes that were held back
+    /// until their parent is written.
+    fn pending(&mut self) -> Vec<(String, DataNode)> {
+        Vec::new()
+    }
+
+    /// Called after the last node, to return nodes still held back, or an error if the
+    /// transformation couldn't be applied. The output file is then incomplete.
+    fn finish(&mut self) -> Result<Vec<(String, DataNode)>, PersistenceError> {
+        Ok(Vec::new())
+    }
+}
+
+impl<F: FnMut(String, DataNode) -> Option<(String, DataNode)>> Transform for F {
+    fn node(&mut self, path: String, node: DataNode) -> Option<(String, DataNode)> {
+        self(path, node)
+    }
+}
+
+/// Result of `rewrite`.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct RewriteReport {
+    pub nodes_read: usize,
+    pub nodes_written: usize,
+}
+
+/// Rewrite a snapshot, streaming its data nodes through a transformation, without loading the
+/// whole tree in memory. Sessions are copied as is.
+///
+/// ZooKeeper requires parents to come before their children: nodes are written in the order they
+/// are read, so transformations that rename nodes must keep this order, or hold nodes back (see
+/// `Transform::pending`). Removing a node requires removing its children. Parent stats
+/// (`cversion`, `pzxid`) aren't updated.
+pub fn rewrite(
+    snapshot: SnapshotFile<InitState>,
+    mut transform: impl Transform,
+    output: impl AsRef<Path>,
+) -> Result<RewriteReport, PersistenceError> {
+    let (sessions, acls) = snapshot.sessions()?.session_map()?;
+    let (mut acls, mut nodes) = acls.acl_map()?;
+    transform.acls(&mut acls);
+
+    let mut writer = SnapshotWriter::create(output)?;
+    writer.sessions(&sessions)?;
+    writer.acls(&acls)?;
+
+    let mut report = RewriteReport::default();
+    let mut write = |path: &str, node: &DataNode| -> Result<(), PersistenceError> {
+        report.nodes_written += 1;
+        writer.node(path, node)
+    };
+
+    let mut nodes_read = 0;
+    for r in nodes.by_ref() {
+        let (path, node) = r?;
+        nodes_read += 1;
+        if let Some((path, node)) = transform.node(path, node) {
+            write(&path, &node)?;
+        }
+        for (path, node) in transform.pending() {
+            write(&path, &node)?;
+        }
+    }
+    nodes.finish()?;
+
+    for (path, node) in transform.finish()? {
+        write(&path, &node)?;
+    }
+    report.nodes_read = nodes_read;
+
+    writer.finish(None)?;
+    Ok(report)
+}
+
+//----- Shrink
+
+/// What `shrink` does with data over the size threshold.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ShrinkMode {
+    /// Replace data with an empty value
+    Drop,
+    /// Keep the beginning of the data, up to the threshold
+    Truncate,
+}
+
+/// Result of `shrink`.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ShrinkReport {
+    pub nodes: usize,
+    /// Number of nodes whose data was dropped or truncated
+    pub shrunk: usize,
+    /// Number of data bytes removed
+    pub bytes_removed: u64,
+}
+
+/// Write a copy of a snapshot where data larger than `max_size` bytes is dropped or truncated,
+/// keeping the tree structure, ACLs, sessions and stats. This is useful to create lightweight
+/// test fixtures from production snapshots.
+pub fn shrink(
+    snapshot: SnapshotFile<InitState>,
+    output: impl AsRef<Path>,
+    max_size: usize,
+    mode: ShrinkMode,
+) -> Result<ShrinkReport, PersistenceError> {
+    let mut report = ShrinkReport::default();
+
+    let rewritten = rewrite(
+        snapshot,
+        |path, mut node: DataNode| {
+            if node.data.len() > max_size {
+                let size = match mode {
+                    ShrinkMode::Drop => 0,
+                    ShrinkMode::Truncate => max_size,
+                };
+                report.shrunk += 1;
+                report.bytes_removed += (node.data.len() - size) as u64;
+                node.data.truncate(size);
+            }
+            Some((path, node))
+        },
+        output,
+    )?;
+
+    report.nodes = rewritten.nodes_read;
+    Ok(report)
+}
+
+//----- Subtree move
+
+fn parent(path: &str) -> &str {
+    path.rfind('/').map_or("", |idx| &path[..idx])
+}
+
+fn is_in_subtree(path: &str, root: &str) -> bool {
+    path.starts_with(root) && (path.len() == root.len() || path[root.len()..].starts_with('/'))
+}
+
+/// Moves a subtree to a new path, keeping data, ACLs and stats. Use it with `rewrite` to edit a
+/// snapshot offline, or `requests` to apply the move on a live ensemble.
+///
+/// Moved nodes are held back until the parent of the destination is written.
+#[derive(Debug)]
+pub struct MoveSubtree {
+    from: String,
+    to: String,
+    parent_written: bool,
+    held: Vec<(String, DataNode)>,
+    moved: usize,
+    conflict: Option<String>,
+}
+
+impl MoveSubtree {
+    /// Move the subtree at `from` to `to`, whose parent must exist and which must not exist.
+    pub fn new(from: &str, to: &str) -> Result<MoveSubtree, ParseError> {
+        for path in &[from, to] {
+            if !path.starts_with('/') || path.ends_with('/') {
+                return Err(ParseError::invalid("path", path));
+            }
+        }
+        if is_in_subtree(to, from) {
+            return Err(ParseError::invalid("move destination, inside the moved subtree", to));
+        }
+
+        Ok(MoveSubtree {
+            from: from.to_owned(),
+            to: to.to_owned(),
+            parent_written: false,
+            held: Vec::new(),
+            moved: 0,
+            conflict: None,
+        })
+    }
+
+    /// The new path of a node, if it's in the moved subtree
+    pub fn rename(&self, path: &str) -> Option<String> {
+        if is_in_subtree(path, &self.from) {
+            Some(format!("{}{}", self.to, &path[self.from.len()..]))
+        } else {
+            None
+        }
+    }
+
+    fn in_destination(&self, path: &str) -> bool {
+        is_in_subtree(path, &self.to)
+    }
+
+    /// Requests that move the subtree on a live ensemble, given the nodes of the tree and the ACL
+    /// cache, for instance read from a recent snapshot.
+    ///
+    /// Nodes are created at the destination parents first, then deleted at the source children
+    /// first. Deletions fail if a node was modified since it was read. Data, ACLs and container
+    /// nodes are preserved, but stats can't be: new nodes have new zxids, times and versions.
+    /// Ephemeral and TTL nodes can't be moved.
+    pub fn requests(
+        &self,
+        nodes: impl IntoIterator<Item = Result<(String, DataNode), PersistenceError>>,
+        acls: &HashMap<ACLRef, Vec<ACL>>,
+    ) -> Result<(Vec<CreateRequest>, Vec<DeleteRequest>), PersistenceError> {
+        let open_acl = vec![ACL {
+            perms: PERM_ALL,
+            id: Id::anyone(),
+        }];
+
+        let mut moved = Vec::new();
+        for r in nodes {
+            let (path, node) = r?;
+            if self.in_destination(&path) {
+                return Err(PersistenceError::Inconsistent(format!(
+                    "Destination '{}' already exists",
+                    path
+                )));
+            }
+            if self.rename(&path).is_some() {
+                moved.push((path, node));
+            }
+        }
+
+        if moved.is_empty() {
+            return Err(PersistenceError::NotFound(format!("No node at '{}'", self.from)));
+        }
+        // Parents before children
+        moved.sort_by(|a, b| a.0.cmp(&b.0));
+
+        let mut creates = Vec::new();
+        let mut deletes = Vec::new();
+        for (path, node) in moved {
+            let flags = match node.stat.ephemeral_info.ephemeral_type() {
+                EphemeralType::Void => CreateMode::Persistent,
+                EphemeralType::Container => CreateMode::Container,
+                _ => {
+                    return Err(PersistenceError::Inconsistent(format!(
+                        "Can't move ephemeral or TTL node '{}'",
+                        path
+                    )))
+                }
+            };
+            let acl = match acls.get(&node.acl) {
+                Some(acl) => acl.clone(),
+                None if node.acl == ACLRef::OPEN_ACL_UNSAFE => open_acl.clone(),
+                None => {
+                    return Err(PersistenceError::Inconsistent(format!(
+                        "Unknown ACL reference {} on {}",
+                        node.acl.0, path
+                    )))
+                }
+            };
+
+            creates.push(CreateRequest {
+                path: self.rename(&path).unwrap(),
+                data: node.data,
+                acl,
+                flags,
+            });
+            deletes.push(DeleteRequest {
+                path,
+                version: OptionalVersion(node.stat.version.0),
+            });
+        }
+        deletes.reverse();
+
+        Ok((creates, deletes))
+    }
+}
+
+impl Transform for MoveSubtree {
+    fn node(&mut self, path: String, node: DataNode) -> Option<(String, DataNode)> {
+        if self.in_destination(&path) {
+            self.conflict.get_or_insert(path.clone());
+        }
+
+        if let Some(new_path) = self.rename(&path) {
+            self.moved += 1;
+            if self.parent_written {
+                return Some((new_path, node));
+            }
+            self.held.push((new_path, node));
+            return None;
+        }
+
+        if path == parent(&self.to) {
+            self.parent_written = true;
+        }
+        Some((path, node))
+    }
+
+    fn pending(&mut self) -> Vec<(String, DataNode)> {
+        if self.parent_written {
+            std::mem::take(&mut self.held)
+        } else {
+            Vec::new()
+        }
+    }
+
+    fn finish(&mut self) -> Result<Vec<(String, DataNode)>, PersistenceError> {
+        if let Some(path) = &self.conflict {
+            return Err(PersistenceError::Inconsistent(format!(
+                "Destination '{}' already exists",
+                path
+            )));
+        }
+        if self.moved == 0 {
+            return Err(PersistenceError::NotFound(format!("No node at '{}'", self.from)));
+        }
+        if !self.parent_written {
+            return Err(PersistenceError::NotFound(format!(
+                "Parent of '{}' doesn't exist",
+                self.to
+            )));
+        }
+        Ok(Vec::new())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::persistence::testing::*;
+    use crate::{Id, PERM_READ};
+
+    #[test]
+    fn rewrite_snapshot() {
+        let input = write_snapshot(
+            "rewrite",
+            &[(1, 10_000)],
+            &[],
+            &[
+                ("", node("", -1, 0, 0)),
+                ("/old", node("a", -1, 0, 1)),
+                ("/old/child", node("b", -1, 0, 2)),
+                ("/tmp", node("", -1, 0, 3)),
+                ("/tmp/x", node("", -1, 0, 4)),
+            ],
+        );
+        let output = input.with_file_name("snapshot.2");
+
+        struct Migration;
+        impl Transform for Migration {
+            fn acls(&mut self, acls: &mut HashMap<ACLRef, Vec<ACL>>) {
+                acls.insert(
+                    ACLRef(1),
+                    vec![ACL {
+                        perms: PERM_READ,
+                        id: Id::anyone(),
+                    }],
+                );
+            }
+
+            fn node(&mut self, path: String, mut node: DataNode) -> Option<(String, DataNode)> {
+                if path == "/tmp" || path.starts_with("/tmp/") {
+                    return None;
+                }
+                if !path.is_empty() {
+                    node.acl = ACLRef(1);
+                }
+                Some((path.replacen("/old", "/new", 1), node))
+            }
+        }
+
+        let report = rewrite(SnapshotFile::new(&input).unwrap(), Migration, &output).unwrap();
+        assert_eq!(
+            report,
+            RewriteReport {
+                nodes_read: 5,
+                nodes_written: 3
+            }
+        );
+
+        let (acls, nodes) = SnapshotFile::new(&output)
+            .unwrap()
+            .sessions()
+            .unwrap()
+            .acl_map()
+            .unwrap();
+        assert_eq!(acls.len(), 1);
+        let nodes = nodes.collect::<Result<Vec<_>, _>>().unwrap();
+        assert_eq!(
+            nodes.iter().map(|(p, n)| (p.as_str(), n.acl.0)).collect::<Vec<_>>(),
+            vec![("", -1), ("/new", 1), ("/new/child", 1)]
+        );
+        assert_eq!(nodes[2].1.data, b"b");
+
+        remove_snapshot(&input);
+    }
+
+    #[test]
+    fn shrink_snapshot() {
+        let input = write_snapshot(
+            "shrink",
+            &[(1, 10_000)],
+            &[],
+            &[
+                ("", node("", -1, 0, 0)),
+                ("/small", node("abc", -1, 0, 1)),
+                ("/large", node("abcdefgh", -1, 1, 2)),
+            ],
+        );
+        let output = input.with_file_name("snapshot.2");
+
+        let data = |mode| {
+            let report = shrink(SnapshotFile::new(&input).unwrap(), &output, 4, mode).unwrap();
+            let (_, nodes) = SnapshotFile::new(&output)
+                .unwrap()
+                .sessions()
+                .unwrap()
+                .acl_map()
+                .unwrap();
+            let nodes = nodes.collect::<Result<Vec<_>, _>>().unwrap();
+            assert_eq!(nodes[2].1.stat, node("", -1, 1, 2).stat);
+            (report, nodes.into_iter().map(|(_, n)| n.data).collect::<Vec<_>>())
+        };
+
+        let (report, nodes) = data(ShrinkMode::Truncate);
+        assert_eq!(nodes, vec![b"".to_vec(), b"abc".to_vec(), b"abcd".to_vec()]);
+        assert_eq!(
+            report,
+            ShrinkReport {
+                nodes: 3,
+                shrunk: 1,
+                bytes_removed: 4
+            }
+        );
+
+        let (report, nodes) = data(ShrinkMode::Drop);
+        assert_eq!(nodes[2], b"");
+        assert_eq!(report.bytes_removed, 8);
+
+        remove_snapshot(&input);
+    }
+
+    #[test]
+    fn move_subtree() {
+        let nodes = [
+            ("", node("", -1, 0, 0)),
+            ("/a", node("", -1, 0, 1)),
+            ("/a/b", node("x", 1, 0, 2)),
+            ("/a/b/c", node("y", -1, i64::MIN, 3)),
+            ("/z", node("", -1, 0, 4)),
+        ];
+        let input = write_snapshot("move-subtree", &[], &[(1, vec![])], &nodes);
+        let output = input.with_file_name("snapshot.2");
+
+        // The destination parent comes after the moved nodes
+        let report = rewrite(
+            SnapshotFile::new(&input).unwrap(),
+            MoveSubtree::new("/a/b", "/z/b").unwrap(),
+            &output,
+        )
+        .unwrap();
+        assert_eq!(report.nodes_written, 5);
+
+        let (_, written) = SnapshotFile::new(&output)
+            .unwrap()
+            .sessions()
+            .unwrap()
+            .acl_map()
+            .unwrap();
+        let written = written.collect::<Result<Vec<_>, _>>().unwrap();
+        assert_eq!(
+            written.iter().map(|(p, _)| p.as_str()).collect::<Vec<_>>(),
+            vec!["", "/a", "/z", "/z/b", "/z/b/c"]
+        );
+        assert_eq!(written[4].1, nodes[3].1);
+
+        let move_to = |to| {
+            rewrite(
+                SnapshotFile::new(&input).unwrap(),
+                MoveSubtree::new("/a/b", to).unwrap(),
+                &output,
+            )
+        };
+        assert!(move_to("/z").is_err()); // exists
+        assert!(move_to("/x/b").is_err()); // no parent
+        assert!(MoveSubtree::new("/a", "/a/b").is_err());
+
+        // Live requests
+        let (_, tree) = SnapshotFile::new(&input)
+            .unwrap()
+            .sessions()
+            .unwrap()
+            .acl_map()
+            .unwrap();
+        let acls = vec![(ACLRef(1), vec![])].into_iter().collect();
+        let (creates, deletes) = MoveSubtree::new("/a", "/z/a").unwrap().requests(tree, &acls).unwrap();
+        assert_eq!(
+            creates.iter().map(|r| r.path.as_str()).collect::<Vec<_>>(),
+            vec!["/z/a", "/z/a/b", "/z/a/b/c"]
+        );
+        assert!(creates[2].flags.is_container());
+        assert_eq!(creates[0].acl[0].id, Id::anyone());
+        assert_eq!(
+            deletes.iter().map(|r| r.path.as_str()).collect::<Vec<_>>(),
+            vec!["/a/b/c", "/a/b", "/a"]
+        );
+
+        remove_snapshot(&input);
+    }
+}
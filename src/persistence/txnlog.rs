@@ -1,16 +1,22 @@
 use ::serde::Deserialize;
+use ::serde::Serialize;
 use serde_derive::Deserialize;
 use serde_derive::Serialize;
 use named_type::NamedType;
 use named_type_derive::NamedType;
 
+use crate::error::PersistenceError;
 use crate::proto::ErrorCode;
 use crate::proto::OpCode;
 use crate::*;
-use crate::serde::EnumEncoding;
-use failure::Error;
+use crate::path::PathMatcher;
+use crate::serde::{CodecError, EnumEncoding};
+use super::checksum::{self, Checksum};
+use super::io::{ReadOptions, ScanReader};
+use super::replay::CancellationToken;
+use byteorder::{BigEndian, ByteOrder, ReadBytesExt, WriteBytesExt};
 use std::fs::File;
-use std::io::BufReader;
+use std::io::{BufReader, BufWriter, Cursor, Read, Seek, SeekFrom, Write};
 use std::iter::Iterator;
 use std::path::Path;
 use std::path::PathBuf;
@@ -19,7 +25,7 @@ use std::path::PathBuf;
 ///
 /// Compared to `ZooKeeper.jute` it doesn't contain the operation type, which is handled in a
 /// type-safe way in `TxnOperation`.
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 #[derive(Serialize, Deserialize)]
 pub struct TxnHeader {
     pub client_id: SessionId,
@@ -28,7 +34,7 @@ pub struct TxnHeader {
     pub time: Timestamp,
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 #[derive(Serialize, Deserialize)]
 pub struct CreateTxn {
     pub path: String,
@@ -38,7 +44,30 @@ pub struct CreateTxn {
     pub parent_c_version: Version,
 }
 
-#[derive(Debug)]
+/// Create txn of ZooKeeper before 3.3, without `parent_c_version`
+#[derive(Deserialize)]
+struct CreateTxnV0 {
+    path: String,
+    data: Vec<u8>,
+    acl: Vec<ACL>,
+    ephemeral: bool,
+}
+
+impl From<CreateTxnV0> for CreateTxn {
+    fn from(txn: CreateTxnV0) -> CreateTxn {
+        // Like SerializeUtils.deserializeTxn: the server then computes the parent's cversion when
+        // applying the txn
+        CreateTxn {
+            path: txn.path,
+            data: txn.data,
+            acl: txn.acl,
+            ephemeral: txn.ephemeral,
+            parent_c_version: Version(-1),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
 #[derive(Serialize, Deserialize)]
 pub struct CreateContainerTxn {
     pub path: String,
@@ -48,7 +77,7 @@ pub struct CreateContainerTxn {
     pub parent_c_version: Version,
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 #[derive(Serialize, Deserialize)]
 pub struct CreateTTLTxn {
     pub path: String,
@@ -59,13 +88,13 @@ pub struct CreateTTLTxn {
     pub ttl: i64,
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 #[derive(Serialize, Deserialize)]
 pub struct DeleteTxn {
     pub path: String,
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 #[derive(Serialize, Deserialize)]
 pub struct SetDataTxn {
     pub path: String,
@@ -74,14 +103,14 @@ pub struct SetDataTxn {
     pub version: Version,
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 #[derive(Serialize, Deserialize)]
 pub struct CheckVersionTxn {
     pub path: String,
     pub version: Version,
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 #[derive(Serialize, Deserialize)]
 pub struct SetACLTxn {
     pub path: String,
@@ -89,34 +118,34 @@ pub struct SetACLTxn {
     pub version: Version,
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 #[derive(Serialize, Deserialize)]
 pub struct SetMaxChildrenTxn {
     pub path: String,
     pub max: i32,
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 #[derive(Serialize, Deserialize)]
 pub struct CreateSessionTxn {
     pub time_out: Duration,
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 #[derive(Serialize, Deserialize)]
 pub struct ErrorTxn {
     pub err: ErrorCode,
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 #[derive(Serialize, Deserialize)]
 pub struct MultiTxn {
     pub txns: Vec<MultiTxnOperation>,
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 #[derive(Deserialize, Serialize)]
-#[derive(NamedType)]
+#[derive(NamedType, IntoStaticStr)]
 pub enum MultiTxnOperation {
     Create(CreateTxn),
     Create2(CreateTxn),
@@ -130,20 +159,33 @@ pub enum MultiTxnOperation {
 }
 
 /// A transaction, composed of its header and operation
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 #[derive(Deserialize, Serialize)]
 pub struct Txn {
     pub header: TxnHeader,
     pub op: TxnOperation,
+    /// Digest of the data tree after this transaction. Only written by ZooKeeper 3.6+ when
+    /// `zookeeper.digest.enabled` is set.
+    #[serde(skip)]
+    pub digest: Option<TxnDigest>,
+}
+
+/// Data tree digest, stored in log records after the transaction.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[derive(Deserialize, Serialize)]
+pub struct TxnDigest {
+    /// Version of the digest algorithm
+    pub version: i32,
+    pub tree_digest: i64,
 }
 
 /// A transaction operation.
 ///
-/// There's a hack in SerializeUtils.deserializeTxn for CreateV0 transactions that don't contain
-/// a version id. We assume the files we process are not ancient enough to have those.
-#[derive(Debug)]
+/// Create txns written before ZooKeeper 3.3 don't contain a parent cversion. Like
+/// SerializeUtils.deserializeTxn, they're read as `Create` with a `parent_c_version` of -1.
+#[derive(Debug, PartialEq)]
 #[derive(Deserialize, Serialize)]
-#[derive(NamedType)]
+#[derive(NamedType, IntoStaticStr)]
 pub enum TxnOperation {
     CreateSession(CreateSessionTxn),
     CloseSession,
@@ -160,6 +202,44 @@ pub enum TxnOperation {
     Multi(MultiTxn),
 }
 
+impl TxnOperation {
+    /// Paths of the nodes affected by this operation
+    pub fn paths(&self) -> Vec<&str> {
+        use TxnOperation::*;
+        match self {
+            CreateSession(_) | CloseSession | Error(_) => Vec::new(),
+            Create(t) | Create2(t) => vec![&t.path],
+            CreateTTL(t) => vec![&t.path],
+            CreateContainer(t) => vec![&t.path],
+            Delete(t) | DeleteContainer(t) => vec![&t.path],
+            Reconfig(t) | SetData(t) => vec![&t.path],
+            SetACL(t) => vec![&t.path],
+            Multi(m) => m.txns.iter().filter_map(MultiTxnOperation::path).collect(),
+        }
+    }
+
+    /// Does this operation affect a node matched by `matcher`?
+    pub fn matches(&self, matcher: &PathMatcher) -> bool {
+        self.paths().into_iter().any(|p| matcher.matches(p))
+    }
+}
+
+impl MultiTxnOperation {
+    /// Path of the node affected by this operation
+    pub fn path(&self) -> Option<&str> {
+        use MultiTxnOperation::*;
+        match self {
+            Create(t) | Create2(t) => Some(&t.path),
+            CreateTTL(t) => Some(&t.path),
+            CreateContainer(t) => Some(&t.path),
+            Delete(t) | DeleteContainer(t) => Some(&t.path),
+            SetData(t) => Some(&t.path),
+            Check(t) => Some(&t.path),
+            Error(_) => None,
+        }
+    }
+}
+
 /// A ZooKeeper transaction log file. After the initial header, it is a sequence of transactions.
 ///
 /// See [`LogFormatter.java`] and [`SerializeUtils.java`] for details.
@@ -167,8 +247,15 @@ pub enum TxnOperation {
 /// [`LogFormatter.java`]: https://github.com/apache/zookeeper/blob/master/zookeeper-server/src/main/java/org/apache/zookeeper/server/LogFormatter.java
 /// [`SerializeUtils.java`]: https://github.com/apache/zookeeper/blob/master/zookeeper-server/src/main/java/org/apache/zookeeper/server/util/SerializeUtils.java
 ///
+/// Records are read as a whole, then deserialized from a buffer, so that we can find what follows
+/// the transaction in the record.
+///
 pub struct TxnlogFile {
-    deser: crate::serde::Deserializer<BufReader<File>>,
+    reader: BufReader<ScanReader>,
+    deser: crate::serde::Deserializer<Cursor<Vec<u8>>>,
+    version: ServerVersion,
+    checksum: Option<Box<dyn Checksum + Send>>,
+    position: u64,
     done: bool,
 }
 
@@ -180,13 +267,22 @@ impl TxnlogFile {
     pub fn find_txnlog(
         dir: impl AsRef<Path>,
         snapshot_zxid: Zxid,
-    ) -> Result<impl Iterator<Item = Result<Txn, Error>>, Error> {
+    ) -> Result<impl Iterator<Item = Result<Txn, PersistenceError>>, PersistenceError> {
+        Self::find_txnlog_with_version(dir, snapshot_zxid, ServerVersion::LATEST)
+    }
+
+    /// Same as `find_txnlog`, reading logs written by a given server version.
+    pub fn find_txnlog_with_version(
+        dir: impl AsRef<Path>,
+        snapshot_zxid: Zxid,
+        version: ServerVersion,
+    ) -> Result<impl Iterator<Item = Result<Txn, PersistenceError>>, PersistenceError> {
         let paths = Self::find_txnlog_paths(dir, snapshot_zxid)?;
 
         // Open all txnfiles, failing if one can't be opened
         let files: Vec<_> = paths
             .into_iter()
-            .map(|path| TxnlogFile::new(path))
+            .map(|path| TxnlogFile::with_version(path, version))
             .collect::<Result<_, _>>()?;
 
         // Flatmap all files, keeping only transactions >= snapshot_zxid
@@ -200,9 +296,32 @@ impl TxnlogFile {
 
     /// Find transaction log files that include or are after `snapshot_zxid`.
     ///
-    pub fn find_txnlog_paths(dir: impl AsRef<Path>, snapshot_zxid: Zxid) -> Result<Vec<PathBuf>, Error> {
-        //
-        // Collect log files as (zxid, path) pairs
+    pub fn find_txnlog_paths(dir: impl AsRef<Path>, snapshot_zxid: Zxid) -> Result<Vec<PathBuf>, PersistenceError> {
+        let zxid_paths = Self::txnlog_zxid_paths(dir)?;
+
+        // Find the highest zxid that is <= snapshot_zxid
+        let max_zxid = zxid_paths
+            .iter()
+            .map(|(zxid, _)| *zxid)
+            .filter(|zxid| zxid <= &snapshot_zxid)
+            .max()
+            .ok_or_else(|| PersistenceError::NotFound(format!("No txnlogs found before zxid {:x}", snapshot_zxid.0)))?;
+
+        let result = zxid_paths
+            .into_iter()
+            .filter_map(|(zxid, path)| if zxid < max_zxid { None } else { Some(path) })
+            .collect();
+
+        Ok(result)
+    }
+
+    /// All transaction log files in a directory, oldest first.
+    pub fn txnlog_paths(dir: impl AsRef<Path>) -> Result<Vec<PathBuf>, PersistenceError> {
+        Ok(Self::txnlog_zxid_paths(dir)?.into_iter().map(|(_, path)| path).collect())
+    }
+
+    /// Collect log files as (zxid, path) pairs, sorted by zxid
+    fn txnlog_zxid_paths(dir: impl AsRef<Path>) -> Result<Vec<(Zxid, PathBuf)>, PersistenceError> {
         let mut zxid_paths = std::fs::read_dir(dir)?
             .filter_map(|r| r.ok())
             .map(|entry| entry.path())
@@ -215,82 +334,478 @@ impl TxnlogFile {
             .filter_map(|path| super::zxid_from_path(&path).map(|zxid| (zxid, path)))
             .collect::<Vec<_>>();
 
-        zxid_paths.sort_by(|(zxid1, _), (zxid2, _)| zxid1.cmp(&zxid2));
+        zxid_paths.sort_by_key(|(zxid, _)| *zxid);
 
-        // Find the highest zxid that is <= snapshot_zxid
-        let max_zxid = zxid_paths
-            .iter()
-            .map(|(zxid, _)| *zxid)
-            .filter(|zxid| zxid <= &snapshot_zxid)
-            .max()
-            .ok_or_else(|| format_err!("No txnlogs found before zxid {:x}", snapshot_zxid.0))?;
+        Ok(zxid_paths)
+    }
 
-        let result = zxid_paths
-            .into_iter()
-            .filter_map(|(zxid, path)| if zxid < max_zxid { None } else { Some(path) })
-            .collect();
+    /// Open a txnlog file, accepting all features of the most recent server version.
+    pub fn new(path: impl AsRef<Path>) -> Result<TxnlogFile, PersistenceError> {
+        Self::with_version(path, ServerVersion::LATEST)
+    }
 
-        Ok(result)
+    /// Open a txnlog file written by a given server version. Features that didn't exist in this
+    /// version (e.g. txn digests before 3.6) are rejected.
+    pub fn with_version(path: impl AsRef<Path>, version: ServerVersion) -> Result<TxnlogFile, PersistenceError> {
+        Self::with_options(path, version, &ReadOptions::default())
     }
 
-    pub fn new(path: impl AsRef<Path>) -> Result<TxnlogFile, Error> {
-        let file = BufReader::new(File::open(path)?);
-        let mut deser = crate::serde::de::from_reader(file);
+    /// Same as `with_version`, with options for the read buffer and kernel hints.
+    pub fn with_options(
+        path: impl AsRef<Path>,
+        version: ServerVersion,
+        options: &ReadOptions,
+    ) -> Result<TxnlogFile, PersistenceError> {
+        let mut reader = options.open(path)?;
+        let header = super::FileHeader::deserialize(&mut crate::serde::de::from_reader(&mut reader))?;
+
+        let mut deser = crate::serde::de::from_reader(Cursor::new(Vec::new()));
 
         // We read length separately for TxnOperations as zero indicates EOF
         deser.add_enum_mapping::<OpCode, TxnOperation>(EnumEncoding::Type);
         deser.add_enum_mapping::<OpCode, MultiTxnOperation>(EnumEncoding::TypeThenLength);
         deser.add_enum::<ErrorCode>();
 
-        let header = super::FileHeader::deserialize(&mut deser)?;
-
         if header.magic != super::TXNLOG_MAGIC {
-            return Err(failure::err_msg("Wrong magic number"));
+            return Err(PersistenceError::WrongMagic);
         }
 
         if header.version != 2 {
-            return Err(failure::err_msg("Wrong version number"));
+            return Err(PersistenceError::WrongVersion(header.version));
         }
 
-        Ok(TxnlogFile { deser, done: false })
+        Ok(TxnlogFile {
+            reader,
+            deser,
+            version,
+            checksum: Some(Box::new(checksum::Adler32)),
+            position: FILE_HEADER_LEN,
+            done: false,
+        })
+    }
+
+    /// Set the algorithm used to verify record checksums (Adler-32 by default), or disable
+    /// verification with `None`.
+    pub fn with_checksum(mut self, checksum: Option<Box<dyn Checksum + Send>>) -> Self {
+        self.checksum = checksum;
+        self
+    }
+
+    /// The server version this file is read for
+    pub fn version(&self) -> ServerVersion {
+        self.version
+    }
+
+    /// Position in the file of the next record
+    pub fn position(&self) -> u64 {
+        self.position
+    }
+
+    /// Read again from the next record, after the end of the log or a partial record, to read the
+    /// txns appended since then. Memory-mapped files don't see appended txns.
+    pub fn resume(&mut self) -> Result<(), PersistenceError> {
+        self.reader.seek(SeekFrom::Start(self.position))?;
+        self.done = false;
+        Ok(())
+    }
+}
+
+/// Length of the file header: magic, version and dbid
+const FILE_HEADER_LEN: u64 = 16;
+
+/// Read the operation of a create txn written before ZooKeeper 3.3.
+fn read_create_v0(deser: &mut crate::serde::Deserializer<Cursor<Vec<u8>>>) -> Result<TxnOperation, CodecError> {
+    if i32::deserialize(&mut *deser)? != OpCode::Create.code() {
+        return Err(CodecError::Eof);
     }
+    let txn = CreateTxnV0::deserialize(deser)?;
+    Ok(TxnOperation::Create(txn.into()))
+}
+
+/// Zxid in the header of a record's bytes: it follows the session id and cxid.
+pub(crate) fn record_zxid(record: &[u8]) -> Option<Zxid> {
+    record.get(12..20).map(|bytes| Zxid(BigEndian::read_i64(bytes)))
 }
 
 impl Iterator for TxnlogFile {
-    type Item = Result<Txn, Error>;
+    type Item = Result<Txn, PersistenceError>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        fn read_next(this: &mut TxnlogFile) -> Result<Option<Txn>, Error> {
+        fn read_next(this: &mut TxnlogFile) -> Result<Option<Txn>, PersistenceError> {
             // An Adler-32 CRC of the bytes that represent the txn (without the length)
-            let _crc = <u64>::deserialize(&mut this.deser)?;
+            let crc = this.reader.read_u64::<BigEndian>()?;
 
-            let length = <u32>::deserialize(&mut this.deser)?;
+            let length = this.reader.read_u32::<BigEndian>()? as usize;
             if length == 0 {
                 // Txnlog files are 64MB pre-allocated files, and zero length indicates end of log
                 return Ok(None);
             }
 
-            let txn = Txn::deserialize(&mut this.deser)?;
+            // Don't trust the length to preallocate the buffer, in case the file is corrupted
+            let buffer = this.deser.get_mut();
+            buffer.get_mut().clear();
+            buffer.set_position(0);
+            (&mut this.reader).take(length as u64).read_to_end(buffer.get_mut())?;
+            if buffer.get_ref().len() != length {
+                return Err(PersistenceError::Partial);
+            }
+
+            let mismatch = this.checksum.as_ref().and_then(|algo| {
+                let bytes = buffer.get_ref();
+                if algo.compute(bytes) == crc {
+                    return None;
+                }
+                Some(PersistenceError::ChecksumMismatch {
+                    algorithm: algo.name(),
+                    zxid: record_zxid(bytes),
+                    detected: checksum::detect(bytes, crc).map(|c| c.name()),
+                })
+            });
+            if let Some(mismatch) = mismatch {
+                // Skip the record if it's complete, so that the next ones can be read. Otherwise
+                // its length can't be trusted to find the next record.
+                if this.reader.read_u8().ok() != Some(0x42) {
+                    this.done = true;
+                    return Err(PersistenceError::Partial);
+                }
+                this.position += 8 + 4 + length as u64 + 1;
+                return Err(mismatch);
+            }
+
+            let header = TxnHeader::deserialize(&mut this.deser)?;
+            let op_position = this.deser.get_ref().position();
+            let op = match TxnOperation::deserialize(&mut this.deser) {
+                Err(CodecError::Eof) => {
+                    // Maybe an old create txn, that is shorter
+                    this.deser.get_mut().set_position(op_position);
+                    read_create_v0(&mut this.deser).map_err(|_| CodecError::Eof)
+                }
+                op => op,
+            };
+
+            let result = match op {
+                Ok(op) => {
+                    let mut txn = Txn { header, op, digest: None };
+
+                    // Remaining bytes in the record are the txn digest
+                    if (this.deser.get_ref().position() as usize) < length {
+                        if this.version < ServerVersion::V3_6 {
+                            return Err(PersistenceError::Corrupted(format!(
+                                "Unexpected data after txn {:x}: txn digests require ZooKeeper 3.6+",
+                                txn.header.zxid.0
+                            )));
+                        }
+                        txn.digest = Some(TxnDigest::deserialize(&mut this.deser)?);
+                    }
+                    Ok(txn)
+                }
+                // The record's length is known: skip it so that the next ones can be read
+                Err(CodecError::UnknownCode { code, .. }) => Err(PersistenceError::UnknownOpCode {
+                    zxid: header.zxid,
+                    code,
+                }),
+                Err(e) => return Err(e.into()),
+            };
 
             // Next byte must be 'B' (0x42) (see LogFormatter.java & o.a.z.s.persistence.Util.java)
-            let b = <u8>::deserialize(&mut this.deser)?;
+            let b = this.reader.read_u8()?;
             if b != 0x42 {
-                return Err(failure::err_msg("Last transaction was partial."));
+                return Err(PersistenceError::Partial);
             }
 
-            Ok(Some(txn))
+            // crc, length, record, 'B'
+            this.position += 8 + 4 + length as u64 + 1;
+
+            result.map(Some)
         }
 
         if self.done {
             None
         } else {
             let result = read_next(self).transpose();
-            self.done = result.is_none();
+            if result.is_none() {
+                self.done = true;
+            }
             result
         }
     }
 }
 
+/// Follows the txnlogs of a live data directory, like `tail -f`: once the end of the log is
+/// reached, it is polled for new txns, and the next txnlog is read once the server has rolled over
+/// to it. The iterator ends only when cancelled.
+///
+/// Records that the server is still writing are read again at the next poll. They're reported as
+/// errors if the server has already rolled over to the next txnlog, as they'll never be complete.
+///
+pub struct TxnlogTail {
+    dir: PathBuf,
+    from: Zxid,
+    version: ServerVersion,
+    /// The txnlog being read, with its zxid
+    current: Option<(Zxid, PathBuf, TxnlogFile)>,
+    /// A more recent txnlog exists: the current one is read one last time
+    draining: bool,
+    poll_interval: std::time::Duration,
+    cancel: CancellationToken,
+}
+
+/// Default interval between polls of the current txnlog
+pub const DEFAULT_POLL_INTERVAL: std::time::Duration = std::time::Duration::from_millis(200);
+
+impl TxnlogTail {
+    /// Follow the txnlogs of a directory, starting at `from`. Txnlogs don't need to exist yet.
+    pub fn new(dir: impl AsRef<Path>, from: Zxid) -> TxnlogTail {
+        TxnlogTail {
+            dir: dir.as_ref().to_path_buf(),
+            from,
+            version: ServerVersion::LATEST,
+            current: None,
+            draining: false,
+            poll_interval: DEFAULT_POLL_INTERVAL,
+            cancel: CancellationToken::new(),
+        }
+    }
+
+    /// Read txnlogs written by a given server version.
+    pub fn with_version(mut self, version: ServerVersion) -> Self {
+        self.version = version;
+        self
+    }
+
+    /// Set the interval between polls when there are no new txns.
+    pub fn with_poll_interval(mut self, interval: std::time::Duration) -> Self {
+        self.poll_interval = interval;
+        self
+    }
+
+    /// Set the token used to stop following txnlogs.
+    pub fn cancellation_token(mut self, token: CancellationToken) -> Self {
+        self.cancel = token;
+        self
+    }
+
+    /// Path of the txnlog being read
+    pub fn path(&self) -> Option<&Path> {
+        self.current.as_ref().map(|(_, path, _)| path.as_path())
+    }
+
+    /// Read the next txn if there is one, without waiting.
+    pub fn poll(&mut self) -> Result<Option<Txn>, PersistenceError> {
+        if self.current.is_none() {
+            self.current = self.open_first()?;
+        }
+        let version = self.version;
+
+        while let Some((zxid, _, log)) = &mut self.current {
+            let err = match log.next() {
+                Some(Ok(txn)) if txn.header.zxid < self.from => continue,
+                Some(Ok(txn)) => return Ok(Some(txn)),
+                Some(Err(e)) if !Self::is_incomplete(&e) => return Err(e),
+                Some(Err(e)) => Some(e),
+                None => None,
+            };
+
+            // End of log, or a record that is still being written
+            let zxid = *zxid;
+            let next = TxnlogFile::txnlog_zxid_paths(&self.dir)?
+                .into_iter()
+                .find(|(z, _)| *z > zxid);
+            let next = match next {
+                Some(next) if self.draining => next,
+                Some(_) => {
+                    // Read txns appended before the server rolled over
+                    self.draining = true;
+                    log.resume()?;
+                    continue;
+                }
+                None => {
+                    log.resume()?;
+                    return Ok(None);
+                }
+            };
+
+            match Self::open(&next.1, version)? {
+                Some(next_log) => {
+                    self.current = Some((next.0, next.1, next_log));
+                    self.draining = false;
+                    if let Some(e) = err {
+                        return Err(e);
+                    }
+                }
+                None => {
+                    // Wait for the header of the next txnlog
+                    log.resume()?;
+                    return Ok(None);
+                }
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Errors of records that may still be written
+    fn is_incomplete(err: &PersistenceError) -> bool {
+        match err {
+            PersistenceError::Partial => true,
+            PersistenceError::Io(e) => e.kind() == std::io::ErrorKind::UnexpectedEof,
+            _ => false,
+        }
+    }
+
+    /// Open the txnlog that contains `from`, or the first one after it, if there's one yet.
+    fn open_first(&self) -> Result<Option<(Zxid, PathBuf, TxnlogFile)>, PersistenceError> {
+        let mut paths = TxnlogFile::txnlog_zxid_paths(&self.dir)?;
+        let (zxid, path) = match paths.iter().rposition(|(zxid, _)| *zxid <= self.from) {
+            Some(pos) => paths.swap_remove(pos),
+            None if !paths.is_empty() => paths.remove(0),
+            None => return Ok(None),
+        };
+        Ok(Self::open(&path, self.version)?.map(|log| (zxid, path, log)))
+    }
+
+    /// Open a txnlog, unless its header hasn't been written yet
+    fn open(path: &Path, version: ServerVersion) -> Result<Option<TxnlogFile>, PersistenceError> {
+        match TxnlogFile::with_version(path, version) {
+            Ok(log) => Ok(Some(log)),
+            Err(PersistenceError::Codec(CodecError::Eof)) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+impl Iterator for TxnlogTail {
+    type Item = Result<Txn, PersistenceError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if self.cancel.is_cancelled() {
+                return None;
+            }
+            match self.poll() {
+                Ok(Some(txn)) => return Some(Ok(txn)),
+                Ok(None) => std::thread::sleep(self.poll_interval),
+                Err(e) => return Some(Err(e)),
+            }
+        }
+    }
+}
+
+/// Txnlog files are extended by chunks of this size, like ZooKeeper's `zookeeper.preAllocSize`
+pub const PREALLOCATION_SIZE: u64 = 64 * 1024 * 1024;
+
+/// Zero bytes after the last record: a zero CRC and length mark the end of the log
+const END_OF_LOG_LEN: u64 = 12;
+
+/// Writes txnlog files that can be read by ZooKeeper servers.
+///
+/// Like the server, the file is extended by `PREALLOCATION_SIZE` chunks filled with zeros, where
+/// a zero record length marks the end of the log. The file name should be `log.<zxid in hex>`, with
+/// the zxid of the first txn.
+///
+pub struct TxnlogWriter {
+    out: BufWriter<File>,
+    ser: crate::serde::Serializer<Vec<u8>>,
+    preallocation: u64,
+    /// Position in the file of the next record
+    position: u64,
+    file_size: u64,
+    checksum: Box<dyn Checksum + Send>,
+}
+
+impl TxnlogWriter {
+    /// Create a txnlog file and write its header.
+    pub fn create(path: impl AsRef<Path>) -> Result<TxnlogWriter, PersistenceError> {
+        let mut out = BufWriter::new(File::create(path)?);
+
+        let header = super::FileHeader {
+            magic: super::TXNLOG_MAGIC,
+            version: 2,
+            dbid: 0,
+        };
+        header.serialize(&mut crate::serde::ser::to_writer(&mut out))?;
+
+        let mut ser = crate::serde::ser::to_writer(Vec::new());
+        ser.add_enum_mapping::<OpCode, TxnOperation>(EnumEncoding::Type);
+        ser.add_enum_mapping::<OpCode, MultiTxnOperation>(EnumEncoding::TypeThenLength);
+        ser.add_enum::<ErrorCode>();
+
+        Ok(TxnlogWriter {
+            out,
+            ser,
+            preallocation: PREALLOCATION_SIZE,
+            position: FILE_HEADER_LEN,
+            file_size: FILE_HEADER_LEN,
+            checksum: Box::new(checksum::Adler32),
+        })
+    }
+
+    /// Set the size of the chunks by which the file is extended. With zero, the file is only
+    /// extended by what is needed for each record.
+    pub fn with_preallocation(mut self, size: u64) -> Self {
+        self.preallocation = size;
+        self
+    }
+
+    /// Set the algorithm of record checksums (Adler-32 by default). The file must then be read
+    /// with the same algorithm.
+    pub fn with_checksum(mut self, checksum: Box<dyn Checksum + Send>) -> Self {
+        self.checksum = checksum;
+        self
+    }
+
+    /// Position in the file of the next record
+    pub fn position(&self) -> u64 {
+        self.position
+    }
+
+    /// Append a txn, followed by its digest if it has one.
+    pub fn append(&mut self, txn: &Txn) -> Result<(), PersistenceError> {
+        self.ser.get_mut().clear();
+        txn.serialize(&mut self.ser)?;
+        if let Some(digest) = &txn.digest {
+            digest.serialize(&mut self.ser)?;
+        }
+
+        // crc, length, record, 'B'
+        let len = 8 + 4 + self.ser.get_ref().len() as u64 + 1;
+        self.pad(len)?;
+
+        let record = self.ser.get_ref();
+        self.out.write_u64::<BigEndian>(self.checksum.compute(record))?;
+        self.out.write_u32::<BigEndian>(record.len() as u32)?;
+        self.out.write_all(record)?;
+        self.out.write_u8(0x42)?;
+        self.position += len;
+
+        Ok(())
+    }
+
+    /// Extend the file with zeros so that a record of `len` bytes is followed by an end of log.
+    fn pad(&mut self, len: u64) -> Result<(), PersistenceError> {
+        let end = self.position + len + END_OF_LOG_LEN;
+        if end <= self.file_size {
+            return Ok(());
+        }
+
+        let size = if self.preallocation == 0 {
+            end
+        } else {
+            end.div_ceil(self.preallocation) * self.preallocation
+        };
+        self.out.get_ref().set_len(size)?;
+        self.file_size = size;
+        Ok(())
+    }
+
+    /// Flush appended txns and sync them to disk, like the server does when committing txns.
+    pub fn commit(&mut self) -> Result<(), PersistenceError> {
+        self.out.flush()?;
+        self.out.get_ref().sync_data()?;
+        Ok(())
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -307,9 +822,9 @@ mod tests {
             let _txn = x.unwrap();
 
             let acl = match &_txn {
-                Txn { header: _, op: Create(c) } => Some(&c.acl),
-                Txn { header: _, op: Create2(c) } => Some(&c.acl),
-                Txn { header: _, op: CreateContainer(c) } => Some(&c.acl),
+                Txn { op: Create(c), .. } => Some(&c.acl),
+                Txn { op: Create2(c), .. } => Some(&c.acl),
+                Txn { op: CreateContainer(c), .. } => Some(&c.acl),
                 _ => None
             };
 
@@ -337,4 +852,302 @@ mod tests {
 
         println!("{} transactions", count);
     }
+
+    #[test]
+    fn txn_digest() {
+        use byteorder::{BigEndian, WriteBytesExt};
+
+        let path = std::env::temp_dir().join("zookeepers-txn-digest.log.1");
+
+        let mut bytes = Vec::new();
+        bytes.write_i32::<BigEndian>(crate::persistence::TXNLOG_MAGIC).unwrap();
+        bytes.write_i32::<BigEndian>(2).unwrap(); // version
+        bytes.write_i64::<BigEndian>(-1).unwrap(); // dbid
+
+        let mut record = Vec::new();
+        record.write_i64::<BigEndian>(1).unwrap(); // session
+        record.write_i32::<BigEndian>(0).unwrap(); // cxid
+        record.write_i64::<BigEndian>(1).unwrap(); // zxid
+        record.write_i64::<BigEndian>(0).unwrap(); // time
+        record.write_i32::<BigEndian>(-11).unwrap(); // close session
+        record.write_i32::<BigEndian>(2).unwrap(); // digest version
+        record.write_i64::<BigEndian>(1234).unwrap(); // digest
+
+        bytes.write_u64::<BigEndian>(checksum::Adler32.compute(&record)).unwrap();
+        bytes.write_u32::<BigEndian>(record.len() as u32).unwrap();
+        bytes.extend_from_slice(&record);
+        bytes.push(b'B');
+        bytes.extend_from_slice(&[0; 12]); // end of log
+        std::fs::write(&path, &bytes).unwrap();
+
+        let txns = TxnlogFile::new(&path).unwrap().collect::<Result<Vec<_>, _>>().unwrap();
+        assert_eq!(txns.len(), 1);
+        assert!(matches!(txns[0].op, CloseSession));
+        assert!(txns[0].op.paths().is_empty());
+        assert_eq!(txns[0].digest, Some(TxnDigest { version: 2, tree_digest: 1234 }));
+
+        let mut txns = TxnlogFile::with_version(&path, ServerVersion::V3_5).unwrap();
+        assert!(txns.next().unwrap().is_err());
+
+        let mut txns = TxnlogFile::new(&path).unwrap().with_checksum(Some(Box::new(checksum::Crc32c)));
+        let err = txns.next().unwrap().unwrap_err();
+        assert_eq!(err.to_string(), "CRC-32C checksum mismatch in txn 1 (matches Adler-32)");
+
+        let txns = TxnlogFile::new(&path).unwrap().with_checksum(None);
+        assert_eq!(txns.count(), 1);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn create_v0() {
+        use crate::persistence::testing::*;
+
+        let dir = temp_dir("create-v0");
+        let create = create_op("/app", "data", true);
+        // Without parent cversion
+        let create_v0 = &create[..create.len() - 4];
+        let bodies = vec![txn_body(1, 10, 1, create_v0), txn_body(2, 10, 1, &create)];
+        let log = write_txnlog(&dir, 1, &bodies);
+
+        let txns = TxnlogFile::new(&log).unwrap().collect::<Result<Vec<_>, _>>().unwrap();
+        assert_eq!(txns.len(), 2);
+        for (txn, parent_c_version) in txns.iter().zip(&[-1, 0]) {
+            match &txn.op {
+                Create(create) => {
+                    assert_eq!(create.path, "/app");
+                    assert!(create.ephemeral);
+                    assert_eq!(create.parent_c_version, Version(*parent_c_version));
+                }
+                op => panic!("Unexpected operation {:?}", op),
+            }
+        }
+
+        // Only create txns have an old format
+        let bodies = vec![txn_body(1, 10, 15, create_v0)];
+        let log = write_txnlog(&dir, 1, &bodies);
+        assert!(TxnlogFile::new(&log).unwrap().next().unwrap().is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn unknown_op_code() {
+        use crate::persistence::testing::*;
+
+        let dir = temp_dir("unknown-op-code");
+        let set_data = path_op("/app", Some("data"));
+        let bodies = vec![
+            txn_body(1, 10, 5, &set_data),
+            txn_body(2, 10, 999, &set_data),
+            // Known operation, but not a txn
+            txn_body(3, 10, OpCode::MultiRead.code(), &set_data),
+            txn_body(4, 10, 5, &set_data),
+        ];
+        let log = write_txnlog(&dir, 1, &bodies);
+
+        let txns = TxnlogFile::new(&log).unwrap().collect::<Vec<_>>();
+        assert_eq!(txns.len(), 4);
+        assert_eq!(txns[0].as_ref().unwrap().header.zxid, Zxid(1));
+        assert!(matches!(
+            txns[1],
+            Err(PersistenceError::UnknownOpCode { zxid: Zxid(2), code: 999 })
+        ));
+        assert!(matches!(
+            txns[2],
+            Err(PersistenceError::UnknownOpCode { zxid: Zxid(3), code: 22 })
+        ));
+        assert_eq!(txns[3].as_ref().unwrap().header.zxid, Zxid(4));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn checksum_mismatch() {
+        use crate::persistence::testing::*;
+
+        let dir = temp_dir("checksum-mismatch");
+        let set_data = path_op("/app", Some("data"));
+        let bodies = (1..4).map(|zxid| txn_body(zxid, 10, 5, &set_data)).collect::<Vec<_>>();
+        let log = write_txnlog(&dir, 1, &bodies);
+        let mut bytes = std::fs::read(&log).unwrap();
+        let record_len = 8 + 4 + bodies[0].len() + 1;
+        // Last data byte of the second record
+        let corrupted = FILE_HEADER_LEN as usize + 2 * record_len - 2;
+        bytes[corrupted] ^= 0xff;
+        std::fs::write(&log, &bytes).unwrap();
+
+        // The corrupted record is skipped
+        let txns = TxnlogFile::new(&log).unwrap().collect::<Vec<_>>();
+        assert_eq!(txns.len(), 3);
+        assert_eq!(txns[0].as_ref().unwrap().header.zxid, Zxid(1));
+        assert!(matches!(
+            txns[1],
+            Err(PersistenceError::ChecksumMismatch { zxid: Some(Zxid(2)), .. })
+        ));
+        assert_eq!(txns[2].as_ref().unwrap().header.zxid, Zxid(3));
+
+        // Unless it's incomplete, which ends the log
+        bytes[corrupted + 1..].iter_mut().for_each(|b| *b = 0);
+        std::fs::write(&log, &bytes).unwrap();
+        let txns = TxnlogFile::new(&log).unwrap().collect::<Vec<_>>();
+        assert_eq!(txns.len(), 2);
+        assert!(matches!(txns[1], Err(PersistenceError::Partial)));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn tail_txnlogs() {
+        use crate::persistence::testing::temp_dir;
+
+        let dir = temp_dir("tail-txnlogs");
+        let txn = |zxid: i64| Txn {
+            header: TxnHeader {
+                client_id: SessionId(1),
+                cxid: Xid(0),
+                zxid: Zxid(zxid),
+                time: Timestamp(0),
+            },
+            op: CloseSession,
+            digest: None,
+        };
+        let zxids = |tail: &mut TxnlogTail| {
+            std::iter::from_fn(|| tail.poll().unwrap())
+                .map(|txn| txn.header.zxid.0)
+                .collect::<Vec<_>>()
+        };
+
+        let mut tail = TxnlogTail::new(&dir, Zxid(2));
+        assert_eq!(zxids(&mut tail), Vec::<i64>::new());
+
+        let mut writer = TxnlogWriter::create(dir.join("log.1")).unwrap().with_preallocation(4096);
+        for zxid in 1..4 {
+            writer.append(&txn(zxid)).unwrap();
+        }
+        writer.commit().unwrap();
+        assert_eq!(zxids(&mut tail), vec![2, 3]);
+        assert_eq!(tail.path(), Some(dir.join("log.1").as_path()));
+
+        writer.append(&txn(4)).unwrap();
+        writer.commit().unwrap();
+        assert_eq!(zxids(&mut tail), vec![4]);
+
+        // Txns appended before the rollover are read before the next txnlog
+        writer.append(&txn(5)).unwrap();
+        writer.commit().unwrap();
+        let mut next = TxnlogWriter::create(dir.join("log.6")).unwrap();
+        next.append(&txn(6)).unwrap();
+        next.commit().unwrap();
+        assert_eq!(zxids(&mut tail), vec![5, 6]);
+        assert_eq!(tail.path(), Some(dir.join("log.6").as_path()));
+
+        // A partial record is read again once complete
+        let record_start = next.position();
+        next.append(&txn(7)).unwrap();
+        next.commit().unwrap();
+        let bytes = std::fs::read(dir.join("log.6")).unwrap();
+        let mut partial = bytes.clone();
+        partial[record_start as usize + 20..].iter_mut().for_each(|b| *b = 0);
+        std::fs::write(dir.join("log.6"), &partial).unwrap();
+        assert_eq!(zxids(&mut tail), Vec::<i64>::new());
+        std::fs::write(dir.join("log.6"), &bytes).unwrap();
+        assert_eq!(zxids(&mut tail), vec![7]);
+
+        let token = CancellationToken::new();
+        token.cancel();
+        assert!(tail.cancellation_token(token).next().is_none());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn write_txnlog() {
+        use crate::persistence::testing::*;
+
+        let dir = temp_dir("write-txnlog");
+        let bodies = [
+            txn_body(1, 10, -10, &[0, 0, 0x75, 0x30]),
+            txn_body(2, 10, 1, &create_op("/app", "data", true)),
+            txn_body(3, 10, 5, &path_op("/app", Some("x"))),
+            txn_body(4, 10, 2, &path_op("/app", None)),
+            txn_body(5, 10, -11, &[]),
+        ];
+        let input = write_txnlog(&dir, 1, &bodies);
+        let mut txns = TxnlogFile::new(&input).unwrap().collect::<Result<Vec<_>, _>>().unwrap();
+
+        txns.push(Txn {
+            header: TxnHeader {
+                client_id: SessionId(11),
+                cxid: Xid(1),
+                zxid: Zxid(6),
+                time: Timestamp(6000),
+            },
+            op: Multi(MultiTxn {
+                txns: vec![
+                    MultiTxnOperation::Check(CheckVersionTxn {
+                        path: "/app".to_owned(),
+                        version: Version(1),
+                    }),
+                    MultiTxnOperation::Error(ErrorTxn {
+                        err: ErrorCode::BadVersion,
+                    }),
+                ],
+            }),
+            digest: Some(TxnDigest {
+                version: 2,
+                tree_digest: 1234,
+            }),
+        });
+
+        let output = dir.join("log.2");
+        let mut writer = TxnlogWriter::create(&output).unwrap().with_preallocation(4096);
+        for txn in &txns {
+            writer.append(txn).unwrap();
+        }
+        writer.commit().unwrap();
+        let position = writer.position();
+        drop(writer);
+
+        assert_eq!(std::fs::metadata(&output).unwrap().len(), 4096);
+        let written = std::fs::read(&output).unwrap();
+        let original = std::fs::read(&input).unwrap();
+        // Same records for the txns that were read, apart from the dbid in the header
+        let len = original.len() - END_OF_LOG_LEN as usize;
+        assert_eq!(written[16..len], original[16..len]);
+        assert!(written[position as usize..].iter().all(|b| *b == 0));
+
+        let read = TxnlogFile::new(&output).unwrap().collect::<Result<Vec<_>, _>>().unwrap();
+        assert_eq!(read, txns);
+
+        let unpadded = dir.join("log.3");
+        let mut writer = TxnlogWriter::create(&unpadded).unwrap().with_preallocation(0);
+        writer.append(&txns[0]).unwrap();
+        writer.commit().unwrap();
+        assert_eq!(std::fs::metadata(&unpadded).unwrap().len(), writer.position() + 12);
+
+        let crc = dir.join("log.4");
+        let mut writer = TxnlogWriter::create(&crc)
+            .unwrap()
+            .with_checksum(Box::new(checksum::Crc32c));
+        for txn in &txns {
+            writer.append(txn).unwrap();
+        }
+        writer.commit().unwrap();
+        drop(writer);
+
+        let read = TxnlogFile::new(&crc)
+            .unwrap()
+            .with_checksum(Some(Box::new(checksum::Crc32c)))
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(read, txns);
+        let err = TxnlogFile::new(&crc).unwrap().next().unwrap().unwrap_err();
+        assert!(matches!(
+            err,
+            PersistenceError::ChecksumMismatch { detected: Some("CRC-32C"), .. }
+        ));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
 }
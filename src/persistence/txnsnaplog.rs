@@ -0,0 +1,134 @@
+//! The snapshots and txnlogs of a server, as a whole.
+//!
+//! `FileTxnSnapLog` mirrors the Java class of the same name: it is given the `dataDir` and
+//! `dataLogDir` of a server's configuration, and restores the data tree from the most recent valid
+//! snapshot and the txns that follow it, or saves the tree to a new snapshot.
+
+use std::path::{Path, PathBuf};
+
+use super::datatree::DataTree;
+use super::replay::Replay;
+use super::snapshot::{SnapshotFile, SnapshotWriter, MAX_SNAPSHOT_CANDIDATES};
+use super::txnlog::TxnlogFile;
+use crate::error::PersistenceError;
+use crate::Zxid;
+
+/// Name of the directory where snapshots and txnlogs are stored, in `dataDir` and `dataLogDir`
+pub const VERSION_DIR: &str = "version-2";
+
+/// Snapshots and txnlogs of a server.
+#[derive(Debug, Clone)]
+pub struct FileTxnSnapLog {
+    snap_dir: PathBuf,
+    log_dir: PathBuf,
+}
+
+impl FileTxnSnapLog {
+    /// Snapshots and txnlogs of a server's `dataDir` and `dataLogDir`, which are the same directory
+    /// if `dataLogDir` isn't set. Like ZooKeeper, files are in their `version-2` subdirectory,
+    /// which is created if needed.
+    pub fn new(data_dir: impl AsRef<Path>, data_log_dir: impl AsRef<Path>) -> Result<Self, PersistenceError> {
+        let snap_dir = data_dir.as_ref().join(VERSION_DIR);
+        let log_dir = data_log_dir.as_ref().join(VERSION_DIR);
+        std::fs::create_dir_all(&snap_dir)?;
+        std::fs::create_dir_all(&log_dir)?;
+        Ok(FileTxnSnapLog { snap_dir, log_dir })
+    }
+
+    /// Directory of snapshots
+    pub fn snap_dir(&self) -> &Path {
+        &self.snap_dir
+    }
+
+    /// Directory of txnlogs
+    pub fn log_dir(&self) -> &Path {
+        &self.log_dir
+    }
+
+    /// Restore the data tree from the most recent valid snapshot and the txns that follow it, and
+    /// return it with the zxid of the last txn.
+    ///
+    /// Without a snapshot, the tree is empty, like a new server's. This fails if there are txnlogs,
+    /// that can't be replayed without their snapshot.
+    pub fn restore(&self) -> Result<(DataTree, Zxid), PersistenceError> {
+        let txnlogs = TxnlogFile::txnlog_paths(&self.log_dir)?;
+
+        let snapshot = SnapshotFile::find_verified_snapshot(&self.snap_dir, MAX_SNAPSHOT_CANDIDATES, false)?;
+        let mut tree = match snapshot {
+            Some(snapshot) => DataTree::from_snapshot(snapshot)?,
+            None if !txnlogs.is_empty() => {
+                return Err(PersistenceError::NotFound(format!(
+                    "No valid snapshot in {}, but there are txnlogs in {}",
+                    self.snap_dir.display(),
+                    self.log_dir.display()
+                )))
+            }
+            None => DataTree::new(),
+        };
+
+        if let Some(first) = txnlogs.first().and_then(super::zxid_from_path) {
+            // The first txnlog can directly follow the snapshot, e.g. the initial snapshot.0 of a
+            // server followed by log.1
+            let from = if first.0 == tree.zxid().0 + 1 {
+                first
+            } else {
+                tree.zxid()
+            };
+            tree.replay(Replay::new(&self.log_dir, from)?)?;
+        }
+
+        let zxid = tree.zxid();
+        Ok((tree, zxid))
+    }
+
+    /// Write a snapshot of `tree` at its zxid, and return its path.
+    pub fn save(&self, tree: &DataTree) -> Result<PathBuf, PersistenceError> {
+        let path = self.snap_dir.join(format!("snapshot.{:x}", tree.zxid().0));
+
+        let mut writer = SnapshotWriter::create(&path)?;
+        writer.sessions(tree.sessions())?;
+        writer.acls(tree.acls())?;
+        for (path, node) in tree.nodes() {
+            writer.node(path, node)?;
+        }
+        writer.finish(None)?.get_ref().sync_all()?;
+
+        Ok(path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::persistence::testing::*;
+
+    #[test]
+    fn restore_and_save() {
+        let dir = temp_dir("file-txn-snap-log");
+        let snap_log = FileTxnSnapLog::new(dir.join("data"), dir.join("log")).unwrap();
+
+        let (tree, zxid) = snap_log.restore().unwrap();
+        assert_eq!((tree.node_count(), zxid), (1, Zxid(0)));
+
+        let bodies = vec![
+            txn_body(1, 10, 1, &create_op("/app", "a", false)),
+            txn_body(2, 10, 5, &path_op("/app", Some("b"))),
+        ];
+        write_txnlog(snap_log.log_dir(), 1, &bodies);
+        assert!(matches!(snap_log.restore(), Err(PersistenceError::NotFound(_))));
+
+        // Initial snapshot of a new server
+        snap_log.save(&DataTree::new()).unwrap();
+        let (tree, zxid) = snap_log.restore().unwrap();
+        assert_eq!(zxid, Zxid(2));
+        assert_eq!(tree.get("/app").unwrap().data, b"b");
+
+        let path = snap_log.save(&tree).unwrap();
+        assert_eq!(path, dir.join("data/version-2/snapshot.2"));
+        let (restored, zxid) = snap_log.restore().unwrap();
+        assert_eq!(zxid, Zxid(2));
+        assert_eq!(restored.nodes().collect::<Vec<_>>(), tree.nodes().collect::<Vec<_>>());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
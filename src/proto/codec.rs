@@ -0,0 +1,221 @@
+//! Framing of the packets exchanged by clients and servers.
+//!
+//! Each packet is prefixed with its length as a 32 bits big-endian integer. Requests start with a
+//! `RequestHeader` and replies with a `ReplyHeader`, followed by a body whose type depends on the
+//! header: the request's operation, or for replies the operation of the request they answer. The
+//! connect request and response are the exception, as they have no header.
+//!
+//! With the `tokio-codec` feature, `PacketCodec` frames packets on async transports.
+
+use std::io::Read;
+
+use serde::{Deserialize, Serialize};
+
+use super::{ReplyHeader, RequestHeader};
+use crate::serde::{slice_deserializer, CodecError, Deserializer, SliceRead};
+
+/// Default maximum length of a packet, same as the default `jute.maxbuffer`
+pub const MAX_PACKET_LENGTH: usize = 0xfffff;
+
+/// Length of the length prefix of packets
+const LENGTH_PREFIX: usize = 4;
+
+/// Serialize a length-prefixed packet. Headers and bodies are serialized as a tuple.
+pub fn encode_packet(body: &impl Serialize) -> Result<Vec<u8>, CodecError> {
+    let mut buf = vec![0u8; LENGTH_PREFIX];
+    crate::serde::to_writer(&mut buf, body)?;
+    let len = (buf.len() - LENGTH_PREFIX) as i32;
+    buf[..LENGTH_PREFIX].copy_from_slice(&len.to_be_bytes());
+    Ok(buf)
+}
+
+/// Serialize a request packet.
+pub fn encode_request(header: &RequestHeader, body: &impl Serialize) -> Result<Vec<u8>, CodecError> {
+    encode_packet(&(header, body))
+}
+
+/// Serialize a request packet whose body is already encoded.
+pub fn encode_request_bytes(header: &RequestHeader, body: &[u8]) -> Result<Vec<u8>, CodecError> {
+    let mut buf = encode_packet(header)?;
+    buf.extend_from_slice(body);
+    let len = (buf.len() - LENGTH_PREFIX) as i32;
+    buf[..LENGTH_PREFIX].copy_from_slice(&len.to_be_bytes());
+    Ok(buf)
+}
+
+/// Serialize a reply packet. Replies with an error have no body: use `&()`.
+pub fn encode_response(header: &ReplyHeader, body: &impl Serialize) -> Result<Vec<u8>, CodecError> {
+    encode_packet(&(header, body))
+}
+
+/// Length of the packet that starts `buf`, without its prefix, or `None` if the prefix is
+/// incomplete.
+pub fn packet_length(buf: &[u8], max_length: usize) -> Result<Option<usize>, CodecError> {
+    let prefix = match buf.get(..LENGTH_PREFIX) {
+        Some(prefix) => prefix,
+        None => return Ok(None),
+    };
+    let len = i32::from_be_bytes([prefix[0], prefix[1], prefix[2], prefix[3]]);
+    if len < 0 {
+        return Err(CodecError::NegativeValue);
+    }
+    if len as usize > max_length {
+        return Err(CodecError::TooLarge(len as usize));
+    }
+    Ok(Some(len as usize))
+}
+
+/// Read a packet, without its length prefix. Invalid lengths are `InvalidData` errors.
+pub fn read_packet(input: &mut impl Read, max_length: usize) -> std::io::Result<Vec<u8>> {
+    let mut prefix = [0u8; LENGTH_PREFIX];
+    input.read_exact(&mut prefix)?;
+    let len = packet_length(&prefix, max_length)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, format!("Invalid packet length: {}", e)))?
+        .unwrap_or_default();
+
+    let mut buf = vec![0u8; len];
+    input.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+/// Read the header of a request packet. The body is then read from the returned deserializer,
+/// according to the header's operation.
+pub fn decode_request(packet: &[u8]) -> Result<(RequestHeader, Deserializer<SliceRead<'_>>), CodecError> {
+    let mut de = slice_deserializer(packet);
+    let header = RequestHeader::deserialize(&mut de)?;
+    Ok((header, de))
+}
+
+/// Read the header of a reply packet. The body, if there's no error, is then read from the
+/// returned deserializer: a `WatcherEvent` for notifications, or the response to the request with
+/// the same xid.
+pub fn decode_response(packet: &[u8]) -> Result<(ReplyHeader, Deserializer<SliceRead<'_>>), CodecError> {
+    let mut de = slice_deserializer(packet);
+    let header = ReplyHeader::deserialize(&mut de)?;
+    Ok((header, de))
+}
+
+#[cfg(feature = "tokio-codec")]
+pub use self::tokio_codec::PacketCodec;
+
+#[cfg(feature = "tokio-codec")]
+mod tokio_codec {
+    use bytes::{Buf, BufMut, BytesMut};
+    use serde::Serialize;
+    use tokio_util::codec::{Decoder, Encoder};
+
+    use super::{packet_length, LENGTH_PREFIX, MAX_PACKET_LENGTH};
+    use crate::serde::CodecError;
+
+    /// Frames packets on async transports: decodes packets without their length prefix, and
+    /// encodes serializable values (e.g. a header and body tuple) as packets.
+    #[derive(Debug, Copy, Clone)]
+    pub struct PacketCodec {
+        max_length: usize,
+    }
+
+    impl Default for PacketCodec {
+        fn default() -> Self {
+            PacketCodec {
+                max_length: MAX_PACKET_LENGTH,
+            }
+        }
+    }
+
+    impl PacketCodec {
+        pub fn new() -> PacketCodec {
+            PacketCodec::default()
+        }
+
+        /// Set the maximum length of packets, in both directions.
+        pub fn with_max_length(mut self, max_length: usize) -> Self {
+            self.max_length = max_length;
+            self
+        }
+    }
+
+    impl Decoder for PacketCodec {
+        type Item = BytesMut;
+        type Error = CodecError;
+
+        fn decode(&mut self, src: &mut BytesMut) -> Result<Option<BytesMut>, CodecError> {
+            let len = match packet_length(src, self.max_length)? {
+                Some(len) => len,
+                None => return Ok(None),
+            };
+            if src.len() < LENGTH_PREFIX + len {
+                src.reserve(LENGTH_PREFIX + len - src.len());
+                return Ok(None);
+            }
+
+            src.advance(LENGTH_PREFIX);
+            Ok(Some(src.split_to(len)))
+        }
+    }
+
+    impl<T: Serialize> Encoder<T> for PacketCodec {
+        type Error = CodecError;
+
+        fn encode(&mut self, item: T, dst: &mut BytesMut) -> Result<(), CodecError> {
+            let start = dst.len();
+            dst.put_i32(0);
+            crate::serde::to_writer((&mut *dst).writer(), &item)?;
+
+            let len = dst.len() - start - LENGTH_PREFIX;
+            if len > self.max_length {
+                dst.truncate(start);
+                return Err(CodecError::TooLarge(len));
+            }
+            dst[start..start + LENGTH_PREFIX].copy_from_slice(&(len as i32).to_be_bytes());
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proto::{GetChildrenResponse, GetDataRequest, OpCode, OpRequest};
+    use crate::{Xid, Zxid};
+
+    #[test]
+    fn request_and_response() {
+        let header = RequestHeader {
+            xid: Xid(7),
+            typ: GetDataRequest::OP_CODE,
+        };
+        let request = GetDataRequest {
+            path: "/app".to_owned(),
+            watch: true,
+        };
+        let packet = encode_request(&header, &request).unwrap();
+        assert_eq!(
+            packet_length(&packet, MAX_PACKET_LENGTH).unwrap(),
+            Some(packet.len() - 4)
+        );
+        assert_eq!(
+            packet_length(&packet, 4).unwrap_err(),
+            CodecError::TooLarge(packet.len() - 4)
+        );
+        assert_eq!(packet_length(&packet[..3], MAX_PACKET_LENGTH).unwrap(), None);
+
+        let frame = read_packet(&mut &packet[..], MAX_PACKET_LENGTH).unwrap();
+        let (header, mut de) = decode_request(&frame).unwrap();
+        assert_eq!((header.xid, header.typ), (Xid(7), OpCode::GetData));
+        assert_eq!(GetDataRequest::deserialize(&mut de).unwrap().path, "/app");
+        assert!(read_packet(&mut &packet[..packet.len() - 1], MAX_PACKET_LENGTH).is_err());
+
+        let header = ReplyHeader {
+            xid: Xid(7),
+            zxid: Zxid(12),
+            err: 0,
+        };
+        let response = GetChildrenResponse {
+            children: vec!["a".to_owned()],
+        };
+        let packet = encode_response(&header, &response).unwrap();
+        let (header, mut de) = decode_response(&packet[4..]).unwrap();
+        assert_eq!((header.xid, header.zxid), (Xid(7), Zxid(12)));
+        assert_eq!(GetChildrenResponse::deserialize(&mut de).unwrap().children, vec!["a"]);
+    }
+}
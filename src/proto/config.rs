@@ -0,0 +1,172 @@
+//! Dynamic ensemble configuration, as stored in the `/zookeeper/config` node.
+//!
+//! See `QuorumPeerConfig.java` and `QuorumPeer.QuorumServer` in ZK server for the format.
+
+use super::GetDataRequest;
+use super::GetDataResponse;
+use crate::error::ParseError;
+use crate::Stat;
+use crate::Zxid;
+
+/// Path of the node holding the ensemble configuration
+pub const CONFIG_NODE: &str = "/zookeeper/config";
+
+impl GetDataRequest {
+    /// Request to read the ensemble configuration (`getConfig` in the Java client)
+    pub fn config(watch: bool) -> GetDataRequest {
+        GetDataRequest {
+            path: CONFIG_NODE.to_owned(),
+            watch,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum LearnerType {
+    Participant,
+    Observer,
+}
+
+/// A server in the ensemble configuration, defined by a `server.<id>` line.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QuorumServer {
+    pub id: i64,
+    /// Address used by followers to connect to the leader
+    pub quorum_address: String,
+    /// Address used for leader election
+    pub election_address: String,
+    pub learner_type: LearnerType,
+    /// Client address, as `port` or `host:port`
+    pub client_address: Option<String>,
+}
+
+/// The ensemble configuration.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QuorumConfig {
+    pub servers: Vec<QuorumServer>,
+    /// Config version, which is the zxid of the reconfiguration that created it
+    pub version: Option<Zxid>,
+}
+
+impl QuorumConfig {
+    /// Parse the configuration data read from `CONFIG_NODE`. Lines other than servers and version
+    /// (e.g. hierarchical quorum groups and weights) are ignored.
+    pub fn parse(data: &[u8]) -> Result<QuorumConfig, ParseError> {
+        let text = std::str::from_utf8(data)?;
+
+        let mut servers = Vec::new();
+        let mut version = None;
+
+        for line in text.lines().map(str::trim).filter(|l| !l.is_empty()) {
+            let (key, value) = split_once(line, '=').ok_or_else(|| ParseError::invalid("config line", line))?;
+
+            if key == "version" {
+                let v = i64::from_str_radix(value, 16).map_err(|_| ParseError::invalid("config version", value))?;
+                version = Some(Zxid(v));
+            } else if let Some(id) = key.strip_prefix("server.") {
+                let id = id.parse::<i64>().map_err(|_| ParseError::invalid("server id", key))?;
+                servers.push(QuorumServer::parse(id, value)?);
+            }
+        }
+
+        Ok(QuorumConfig { servers, version })
+    }
+
+    /// Parse the response of a `GetDataRequest::config()` request.
+    pub fn from_response(response: &GetDataResponse) -> Result<(QuorumConfig, &Stat), ParseError> {
+        Ok((QuorumConfig::parse(&response.data)?, &response.stat))
+    }
+
+    /// Client addresses of participants and observers
+    pub fn client_addresses(&self) -> impl Iterator<Item = &str> {
+        self.servers.iter().filter_map(|s| s.client_address.as_deref())
+    }
+}
+
+impl QuorumServer {
+    /// Parse a server definition: `host:quorumPort:electionPort[:type][;[clientHost:]clientPort]`
+    fn parse(id: i64, value: &str) -> Result<QuorumServer, ParseError> {
+        let (server, client) = match split_once(value, ';') {
+            Some((server, client)) => (server, Some(client.trim())),
+            None => (value, None),
+        };
+
+        // IPv6 addresses are enclosed in brackets
+        let (host, ports) = if server.starts_with('[') {
+            let end = server
+                .find(']')
+                .ok_or_else(|| ParseError::invalid("server address", server))?;
+            (&server[..=end], &server[end + 1..])
+        } else {
+            match server.find(':') {
+                Some(idx) => (&server[..idx], &server[idx..]),
+                None => (server, ""),
+            }
+        };
+
+        let parts = ports.split(':').skip(1).collect::<Vec<_>>();
+        if parts.len() < 2 || parts.len() > 3 {
+            return Err(ParseError::invalid("server address", server));
+        }
+
+        let learner_type = match parts.get(2) {
+            None | Some(&"participant") => LearnerType::Participant,
+            Some(&"observer") => LearnerType::Observer,
+            Some(other) => return Err(ParseError::invalid("learner type", other)),
+        };
+
+        Ok(QuorumServer {
+            id,
+            quorum_address: format!("{}:{}", host, parts[0]),
+            election_address: format!("{}:{}", host, parts[1]),
+            learner_type,
+            client_address: client.filter(|c| !c.is_empty()).map(str::to_owned),
+        })
+    }
+}
+
+fn split_once(s: &str, sep: char) -> Option<(&str, &str)> {
+    let idx = s.find(sep)?;
+    Some((s[..idx].trim(), s[idx + 1..].trim()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_config() {
+        let data = b"server.1=zk1:2888:3888:participant;0.0.0.0:2181\n\
+                     server.2=[::1]:2889:3889;2182\n\
+                     server.3=zk3:2888:3888:observer\n\
+                     version=100000003\n";
+
+        let config = QuorumConfig::parse(data).unwrap();
+
+        assert_eq!(config.version, Some(Zxid(0x1_0000_0003)));
+        assert_eq!(config.servers.len(), 3);
+
+        assert_eq!(
+            config.servers[0],
+            QuorumServer {
+                id: 1,
+                quorum_address: "zk1:2888".to_owned(),
+                election_address: "zk1:3888".to_owned(),
+                learner_type: LearnerType::Participant,
+                client_address: Some("0.0.0.0:2181".to_owned()),
+            }
+        );
+
+        assert_eq!(config.servers[1].quorum_address, "[::1]:2889");
+        assert_eq!(config.servers[1].client_address, Some("2182".to_owned()));
+        assert_eq!(config.servers[2].learner_type, LearnerType::Observer);
+        assert_eq!(config.servers[2].client_address, None);
+
+        assert_eq!(
+            config.client_addresses().collect::<Vec<_>>(),
+            vec!["0.0.0.0:2181", "2182"]
+        );
+
+        assert!(QuorumConfig::parse(b"server.1=zk1").is_err());
+    }
+}
@@ -12,8 +12,16 @@ use super::Stat;
 use super::Version;
 use super::Xid;
 use super::Zxid;
+use super::ServerVersion;
 use super::ACL;
+use super::MAX_TTL;
 
+use crate::error::ParseError;
+use num_traits::{FromPrimitive, ToPrimitive};
+use strum::IntoEnumIterator;
+
+pub mod codec;
+pub mod config;
 
 // See https://github.com/apache/zookeeper/blob/trunk/src/zookeeper.jute
 
@@ -22,44 +30,180 @@ pub trait Request {
     type Response;
 }
 
+/// Requests that are sent after a `RequestHeader`, whose type is the request's operation code.
+///
+/// Some request bodies are used with several operation codes (e.g. `CreateRequest`). In that case
+/// each operation has its own wrapper type, so that the operation code can't be chosen incorrectly.
+pub trait OpRequest: Request {
+    const OP_CODE: OpCode;
+}
+
 // See ZooDefs.java
 
-#[derive(Debug, PartialEq)]
-#[derive(Serialize, Deserialize)]
-#[derive(ToPrimitive)]
+/// Operation codes, found in `RequestHeader` and `MultiHeader`. Codes that are unknown to this
+/// crate, e.g. operations added by a more recent server, are kept as `Unknown`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 #[derive(IntoStaticStr, EnumIter)]
 pub enum OpCode {
-    Notification = 0,
-    Create = 1,
-    Delete = 2,
-    Exists = 3,
-    GetData = 4,
-    SetData = 5,
-    GetACL = 6,
-    SetACL = 7,
-    GetChildren = 8,
-    Sync = 9,
-    // 10 not used
-    Ping = 11,
-    GetChildren2 = 12,
-    Check = 13,
-    Multi = 14,
-    Create2 = 15,
-    Reconfig = 16,
-    CheckWatches = 17,
-    RemoveWatches = 18,
-    CreateContainer = 19,
-    DeleteContainer = 20,
-    CreateTTL = 21,
-    Auth = 100,
-    SetWatches = 101,
-    Sasl = 102,
-    CreateSession = -10,
-    CloseSession = -11,
-    Error = -1,
-}
-
-#[derive(Debug, PartialEq, PartialOrd)]
+    Notification,
+    Create,
+    Delete,
+    Exists,
+    GetData,
+    SetData,
+    GetACL,
+    SetACL,
+    GetChildren,
+    Sync,
+    Ping,
+    GetChildren2,
+    Check,
+    Multi,
+    Create2,
+    Reconfig,
+    CheckWatches,
+    RemoveWatches,
+    CreateContainer,
+    DeleteContainer,
+    CreateTTL,
+    MultiRead,
+    Auth,
+    SetWatches,
+    Sasl,
+    GetEphemerals,
+    GetAllChildrenNumber,
+    SetWatches2,
+    AddWatch,
+    WhoAmI,
+    CreateSession,
+    CloseSession,
+    Error,
+    Unknown(i32),
+}
+
+impl OpCode {
+    /// The first release line that supports this operation
+    pub fn since(&self) -> ServerVersion {
+        use OpCode::*;
+        match self {
+            Create2 | Reconfig | CheckWatches | RemoveWatches | CreateContainer | DeleteContainer | CreateTTL => {
+                ServerVersion::V3_5
+            }
+            MultiRead | GetEphemerals | GetAllChildrenNumber | SetWatches2 | AddWatch => ServerVersion::V3_6,
+            WhoAmI => ServerVersion::V3_7,
+            // More recent than this crate
+            Unknown(_) => ServerVersion::LATEST,
+            _ => ServerVersion::V3_4,
+        }
+    }
+
+    pub fn is_supported_by(&self, version: ServerVersion) -> bool {
+        self.since() <= version
+    }
+
+    /// The operation with a numeric code. Codes of operations more recent than this crate are
+    /// `None`: see `From<i32>` to keep them as `Unknown`.
+    pub fn from_code(code: i32) -> Option<OpCode> {
+        OpCode::iter().find(|op| !op.is_unknown() && op.code() == code)
+    }
+
+    /// The numeric code of the operation.
+    pub fn code(&self) -> i32 {
+        use OpCode::*;
+        match *self {
+            Notification => 0,
+            Create => 1,
+            Delete => 2,
+            Exists => 3,
+            GetData => 4,
+            SetData => 5,
+            GetACL => 6,
+            SetACL => 7,
+            GetChildren => 8,
+            Sync => 9,
+            // 10 not used
+            Ping => 11,
+            GetChildren2 => 12,
+            Check => 13,
+            Multi => 14,
+            Create2 => 15,
+            Reconfig => 16,
+            CheckWatches => 17,
+            RemoveWatches => 18,
+            CreateContainer => 19,
+            DeleteContainer => 20,
+            CreateTTL => 21,
+            MultiRead => 22,
+            Auth => 100,
+            SetWatches => 101,
+            Sasl => 102,
+            GetEphemerals => 103,
+            GetAllChildrenNumber => 104,
+            SetWatches2 => 105,
+            AddWatch => 106,
+            WhoAmI => 107,
+            CreateSession => -10,
+            CloseSession => -11,
+            Error => -1,
+            Unknown(code) => code,
+        }
+    }
+
+    pub fn is_unknown(&self) -> bool {
+        matches!(self, OpCode::Unknown(_))
+    }
+
+    /// Check that a server supports this operation, returning the `Unimplemented` error it would
+    /// send otherwise (if it doesn't choke on the request and close the connection).
+    pub fn check_supported_by(&self, version: ServerVersion) -> Result<(), ErrorCode> {
+        if self.is_supported_by(version) {
+            Ok(())
+        } else {
+            Err(ErrorCode::Unimplemented)
+        }
+    }
+}
+
+impl From<i32> for OpCode {
+    fn from(code: i32) -> OpCode {
+        OpCode::from_code(code).unwrap_or(OpCode::Unknown(code))
+    }
+}
+
+impl ToPrimitive for OpCode {
+    fn to_i64(&self) -> Option<i64> {
+        Some(self.code().into())
+    }
+
+    fn to_u64(&self) -> Option<u64> {
+        self.code().to_u64()
+    }
+}
+
+impl FromPrimitive for OpCode {
+    fn from_i64(n: i64) -> Option<OpCode> {
+        n.to_i32().map(OpCode::from)
+    }
+
+    fn from_u64(n: u64) -> Option<OpCode> {
+        n.to_i32().map(OpCode::from)
+    }
+}
+
+/// Serialized as its numeric code, so that unknown codes are kept as they are.
+impl serde::Serialize for OpCode {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_i32(self.code())
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for OpCode {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<OpCode, D::Error> {
+        <i32 as serde::Deserialize>::deserialize(deserializer).map(OpCode::from)
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd)]
 #[derive(Serialize, Deserialize)]
 #[derive(ToPrimitive)]
 #[derive(IntoStaticStr, EnumIter)]
@@ -132,8 +276,16 @@ pub enum ErrorCode {
     EphemeralOnLocalSession = -120,
     /// Attempts to remove a non-existing watcher
     NoWatcher = -121,
+    /// Request not completed within max allowed time (3.6+)
+    RequestTimeout = -122,
     /// Attempts to perform a reconfiguration operation when reconfiguration feature is disabled.
     ReconfigDisabled = -123,
+    /// The session was closed by the server because it requires SASL authentication (3.6+)
+    SessionClosedRequireSaslAuth = -124,
+    /// Exceeded the hard quota set on a path (3.7+)
+    QuotaExceeded = -125,
+    /// Operation was throttled and not executed at all (3.7+)
+    Throttled = -127,
 }
 
 impl ErrorCode {
@@ -146,13 +298,29 @@ impl ErrorCode {
     }
 }
 
+impl ErrorCode {
+    /// The error with a numeric code, as found in `ReplyHeader`
+    pub fn from_code(code: i32) -> Option<ErrorCode> {
+        ErrorCode::iter().find(|e| e.to_i32() == Some(code))
+    }
+}
+
+impl std::fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{:?} ({})", self, self.to_i32().unwrap_or_default())
+    }
+}
+
+/// Errors returned by the server, which the client returns as `ClientError::Server`.
+impl std::error::Error for ErrorCode {}
+
 
 #[derive(Debug)]
 #[derive(Serialize, Deserialize)]
 pub struct RequestHeader {
     pub xid: Xid,
     #[serde(rename = "type")]
-    pub typ: i32,
+    pub typ: OpCode,
 }
 
 #[derive(Debug)]
@@ -167,7 +335,7 @@ pub struct ReplyHeader {
 #[derive(Serialize, Deserialize)]
 pub struct MultiHeader {
     #[serde(rename = "type")]
-    pub typ: i32,
+    pub typ: OpCode,
     pub done: bool,
     pub err: i32,
 }
@@ -185,7 +353,7 @@ pub struct ErrorResponse {
 // Note: sent with xid -4 (see ClientCnxn.java)
 pub struct AuthPacket {
     #[serde(rename = "type")]
-    pub typ: i32,
+    pub typ: OpCode,
     pub scheme: String,
     #[serde(with = "serde_bytes")]
     pub buffer: Vec<u8>,
@@ -195,6 +363,10 @@ impl Request for AuthPacket {
     type Response = ();
 }
 
+impl OpRequest for AuthPacket {
+    const OP_CODE: OpCode = OpCode::Auth;
+}
+
 //---- Connect
 
 #[derive(Debug)]
@@ -206,6 +378,40 @@ pub struct ConnectRequest {
     pub session_id: SessionId,
     #[serde(with = "serde_bytes")]
     pub passwd: Vec<u8>,
+    /// Accept a connection to a read-only server, that has been partitioned from the ensemble.
+    /// Appended by clients since ZooKeeper 3.4, and optional for servers.
+    pub read_only: Option<bool>,
+}
+
+/// The only protocol version ever used by ZooKeeper. It doesn't change with server releases and
+/// therefore can't be used to find what a server supports: see `ServerVersion`.
+pub const PROTOCOL_VERSION: i32 = 0;
+
+impl ConnectRequest {
+    /// Request a new session
+    pub fn new_session(time_out: Duration) -> ConnectRequest {
+        ConnectRequest {
+            protocol_version: PROTOCOL_VERSION,
+            last_zxid_seen: Zxid(0),
+            time_out,
+            session_id: SessionId(0),
+            passwd: vec![0; 16],
+            read_only: None,
+        }
+    }
+
+    /// Resume an existing session, e.g. on another server after a connection loss. `last_zxid_seen`
+    /// ensures we don't connect to a server that is behind what this session has already seen.
+    pub fn resume(session_id: SessionId, passwd: Vec<u8>, last_zxid_seen: Zxid, time_out: Duration) -> ConnectRequest {
+        ConnectRequest {
+            protocol_version: PROTOCOL_VERSION,
+            last_zxid_seen,
+            time_out,
+            session_id,
+            passwd,
+            read_only: None,
+        }
+    }
 }
 
 impl Request for ConnectRequest {
@@ -220,6 +426,32 @@ pub struct ConnectResponse {
     pub session_id: SessionId,
     #[serde(with = "serde_bytes")]
     pub passwd: Vec<u8>,
+    /// Is the server read-only? Appended by servers since ZooKeeper 3.4.
+    pub read_only: Option<bool>,
+}
+
+impl ConnectResponse {
+    /// Did the server accept the session? When a session can't be resumed because it has expired,
+    /// the server replies with a zero session id and timeout (see `ZooKeeperServer.finishSessionInit()`).
+    pub fn is_session_valid(&self) -> bool {
+        self.session_id.0 != 0 && self.time_out.0 > 0
+    }
+}
+
+//---- Close session
+
+/// Closes the session. It has no body, and the server replies once the session's ephemeral nodes
+/// have been deleted.
+#[derive(Debug)]
+#[derive(Serialize, Deserialize)]
+pub struct CloseSessionRequest;
+
+impl Request for CloseSessionRequest {
+    type Response = ();
+}
+
+impl OpRequest for CloseSessionRequest {
+    const OP_CODE: OpCode = OpCode::CloseSession;
 }
 
 //---- Create
@@ -238,29 +470,114 @@ impl Request for CreateRequest {
     type Response = CreateResponse;
 }
 
+impl OpRequest for CreateRequest {
+    const OP_CODE: OpCode = OpCode::Create;
+}
+
 #[derive(Debug)]
 #[derive(Serialize, Deserialize)]
 pub struct CreateResponse {
     pub path: String,
 }
 
+//---- Create 2
+
+/// Same as `CreateRequest`, but the response also contains the `Stat` of the new node.
+#[derive(Debug)]
+#[derive(Serialize, Deserialize)]
+pub struct Create2Request(pub CreateRequest);
+
+impl From<CreateRequest> for Create2Request {
+    fn from(req: CreateRequest) -> Self {
+        Create2Request(req)
+    }
+}
+
+impl Request for Create2Request {
+    type Response = Create2Response;
+}
+
+impl OpRequest for Create2Request {
+    const OP_CODE: OpCode = OpCode::Create2;
+}
+
+//---- Create container
+
+/// Creates a container node. It's a `CreateRequest` with the `Container` mode, but sent with its
+/// own operation code and getting a `Create2Response` (see `ZooKeeper.create()` in the Java client).
+#[derive(Debug)]
+#[derive(Serialize, Deserialize)]
+pub struct CreateContainerRequest(pub CreateRequest);
+
+impl CreateContainerRequest {
+    pub fn new(path: impl Into<String>, data: Vec<u8>, acl: Vec<ACL>) -> CreateContainerRequest {
+        CreateContainerRequest(CreateRequest {
+            path: path.into(),
+            data,
+            acl,
+            flags: CreateMode::Container,
+        })
+    }
+}
+
+impl Request for CreateContainerRequest {
+    type Response = Create2Response;
+}
+
+impl OpRequest for CreateContainerRequest {
+    const OP_CODE: OpCode = OpCode::CreateContainer;
+}
+
 //---- Create TTL
 
 #[derive(Debug)]
 #[derive(Serialize, Deserialize)]
-struct CreateTTLRequest {
+pub struct CreateTTLRequest {
     pub path: String,
     #[serde(with = "serde_bytes")]
     pub data: Vec<u8>,
     pub acl: Vec<ACL>,
     pub flags: CreateMode,
-    pub ttl: Duration,
+    /// Time to live, in milliseconds
+    pub ttl: i64,
+}
+
+impl CreateTTLRequest {
+    /// Create a request, checking that `flags` is a TTL mode and that `ttl` is within the limits
+    /// accepted by the server (see `EphemeralType.java`).
+    pub fn new(
+        path: impl Into<String>,
+        data: Vec<u8>,
+        acl: Vec<ACL>,
+        flags: CreateMode,
+        ttl: i64,
+    ) -> Result<CreateTTLRequest, ParseError> {
+        if !flags.is_ttl() {
+            return Err(ParseError::invalid("TTL create mode", format!("{:?}", flags)));
+        }
+
+        if ttl <= 0 || ttl > MAX_TTL {
+            return Err(ParseError::invalid("TTL, outside of the (0, MAX_TTL] range", ttl));
+        }
+
+        Ok(CreateTTLRequest {
+            path: path.into(),
+            data,
+            acl,
+            flags,
+            ttl,
+        })
+    }
 }
 
 impl Request for CreateTTLRequest {
     type Response = Create2Response;
 }
 
+impl OpRequest for CreateTTLRequest {
+    const OP_CODE: OpCode = OpCode::CreateTTL;
+}
+
 #[derive(Debug)]
 #[derive(Serialize, Deserialize)]
 pub struct Create2Response {
@@ -283,6 +600,10 @@ impl Request for SetDataRequest {
     type Response = SetDataResponse;
 }
 
+impl OpRequest for SetDataRequest {
+    const OP_CODE: OpCode = OpCode::SetData;
+}
+
 #[derive(Debug)]
 #[derive(Serialize, Deserialize)]
 pub struct SetDataResponse {
@@ -302,6 +623,10 @@ impl Request for GetDataRequest {
     type Response = GetDataResponse;
 }
 
+impl OpRequest for GetDataRequest {
+    const OP_CODE: OpCode = OpCode::GetData;
+}
+
 #[derive(Debug)]
 #[derive(Serialize, Deserialize)]
 pub struct GetDataResponse {
@@ -323,6 +648,54 @@ impl Request for DeleteRequest {
     type Response = ();
 }
 
+impl OpRequest for DeleteRequest {
+    const OP_CODE: OpCode = OpCode::Delete;
+}
+
+//---- Delete container
+
+/// Deletes an empty container node. Issued by the server's `ContainerManager`.
+///
+/// The body of this request isn't jute-encoded: it's the raw UTF-8 bytes of the path, without
+/// a length prefix (see `ContainerManager.java` and `PrepRequestProcessor.java`). It can therefore
+/// only be decoded from a complete packet body, using `from_bytes`.
+///
+/// The resulting transaction is a `DeleteTxn`.
+#[derive(Debug)]
+pub struct DeleteContainerRequest {
+    pub path: String,
+}
+
+impl DeleteContainerRequest {
+    pub fn from_bytes(body: &[u8]) -> Result<DeleteContainerRequest, std::str::Utf8Error> {
+        Ok(DeleteContainerRequest {
+            path: std::str::from_utf8(body)?.to_owned(),
+        })
+    }
+}
+
+impl serde::Serialize for DeleteContainerRequest {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use serde::ser::SerializeTuple;
+
+        // Tuples have no length prefix
+        let bytes = self.path.as_bytes();
+        let mut tuple = serializer.serialize_tuple(bytes.len())?;
+        for b in bytes {
+            tuple.serialize_element(b)?;
+        }
+        tuple.end()
+    }
+}
+
+impl Request for DeleteContainerRequest {
+    type Response = ();
+}
+
+impl OpRequest for DeleteContainerRequest {
+    const OP_CODE: OpCode = OpCode::DeleteContainer;
+}
+
 //---- Get children
 
 #[derive(Debug)]
@@ -336,6 +709,10 @@ impl Request for GetChildrenRequest {
     type Response = GetChildrenResponse;
 }
 
+impl OpRequest for GetChildrenRequest {
+    const OP_CODE: OpCode = OpCode::GetChildren;
+}
+
 #[derive(Debug)]
 #[derive(Serialize, Deserialize)]
 pub struct GetChildrenResponse {
@@ -356,6 +733,10 @@ impl Request for GetChildren2Request {
     type Response = GetChildren2Response;
 }
 
+impl OpRequest for GetChildren2Request {
+    const OP_CODE: OpCode = OpCode::GetChildren2;
+}
+
 #[derive(Debug)]
 #[derive(Serialize, Deserialize)]
 pub struct GetChildren2Response {
@@ -376,6 +757,259 @@ impl Request for CheckVersionRequest {
     type Response = ();
 }
 
+impl OpRequest for CheckVersionRequest {
+    const OP_CODE: OpCode = OpCode::Check;
+}
+
+//---- Multi
+
+impl MultiHeader {
+    /// Header of an operation or result, followed by its body
+    fn new(op: OpCode, err: i32) -> MultiHeader {
+        MultiHeader {
+            typ: op,
+            done: false,
+            err,
+        }
+    }
+
+    /// Header that terminates a multi request or response
+    fn done() -> MultiHeader {
+        MultiHeader {
+            typ: OpCode::Error,
+            done: true,
+            err: -1,
+        }
+    }
+}
+
+/// An operation of a multi request. `GetChildren` and `GetData` are only accepted in read-only
+/// multi requests, which can't be mixed with writes.
+#[derive(Debug)]
+pub enum Op {
+    Create(CreateRequest),
+    Create2(CreateRequest),
+    CreateContainer(CreateRequest),
+    CreateTTL(CreateTTLRequest),
+    Delete(DeleteRequest),
+    SetData(SetDataRequest),
+    Check(CheckVersionRequest),
+    GetChildren(GetChildrenRequest),
+    GetData(GetDataRequest),
+}
+
+impl Op {
+    pub fn op_code(&self) -> OpCode {
+        match self {
+            Op::Create(_) => OpCode::Create,
+            Op::Create2(_) => OpCode::Create2,
+            Op::CreateContainer(_) => OpCode::CreateContainer,
+            Op::CreateTTL(_) => OpCode::CreateTTL,
+            Op::Delete(_) => OpCode::Delete,
+            Op::SetData(_) => OpCode::SetData,
+            Op::Check(_) => OpCode::Check,
+            Op::GetChildren(_) => OpCode::GetChildren,
+            Op::GetData(_) => OpCode::GetData,
+        }
+    }
+}
+
+/// Operations applied atomically. Each operation is preceded by a `MultiHeader`, and the list is
+/// terminated by a header with `done` set (see `MultiOperationRecord.java`).
+#[derive(Debug)]
+pub struct MultiRequest {
+    pub ops: Vec<Op>,
+}
+
+impl Request for MultiRequest {
+    type Response = MultiResponse;
+}
+
+impl OpRequest for MultiRequest {
+    const OP_CODE: OpCode = OpCode::Multi;
+}
+
+/// The result of an operation of a multi request.
+#[derive(Debug)]
+pub enum OpResult {
+    /// Result of `Create` and `CreateContainer`
+    Create(CreateResponse),
+    /// Result of `Create2` and `CreateTTL`
+    Create2(Create2Response),
+    Delete,
+    SetData(SetDataResponse),
+    Check,
+    GetChildren(GetChildrenResponse),
+    GetData(GetDataResponse),
+    Error(ErrorCode),
+}
+
+/// Results of a multi request, in the order of its operations (see `MultiResponse.java`).
+///
+/// When an operation fails, no operation is applied and all results are errors: `Ok` for the
+/// operations before the failed one, and `RuntimeInconsistency` for those after it.
+#[derive(Debug)]
+pub struct MultiResponse {
+    pub results: Vec<OpResult>,
+}
+
+impl MultiResponse {
+    /// The error of the operation that caused the request to fail.
+    pub fn error(&self) -> Option<&ErrorCode> {
+        self.results.iter().find_map(|r| match r {
+            OpResult::Error(ErrorCode::Ok) => None,
+            OpResult::Error(err) => Some(err),
+            _ => None,
+        })
+    }
+}
+
+impl serde::Serialize for MultiRequest {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use serde::ser::SerializeTuple;
+
+        let mut tuple = serializer.serialize_tuple(self.ops.len() * 2 + 1)?;
+        for op in &self.ops {
+            tuple.serialize_element(&MultiHeader::new(op.op_code(), -1))?;
+            match op {
+                Op::Create(r) | Op::Create2(r) | Op::CreateContainer(r) => tuple.serialize_element(r)?,
+                Op::CreateTTL(r) => tuple.serialize_element(r)?,
+                Op::Delete(r) => tuple.serialize_element(r)?,
+                Op::SetData(r) => tuple.serialize_element(r)?,
+                Op::Check(r) => tuple.serialize_element(r)?,
+                Op::GetChildren(r) => tuple.serialize_element(r)?,
+                Op::GetData(r) => tuple.serialize_element(r)?,
+            }
+        }
+        tuple.serialize_element(&MultiHeader::done())?;
+        tuple.end()
+    }
+}
+
+impl serde::Serialize for MultiResponse {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use serde::ser::{Error, SerializeTuple};
+
+        let mut tuple = serializer.serialize_tuple(self.results.len() * 2 + 1)?;
+        for result in &self.results {
+            let op = match result {
+                OpResult::Create(_) => OpCode::Create,
+                OpResult::Create2(_) => OpCode::Create2,
+                OpResult::Delete => OpCode::Delete,
+                OpResult::SetData(_) => OpCode::SetData,
+                OpResult::Check => OpCode::Check,
+                OpResult::GetChildren(_) => OpCode::GetChildren,
+                OpResult::GetData(_) => OpCode::GetData,
+                OpResult::Error(_) => OpCode::Error,
+            };
+            let err = match result {
+                OpResult::Error(err) => err
+                    .to_i32()
+                    .ok_or_else(|| S::Error::custom(format!("No code for {:?}", err)))?,
+                _ => 0,
+            };
+            tuple.serialize_element(&MultiHeader::new(op, err))?;
+            match result {
+                OpResult::Create(r) => tuple.serialize_element(r)?,
+                OpResult::Create2(r) => tuple.serialize_element(r)?,
+                OpResult::SetData(r) => tuple.serialize_element(r)?,
+                OpResult::GetChildren(r) => tuple.serialize_element(r)?,
+                OpResult::GetData(r) => tuple.serialize_element(r)?,
+                // ErrorResponse
+                OpResult::Error(_) => tuple.serialize_element(&err)?,
+                OpResult::Delete | OpResult::Check => {}
+            }
+        }
+        tuple.serialize_element(&MultiHeader::done())?;
+        tuple.end()
+    }
+}
+
+/// Reads the header/body pairs of a multi request or response, until the `done` header. The
+/// length of the tuple is unknown, as jute has no length for it.
+struct MultiVisitor<T>(std::marker::PhantomData<T>);
+
+/// Reads the body that follows a multi header.
+trait MultiItem: Sized {
+    fn read<'de, A: serde::de::SeqAccess<'de>>(header: &MultiHeader, seq: &mut A) -> Result<Self, A::Error>;
+}
+
+fn next_multi_element<'de, T: serde::Deserialize<'de>, A: serde::de::SeqAccess<'de>>(seq: &mut A) -> Result<T, A::Error> {
+    seq.next_element()?
+        .ok_or_else(|| serde::de::Error::custom("Unexpected end of multi"))
+}
+
+impl MultiItem for Op {
+    fn read<'de, A: serde::de::SeqAccess<'de>>(header: &MultiHeader, seq: &mut A) -> Result<Op, A::Error> {
+        Ok(match header.typ {
+            OpCode::Create => Op::Create(next_multi_element(seq)?),
+            OpCode::Create2 => Op::Create2(next_multi_element(seq)?),
+            OpCode::CreateContainer => Op::CreateContainer(next_multi_element(seq)?),
+            OpCode::CreateTTL => Op::CreateTTL(next_multi_element(seq)?),
+            OpCode::Delete => Op::Delete(next_multi_element(seq)?),
+            OpCode::SetData => Op::SetData(next_multi_element(seq)?),
+            OpCode::Check => Op::Check(next_multi_element(seq)?),
+            OpCode::GetChildren => Op::GetChildren(next_multi_element(seq)?),
+            OpCode::GetData => Op::GetData(next_multi_element(seq)?),
+            op => return Err(serde::de::Error::custom(format!("Invalid operation {:?} in multi", op))),
+        })
+    }
+}
+
+impl MultiItem for OpResult {
+    fn read<'de, A: serde::de::SeqAccess<'de>>(header: &MultiHeader, seq: &mut A) -> Result<OpResult, A::Error> {
+        Ok(match header.typ {
+            OpCode::Create | OpCode::CreateContainer => OpResult::Create(next_multi_element(seq)?),
+            OpCode::Create2 | OpCode::CreateTTL => OpResult::Create2(next_multi_element(seq)?),
+            OpCode::Delete | OpCode::DeleteContainer => OpResult::Delete,
+            OpCode::SetData => OpResult::SetData(next_multi_element(seq)?),
+            OpCode::Check => OpResult::Check,
+            OpCode::GetChildren => OpResult::GetChildren(next_multi_element(seq)?),
+            OpCode::GetData => OpResult::GetData(next_multi_element(seq)?),
+            OpCode::Error => {
+                let code: i32 = next_multi_element(seq)?;
+                let err = ErrorCode::from_code(code)
+                    .ok_or_else(|| serde::de::Error::custom(format!("Unknown error code {} in multi", code)))?;
+                OpResult::Error(err)
+            }
+            op => return Err(serde::de::Error::custom(format!("Invalid result {:?} in multi", op))),
+        })
+    }
+}
+
+impl<'de, T: MultiItem> serde::de::Visitor<'de> for MultiVisitor<T> {
+    type Value = Vec<T>;
+
+    fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+        formatter.write_str("multi headers and bodies")
+    }
+
+    fn visit_seq<A: serde::de::SeqAccess<'de>>(self, mut seq: A) -> Result<Vec<T>, A::Error> {
+        let mut items = Vec::new();
+        loop {
+            let header: MultiHeader = next_multi_element(&mut seq)?;
+            if header.done {
+                return Ok(items);
+            }
+            items.push(T::read(&header, &mut seq)?);
+        }
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for MultiRequest {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let ops = deserializer.deserialize_tuple(usize::MAX, MultiVisitor(std::marker::PhantomData))?;
+        Ok(MultiRequest { ops })
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for MultiResponse {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let results = deserializer.deserialize_tuple(usize::MAX, MultiVisitor(std::marker::PhantomData))?;
+        Ok(MultiResponse { results })
+    }
+}
+
 //---- Reconfig
 
 #[derive(Debug)]
@@ -391,6 +1025,10 @@ impl Request for ReconfigRequest {
     type Response = GetDataResponse;
 }
 
+impl OpRequest for ReconfigRequest {
+    const OP_CODE: OpCode = OpCode::Reconfig;
+}
+
 //---- Set SASL
 
 #[derive(Debug)]
@@ -424,6 +1062,10 @@ impl Request for GetSASLRequest {
     type Response = SetSASLResponse; // Same response type as SetSASL
 }
 
+impl OpRequest for GetSASLRequest {
+    const OP_CODE: OpCode = OpCode::Sasl;
+}
+
 //---- Get max children
 
 /// Exists in zookeeper.jute but doesn't seem to be used in the ZK server code base
@@ -468,6 +1110,10 @@ impl Request for SyncRequest {
     type Response = SyncResponse;
 }
 
+impl OpRequest for SyncRequest {
+    const OP_CODE: OpCode = OpCode::Sync;
+}
+
 #[derive(Debug)]
 #[derive(Serialize, Deserialize)]
 pub struct SyncResponse {
@@ -486,6 +1132,10 @@ impl Request for GetACLRequest {
     type Response = GetACLResponse;
 }
 
+impl OpRequest for GetACLRequest {
+    const OP_CODE: OpCode = OpCode::GetACL;
+}
+
 #[derive(Debug)]
 #[derive(Serialize, Deserialize)]
 pub struct GetACLResponse {
@@ -507,6 +1157,10 @@ impl Request for SetACLRequest {
     type Response = SetACLResponse;
 }
 
+impl OpRequest for SetACLRequest {
+    const OP_CODE: OpCode = OpCode::SetACL;
+}
+
 #[derive(Debug)]
 #[derive(Serialize, Deserialize)]
 pub struct SetACLResponse {
@@ -526,6 +1180,10 @@ impl Request for ExistsRequest {
     type Response = ExistsResponse;
 }
 
+impl OpRequest for ExistsRequest {
+    const OP_CODE: OpCode = OpCode::Exists;
+}
+
 #[derive(Debug)]
 #[derive(Serialize, Deserialize)]
 pub struct ExistsResponse {
@@ -536,8 +1194,11 @@ pub struct ExistsResponse {
 //---- Watcher
 
 // See Watcher.java
-#[derive(Debug)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 #[derive(Serialize, Deserialize)]
+#[derive(ToPrimitive)]
+#[derive(IntoStaticStr, EnumIter)]
+#[derive(NamedType)]
 pub enum WatcherEventType {
     None = -1,
     NodeCreated = 1,
@@ -549,8 +1210,11 @@ pub enum WatcherEventType {
 }
 
 // See Watcher.java
-#[derive(Debug)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 #[derive(Serialize, Deserialize)]
+#[derive(ToPrimitive)]
+#[derive(IntoStaticStr, EnumIter)]
+#[derive(NamedType)]
 pub enum KeeperState {
     /// The client is in the disconnected state - it is not connected
     /// to any server in the ensemble.
@@ -582,9 +1246,22 @@ pub enum KeeperState {
     /// create a new client connection (instantiate a new ZooKeeper
     /// instance) if you with to access the ensemble. */
     Expired = -112,
+
+    /// The client has been closed. This state is never generated by
+    /// the server, but is generated locally when a client calls
+    /// close().
+    Closed = 7,
 }
 
-#[derive(Debug)]
+impl KeeperState {
+    /// Is this a state from which a client can't recover, and that will never change again?
+    /// Recipes usually need to distinguish an explicit close from an expiry.
+    pub fn is_terminal(&self) -> bool {
+        matches!(self, KeeperState::Expired | KeeperState::Closed)
+    }
+}
+
+#[derive(Debug, Clone)]
 #[derive(Serialize, Deserialize)]
 pub struct WatcherEvent {
     #[serde(rename = "type")]
@@ -595,8 +1272,11 @@ pub struct WatcherEvent {
 }
 
 // See Watcher.java
-#[derive(Debug)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 #[derive(Serialize, Deserialize)]
+#[derive(ToPrimitive)]
+#[derive(IntoStaticStr, EnumIter)]
+#[derive(NamedType)]
 pub enum WatcherType {
     Children = 1,
     Data = 2,
@@ -619,6 +1299,56 @@ impl Request for SetWatches {
     type Response = ();
 }
 
+impl OpRequest for SetWatches {
+    const OP_CODE: OpCode = OpCode::SetWatches;
+}
+
+//---- Set watches 2
+
+/// Same as `SetWatches` with the addition of persistent watches, introduced in ZK 3.6.
+///
+/// The Java client only sends it when there are persistent watches, so that older servers can
+/// still be used otherwise: see `to_set_watches`.
+#[derive(Debug)]
+#[derive(Serialize, Deserialize)]
+// Note: sent with Xid(-8) (see ClientCnxn.java)
+pub struct SetWatches2 {
+    pub relative_zxid: Zxid,
+    pub data_watches: Vec<String>,
+    pub exist_watches: Vec<String>,
+    pub child_watches: Vec<String>,
+    pub persistent_watches: Vec<String>,
+    pub persistent_recursive_watches: Vec<String>,
+}
+
+impl SetWatches2 {
+    pub fn has_persistent_watches(&self) -> bool {
+        !self.persistent_watches.is_empty() || !self.persistent_recursive_watches.is_empty()
+    }
+
+    /// The equivalent `SetWatches` request, if there are no persistent watches.
+    pub fn to_set_watches(&self) -> Option<SetWatches> {
+        if self.has_persistent_watches() {
+            return None;
+        }
+
+        Some(SetWatches {
+            relative_zxid: self.relative_zxid,
+            data_watches: self.data_watches.clone(),
+            exist_watches: self.exist_watches.clone(),
+            child_watches: self.child_watches.clone(),
+        })
+    }
+}
+
+impl Request for SetWatches2 {
+    type Response = ();
+}
+
+impl OpRequest for SetWatches2 {
+    const OP_CODE: OpCode = OpCode::SetWatches2;
+}
+
 //---- Check watches
 
 #[derive(Debug)]
@@ -633,6 +1363,10 @@ impl Request for CheckWatchesRequest {
     type Response = ();
 }
 
+impl OpRequest for CheckWatchesRequest {
+    const OP_CODE: OpCode = OpCode::CheckWatches;
+}
+
 #[derive(Debug)]
 #[derive(Serialize, Deserialize)]
 pub struct RemoveWatchesRequest {
@@ -644,3 +1378,158 @@ pub struct RemoveWatchesRequest {
 impl Request for RemoveWatchesRequest {
     type Response = ();
 }
+
+impl OpRequest for RemoveWatchesRequest {
+    const OP_CODE: OpCode = OpCode::RemoveWatches;
+}
+
+//---- Add watch
+
+// See AddWatchMode.java
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[derive(Serialize, Deserialize)]
+#[derive(ToPrimitive)]
+#[derive(IntoStaticStr, EnumIter)]
+#[derive(NamedType)]
+pub enum AddWatchMode {
+    /// A watch on a node that isn't removed when triggered
+    Persistent = 0,
+    /// Same as `Persistent`, also triggered by changes of the node's descendants, except child
+    /// list changes
+    PersistentRecursive = 1,
+}
+
+/// Sets a persistent watch, introduced in ZK 3.6.
+#[derive(Debug)]
+#[derive(Serialize, Deserialize)]
+pub struct AddWatchRequest {
+    pub path: String,
+    pub mode: AddWatchMode,
+}
+
+impl Request for AddWatchRequest {
+    type Response = ErrorResponse;
+}
+
+impl OpRequest for AddWatchRequest {
+    const OP_CODE: OpCode = OpCode::AddWatch;
+}
+
+//---- Transport
+
+/// Checks if the first bytes of a client connection are a TLS record, for servers that accept both
+/// plaintext and TLS on the client port (port unification, see `UnifiedServerSocket.java`).
+///
+/// Returns `None` if there are not enough bytes to decide. A plaintext connection starts with the
+/// length of the `ConnectRequest`, whose first byte is zero.
+pub fn is_tls_record(bytes: &[u8]) -> Option<bool> {
+    if bytes.len() < 5 {
+        return None;
+    }
+
+    // Content type: change_cipher_spec, alert, handshake or application_data
+    let content_type_ok = (20..=23).contains(&bytes[0]);
+    // Protocol version: SSL 3.0 to TLS 1.3 all use major version 3
+    let version_ok = bytes[1] == 3 && bytes[2] <= 4;
+    // Record length, at most 2^14 + 2048 bytes
+    let length = u16::from_be_bytes([bytes[3], bytes[4]]);
+    let length_ok = length > 0 && length <= 16384 + 2048;
+
+    Some(content_type_ok && version_ok && length_ok)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detect_tls() {
+        // TLS 1.2 ClientHello
+        assert_eq!(is_tls_record(&[0x16, 0x03, 0x01, 0x02, 0x00, 0x01]), Some(true));
+        // ConnectRequest length
+        assert_eq!(is_tls_record(&[0x00, 0x00, 0x00, 0x2d, 0x00, 0x00]), Some(false));
+        // Four letter word
+        assert_eq!(is_tls_record(b"srvr\n"), Some(false));
+
+        assert_eq!(is_tls_record(&[0x16, 0x03]), None);
+    }
+
+    #[test]
+    fn error_codes() {
+        assert_eq!(ErrorCode::from_code(-127), Some(ErrorCode::Throttled));
+        assert_eq!(ErrorCode::from_code(-126), None);
+
+        for code in &[-122, -124, -125, -127] {
+            let response: ErrorResponse = crate::serde::from_slice(&i32::to_be_bytes(*code)).unwrap();
+            assert!(response.err.is_api_error());
+            assert!(!response.err.is_system_error());
+        }
+    }
+
+    #[test]
+    fn op_codes() {
+        assert_eq!(OpCode::from(107), OpCode::WhoAmI);
+        assert_eq!(OpCode::from(10), OpCode::Unknown(10));
+        assert_eq!(OpCode::from_code(10), None);
+        assert_eq!(OpCode::from_i64(-11), Some(OpCode::CloseSession));
+        assert_eq!(OpCode::Unknown(10).to_i32(), Some(10));
+        assert_eq!(OpCode::from(0), OpCode::Notification);
+        assert!(!OpCode::Unknown(108).is_supported_by(ServerVersion::V3_6));
+
+        // Requests of a more recent client can still be read, e.g. by a proxy
+        let bytes = [0, 0, 0, 1, 0, 0, 0, 200];
+        let header: RequestHeader = crate::serde::from_slice(&bytes).unwrap();
+        assert_eq!((header.xid, header.typ), (Xid(1), OpCode::Unknown(200)));
+        assert_eq!(crate::serde::to_vec(&header).unwrap(), bytes);
+    }
+
+    #[test]
+    fn multi_round_trip() {
+        use crate::serde::{de, ser};
+        use serde::{Deserialize, Serialize};
+
+        let ops = vec![
+            Op::Create(CreateRequest {
+                path: "/a".to_owned(),
+                data: b"data".to_vec(),
+                acl: vec![],
+                flags: CreateMode::Persistent,
+            }),
+            Op::Check(CheckVersionRequest {
+                path: "/b".to_owned(),
+                version: Version(3),
+            }),
+            Op::Delete(DeleteRequest {
+                path: "/c".to_owned(),
+                version: OptionalVersion(-1),
+            }),
+        ];
+        let mut ser = ser::to_writer(Vec::new());
+        ser.add_enum::<CreateMode>();
+        MultiRequest { ops }.serialize(&mut ser).unwrap();
+        let bytes = ser.into_inner();
+        // Header (4 + 1 + 4) before each op and at the end
+        assert_eq!(&bytes[bytes.len() - 9..], &[255, 255, 255, 255, 1, 255, 255, 255, 255]);
+
+        let mut de = de::from_reader(&bytes[..]);
+        de.add_enum::<CreateMode>();
+        let request = MultiRequest::deserialize(&mut de).unwrap();
+        let codes = request.ops.iter().map(Op::op_code).collect::<Vec<_>>();
+        assert_eq!(codes, vec![OpCode::Create, OpCode::Check, OpCode::Delete]);
+
+        // Partial failure: the check failed
+        let response = MultiResponse {
+            results: vec![
+                OpResult::Error(ErrorCode::Ok),
+                OpResult::Error(ErrorCode::BadVersion),
+                OpResult::Error(ErrorCode::RuntimeInconsistency),
+            ],
+        };
+        let mut ser = ser::to_writer(Vec::new());
+        response.serialize(&mut ser).unwrap();
+        let bytes = ser.into_inner();
+        let response = MultiResponse::deserialize(&mut de::from_reader(&bytes[..])).unwrap();
+        assert_eq!(response.results.len(), 3);
+        assert_eq!(response.error(), Some(&ErrorCode::BadVersion));
+    }
+}
@@ -0,0 +1,203 @@
+//! A double barrier, which participants enter and leave together.
+//!
+//! See the double barrier of the ZooKeeper recipes. Each participant creates an ephemeral
+//! sequential node under the barrier's path when entering. The one that completes the expected
+//! number of participants creates a `ready` node, that the others wait for.
+//!
+//! When leaving, participants delete their node and wait for the others: the participant with
+//! the lowest node waits for the highest one, and the others wait for the lowest one. The last one
+//! out deletes the `ready` node, so that the barrier can be used again.
+
+use std::time::{Duration, Instant};
+
+use super::{ensure_path, open_acl, sequence, Trigger};
+use crate::client::sync::{Transport, ZooKeeper};
+use crate::error::{ClientError, ParseError, RecipeError};
+use crate::path::ZkPath;
+use crate::proto::ErrorCode;
+use crate::{CreateMode, OptionalVersion, ACL};
+
+/// Name of the participant nodes, followed by their sequence number
+const MEMBER_PREFIX: &str = "member-";
+/// Created once all participants have entered
+const READY_NODE: &str = "ready";
+
+/// A participant of a double barrier.
+#[derive(Debug)]
+pub struct DoubleBarrier {
+    path: ZkPath,
+    size: usize,
+    acl: Vec<ACL>,
+    /// Node of the participant, once entered
+    node: Option<String>,
+}
+
+impl DoubleBarrier {
+    /// A barrier for `size` participants, which must all use the same size.
+    pub fn new(path: &str, size: usize) -> Result<DoubleBarrier, RecipeError> {
+        if size == 0 {
+            return Err(ParseError::invalid("barrier size", size).into());
+        }
+        Ok(DoubleBarrier {
+            path: ZkPath::new(path)?,
+            size,
+            acl: open_acl(),
+            node: None,
+        })
+    }
+
+    /// Set the ACL of the nodes created by the barrier (open to anyone by default).
+    pub fn with_acl(mut self, acl: Vec<ACL>) -> Self {
+        self.acl = acl;
+        self
+    }
+
+    /// Enter the barrier, and wait for up to `timeout` until all participants have entered. On
+    /// failure, the participant is withdrawn.
+    pub fn enter<S: Transport>(&mut self, zk: &mut ZooKeeper<S>, timeout: Duration) -> Result<(), RecipeError> {
+        if self.node.is_some() {
+            return Err(RecipeError::InvalidState(format!(
+                "Barrier {} already entered",
+                self.path
+            )));
+        }
+        let deadline = Instant::now() + timeout;
+        ensure_path(zk, &self.path, &self.acl)?;
+        let node = self.path.child(MEMBER_PREFIX)?;
+        let node = zk.create(node.as_str(), &[], self.acl.clone(), CreateMode::EphemeralSequential)?;
+
+        match self.wait_ready(zk, deadline) {
+            Ok(()) => {
+                self.node = Some(node);
+                Ok(())
+            }
+            Err(e) => {
+                // Without a connection, the node is deleted with the session
+                let _ = zk.delete(&node, OptionalVersion(-1));
+                Err(e)
+            }
+        }
+    }
+
+    /// Leave the barrier, and wait for up to `timeout` until all participants have left.
+    pub fn leave<S: Transport>(&mut self, zk: &mut ZooKeeper<S>, timeout: Duration) -> Result<(), RecipeError> {
+        let node = match self.node.take() {
+            Some(node) => node,
+            None => return Err(RecipeError::InvalidState(format!("Barrier {} not entered", self.path))),
+        };
+        let deadline = Instant::now() + timeout;
+        let name = node.rsplit('/').next().unwrap_or_default().to_owned();
+
+        loop {
+            let members = self.members(zk)?;
+            let (lowest, highest) = match (members.first(), members.last()) {
+                (Some(lowest), Some(highest)) => (lowest, highest),
+                _ => return Ok(()),
+            };
+            if members.len() == 1 && *lowest == name {
+                delete(zk, &node)?;
+                // Last one out
+                return Ok(delete(zk, self.path.child(READY_NODE)?.as_str())?);
+            }
+
+            let awaited = if *lowest == name {
+                highest
+            } else {
+                delete(zk, &node)?;
+                lowest
+            };
+            let trigger = Trigger::default();
+            let awaited = self.path.child(awaited)?;
+            if zk.watch_exists(awaited.as_str(), trigger.watcher())?.is_some() {
+                trigger.wait(zk, deadline)?;
+            }
+        }
+    }
+
+    fn wait_ready<S: Transport>(&self, zk: &mut ZooKeeper<S>, deadline: Instant) -> Result<(), RecipeError> {
+        let ready = self.path.child(READY_NODE)?;
+        loop {
+            let trigger = Trigger::default();
+            if zk.watch_exists(ready.as_str(), trigger.watcher())?.is_some() {
+                return Ok(());
+            }
+            if self.members(zk)?.len() >= self.size {
+                return match zk.create(ready.as_str(), &[], self.acl.clone(), CreateMode::Persistent) {
+                    Ok(_) | Err(ClientError::Server(ErrorCode::NodeExists)) => Ok(()),
+                    Err(e) => Err(e.into()),
+                };
+            }
+            trigger.wait(zk, deadline)?;
+        }
+    }
+
+    /// Names of the participant nodes, in the order of their sequence numbers.
+    fn members<S: Transport>(&self, zk: &mut ZooKeeper<S>) -> Result<Vec<String>, RecipeError> {
+        let mut members = zk
+            .get_children(self.path.as_str(), false)?
+            .into_iter()
+            .filter(|c| c.starts_with(MEMBER_PREFIX))
+            .filter_map(|c| sequence(&c).map(|s| (s, c)))
+            .collect::<Vec<_>>();
+        members.sort();
+        Ok(members.into_iter().map(|(_, c)| c).collect())
+    }
+}
+
+/// Delete a node, if it exists.
+fn delete<S: Transport>(zk: &mut ZooKeeper<S>, path: &str) -> Result<(), ClientError> {
+    match zk.delete(path, OptionalVersion(-1)) {
+        Ok(()) | Err(ClientError::Server(ErrorCode::NoNode)) => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::testing::MemoryServer;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    #[test]
+    fn double_barrier() {
+        let server = MemoryServer::start();
+        let connect = || ZooKeeper::connect(server.addr(), crate::Duration(10_000)).unwrap();
+        let timeout = Duration::from_secs(5);
+
+        // Alone, a participant times out and withdraws
+        let mut zk = connect();
+        let mut barrier = DoubleBarrier::new("/app/barrier", 3).unwrap();
+        let err = barrier.enter(&mut zk, Duration::from_millis(100)).unwrap_err();
+        assert!(matches!(err, RecipeError::Timeout));
+        assert!(matches!(
+            barrier.leave(&mut zk, timeout),
+            Err(RecipeError::InvalidState(_))
+        ));
+
+        let entered = Arc::new(AtomicUsize::new(0));
+        let left = Arc::new(AtomicUsize::new(0));
+        let participants = (0..3)
+            .map(|_| {
+                let mut zk = connect();
+                let (entered, left) = (entered.clone(), left.clone());
+                std::thread::spawn(move || {
+                    let mut barrier = DoubleBarrier::new("/app/barrier", 3).unwrap();
+                    entered.fetch_add(1, Ordering::SeqCst);
+                    barrier.enter(&mut zk, timeout).unwrap();
+                    assert_eq!(entered.load(Ordering::SeqCst), 3);
+
+                    left.fetch_add(1, Ordering::SeqCst);
+                    barrier.leave(&mut zk, timeout).unwrap();
+                    assert_eq!(left.load(Ordering::SeqCst), 3);
+                })
+            })
+            .collect::<Vec<_>>();
+        for participant in participants {
+            participant.join().unwrap();
+        }
+
+        // Ready to be used again
+        assert!(zk.get_children("/app/barrier", false).unwrap().is_empty());
+    }
+}
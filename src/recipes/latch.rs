@@ -0,0 +1,132 @@
+//! A latch that opens once it has been counted down to zero.
+//!
+//! Like Java's `CountDownLatch`, across processes. The latch is a persistent node whose data is
+//! the remaining count, in decimal. It is counted down with conditional updates of its version, so
+//! that concurrent decrements aren't lost. Waiters watch its data until the count is zero.
+//!
+//! The count isn't tied to sessions: a process that fails before counting down blocks the
+//! waiters until their timeout.
+
+use std::time::{Duration, Instant};
+
+use super::{ensure_path, open_acl, Trigger};
+use crate::client::sync::{Transport, ZooKeeper};
+use crate::error::{ClientError, RecipeError};
+use crate::path::ZkPath;
+use crate::proto::ErrorCode;
+use crate::{CreateMode, ACL};
+
+/// A latch, shared by the clients that use the same path.
+#[derive(Debug, Clone)]
+pub struct CountDownLatch {
+    path: ZkPath,
+    acl: Vec<ACL>,
+}
+
+impl CountDownLatch {
+    pub fn new(path: &str) -> Result<CountDownLatch, RecipeError> {
+        Ok(CountDownLatch {
+            path: ZkPath::new(path)?,
+            acl: open_acl(),
+        })
+    }
+
+    /// Set the ACL of the nodes created by the latch (open to anyone by default).
+    pub fn with_acl(mut self, acl: Vec<ACL>) -> Self {
+        self.acl = acl;
+        self
+    }
+
+    /// Create the latch with a count, unless it already exists. Returns whether it was created.
+    pub fn create<S: Transport>(&self, zk: &mut ZooKeeper<S>, count: u32) -> Result<bool, RecipeError> {
+        if let Some(parent) = self.path.parent() {
+            ensure_path(zk, &parent, &self.acl)?;
+        }
+        let data = count.to_string();
+        match zk.create(
+            self.path.as_str(),
+            data.as_bytes(),
+            self.acl.clone(),
+            CreateMode::Persistent,
+        ) {
+            Ok(_) => Ok(true),
+            Err(ClientError::Server(ErrorCode::NodeExists)) => Ok(false),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// The remaining count.
+    pub fn count<S: Transport>(&self, zk: &mut ZooKeeper<S>) -> Result<u32, RecipeError> {
+        let (data, _) = zk.get_data(self.path.as_str(), false)?;
+        self.parse(&data)
+    }
+
+    /// Decrement the count, unless it's already zero. Returns the new count.
+    pub fn count_down<S: Transport>(&self, zk: &mut ZooKeeper<S>) -> Result<u32, RecipeError> {
+        loop {
+            let (data, stat) = zk.get_data(self.path.as_str(), false)?;
+            let count = match self.parse(&data)? {
+                0 => return Ok(0),
+                count => count - 1,
+            };
+            match zk.set_data(self.path.as_str(), count.to_string().as_bytes(), stat.version) {
+                Ok(_) => return Ok(count),
+                // Counted down by another client
+                Err(ClientError::Server(ErrorCode::BadVersion)) => continue,
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+
+    /// Wait for up to `timeout` until the count is zero.
+    pub fn wait<S: Transport>(&self, zk: &mut ZooKeeper<S>, timeout: Duration) -> Result<(), RecipeError> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let trigger = Trigger::default();
+            let (data, _) = zk.watch_data(self.path.as_str(), trigger.watcher())?;
+            if self.parse(&data)? == 0 {
+                return Ok(());
+            }
+            trigger.wait(zk, deadline)?;
+        }
+    }
+
+    fn parse(&self, data: &[u8]) -> Result<u32, RecipeError> {
+        std::str::from_utf8(data)
+            .ok()
+            .and_then(|s| s.parse().ok())
+            .ok_or_else(|| RecipeError::InvalidState(format!("Invalid count in latch {}", self.path)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::testing::MemoryServer;
+
+    #[test]
+    fn count_down_latch() {
+        let server = MemoryServer::start();
+        let connect = || ZooKeeper::connect(server.addr(), crate::Duration(10_000)).unwrap();
+        let latch = CountDownLatch::new("/app/latch").unwrap();
+
+        let mut zk = connect();
+        assert!(latch.create(&mut zk, 2).unwrap());
+        assert!(!latch.create(&mut zk, 5).unwrap());
+        let err = latch.wait(&mut zk, Duration::from_millis(100)).unwrap_err();
+        assert!(matches!(err, RecipeError::Timeout));
+
+        let waiter = {
+            let mut zk = connect();
+            let latch = latch.clone();
+            std::thread::spawn(move || latch.wait(&mut zk, Duration::from_secs(5)))
+        };
+        let mut other = connect();
+        assert_eq!(latch.count_down(&mut zk).unwrap(), 1);
+        assert_eq!(latch.count_down(&mut other).unwrap(), 0);
+        waiter.join().unwrap().unwrap();
+
+        assert_eq!(latch.count_down(&mut zk).unwrap(), 0);
+        assert_eq!(latch.count(&mut other).unwrap(), 0);
+    }
+}
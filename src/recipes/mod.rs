@@ -0,0 +1,79 @@
+//! Coordination recipes built on the sync client.
+//!
+//! See the recipes of the ZooKeeper documentation. Each recipe keeps its nodes under a path,
+//! which is created with its ancestors if needed. The participants of semaphores and barriers are
+//! ephemeral nodes: they're removed when their session ends, so that a crashed process doesn't
+//! block the others.
+//!
+//! There is no background thread: a participant that waits for others reads their watch
+//! notifications with `ZooKeeper::wait_notifications`, which keeps the session alive. Waits have a
+//! timeout, and fail with `RecipeError::Timeout`.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
+
+use crate::client::sync::{Transport, ZooKeeper};
+use crate::client::watch::Watcher;
+use crate::error::{ClientError, RecipeError};
+use crate::path::ZkPath;
+use crate::proto::{ErrorCode, WatcherEvent};
+use crate::{CreateMode, Id, ACL, PERM_ALL};
+
+pub mod barrier;
+pub mod latch;
+pub mod semaphore;
+
+/// The ACL of recipe nodes, unless set otherwise.
+fn open_acl() -> Vec<ACL> {
+    vec![ACL {
+        perms: PERM_ALL,
+        id: Id::anyone(),
+    }]
+}
+
+/// Create a node and its missing ancestors, as empty persistent nodes.
+fn ensure_path<S: Transport>(zk: &mut ZooKeeper<S>, path: &ZkPath, acl: &[ACL]) -> Result<(), ClientError> {
+    if path.is_root() || zk.exists(path.as_str(), false)?.is_some() {
+        return Ok(());
+    }
+    if let Some(parent) = path.parent() {
+        ensure_path(zk, &parent, acl)?;
+    }
+    match zk.create(path.as_str(), &[], acl.to_vec(), CreateMode::Persistent) {
+        Ok(_) | Err(ClientError::Server(ErrorCode::NodeExists)) => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+/// Sequence number of a sequential node, from the 10 digits appended to its name.
+fn sequence(name: &str) -> Option<u32> {
+    name.len()
+        .checked_sub(10)
+        .and_then(|start| name.get(start..))
+        .and_then(|digits| digits.parse().ok())
+}
+
+/// A flag raised by a watch, whatever its event: a change of the connection state also wakes up
+/// a waiting participant, which then finds out about it with its next request.
+#[derive(Clone, Default)]
+struct Trigger(Arc<AtomicBool>);
+
+impl Trigger {
+    fn watcher(&self) -> impl Watcher + 'static {
+        let flag = self.0.clone();
+        move |_: &WatcherEvent| flag.store(true, Ordering::SeqCst)
+    }
+
+    /// Wait until the watch is triggered, or fail at `deadline`.
+    fn wait<S: Transport>(&self, zk: &mut ZooKeeper<S>, deadline: Instant) -> Result<(), RecipeError> {
+        while !self.0.load(Ordering::SeqCst) {
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(RecipeError::Timeout);
+            }
+            zk.wait_notifications(deadline - now)?;
+        }
+        Ok(())
+    }
+}
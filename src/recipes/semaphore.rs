@@ -0,0 +1,210 @@
+//! A semaphore that grants up to a number of leases.
+//!
+//! See Curator's `InterProcessSemaphoreV2`. A client that asks for a lease creates an ephemeral
+//! sequential node under the semaphore's path. Leases are granted in the order of the nodes'
+//! sequence numbers: the first `max_leases` nodes hold one, and the others wait for a change of
+//! the children, which is fair. All clients of a semaphore must use the same number of leases,
+//! which isn't stored.
+//!
+//! A lease is released by deleting its node. It is also released when its session ends: the
+//! leases of a crashed process are released once its session expires. A lease whose node is
+//! deleted by another client, e.g. by an administrator, is revoked: its holder can be told with
+//! `Lease::watch_revoked`.
+
+use std::time::{Duration, Instant};
+
+use super::{ensure_path, open_acl, sequence, Trigger};
+use crate::client::sync::{Transport, ZooKeeper};
+use crate::client::watch::Watcher;
+use crate::error::{ClientError, ParseError, RecipeError};
+use crate::path::ZkPath;
+use crate::proto::ErrorCode;
+use crate::{CreateMode, OptionalVersion, ACL};
+
+/// Name of the lease nodes, followed by their sequence number
+const LEASE_PREFIX: &str = "lease-";
+
+/// A semaphore, shared by the clients that use the same path.
+#[derive(Debug, Clone)]
+pub struct Semaphore {
+    path: ZkPath,
+    max_leases: usize,
+    acl: Vec<ACL>,
+}
+
+impl Semaphore {
+    pub fn new(path: &str, max_leases: usize) -> Result<Semaphore, RecipeError> {
+        if max_leases == 0 {
+            return Err(ParseError::invalid("number of leases", max_leases).into());
+        }
+        Ok(Semaphore {
+            path: ZkPath::new(path)?,
+            max_leases,
+            acl: open_acl(),
+        })
+    }
+
+    /// Set the ACL of the nodes created by the semaphore (open to anyone by default).
+    pub fn with_acl(mut self, acl: Vec<ACL>) -> Self {
+        self.acl = acl;
+        self
+    }
+
+    pub fn path(&self) -> &str {
+        self.path.as_str()
+    }
+
+    /// Acquire a lease, waiting for up to `timeout` until one is available. On failure, the
+    /// request for a lease is withdrawn.
+    pub fn acquire<S: Transport>(&self, zk: &mut ZooKeeper<S>, timeout: Duration) -> Result<Lease, RecipeError> {
+        let deadline = Instant::now() + timeout;
+        ensure_path(zk, &self.path, &self.acl)?;
+        let node = self.path.child(LEASE_PREFIX)?;
+        let lease = Lease {
+            path: zk.create(node.as_str(), &[], self.acl.clone(), CreateMode::EphemeralSequential)?,
+        };
+
+        match self.wait_turn(zk, &lease, deadline) {
+            Ok(()) => Ok(lease),
+            Err(e) => {
+                // Without a connection, the node is deleted with the session
+                let _ = lease.release(zk);
+                Err(e)
+            }
+        }
+    }
+
+    /// Number of clients that hold or wait for a lease.
+    pub fn participants<S: Transport>(&self, zk: &mut ZooKeeper<S>) -> Result<usize, RecipeError> {
+        match zk.get_children(self.path.as_str(), false) {
+            Ok(children) => Ok(children.iter().filter(|c| c.starts_with(LEASE_PREFIX)).count()),
+            Err(ClientError::Server(ErrorCode::NoNode)) => Ok(0),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Wait until there are less than `max_leases` nodes before the lease's node.
+    fn wait_turn<S: Transport>(
+        &self,
+        zk: &mut ZooKeeper<S>,
+        lease: &Lease,
+        deadline: Instant,
+    ) -> Result<(), RecipeError> {
+        let name = lease.name();
+        let own = sequence(name).ok_or_else(|| ParseError::invalid("lease node", &lease.path))?;
+        loop {
+            let trigger = Trigger::default();
+            let children = zk.watch_children(self.path.as_str(), trigger.watcher())?;
+            if !children.iter().any(|c| c == name) {
+                return Err(RecipeError::InvalidState(format!(
+                    "Lease {} deleted while waiting",
+                    lease.path
+                )));
+            }
+            let before = children
+                .iter()
+                .filter(|c| c.starts_with(LEASE_PREFIX))
+                .filter_map(|c| sequence(c))
+                .filter(|s| *s < own)
+                .count();
+            if before < self.max_leases {
+                return Ok(());
+            }
+            trigger.wait(zk, deadline)?;
+        }
+    }
+}
+
+/// A lease granted by a semaphore. It must be released explicitly, or with the end of the
+/// session: dropping it doesn't release it.
+#[derive(Debug)]
+pub struct Lease {
+    path: String,
+}
+
+impl Lease {
+    /// Path of the lease's node
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    fn name(&self) -> &str {
+        self.path.rsplit('/').next().unwrap_or_default()
+    }
+
+    /// Is the lease still held? It isn't once revoked, or once its session has expired.
+    pub fn is_held<S: Transport>(&self, zk: &mut ZooKeeper<S>) -> Result<bool, RecipeError> {
+        Ok(zk.exists(&self.path, false)?.is_some())
+    }
+
+    /// Tell `watcher` when the lease is revoked, with the `NodeDeleted` event of its node. Returns
+    /// false if it's already revoked, in which case the watcher isn't set.
+    pub fn watch_revoked<S: Transport>(
+        &self,
+        zk: &mut ZooKeeper<S>,
+        watcher: impl Watcher + 'static,
+    ) -> Result<bool, RecipeError> {
+        match zk.watch_data(&self.path, watcher) {
+            Ok(_) => Ok(true),
+            Err(ClientError::Server(ErrorCode::NoNode)) => Ok(false),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Release the lease, by deleting its node. A lease that was revoked is already released.
+    pub fn release<S: Transport>(self, zk: &mut ZooKeeper<S>) -> Result<(), RecipeError> {
+        match zk.delete(&self.path, OptionalVersion(-1)) {
+            Ok(()) | Err(ClientError::Server(ErrorCode::NoNode)) => Ok(()),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::testing::MemoryServer;
+    use crate::proto::{WatcherEvent, WatcherEventType};
+    use std::sync::mpsc;
+
+    #[test]
+    fn semaphore() {
+        let server = MemoryServer::start();
+        let connect = || ZooKeeper::connect(server.addr(), crate::Duration(10_000)).unwrap();
+        let semaphore = Semaphore::new("/app/semaphore", 2).unwrap();
+        let timeout = Duration::from_secs(5);
+
+        let mut first = connect();
+        let mut second = connect();
+        let lease1 = semaphore.acquire(&mut first, timeout).unwrap();
+        let lease2 = semaphore.acquire(&mut second, timeout).unwrap();
+        assert_eq!(lease1.path(), "/app/semaphore/lease-0000000000");
+
+        // No lease is left: a third client waits until the first one is released
+        let mut third = connect();
+        let err = semaphore.acquire(&mut third, Duration::from_millis(100)).unwrap_err();
+        assert!(matches!(err, RecipeError::Timeout));
+        assert_eq!(semaphore.participants(&mut third).unwrap(), 2);
+
+        let waiter = std::thread::spawn(move || {
+            let lease = semaphore.acquire(&mut third, timeout).unwrap();
+            (third, lease)
+        });
+        std::thread::sleep(Duration::from_millis(100));
+        lease1.release(&mut first).unwrap();
+        let (mut third, lease3) = waiter.join().unwrap();
+        assert!(lease3.is_held(&mut third).unwrap());
+
+        // Revoked by another client
+        let (sender, receiver) = mpsc::channel::<WatcherEvent>();
+        assert!(lease2.watch_revoked(&mut second, sender).unwrap());
+        first.delete(lease2.path(), OptionalVersion(-1)).unwrap();
+        assert!(second.wait_notifications(timeout).unwrap());
+        assert_eq!(receiver.try_recv().unwrap().typ, WatcherEventType::NodeDeleted);
+        assert!(!lease2.is_held(&mut second).unwrap());
+
+        // Released with the session
+        third.close().unwrap();
+        assert!(!lease3.is_held(&mut first).unwrap());
+    }
+}
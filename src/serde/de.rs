@@ -1,11 +1,10 @@
 use std::collections::HashMap;
+use std::io;
 use std::io::Read;
 
 use serde::de::{self, DeserializeSeed, EnumAccess, IntoDeserializer, MapAccess, SeqAccess, VariantAccess, Visitor};
 
-use byteorder::{BigEndian, ReadBytesExt};
-
-use super::error::{Error, Result};
+use super::error::{CodecError, Result};
 use super::EnumEncoding;
 use super::MAX_LENGTH;
 
@@ -27,10 +26,14 @@ where
     T: ToPrimitive,
     T: Into<&'static str>,
 {
+    /// The first variant with a code wins, e.g. over a catch-all variant like `OpCode::Unknown`
+    /// that is iterated with a default code.
     fn codes_to_names() -> HashMap<i32, &'static str> {
-        T::iter()
-            .map(|v| (v.to_i32().expect("Cannot convert to i32"), v.into()))
-            .collect()
+        let mut names = HashMap::new();
+        for v in T::iter() {
+            names.entry(v.to_i32().expect("Cannot convert to i32")).or_insert_with(|| v.into());
+        }
+        names
     }
 
     fn names_to_codes() -> HashMap<&'static str, i32> {
@@ -43,6 +46,74 @@ where
     }
 }
 
+/// Input of a `Deserializer`: any `Read`, which strings and byte arrays are copied from, or a
+/// `SliceRead`, which they can be borrowed from.
+///
+/// Methods don't have the names of those of `Read`, to avoid ambiguities on readers.
+pub trait JuteRead<'de> {
+    /// Same as `Read::read_exact`
+    fn read_fully(&mut self, buf: &mut [u8]) -> io::Result<()>;
+
+    /// Read the next `len` bytes if they can be borrowed from the input. Otherwise return `None`
+    /// and read nothing.
+    fn read_borrowed(&mut self, len: usize) -> io::Result<Option<&'de [u8]>>;
+
+    /// Has all the input been read? `None` if it can't be known without reading more.
+    fn at_end(&mut self) -> io::Result<Option<bool>>;
+}
+
+impl<'de, R: Read> JuteRead<'de> for R {
+    fn read_fully(&mut self, buf: &mut [u8]) -> io::Result<()> {
+        Read::read_exact(self, buf)
+    }
+
+    fn read_borrowed(&mut self, _len: usize) -> io::Result<Option<&'de [u8]>> {
+        Ok(None)
+    }
+
+    fn at_end(&mut self) -> io::Result<Option<bool>> {
+        Ok(None)
+    }
+}
+
+/// A byte slice input, that strings and byte arrays are borrowed from.
+pub struct SliceRead<'de> {
+    slice: &'de [u8],
+    /// The whole input
+    input: &'de [u8],
+}
+
+impl<'de> SliceRead<'de> {
+    /// The bytes that haven't been read yet
+    pub fn remaining(&self) -> &'de [u8] {
+        self.slice
+    }
+
+    /// The bytes that have been read
+    pub fn consumed(&self) -> &'de [u8] {
+        &self.input[..self.input.len() - self.slice.len()]
+    }
+}
+
+impl<'de> JuteRead<'de> for SliceRead<'de> {
+    fn read_fully(&mut self, buf: &mut [u8]) -> io::Result<()> {
+        Read::read_exact(&mut self.slice, buf)
+    }
+
+    fn read_borrowed(&mut self, len: usize) -> io::Result<Option<&'de [u8]>> {
+        if len > self.slice.len() {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        let (bytes, rest) = self.slice.split_at(len);
+        self.slice = rest;
+        Ok(Some(bytes))
+    }
+
+    fn at_end(&mut self) -> io::Result<Option<bool>> {
+        Ok(Some(self.slice.is_empty()))
+    }
+}
+
 pub struct Deserializer<R> {
     reader: R,
 
@@ -57,7 +128,27 @@ pub fn from_reader<R: Read>(reader: R) -> Deserializer<R> {
     }
 }
 
-impl<'de, R: Read> Deserializer<R> {
+/// A deserializer that borrows strings and byte arrays from `slice`, e.g. to deserialize `&str`
+/// and `&[u8]` fields without allocating.
+pub fn from_slice(slice: &[u8]) -> Deserializer<SliceRead<'_>> {
+    Deserializer {
+        reader: SliceRead { slice, input: slice },
+        enum_mappings: HashMap::new(),
+    }
+}
+
+impl<R> Deserializer<R> {
+    /// Get a reference to the underlying reader.
+    pub fn get_ref(&self) -> &R {
+        &self.reader
+    }
+
+    /// Get a mutable reference to the underlying reader. Reading from it will break the
+    /// deserialization stream unless it's at a record boundary.
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.reader
+    }
+
     /// Add a discriminant mapping for struct enum types.
     pub fn add_enum_mapping<E: OpCodeEnum, T: NamedType>(&mut self, order: EnumEncoding) {
         self.enum_mappings
@@ -71,113 +162,149 @@ impl<'de, R: Read> Deserializer<R> {
     }
 }
 
-impl<'de, 'a, R: Read> de::Deserializer<'de> for &'a mut Deserializer<R> {
-    type Error = Error;
+impl<'de, R: JuteRead<'de>> Deserializer<R> {
+    fn read_array<const N: usize>(&mut self) -> Result<[u8; N]> {
+        read_array(&mut self.reader)
+    }
+
+    fn read_i32(&mut self) -> Result<i32> {
+        read_i32(&mut self.reader)
+    }
+
+    /// Read a length-prefixed byte array, borrowing it from the input if possible. Only strings
+    /// are limited to `max_len`: node data can be larger if servers raise `jute.maxbuffer`.
+    fn read_bytes(&mut self, max_len: Option<usize>) -> Result<Bytes<'de>> {
+        // Java writes null buffers with a length of -1
+        let len = match self.read_i32()? {
+            len if len < 0 => return Ok(Bytes::Borrowed(&[])),
+            len => len as usize,
+        };
+        if max_len.is_some_and(|max_len| len > max_len) {
+            return Err(CodecError::TooLarge(len));
+        }
+
+        if let Some(bytes) = self.reader.read_borrowed(len)? {
+            return Ok(Bytes::Borrowed(bytes));
+        }
+        let mut bytes = vec![0; len];
+        self.reader.read_fully(&mut bytes)?;
+        Ok(Bytes::Owned(bytes))
+    }
+}
+
+enum Bytes<'de> {
+    Borrowed(&'de [u8]),
+    Owned(Vec<u8>),
+}
+
+fn read_array<'de, const N: usize>(reader: &mut impl JuteRead<'de>) -> Result<[u8; N]> {
+    let mut buf = [0; N];
+    reader.read_fully(&mut buf)?;
+    Ok(buf)
+}
+
+fn read_i32<'de>(reader: &mut impl JuteRead<'de>) -> Result<i32> {
+    Ok(i32::from_be_bytes(read_array(reader)?))
+}
+
+impl<'de, 'a, R: JuteRead<'de>> de::Deserializer<'de> for &'a mut Deserializer<R> {
+    type Error = CodecError;
     fn deserialize_any<V: Visitor<'de>>(self, _visitor: V) -> Result<V::Value> {
-        unimplemented!()
+        // Jute isn't self-describing: the type of a value must be known to read it
+        Err(CodecError::UnsupportedType("any"))
     }
 
     fn deserialize_bool<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
-        visitor.visit_bool(self.reader.read_u8()? != 0)
+        visitor.visit_bool(self.read_array::<1>()?[0] != 0)
     }
 
     fn deserialize_i8<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
-        visitor.visit_i8(self.reader.read_i8()?)
+        visitor.visit_i8(i8::from_be_bytes(self.read_array()?))
     }
 
     fn deserialize_i16<V: Visitor<'de>>(self, _visitor: V) -> Result<V::Value> {
-        // Jute only supports 8, 32 & 64 bits integers. We make a deliberate choice to fail
-        // hard as it's not a runtime failure, but an error in the struct definition.
-        // Same for other unsupported types.
-        unimplemented!()
+        // Jute only supports 8, 32 & 64 bits integers. It's not a runtime failure, but an error
+        // in the struct definition. Same for other unsupported types.
+        Err(CodecError::UnsupportedType("i16"))
     }
 
     fn deserialize_i32<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
-        visitor.visit_i32(self.reader.read_i32::<BigEndian>()?)
+        visitor.visit_i32(self.read_i32()?)
     }
 
     fn deserialize_i64<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
-        visitor.visit_i64(self.reader.read_i64::<BigEndian>()?)
+        visitor.visit_i64(i64::from_be_bytes(self.read_array()?))
     }
 
     fn deserialize_u8<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
-        visitor.visit_u8(self.reader.read_u8()?)
+        visitor.visit_u8(u8::from_be_bytes(self.read_array()?))
     }
 
     fn deserialize_u16<V: Visitor<'de>>(self, _visitor: V) -> Result<V::Value> {
-        unimplemented!()
+        Err(CodecError::UnsupportedType("u16"))
     }
 
     fn deserialize_u32<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
-        visitor.visit_u32(self.reader.read_u32::<BigEndian>()?)
+        visitor.visit_u32(u32::from_be_bytes(self.read_array()?))
     }
 
     fn deserialize_u64<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
-        visitor.visit_u64(self.reader.read_u64::<BigEndian>()?)
+        visitor.visit_u64(u64::from_be_bytes(self.read_array()?))
     }
 
     fn deserialize_f32<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
-        visitor.visit_f32(self.reader.read_f32::<BigEndian>()?)
+        visitor.visit_f32(f32::from_be_bytes(self.read_array()?))
     }
 
     fn deserialize_f64<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
-        visitor.visit_f64(self.reader.read_f64::<BigEndian>()?)
+        visitor.visit_f64(f64::from_be_bytes(self.read_array()?))
     }
 
     fn deserialize_char<V: Visitor<'de>>(self, _visitor: V) -> Result<V::Value> {
-        unimplemented!()
+        Err(CodecError::UnsupportedType("char"))
     }
 
     fn deserialize_str<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
-        let len = self.reader.read_u32::<BigEndian>()? as usize;
-
-        if len > MAX_LENGTH {
-            return Err(Error::TooLarge(len));
+        match self.read_bytes(Some(MAX_LENGTH))? {
+            Bytes::Borrowed(bytes) => visitor.visit_borrowed_str(std::str::from_utf8(bytes)?),
+            Bytes::Owned(bytes) => visitor.visit_string(String::from_utf8(bytes)?),
         }
-
-        let mut chars = vec![0; len];
-        let buffer = chars.as_mut_slice();
-        self.reader.read_exact(buffer)?;
-
-        visitor.visit_str(std::str::from_utf8(buffer)?)
     }
 
     fn deserialize_string<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
-        let len = self.reader.read_u32::<BigEndian>()? as usize;
-        if len > MAX_LENGTH {
-            return Err(Error::TooLarge(len));
-        }
-
-        let mut chars = vec![0; len];
-        self.reader.read_exact(&mut chars)?;
-
-        visitor.visit_string(String::from_utf8(chars)?)
+        self.deserialize_str(visitor)
     }
 
-    fn deserialize_bytes<V: Visitor<'de>>(self, _visitor: V) -> Result<V::Value> {
-        unimplemented!()
+    fn deserialize_bytes<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        // Called for &[u8] fields with serde(borrow)
+        match self.read_bytes(None)? {
+            Bytes::Borrowed(bytes) => visitor.visit_borrowed_bytes(bytes),
+            Bytes::Owned(bytes) => visitor.visit_byte_buf(bytes),
+        }
     }
 
     fn deserialize_byte_buf<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
         // Called for Vec<u8> fields with serde(with="serde_bytes")
-        let len = self.reader.read_u32::<BigEndian>()? as usize;
-
-        let mut bytes = vec![0; len];
-        self.reader.read_exact(&mut bytes)?;
-
-        visitor.visit_byte_buf(bytes)
+        self.deserialize_bytes(visitor)
     }
 
-    fn deserialize_option<V: Visitor<'de>>(self, _visitor: V) -> Result<V::Value> {
-        unimplemented!()
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        // Options are trailing fields that were added in later versions, and are present only if
+        // there are bytes remaining (e.g. the read-only flag of `ConnectRequest`).
+        match self.reader.at_end()? {
+            Some(true) => visitor.visit_none(),
+            Some(false) => visitor.visit_some(self),
+            None => Err(CodecError::Message("Optional fields can only be read from a slice".to_owned())),
+        }
     }
 
-    fn deserialize_unit<V: Visitor<'de>>(self, _visitor: V) -> Result<V::Value> {
-        unimplemented!()
+    fn deserialize_unit<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        // Nothing on the wire, e.g. requests that have no body
+        visitor.visit_unit()
     }
 
-    fn deserialize_unit_struct<V: Visitor<'de>>(self, _name: &'static str, _visitor: V) -> Result<V::Value> {
-        unimplemented!()
+    fn deserialize_unit_struct<V: Visitor<'de>>(self, _name: &'static str, visitor: V) -> Result<V::Value> {
+        visitor.visit_unit()
     }
 
     fn deserialize_newtype_struct<V: Visitor<'de>>(self, _name: &'static str, visitor: V) -> Result<V::Value> {
@@ -185,7 +312,7 @@ impl<'de, 'a, R: Read> de::Deserializer<'de> for &'a mut Deserializer<R> {
     }
 
     fn deserialize_seq<V: Visitor<'de>>(mut self, visitor: V) -> Result<V::Value> {
-        let read_size = self.reader.read_i32::<BigEndian>()?;
+        let read_size = self.read_i32()?;
 
         // The java encoding distinguishes null vectors (length -1) from empty vectors (length 0)
         // We don't find such a distinction though in the C/C++ code and sampling the ZK server
@@ -195,7 +322,7 @@ impl<'de, 'a, R: Read> de::Deserializer<'de> for &'a mut Deserializer<R> {
         } else {
             read_size
                 .to_usize()
-                .ok_or_else(|| Error::Message("Size value too large".to_owned()))?
+                .ok_or_else(|| CodecError::Message("Size value too large".to_owned()))?
         };
 
         visitor.visit_seq(JuteAccess { size, de: &mut self })
@@ -219,14 +346,14 @@ impl<'de, 'a, R: Read> de::Deserializer<'de> for &'a mut Deserializer<R> {
     }
 
     fn deserialize_map<V: Visitor<'de>>(mut self, visitor: V) -> Result<V::Value> {
-        let read_size = self.reader.read_i32::<BigEndian>()?;
+        let read_size = self.read_i32()?;
 
         let size = if read_size < 0 {
             0
         } else {
             read_size
                 .to_usize()
-                .ok_or_else(|| Error::Message("Size value too large".to_owned()))?
+                .ok_or_else(|| CodecError::Message("Size value too large".to_owned()))?
         };
 
         visitor.visit_map(JuteAccess { size, de: &mut self })
@@ -245,31 +372,32 @@ impl<'de, 'a, R: Read> de::Deserializer<'de> for &'a mut Deserializer<R> {
     fn deserialize_enum<V: Visitor<'de>>(
         mut self,
         name: &'static str,
-        _variants: &'static [&'static str],
+        variants: &'static [&'static str],
         visitor: V,
     ) -> Result<V::Value> {
         visitor.visit_enum(JuteEnumAccess {
             enum_type: name,
+            variants,
             de: &mut self,
         })
     }
 
     fn deserialize_identifier<V: Visitor<'de>>(self, _visitor: V) -> Result<V::Value> {
-        unimplemented!()
+        Err(CodecError::UnsupportedType("identifier"))
     }
 
     fn deserialize_ignored_any<V: Visitor<'de>>(self, _visitor: V) -> Result<V::Value> {
-        unimplemented!()
+        Err(CodecError::UnsupportedType("ignored_any"))
     }
 }
 
-struct JuteAccess<'a, R: Read> {
+struct JuteAccess<'a, R> {
     de: &'a mut Deserializer<R>,
     size: usize,
 }
 
-impl<'a, 'de: 'a, R: Read> SeqAccess<'de> for JuteAccess<'a, R> {
-    type Error = super::error::Error;
+impl<'a, 'de: 'a, R: JuteRead<'de>> SeqAccess<'de> for JuteAccess<'a, R> {
+    type Error = CodecError;
 
     fn next_element_seed<T: DeserializeSeed<'de>>(&mut self, seed: T) -> Result<Option<T::Value>> {
         if self.size == 0 {
@@ -285,8 +413,8 @@ impl<'a, 'de: 'a, R: Read> SeqAccess<'de> for JuteAccess<'a, R> {
     }
 }
 
-impl<'a, 'de: 'a, R: Read> MapAccess<'de> for JuteAccess<'a, R> {
-    type Error = super::error::Error;
+impl<'a, 'de: 'a, R: JuteRead<'de>> MapAccess<'de> for JuteAccess<'a, R> {
+    type Error = CodecError;
 
     fn next_key_seed<K: DeserializeSeed<'de>>(&mut self, seed: K) -> Result<Option<K::Value>> {
         if self.size == 0 {
@@ -305,13 +433,14 @@ impl<'a, 'de: 'a, R: Read> MapAccess<'de> for JuteAccess<'a, R> {
         Some(self.size)
     }
 }
-struct JuteEnumAccess<'a, R: Read> {
+struct JuteEnumAccess<'a, R> {
     de: &'a mut Deserializer<R>,
     enum_type: &'static str,
+    variants: &'static [&'static str],
 }
 
-impl<'a, 'de: 'a, R: Read> EnumAccess<'de> for JuteEnumAccess<'a, R> {
-    type Error = super::error::Error;
+impl<'a, 'de: 'a, R: JuteRead<'de>> EnumAccess<'de> for JuteEnumAccess<'a, R> {
+    type Error = CodecError;
     type Variant = Self;
 
     fn variant_seed<V>(self, seed: V) -> Result<(V::Value, Self::Variant)>
@@ -322,32 +451,38 @@ impl<'a, 'de: 'a, R: Read> EnumAccess<'de> for JuteEnumAccess<'a, R> {
             .de
             .enum_mappings
             .get(self.enum_type)
-            .ok_or_else(|| Error::Message(format!("Cannot find mapping for type {}", self.enum_type)))?;
+            .ok_or_else(|| CodecError::Message(format!("Cannot find mapping for type {}", self.enum_type)))?;
 
         let d = match order {
-            EnumEncoding::Type => self.de.reader.read_i32::<BigEndian>()?,
+            EnumEncoding::Type => read_i32(&mut self.de.reader)?,
             EnumEncoding::LengthThenType => {
-                self.de.reader.read_i32::<BigEndian>()?; // length, ignore
-                self.de.reader.read_i32::<BigEndian>()? // type
+                read_i32(&mut self.de.reader)?; // length, ignore
+                read_i32(&mut self.de.reader)? // type
             }
             EnumEncoding::TypeThenLength => {
-                let typ = self.de.reader.read_i32::<BigEndian>()?;
-                self.de.reader.read_i32::<BigEndian>()?; // length, ignore
+                let typ = read_i32(&mut self.de.reader)?;
+                read_i32(&mut self.de.reader)?; // length, ignore
                 typ
             }
         };
 
+        // Codes may be known to the discriminant enum but not have a variant in this one, e.g. the
+        // operation code of a read request in a transaction
         let idx = mappings
             .get(&d)
-            .ok_or_else(|| Error::Message(format!("Wrong discriminant for {}: {}", self.enum_type, d)))?;
+            .filter(|name| self.variants.contains(name))
+            .ok_or(CodecError::UnknownCode {
+                enum_type: self.enum_type,
+                code: d,
+            })?;
 
         let val: Result<_> = seed.deserialize(idx.into_deserializer());
         Ok((val?, self))
     }
 }
 
-impl<'a, 'de: 'a, R: Read> VariantAccess<'de> for JuteEnumAccess<'a, R> {
-    type Error = super::error::Error;
+impl<'a, 'de: 'a, R: JuteRead<'de>> VariantAccess<'de> for JuteEnumAccess<'a, R> {
+    type Error = CodecError;
 
     fn unit_variant(self) -> Result<()> {
         Ok(())
@@ -388,6 +523,20 @@ pub mod test {
         _x: i32,
     }
 
+    #[derive(Debug, PartialEq, Deserialize)]
+    struct Empty;
+
+    #[test]
+    fn test_unit_struct() {
+        let data: Vec<u8> = vec![0x01];
+        let mut bytes = data.as_slice();
+
+        let mut deser = super::from_reader(&mut bytes);
+        assert_eq!(Empty::deserialize(&mut deser).expect("Failed to deserialize"), Empty);
+        // Nothing consumed
+        assert_eq!(bytes.len(), 1);
+    }
+
     #[test]
     fn test_deser() {
         let data: Vec<u8> = vec![
@@ -414,6 +563,70 @@ pub mod test {
         assert_eq!(foo.z.get(&0xF), Some(&("abcd".to_owned())));
     }
 
+    #[derive(Deserialize)]
+    struct Borrowed<'a> {
+        s: &'a str,
+        b: &'a [u8],
+    }
+
+    #[test]
+    fn test_borrowed() {
+        let data: Vec<u8> = vec![
+            0x00, 0x00, 0x00, 0x02, // string length
+            0x61, 0x62, // "ab"
+            0x00, 0x00, 0x00, 0x03, // bytes length
+            0x01, 0x02, 0x03, // bytes
+            0xFF, // trailing
+        ];
+
+        let mut deser = super::from_slice(&data);
+        let borrowed = Borrowed::deserialize(&mut deser).expect("Failed to deserialize");
+
+        assert_eq!(borrowed.s, "ab");
+        assert_eq!(borrowed.b, &[1, 2, 3]);
+        assert_eq!(borrowed.s.as_ptr(), data[4..].as_ptr());
+        assert_eq!(deser.get_ref().remaining(), &[0xFF]);
+        assert_eq!(deser.get_ref().consumed(), &data[..data.len() - 1]);
+
+        // Readers can't lend their content
+        let mut bytes = data.as_slice();
+        let mut deser = super::from_reader(&mut bytes);
+        assert!(Borrowed::deserialize(&mut deser).is_err());
+    }
+
+    use super::{CodecError, MAX_LENGTH};
+
+    #[derive(Deserialize)]
+    struct Data {
+        #[serde(with = "serde_bytes")]
+        data: Vec<u8>,
+        s: String,
+    }
+
+    #[test]
+    fn test_bytes_length() {
+        // Null buffers are read as empty, and byte arrays can be larger than strings
+        let mut data = vec![0xFF, 0xFF, 0xFF, 0xFF, 0x00, 0x00, 0x00, 0x00];
+        let deser = Data::deserialize(&mut super::from_slice(&data)).expect("Failed to deserialize");
+        assert!(deser.data.is_empty());
+
+        // A length-prefixed array of `len` bytes
+        let array = |len: usize| {
+            let mut array = (len as i32).to_be_bytes().to_vec();
+            array.resize(4 + len, 0x61);
+            array
+        };
+        let len = MAX_LENGTH + 1;
+        data = [array(len), array(len)].concat();
+        let result = Data::deserialize(&mut super::from_reader(data.as_slice()));
+        assert!(matches!(result, Err(CodecError::TooLarge(n)) if n == len));
+
+        data = [array(len), array(1)].concat();
+        let deser = Data::deserialize(&mut super::from_reader(data.as_slice())).expect("Failed to deserialize");
+        assert_eq!(deser.data.len(), len);
+        assert_eq!(deser.s, "a");
+    }
+
     //---------------------
 
     use named_type::NamedType;
@@ -463,5 +676,18 @@ pub mod test {
         println!("FooBar = {:?}", foobar);
 
         assert_eq!(foobar, FooBar::Bar("abcd".to_owned()));
+
+        let data: Vec<u8> = vec![
+            0x00, 0x00, 0x00, 0x05, // Unknown discriminant
+        ];
+        let mut deser = super::from_slice(&data);
+        deser.add_enum_mapping::<FooBarCode, FooBar>(super::EnumEncoding::Type);
+        assert_eq!(
+            FooBar::deserialize(&mut deser),
+            Err(super::CodecError::UnknownCode {
+                enum_type: "FooBar",
+                code: 5
+            })
+        );
     }
 }
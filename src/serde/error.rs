@@ -1,60 +1,61 @@
-use std::fmt::{Display, Formatter};
+use std::fmt::Display;
 
 use serde::{de, ser};
+use thiserror::Error;
 
-pub type Result<T> = std::result::Result<T, Error>;
+pub type Result<T> = std::result::Result<T, CodecError>;
 
-#[derive(Clone, Debug, PartialEq)]
-pub enum Error {
+/// Errors of the jute encoding.
+#[derive(Clone, Debug, PartialEq, Error)]
+#[non_exhaustive]
+pub enum CodecError {
+    #[error("{0}")]
     Message(String),
+    #[error("too large: {0}")]
     TooLarge(usize),
+    #[error("negative value")]
     NegativeValue,
+    #[error("unexpected end of input")]
     Eof,
+    /// An enum discriminant that has no variant in `enum_type`, e.g. an operation code introduced
+    /// by a more recent server.
+    #[error("unknown {enum_type} code {code}")]
+    UnknownCode { enum_type: &'static str, code: i32 },
+    /// A Rust type that has no jute encoding, which is an error in the definition of a record.
+    #[error("unsupported type {0}")]
+    UnsupportedType(&'static str),
 }
 
-impl From<std::io::Error> for Error {
+impl From<std::io::Error> for CodecError {
     fn from(io_err: std::io::Error) -> Self {
         use std::io::ErrorKind;
         match io_err.kind() {
-            ErrorKind::WouldBlock | ErrorKind::UnexpectedEof => Error::Eof,
-            _ => Error::Message(io_err.to_string()),
+            ErrorKind::WouldBlock | ErrorKind::UnexpectedEof => CodecError::Eof,
+            _ => CodecError::Message(io_err.to_string()),
         }
     }
 }
 
-impl From<std::str::Utf8Error> for Error {
+impl From<std::str::Utf8Error> for CodecError {
     fn from(err: std::str::Utf8Error) -> Self {
-        Error::Message(err.to_string())
+        CodecError::Message(err.to_string())
     }
 }
 
-impl From<std::string::FromUtf8Error> for Error {
+impl From<std::string::FromUtf8Error> for CodecError {
     fn from(err: std::string::FromUtf8Error) -> Self {
-        Error::Message(err.to_string())
+        CodecError::Message(err.to_string())
     }
 }
 
-impl ser::Error for Error {
+impl ser::Error for CodecError {
     fn custom<T: Display>(msg: T) -> Self {
-        Error::Message(msg.to_string())
+        CodecError::Message(msg.to_string())
     }
 }
 
-impl de::Error for Error {
+impl de::Error for CodecError {
     fn custom<T: Display>(msg: T) -> Self {
-        Error::Message(msg.to_string())
+        CodecError::Message(msg.to_string())
     }
 }
-
-impl Display for Error {
-    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
-        match *self {
-            Error::Message(ref msg) => f.write_str(msg),
-            Error::TooLarge(size) => f.write_fmt(format_args!("too large: {}", size)),
-            Error::NegativeValue => f.write_str("negative value"),
-            Error::Eof => f.write_str("unexpected end of input"),
-        }
-    }
-}
-
-impl std::error::Error for Error {}
@@ -6,8 +6,21 @@ pub mod de;
 pub mod error;
 pub mod ser;
 
+#[cfg(test)]
+mod proptests;
+
 pub use de::Deserializer;
 pub use de::OpCodeEnum;
+pub use de::SliceRead;
+pub use ser::Serializer;
+pub use error::CodecError;
+
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::io::{Read, Write};
+
+use crate::proto::{AddWatchMode, ErrorCode, KeeperState, WatcherEventType, WatcherType};
+use crate::CreateMode;
 
 const MAX_LENGTH: usize = 1024 * 1024; // FIXME: make configurable
 
@@ -19,8 +32,77 @@ const MAX_LENGTH: usize = 1024 * 1024; // FIXME: make configurable
 /// - in some places though we need to read the length beforehand, so we need to instruct the
 ///   serializer/deserializer to only handle the type.
 ///
+#[derive(Debug, Copy, Clone)]
 pub enum EnumEncoding {
     TypeThenLength,
     LengthThenType,
     Type,
 }
+
+/// A serializer with the enum mappings of ZooKeeper requests, responses and transactions.
+pub fn serializer<W: Write>(writer: W) -> Serializer<W> {
+    let mut ser = ser::to_writer(writer);
+    ser.add_enum::<CreateMode>();
+    ser.add_enum::<ErrorCode>();
+    ser.add_enum::<WatcherEventType>();
+    ser.add_enum::<WatcherType>();
+    ser.add_enum::<KeeperState>();
+    ser.add_enum::<AddWatchMode>();
+    #[cfg(feature = "persistence")]
+    {
+        use crate::persistence::txnlog::{MultiTxnOperation, TxnOperation};
+        use crate::proto::OpCode;
+        ser.add_enum_mapping::<OpCode, TxnOperation>(EnumEncoding::Type);
+        ser.add_enum_mapping::<OpCode, MultiTxnOperation>(EnumEncoding::TypeThenLength);
+    }
+    ser
+}
+
+/// A deserializer with the same enum mappings as `serializer`.
+pub fn deserializer<R: Read>(reader: R) -> Deserializer<R> {
+    with_enum_mappings(de::from_reader(reader))
+}
+
+/// A deserializer with the same enum mappings as `serializer`, that borrows strings and byte
+/// arrays from `bytes`.
+pub fn slice_deserializer(bytes: &[u8]) -> Deserializer<SliceRead<'_>> {
+    with_enum_mappings(de::from_slice(bytes))
+}
+
+fn with_enum_mappings<R>(mut de: Deserializer<R>) -> Deserializer<R> {
+    de.add_enum::<CreateMode>();
+    de.add_enum::<ErrorCode>();
+    de.add_enum::<WatcherEventType>();
+    de.add_enum::<WatcherType>();
+    de.add_enum::<KeeperState>();
+    de.add_enum::<AddWatchMode>();
+    #[cfg(feature = "persistence")]
+    {
+        use crate::persistence::txnlog::{MultiTxnOperation, TxnOperation};
+        use crate::proto::OpCode;
+        de.add_enum_mapping::<OpCode, TxnOperation>(EnumEncoding::Type);
+        de.add_enum_mapping::<OpCode, MultiTxnOperation>(EnumEncoding::TypeThenLength);
+    }
+    de
+}
+
+pub fn to_writer<W: Write, T: ?Sized + Serialize>(writer: W, value: &T) -> error::Result<()> {
+    value.serialize(&mut serializer(writer))
+}
+
+pub fn to_vec<T: ?Sized + Serialize>(value: &T) -> error::Result<Vec<u8>> {
+    let mut ser = serializer(Vec::new());
+    value.serialize(&mut ser)?;
+    Ok(ser.into_inner())
+}
+
+pub fn from_reader<R: Read, T: DeserializeOwned>(reader: R) -> error::Result<T> {
+    T::deserialize(&mut deserializer(reader))
+}
+
+/// Deserialize a value from the start of `bytes`. Bytes that follow it are ignored.
+///
+/// Strings and byte arrays are borrowed from `bytes` when `T` allows it.
+pub fn from_slice<'de, T: Deserialize<'de>>(bytes: &'de [u8]) -> error::Result<T> {
+    T::deserialize(&mut slice_deserializer(bytes))
+}
@@ -0,0 +1,363 @@
+//! Round trip of the protocol and transaction structs through the serializer and deserializer.
+//!
+//! Most structs don't implement `PartialEq`, so a value is checked by serializing it again once
+//! deserialized: both encodings must be identical, and fully consumed by the deserializer.
+
+use ::serde::de::DeserializeOwned;
+use ::serde::Serialize;
+use proptest::collection::vec;
+use proptest::prelude::*;
+use proptest::test_runner::TestRunner;
+use std::fmt::Debug;
+use strum::IntoEnumIterator;
+
+use crate::proto::*;
+use crate::{Duration, Id, OptionalVersion, Perms, SessionId, Stat, Timestamp, Version, Xid, Zxid, ACL};
+
+fn check<T, S>(strategy: S)
+where
+    T: Serialize + DeserializeOwned + Debug,
+    S: Strategy<Value = T>,
+{
+    TestRunner::default()
+        .run(&strategy, |value| {
+            let bytes = super::to_vec(&value).unwrap();
+            let mut de = super::slice_deserializer(&bytes);
+            let decoded = T::deserialize(&mut de).unwrap();
+            let remaining = de.get_ref().remaining();
+            prop_assert!(remaining.is_empty(), "{} bytes not read", remaining.len());
+            prop_assert_eq!(super::to_vec(&decoded).unwrap(), bytes);
+            Ok(())
+        })
+        .unwrap();
+}
+
+/// Any variant of a field-less enum
+fn variant<E, I>() -> impl Strategy<Value = E>
+where
+    E: IntoEnumIterator<Iterator = I> + Debug,
+    I: Iterator<Item = E>,
+{
+    (0..E::iter().count()).prop_map(|i| E::iter().nth(i).unwrap())
+}
+
+/// A value from one of `n` strategies. Unlike `prop_oneof!`, values don't have to be `Clone`.
+fn one_of<T: Debug + 'static>(n: usize, strategy: fn(usize) -> BoxedStrategy<T>) -> impl Strategy<Value = T> {
+    (0..n).prop_flat_map(strategy)
+}
+
+fn string() -> impl Strategy<Value = String> {
+    ".{0,12}"
+}
+
+fn strings() -> impl Strategy<Value = Vec<String>> {
+    vec(string(), 0..4)
+}
+
+fn bytes() -> impl Strategy<Value = Vec<u8>> {
+    vec(any::<u8>(), 0..16)
+}
+
+fn acls() -> impl Strategy<Value = Vec<ACL>> {
+    let acl = (any::<u32>(), string(), string()).prop_map(|(perms, scheme, id)| ACL {
+        perms: Perms(perms),
+        id: Id { scheme, id },
+    });
+    vec(acl, 0..3)
+}
+
+fn stat() -> impl Strategy<Value = Stat> {
+    let times = any::<(i64, i64, u64, u64)>();
+    let rest = any::<(i32, i32, i32, i64, i32, i32, i64)>();
+    (times, rest).prop_map(|((czxid, mzxid, ctime, mtime), rest)| Stat {
+        czxid: Zxid(czxid),
+        mzxid: Zxid(mzxid),
+        ctime: Timestamp(ctime),
+        mtime: Timestamp(mtime),
+        version: Version(rest.0),
+        cversion: Version(rest.1),
+        aversion: Version(rest.2),
+        ephemeral_owner: SessionId(rest.3),
+        data_length: rest.4,
+        num_children: rest.5,
+        pzxid: Zxid(rest.6),
+    })
+}
+
+fn create_request() -> impl Strategy<Value = CreateRequest> {
+    (string(), bytes(), acls(), variant()).prop_map(|(path, data, acl, flags)| CreateRequest { path, data, acl, flags })
+}
+
+fn path_watch() -> impl Strategy<Value = (String, bool)> {
+    (string(), any::<bool>())
+}
+
+#[test]
+fn proto_round_trip() {
+    check(any::<(i32, i32)>().prop_map(|(xid, typ)| RequestHeader { xid: Xid(xid), typ: OpCode::from(typ) }));
+    check(any::<(i32, i64, i32)>().prop_map(|(xid, zxid, err)| ReplyHeader {
+        xid: Xid(xid),
+        zxid: Zxid(zxid),
+        err,
+    }));
+    check(any::<(i32, bool, i32)>().prop_map(|(typ, done, err)| MultiHeader { typ: OpCode::from(typ), done, err }));
+    check(variant().prop_map(|err| ErrorResponse { err }));
+    check((any::<i32>(), string(), bytes()).prop_map(|(typ, scheme, buffer)| AuthPacket {
+        typ: OpCode::from(typ),
+        scheme,
+        buffer,
+    }));
+    check((any::<(i32, i64, i32, i64)>(), bytes(), any::<Option<bool>>()).prop_map(
+        |((protocol_version, zxid, time_out, session), passwd, read_only)| ConnectRequest {
+            protocol_version,
+            last_zxid_seen: Zxid(zxid),
+            time_out: Duration(time_out),
+            session_id: SessionId(session),
+            passwd,
+            read_only,
+        },
+    ));
+    check((any::<(i32, i32, i64)>(), bytes(), any::<Option<bool>>()).prop_map(
+        |((protocol_version, time_out, session), passwd, read_only)| ConnectResponse {
+            protocol_version,
+            time_out: Duration(time_out),
+            session_id: SessionId(session),
+            passwd,
+            read_only,
+        },
+    ));
+    check(Just(()).prop_map(|_| CloseSessionRequest));
+    check(create_request());
+    check(create_request().prop_map(Create2Request));
+    check(create_request().prop_map(CreateContainerRequest));
+    check((create_request(), any::<i64>()).prop_map(|(r, ttl)| CreateTTLRequest {
+        path: r.path,
+        data: r.data,
+        acl: r.acl,
+        flags: r.flags,
+        ttl,
+    }));
+    check(string().prop_map(|path| CreateResponse { path }));
+    check((string(), stat()).prop_map(|(path, stat)| Create2Response { path, stat }));
+    check(
+        (string(), bytes(), any::<i32>()).prop_map(|(path, data, v)| SetDataRequest {
+            path,
+            data,
+            version: Version(v),
+        }),
+    );
+    check(stat().prop_map(|stat| SetDataResponse { stat }));
+    check(path_watch().prop_map(|(path, watch)| GetDataRequest { path, watch }));
+    check((bytes(), stat()).prop_map(|(data, stat)| GetDataResponse { data, stat }));
+    check((string(), any::<i32>()).prop_map(|(path, v)| DeleteRequest {
+        path,
+        version: OptionalVersion(v),
+    }));
+    // Not jute-encoded, and only decoded with `from_bytes`
+    let request = DeleteContainerRequest { path: "/a".to_owned() };
+    assert_eq!(super::to_vec(&request).unwrap(), b"/a");
+    check(path_watch().prop_map(|(path, watch)| GetChildrenRequest { path, watch }));
+    check(strings().prop_map(|children| GetChildrenResponse { children }));
+    check(path_watch().prop_map(|(path, watch)| GetChildren2Request { path, watch }));
+    check((strings(), stat()).prop_map(|(children, stat)| GetChildren2Response { children, stat }));
+    check((string(), any::<i32>()).prop_map(|(path, v)| CheckVersionRequest {
+        path,
+        version: Version(v),
+    }));
+    check(
+        (string(), string(), string(), any::<i64>()).prop_map(|(joining, leaving, members, id)| ReconfigRequest {
+            joining_servers: joining,
+            leaving_servers: leaving,
+            new_members: members,
+            cur_config_id: id,
+        }),
+    );
+    check(bytes().prop_map(|token| SetSASLRequest { token }));
+    check(bytes().prop_map(|token| SetSASLResponse { token }));
+    check(bytes().prop_map(|token| GetSASLRequest { token }));
+    check(string().prop_map(|path| GetMaxChildrenRequest { path }));
+    check(any::<i32>().prop_map(|max| GetMaxChildrenResponse { max }));
+    check((string(), any::<i32>()).prop_map(|(path, max)| SetMaxChildrenRequest { path, max }));
+    check(string().prop_map(|path| SyncRequest { path }));
+    check(string().prop_map(|path| SyncResponse { path }));
+    check(string().prop_map(|path| GetACLRequest { path }));
+    check((acls(), stat()).prop_map(|(acl, stat)| GetACLResponse { acl, stat }));
+    check(
+        (string(), acls(), any::<i32>()).prop_map(|(path, acl, v)| SetACLRequest {
+            path,
+            acl,
+            version: OptionalVersion(v),
+        }),
+    );
+    check(stat().prop_map(|stat| SetACLResponse { stat }));
+    check(path_watch().prop_map(|(path, watch)| ExistsRequest { path, watch }));
+    check(stat().prop_map(|stat| ExistsResponse { stat }));
+    check((variant(), variant(), string()).prop_map(|(typ, state, path)| WatcherEvent { typ, state, path }));
+    check(
+        (any::<i64>(), strings(), strings(), strings()).prop_map(|(zxid, data, exist, child)| SetWatches {
+            relative_zxid: Zxid(zxid),
+            data_watches: data,
+            exist_watches: exist,
+            child_watches: child,
+        }),
+    );
+    check(
+        (any::<i64>(), strings(), strings(), strings(), strings(), strings()).prop_map(
+            |(zxid, data, exist, child, persistent, recursive)| SetWatches2 {
+                relative_zxid: Zxid(zxid),
+                data_watches: data,
+                exist_watches: exist,
+                child_watches: child,
+                persistent_watches: persistent,
+                persistent_recursive_watches: recursive,
+            },
+        ),
+    );
+    check((string(), variant()).prop_map(|(path, typ)| CheckWatchesRequest { path, typ }));
+    check((string(), variant()).prop_map(|(path, typ)| RemoveWatchesRequest { path, typ }));
+
+    let op = one_of(5, |i| match i {
+        0 => create_request().prop_map(Op::Create).boxed(),
+        1 => create_request().prop_map(Op::Create2).boxed(),
+        2 => (string(), any::<i32>())
+            .prop_map(|(path, v)| {
+                Op::Delete(DeleteRequest {
+                    path,
+                    version: OptionalVersion(v),
+                })
+            })
+            .boxed(),
+        3 => (string(), any::<i32>())
+            .prop_map(|(path, v)| {
+                Op::Check(CheckVersionRequest {
+                    path,
+                    version: Version(v),
+                })
+            })
+            .boxed(),
+        _ => path_watch()
+            .prop_map(|(path, watch)| Op::GetData(GetDataRequest { path, watch }))
+            .boxed(),
+    });
+    check(vec(op, 0..4).prop_map(|ops| MultiRequest { ops }));
+
+    let result = one_of(6, |i| match i {
+        0 => string()
+            .prop_map(|path| OpResult::Create(CreateResponse { path }))
+            .boxed(),
+        1 => (string(), stat())
+            .prop_map(|(path, stat)| OpResult::Create2(Create2Response { path, stat }))
+            .boxed(),
+        2 => Just(()).prop_map(|_| OpResult::Delete).boxed(),
+        3 => stat()
+            .prop_map(|stat| OpResult::SetData(SetDataResponse { stat }))
+            .boxed(),
+        4 => strings()
+            .prop_map(|children| OpResult::GetChildren(GetChildrenResponse { children }))
+            .boxed(),
+        _ => variant().prop_map(OpResult::Error).boxed(),
+    });
+    check(vec(result, 0..4).prop_map(|results| MultiResponse { results }));
+}
+
+#[cfg(feature = "persistence")]
+mod txn {
+    use super::*;
+    use crate::persistence::txnlog::*;
+
+    fn create() -> impl Strategy<Value = CreateTxn> {
+        (string(), bytes(), acls(), any::<bool>(), any::<i32>()).prop_map(|(path, data, acl, ephemeral, v)| CreateTxn {
+            path,
+            data,
+            acl,
+            ephemeral,
+            parent_c_version: Version(v),
+        })
+    }
+
+    fn set_data() -> impl Strategy<Value = SetDataTxn> {
+        (string(), bytes(), any::<i32>()).prop_map(|(path, data, v)| SetDataTxn {
+            path,
+            data,
+            version: Version(v),
+        })
+    }
+
+    fn header() -> impl Strategy<Value = TxnHeader> {
+        any::<(i64, i32, i64, u64)>().prop_map(|(session, cxid, zxid, time)| TxnHeader {
+            client_id: SessionId(session),
+            cxid: Xid(cxid),
+            zxid: Zxid(zxid),
+            time: Timestamp(time),
+        })
+    }
+
+    fn multi_op() -> impl Strategy<Value = MultiTxnOperation> {
+        one_of(4, |i| match i {
+            0 => create().prop_map(MultiTxnOperation::Create).boxed(),
+            1 => string()
+                .prop_map(|path| MultiTxnOperation::Delete(DeleteTxn { path }))
+                .boxed(),
+            2 => set_data().prop_map(MultiTxnOperation::SetData).boxed(),
+            _ => variant()
+                .prop_map(|err| MultiTxnOperation::Error(ErrorTxn { err }))
+                .boxed(),
+        })
+    }
+
+    #[test]
+    fn txn_round_trip() {
+        check(header());
+        check(create());
+        check(
+            (string(), bytes(), acls(), any::<i32>()).prop_map(|(path, data, acl, v)| CreateContainerTxn {
+                path,
+                data,
+                acl,
+                parent_c_version: Version(v),
+            }),
+        );
+        check(
+            (string(), bytes(), acls(), any::<i32>(), any::<i64>()).prop_map(|(path, data, acl, v, ttl)| {
+                CreateTTLTxn {
+                    path,
+                    data,
+                    acl,
+                    parent_c_version: Version(v),
+                    ttl,
+                }
+            }),
+        );
+        check(string().prop_map(|path| DeleteTxn { path }));
+        check(set_data());
+        check((string(), any::<i32>()).prop_map(|(path, v)| CheckVersionTxn {
+            path,
+            version: Version(v),
+        }));
+        check((string(), acls(), any::<i32>()).prop_map(|(path, acl, v)| SetACLTxn {
+            path,
+            acl,
+            version: Version(v),
+        }));
+        check((string(), any::<i32>()).prop_map(|(path, max)| SetMaxChildrenTxn { path, max }));
+        check(any::<i32>().prop_map(|t| CreateSessionTxn { time_out: Duration(t) }));
+        check(variant().prop_map(|err| ErrorTxn { err }));
+
+        let op = one_of(5, |i| match i {
+            0 => any::<i32>()
+                .prop_map(|t| TxnOperation::CreateSession(CreateSessionTxn { time_out: Duration(t) }))
+                .boxed(),
+            1 => Just(()).prop_map(|_| TxnOperation::CloseSession).boxed(),
+            2 => create().prop_map(TxnOperation::Create).boxed(),
+            3 => set_data().prop_map(TxnOperation::SetData).boxed(),
+            _ => vec(multi_op(), 0..4)
+                .prop_map(|txns| TxnOperation::Multi(MultiTxn { txns }))
+                .boxed(),
+        });
+        check((header(), op).prop_map(|(header, op)| Txn {
+            header,
+            op,
+            digest: None,
+        }));
+    }
+}
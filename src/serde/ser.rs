@@ -1 +1,493 @@
+use std::collections::HashMap;
+use std::io::Write;
 
+use serde::ser::{self, Serialize};
+
+use byteorder::{BigEndian, WriteBytesExt};
+
+use super::de::OpCodeEnum;
+use super::error::{CodecError, Result};
+use super::EnumEncoding;
+
+use named_type::NamedType;
+
+pub struct Serializer<W> {
+    writer: W,
+
+    /// Struct enum type -> (enum variant name -> enum variant discriminant)
+    enum_mappings: HashMap<&'static str, (HashMap<&'static str, i32>, EnumEncoding)>,
+}
+
+pub fn to_writer<W: Write>(writer: W) -> Serializer<W> {
+    Serializer {
+        writer,
+        enum_mappings: HashMap::new(),
+    }
+}
+
+impl<W: Write> Serializer<W> {
+    /// Get a reference to the underlying writer.
+    pub fn get_ref(&self) -> &W {
+        &self.writer
+    }
+
+    /// Get a mutable reference to the underlying writer.
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.writer
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+
+    /// Add a discriminant mapping for struct enum types.
+    pub fn add_enum_mapping<E: OpCodeEnum, T: NamedType>(&mut self, order: EnumEncoding) {
+        self.enum_mappings
+            .insert(T::short_type_name(), (E::names_to_codes(), order));
+    }
+
+    /// Add mappings for a field-less enum
+    pub fn add_enum<E: OpCodeEnum + NamedType>(&mut self) {
+        self.enum_mappings
+            .insert(E::short_type_name(), (E::names_to_codes(), EnumEncoding::Type));
+    }
+
+    fn discriminant(&self, name: &'static str, variant: &'static str) -> Result<(i32, EnumEncoding)> {
+        let (mappings, order) = self
+            .enum_mappings
+            .get(name)
+            .ok_or_else(|| CodecError::Message(format!("Cannot find mapping for type {}", name)))?;
+
+        let code = mappings
+            .get(variant)
+            .ok_or_else(|| CodecError::Message(format!("Wrong variant for {}: {}", name, variant)))?;
+
+        Ok((*code, *order))
+    }
+
+    /// Serialize a value in a buffer, to know its length.
+    fn serialize_buffered<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<Vec<u8>> {
+        let mut nested = Serializer {
+            writer: Vec::new(),
+            enum_mappings: std::mem::take(&mut self.enum_mappings),
+        };
+        let result = value.serialize(&mut nested);
+        let Serializer { writer, enum_mappings } = nested;
+        self.enum_mappings = enum_mappings;
+        result.map(|_| writer)
+    }
+
+    fn write_length(&mut self, len: usize) -> Result<()> {
+        if len > i32::MAX as usize {
+            return Err(CodecError::TooLarge(len));
+        }
+        self.writer.write_i32::<BigEndian>(len as i32)?;
+        Ok(())
+    }
+}
+
+impl<W: Write> ser::Serializer for &mut Serializer<W> {
+    type Ok = ();
+    type Error = CodecError;
+
+    type SerializeSeq = Self;
+    type SerializeTuple = Self;
+    type SerializeTupleStruct = Self;
+    type SerializeTupleVariant = Self;
+    type SerializeMap = Self;
+    type SerializeStruct = Self;
+    type SerializeStructVariant = Self;
+
+    fn serialize_bool(self, v: bool) -> Result<()> {
+        self.writer.write_u8(v as u8)?;
+        Ok(())
+    }
+
+    fn serialize_i8(self, v: i8) -> Result<()> {
+        self.writer.write_i8(v)?;
+        Ok(())
+    }
+
+    fn serialize_i16(self, _v: i16) -> Result<()> {
+        // Jute only supports 8, 32 & 64 bits integers (see the deserializer)
+        Err(CodecError::UnsupportedType("i16"))
+    }
+
+    fn serialize_i32(self, v: i32) -> Result<()> {
+        self.writer.write_i32::<BigEndian>(v)?;
+        Ok(())
+    }
+
+    fn serialize_i64(self, v: i64) -> Result<()> {
+        self.writer.write_i64::<BigEndian>(v)?;
+        Ok(())
+    }
+
+    fn serialize_u8(self, v: u8) -> Result<()> {
+        self.writer.write_u8(v)?;
+        Ok(())
+    }
+
+    fn serialize_u16(self, _v: u16) -> Result<()> {
+        Err(CodecError::UnsupportedType("u16"))
+    }
+
+    fn serialize_u32(self, v: u32) -> Result<()> {
+        self.writer.write_u32::<BigEndian>(v)?;
+        Ok(())
+    }
+
+    fn serialize_u64(self, v: u64) -> Result<()> {
+        self.writer.write_u64::<BigEndian>(v)?;
+        Ok(())
+    }
+
+    fn serialize_f32(self, v: f32) -> Result<()> {
+        self.writer.write_f32::<BigEndian>(v)?;
+        Ok(())
+    }
+
+    fn serialize_f64(self, v: f64) -> Result<()> {
+        self.writer.write_f64::<BigEndian>(v)?;
+        Ok(())
+    }
+
+    fn serialize_char(self, _v: char) -> Result<()> {
+        Err(CodecError::UnsupportedType("char"))
+    }
+
+    fn serialize_str(self, v: &str) -> Result<()> {
+        self.serialize_bytes(v.as_bytes())
+    }
+
+    fn serialize_bytes(self, v: &[u8]) -> Result<()> {
+        self.write_length(v.len())?;
+        self.writer.write_all(v)?;
+        Ok(())
+    }
+
+    fn serialize_none(self) -> Result<()> {
+        // Absent trailing optional field (see `de::Deserializer::deserialize_option`)
+        Ok(())
+    }
+
+    fn serialize_some<T: ?Sized + Serialize>(self, value: &T) -> Result<()> {
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<()> {
+        // Nothing on the wire
+        Ok(())
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<()> {
+        Ok(())
+    }
+
+    fn serialize_unit_variant(self, name: &'static str, _index: u32, variant: &'static str) -> Result<()> {
+        let (code, order) = self.discriminant(name, variant)?;
+        match order {
+            EnumEncoding::Type => self.writer.write_i32::<BigEndian>(code)?,
+            EnumEncoding::LengthThenType => {
+                self.writer.write_i32::<BigEndian>(4)?;
+                self.writer.write_i32::<BigEndian>(code)?;
+            }
+            EnumEncoding::TypeThenLength => {
+                self.writer.write_i32::<BigEndian>(code)?;
+                self.writer.write_i32::<BigEndian>(0)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn serialize_newtype_struct<T: ?Sized + Serialize>(self, _name: &'static str, value: &T) -> Result<()> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: ?Sized + Serialize>(
+        self,
+        name: &'static str,
+        _index: u32,
+        variant: &'static str,
+        value: &T,
+    ) -> Result<()> {
+        let (code, order) = self.discriminant(name, variant)?;
+        match order {
+            EnumEncoding::Type => {
+                self.writer.write_i32::<BigEndian>(code)?;
+                value.serialize(self)
+            }
+            EnumEncoding::LengthThenType => {
+                let bytes = self.serialize_buffered(value)?;
+                self.write_length(bytes.len() + 4)?;
+                self.writer.write_i32::<BigEndian>(code)?;
+                self.writer.write_all(&bytes)?;
+                Ok(())
+            }
+            EnumEncoding::TypeThenLength => {
+                let bytes = self.serialize_buffered(value)?;
+                self.writer.write_i32::<BigEndian>(code)?;
+                self.serialize_bytes(&bytes)
+            }
+        }
+    }
+
+    fn serialize_seq(self, len: Option<usize>) -> Result<Self::SerializeSeq> {
+        let len = len.ok_or_else(|| CodecError::Message("Sequences must have a known length".to_owned()))?;
+        self.write_length(len)?;
+        Ok(self)
+    }
+
+    fn serialize_tuple(self, _len: usize) -> Result<Self::SerializeTuple> {
+        // A tuple is just a sequence of values
+        Ok(self)
+    }
+
+    fn serialize_tuple_struct(self, _name: &'static str, len: usize) -> Result<Self::SerializeTupleStruct> {
+        self.serialize_tuple(len)
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        name: &'static str,
+        _index: u32,
+        variant: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeTupleVariant> {
+        self.serialize_variant_type(name, variant)?;
+        Ok(self)
+    }
+
+    fn serialize_map(self, len: Option<usize>) -> Result<Self::SerializeMap> {
+        let len = len.ok_or_else(|| CodecError::Message("Maps must have a known length".to_owned()))?;
+        self.write_length(len)?;
+        Ok(self)
+    }
+
+    fn serialize_struct(self, _name: &'static str, _len: usize) -> Result<Self::SerializeStruct> {
+        // Field names are not stored, fields are written in order
+        Ok(self)
+    }
+
+    fn serialize_struct_variant(
+        self,
+        name: &'static str,
+        _index: u32,
+        variant: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeStructVariant> {
+        self.serialize_variant_type(name, variant)?;
+        Ok(self)
+    }
+}
+
+impl<W: Write> Serializer<W> {
+    /// Write the discriminant of a tuple or struct variant. Their fields are written as they are
+    /// serialized, so the encoding can't have a length.
+    fn serialize_variant_type(&mut self, name: &'static str, variant: &'static str) -> Result<()> {
+        match self.discriminant(name, variant)? {
+            (code, EnumEncoding::Type) => {
+                self.writer.write_i32::<BigEndian>(code)?;
+                Ok(())
+            }
+            _ => Err(CodecError::Message(format!(
+                "Only newtype variants of {} can be encoded with a length",
+                name
+            ))),
+        }
+    }
+}
+
+impl<W: Write> ser::SerializeSeq for &mut Serializer<W> {
+    type Ok = ();
+    type Error = CodecError;
+
+    fn serialize_element<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<()> {
+        value.serialize(&mut **self)
+    }
+
+    fn end(self) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl<W: Write> ser::SerializeTuple for &mut Serializer<W> {
+    type Ok = ();
+    type Error = CodecError;
+
+    fn serialize_element<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<()> {
+        value.serialize(&mut **self)
+    }
+
+    fn end(self) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl<W: Write> ser::SerializeTupleStruct for &mut Serializer<W> {
+    type Ok = ();
+    type Error = CodecError;
+
+    fn serialize_field<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<()> {
+        value.serialize(&mut **self)
+    }
+
+    fn end(self) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl<W: Write> ser::SerializeTupleVariant for &mut Serializer<W> {
+    type Ok = ();
+    type Error = CodecError;
+
+    fn serialize_field<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<()> {
+        value.serialize(&mut **self)
+    }
+
+    fn end(self) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl<W: Write> ser::SerializeMap for &mut Serializer<W> {
+    type Ok = ();
+    type Error = CodecError;
+
+    fn serialize_key<T: ?Sized + Serialize>(&mut self, key: &T) -> Result<()> {
+        key.serialize(&mut **self)
+    }
+
+    fn serialize_value<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<()> {
+        value.serialize(&mut **self)
+    }
+
+    fn end(self) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl<W: Write> ser::SerializeStruct for &mut Serializer<W> {
+    type Ok = ();
+    type Error = CodecError;
+
+    fn serialize_field<T: ?Sized + Serialize>(&mut self, _key: &'static str, value: &T) -> Result<()> {
+        value.serialize(&mut **self)
+    }
+
+    fn end(self) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl<W: Write> ser::SerializeStructVariant for &mut Serializer<W> {
+    type Ok = ();
+    type Error = CodecError;
+
+    fn serialize_field<T: ?Sized + Serialize>(&mut self, _key: &'static str, value: &T) -> Result<()> {
+        value.serialize(&mut **self)
+    }
+
+    fn end(self) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+
+    use serde::{Deserialize, Serialize};
+    use serde_derive::{Deserialize, Serialize};
+
+    use named_type_derive::*;
+
+    use crate::proto::OpCode;
+    use crate::serde::CodecError;
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    #[derive(NamedType)]
+    enum FooBar {
+        Create(i32),
+        Delete(String),
+    }
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    struct Record {
+        x: i32,
+        flag: bool,
+        name: String,
+        values: Vec<i64>,
+        ops: Vec<FooBar>,
+    }
+
+    #[test]
+    fn test_ser() {
+        let record = Record {
+            x: 0x01020304,
+            flag: true,
+            name: "ab".to_owned(),
+            values: vec![5],
+            ops: vec![FooBar::Create(6), FooBar::Delete("c".to_owned())],
+        };
+
+        let mut ser = super::to_writer(Vec::new());
+        ser.add_enum_mapping::<OpCode, FooBar>(super::EnumEncoding::TypeThenLength);
+        record.serialize(&mut ser).unwrap();
+        let bytes = ser.into_inner();
+
+        #[rustfmt::skip]
+        let expected: Vec<u8> = vec![
+            0x01, 0x02, 0x03, 0x04, // i32
+            0x01, // bool
+            0x00, 0x00, 0x00, 0x02, 0x61, 0x62, // "ab"
+            0x00, 0x00, 0x00, 0x01, // vector length
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x05, // i64
+            0x00, 0x00, 0x00, 0x02, // vector length
+            0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x04, // Create discriminant and length
+            0x00, 0x00, 0x00, 0x06, // i32
+            0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00, 0x05, // Delete discriminant and length
+            0x00, 0x00, 0x00, 0x01, 0x63, // "c"
+        ];
+        assert_eq!(bytes, expected);
+
+        let mut deser = crate::serde::de::from_reader(&bytes[..]);
+        deser.add_enum_mapping::<OpCode, FooBar>(super::EnumEncoding::TypeThenLength);
+        assert_eq!(Record::deserialize(&mut deser).unwrap(), record);
+    }
+
+    #[test]
+    fn test_enum_encodings() {
+        use super::EnumEncoding::*;
+
+        let value = FooBar::Delete("c".to_owned());
+        let cases = vec![
+            (Type, vec![0, 0, 0, 2]),
+            (LengthThenType, vec![0, 0, 0, 9, 0, 0, 0, 2]),
+            (TypeThenLength, vec![0, 0, 0, 2, 0, 0, 0, 5]),
+        ];
+
+        for (encoding, prefix) in cases {
+            let mut ser = super::to_writer(Vec::new());
+            ser.add_enum_mapping::<OpCode, FooBar>(encoding);
+            value.serialize(&mut ser).unwrap();
+            let bytes = ser.into_inner();
+            assert_eq!(bytes, [&prefix[..], &[0, 0, 0, 1, 0x63]].concat(), "{:?}", encoding);
+
+            let mut deser = crate::serde::de::from_reader(&bytes[..]);
+            deser.add_enum_mapping::<OpCode, FooBar>(encoding);
+            assert_eq!(FooBar::deserialize(&mut deser).unwrap(), value);
+        }
+    }
+
+    #[test]
+    fn test_unsupported_types() {
+        let mut ser = super::to_writer(Vec::new());
+        assert_eq!(1i16.serialize(&mut ser), Err(CodecError::UnsupportedType("i16")));
+        assert_eq!(1u16.serialize(&mut ser), Err(CodecError::UnsupportedType("u16")));
+        assert_eq!('a'.serialize(&mut ser), Err(CodecError::UnsupportedType("char")));
+        assert!(ser.into_inner().is_empty());
+
+        let mut deser = crate::serde::de::from_reader(&[0u8, 1][..]);
+        assert_eq!(i16::deserialize(&mut deser), Err(CodecError::UnsupportedType("i16")));
+    }
+}
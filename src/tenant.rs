@@ -0,0 +1,198 @@
+//! Multi-tenant namespaces: map authenticated identities to a tenant, whose requests are confined
+//! to a subtree and a set of operations.
+//!
+//! Request paths are relative to the tenant's root, like a client chroot, and are rewritten to
+//! absolute paths. The policy only looks at operation codes and paths, so that it can be enforced
+//! by a proxy in front of an ensemble on decoded requests. Operations of multi requests must be
+//! checked one by one.
+
+use crate::error::ParseError;
+use crate::proto::{ErrorCode, OpCode};
+use crate::Id;
+
+/// Data operations a tenant can use by default: everything but `Reconfig`, which changes the
+/// whole ensemble.
+pub const DEFAULT_OPS: &[OpCode] = &[
+    OpCode::Create,
+    OpCode::Create2,
+    OpCode::CreateContainer,
+    OpCode::CreateTTL,
+    OpCode::Delete,
+    OpCode::Exists,
+    OpCode::GetData,
+    OpCode::SetData,
+    OpCode::GetACL,
+    OpCode::SetACL,
+    OpCode::GetChildren,
+    OpCode::GetChildren2,
+    OpCode::Sync,
+    OpCode::Check,
+    OpCode::Multi,
+    OpCode::CheckWatches,
+    OpCode::RemoveWatches,
+];
+
+/// Session management operations, that are always allowed. Paths in `SetWatches` must still be
+/// rewritten.
+fn is_session_op(op: OpCode) -> bool {
+    use OpCode::*;
+    matches!(
+        op,
+        Notification | Ping | Auth | Sasl | SetWatches | SetWatches2 | CreateSession | CloseSession
+    )
+}
+
+#[derive(Debug, Clone)]
+pub struct Tenant {
+    pub name: String,
+    /// Root of the namespace, that paths in requests are relative to
+    pub root: String,
+    /// Identities that belong to this tenant
+    pub ids: Vec<Id>,
+    /// Allowed operations, in addition to session management
+    pub ops: Vec<OpCode>,
+}
+
+impl Tenant {
+    /// A tenant allowed to use `DEFAULT_OPS` in the `root` subtree.
+    pub fn new(name: &str, root: &str) -> Result<Tenant, ParseError> {
+        if !root.starts_with('/') || (root.len() > 1 && root.ends_with('/')) {
+            return Err(ParseError::invalid("tenant root", root));
+        }
+
+        Ok(Tenant {
+            name: name.to_owned(),
+            root: if root == "/" { String::new() } else { root.to_owned() },
+            ids: Vec::new(),
+            ops: DEFAULT_OPS.to_vec(),
+        })
+    }
+
+    pub fn id(mut self, id: Id) -> Tenant {
+        self.ids.push(id);
+        self
+    }
+
+    /// Restrict allowed operations, e.g. to read-only ones.
+    pub fn ops(mut self, ops: &[OpCode]) -> Tenant {
+        self.ops = ops.to_vec();
+        self
+    }
+
+    pub fn allows(&self, op: OpCode) -> bool {
+        is_session_op(op) || self.ops.contains(&op)
+    }
+
+    /// Absolute path of a path in this tenant's namespace. ZooKeeper rejects relative segments
+    /// ("." and ".."), so there's no way to escape the namespace.
+    pub fn rewrite(&self, path: &str) -> Result<String, ErrorCode> {
+        if !path.starts_with('/') {
+            return Err(ErrorCode::BadArguments);
+        }
+        if path == "/" && !self.root.is_empty() {
+            return Ok(self.root.clone());
+        }
+        Ok(format!("{}{}", self.root, path))
+    }
+
+    /// Path in this tenant's namespace of an absolute path, e.g. in responses and watch events.
+    pub fn unrewrite<'a>(&self, path: &'a str) -> Option<&'a str> {
+        let relative = path.strip_prefix(self.root.as_str())?;
+        if relative.is_empty() {
+            Some("/")
+        } else if relative.starts_with('/') {
+            Some(relative)
+        } else {
+            None
+        }
+    }
+
+    /// Check that an operation is allowed, and rewrite its path.
+    pub fn check(&self, op: OpCode, path: &str) -> Result<String, ErrorCode> {
+        if !self.allows(op) {
+            return Err(ErrorCode::NoAuth);
+        }
+        self.rewrite(path)
+    }
+}
+
+/// An ordered list of tenants. A session belongs to the first tenant that has one of its
+/// authenticated identities.
+#[derive(Debug, Clone, Default)]
+pub struct TenantPolicy {
+    tenants: Vec<Tenant>,
+}
+
+impl TenantPolicy {
+    pub fn new() -> TenantPolicy {
+        Self::default()
+    }
+
+    pub fn tenant(mut self, tenant: Tenant) -> TenantPolicy {
+        self.tenants.push(tenant);
+        self
+    }
+
+    pub fn tenants(&self) -> &[Tenant] {
+        &self.tenants
+    }
+
+    /// The tenant of a session, given its authenticated identities
+    pub fn tenant_for(&self, ids: &[Id]) -> Option<&Tenant> {
+        self.tenants.iter().find(|t| t.ids.iter().any(|id| ids.contains(id)))
+    }
+
+    /// Check a request of a session, returning its rewritten path, or the error to send back.
+    /// Sessions that belong to no tenant are rejected.
+    pub fn check(&self, ids: &[Id], op: OpCode, path: &str) -> Result<String, ErrorCode> {
+        self.tenant_for(ids).ok_or(ErrorCode::NoAuth)?.check(op, path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tenant_policy() {
+        let alice = Id::new("digest", "alice:xxx");
+        let bob = Id::new("x509", "CN=bob");
+
+        let policy = TenantPolicy::new()
+            .tenant(Tenant::new("a", "/tenants/a").unwrap().id(alice.clone()))
+            .tenant(
+                Tenant::new("b", "/tenants/b")
+                    .unwrap()
+                    .id(bob.clone())
+                    .ops(&[OpCode::GetData, OpCode::Exists]),
+            );
+
+        let ip = Id::new("ip", "10.0.0.1");
+        let alice_ids = vec![ip.clone(), alice];
+        assert_eq!(policy.tenant_for(&alice_ids).unwrap().name, "a");
+        assert_eq!(policy.check(&alice_ids, OpCode::Create, "/x").unwrap(), "/tenants/a/x");
+        assert_eq!(
+            policy.check(&alice_ids, OpCode::GetChildren, "/").unwrap(),
+            "/tenants/a"
+        );
+        assert_eq!(policy.check(&alice_ids, OpCode::Reconfig, "/"), Err(ErrorCode::NoAuth));
+        assert_eq!(
+            policy.check(&alice_ids, OpCode::Exists, "x"),
+            Err(ErrorCode::BadArguments)
+        );
+
+        assert!(policy.check(std::slice::from_ref(&bob), OpCode::GetData, "/y").is_ok());
+        assert_eq!(policy.check(&[bob], OpCode::SetData, "/y"), Err(ErrorCode::NoAuth));
+        assert_eq!(policy.check(&[ip], OpCode::GetData, "/y"), Err(ErrorCode::NoAuth));
+
+        let tenant = &policy.tenants()[0];
+        assert_eq!(tenant.unrewrite("/tenants/a/x"), Some("/x"));
+        assert_eq!(tenant.unrewrite("/tenants/a"), Some("/"));
+        assert_eq!(tenant.unrewrite("/tenants/ab"), None);
+
+        let root = Tenant::new("root", "/").unwrap();
+        assert_eq!(root.rewrite("/x").unwrap(), "/x");
+        assert_eq!(root.unrewrite("/x"), Some("/x"));
+        assert!(Tenant::new("bad", "/a/").is_err());
+    }
+}